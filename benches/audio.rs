@@ -0,0 +1,100 @@
+//! Benchmarks for the real-time audio path.
+//!
+//! Run with `cargo bench`, compare against a baseline with
+//! `cargo bench -- --save-baseline before` / `--baseline before`.
+
+use audiopus::coder::Encoder;
+use audiopus::{ Application, Channels, SampleRate };
+use criterion::{ black_box, criterion_group, criterion_main, BenchmarkId, Criterion };
+use slog::{ o, Discard, Logger };
+use tsproto_packets::packets::CodecType;
+
+// Needed by the included modules, mirrors main.rs. Their test modules are
+// also built by `cargo clippy --all-targets`, hence the allows below.
+use tsclientlib::ClientId;
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+const SAMPLE_RATE: usize = 48000;
+const STEREO_20MS: usize = 1920;
+
+#[path = "../src/audio.rs"]
+#[allow(dead_code, unused_imports)]
+mod audio;
+#[path = "../src/pool.rs"]
+#[allow(dead_code, unused_imports)]
+mod pool;
+#[path = "../src/discord_audiohandler.rs"]
+#[allow(dead_code, unused_imports)]
+mod discord_audiohandler;
+
+use discord_audiohandler::AudioHandler;
+
+fn encoder() -> Encoder {
+    Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Voip).unwrap()
+}
+
+fn sine_frame(freq: f32, offset: usize) -> Vec<f32> {
+    (0..STEREO_20MS)
+        .map(|i| {
+            let t = ((offset + i / 2) as f32) / 48000.0;
+            0.5 * (2.0 * std::f32::consts::PI * freq * t).sin()
+        })
+        .collect()
+}
+
+/// One second of a sine as Opus packets, replayed in a loop by the benchmarks.
+fn opus_sine(freq: f32) -> Vec<Vec<u8>> {
+    let encoder = encoder();
+    (0..50)
+        .map(|n| {
+            let mut out = [0; MAX_OPUS_FRAME_SIZE];
+            let len = encoder.encode_float(&sine_frame(freq, n * (STEREO_20MS / 2)), &mut out).unwrap();
+            out[..len].to_vec()
+        })
+        .collect()
+}
+
+/// Feed one packet per talker and mix one 20 ms frame, like a bridge tick.
+fn mix_sources(c: &mut Criterion) {
+    let mut group = c.benchmark_group("discord_fill_buffer");
+    for talkers in [1u32, 4, 16] {
+        let packets: Vec<_> = (0..talkers).map(|t| opus_sine(220.0 + (t as f32) * 55.0)).collect();
+        let mut handler = AudioHandler::<u32>::new(Logger::root(Discard, o!()));
+        let pool = handler.frame_pool();
+        let mut sequence = 0u16;
+        let mut buf = vec![0.0; STEREO_20MS];
+
+        group.bench_with_input(BenchmarkId::from_parameter(talkers), &talkers, |b, _| {
+            b.iter(|| {
+                for (ssrc, stream) in packets.iter().enumerate() {
+                    let packet = pool.frame_from(&stream[(sequence as usize) % stream.len()]);
+                    let _ = handler.handle_packet(ssrc as u32, sequence, packet);
+                }
+                sequence = sequence.wrapping_add(1);
+                handler.fill_buffer(black_box(&mut buf));
+            })
+        });
+    }
+    group.finish();
+}
+
+fn gain_clamp(c: &mut Criterion) {
+    let frame = sine_frame(440.0, 0);
+    c.bench_function("apply_gain_clamped", |b| {
+        b.iter_batched_ref(
+            || frame.clone(),
+            |buf| audio::apply_gain_clamped(black_box(buf), 3.0),
+            criterion::BatchSize::SmallInput
+        )
+    });
+}
+
+fn encode(c: &mut Criterion) {
+    let encoder = encoder();
+    let frame = sine_frame(440.0, 0);
+    c.bench_function("encode_ts_packet", |b| {
+        b.iter(|| audio::encode_ts_packet(&encoder, black_box(&frame), CodecType::OpusMusic).unwrap())
+    });
+}
+
+criterion_group!(benches, mix_sources, gain_clamp, encode);
+criterion_main!(benches);
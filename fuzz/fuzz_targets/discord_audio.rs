@@ -0,0 +1,36 @@
+#![no_main]
+//! Arbitrary RTP packets through the parser into the Discord jitter buffer and mixer.
+
+use libfuzzer_sys::fuzz_target;
+use slog::{ o, Discard, Logger };
+
+// Needed by the included modules, mirrors main.rs
+use tsclientlib::ClientId;
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+const SAMPLE_RATE: usize = 48000;
+
+#[path = "../../src/audio.rs"]
+#[allow(dead_code, unused_imports)]
+mod audio;
+#[path = "../../src/pool.rs"]
+#[allow(dead_code)]
+mod pool;
+#[path = "../../src/discord_audiohandler.rs"]
+#[allow(dead_code)]
+mod discord_audiohandler;
+#[path = "../../src/rtp.rs"]
+mod rtp;
+
+fuzz_target!(|data: &[u8]| {
+    let mut handler = discord_audiohandler::AudioHandler::<u32>::new(Logger::root(Discard, o!()));
+    // Split the input into several packets, so queue state is exercised too
+    for chunk in data.split(|b| *b == 0xff) {
+        if let Some(packet) = rtp::parse(chunk) {
+            let _ = handler.handle_packet(packet.ssrc, packet.sequence, packet.payload.to_vec());
+        }
+        let mut buf = [0.0; 1920];
+        // Mirrors the Discord to TeamSpeak tick, `fill_buffer` is test only
+        handler.fill_buffer_with_proc(&mut buf, |_, _| {});
+        handler.apply_global_volume(&mut buf);
+    }
+});
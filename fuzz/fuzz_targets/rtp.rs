@@ -0,0 +1,15 @@
+#![no_main]
+//! Arbitrary bytes into the RTP parser used for Discord voice packets.
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/rtp.rs"]
+mod rtp;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(packet) = rtp::parse(data) {
+        // The payload has to be a non-empty suffix of the input
+        assert!(!packet.payload.is_empty());
+        assert!(data.ends_with(packet.payload));
+    }
+});
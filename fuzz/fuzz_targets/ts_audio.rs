@@ -0,0 +1,26 @@
+#![no_main]
+//! Arbitrary server to client packets into the TeamSpeak audio handler.
+
+use libfuzzer_sys::fuzz_target;
+use slog::{ o, Discard, Logger };
+use tsclientlib::audio::AudioHandler;
+use tsclientlib::ClientId;
+use tsproto_packets::packets::{ AudioData, Direction, InAudioBuf };
+
+fuzz_target!(|data: &[u8]| {
+    let mut handler = AudioHandler::<ClientId>::new(Logger::root(Discard, o!()));
+    for chunk in data.split(|b| *b == 0xff) {
+        let packet = match InAudioBuf::try_new(Direction::S2C, chunk.to_vec()) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        // Mirrors TsEventHandler::handle
+        let from = match packet.data().data() {
+            AudioData::S2C { from, .. } | AudioData::S2CWhisper { from, .. } => *from,
+            _ => continue,
+        };
+        let _ = handler.handle_packet(ClientId(from), packet);
+        let mut buf = [0.0; 1920];
+        handler.fill_buffer(&mut buf);
+    }
+});
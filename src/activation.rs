@@ -0,0 +1,63 @@
+//! Idling the bridge while one side is empty.
+//!
+//! Nobody would hear the audio anyway, so while no person is on one of the
+//! sides nothing is decoded, encoded or sent, the connections stay up.
+
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Duration;
+
+/// How often both sides are checked for people.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether the bridge idles, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct Activation {
+    idle: Arc<AtomicBool>,
+    /// Whether the last check found people on both sides, `None` before the first.
+    active: Arc<StdMutex<Option<bool>>>,
+}
+
+impl Activation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set while nothing should be processed.
+    pub fn idle_flag(&self) -> Arc<AtomicBool> {
+        self.idle.clone()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    /// Activate if people are on both sides, idle otherwise.
+    ///
+    /// `Some(active)` if that changed, not for the first check.
+    pub fn update(&self, discord_people: bool, ts_people: bool) -> Option<bool> {
+        let active = discord_people && ts_people;
+        self.idle.store(!active, Ordering::Relaxed);
+        let previous = self.active.lock().expect("Can't lock activation!").replace(active);
+        previous.filter(|&previous| previous != active).map(|_| active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activation_needs_both_sides() {
+        let activation = Activation::new();
+        assert!(!activation.is_idle());
+        assert_eq!(activation.update(true, false), None);
+        assert!(activation.is_idle());
+        assert_eq!(activation.update(false, true), None);
+        assert_eq!(activation.update(true, true), Some(true));
+        assert!(!activation.is_idle());
+        assert_eq!(activation.update(true, true), None);
+        assert_eq!(activation.update(false, true), Some(false));
+        assert!(activation.idle_flag().load(Ordering::Relaxed));
+    }
+}
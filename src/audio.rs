@@ -0,0 +1,962 @@
+//! Audio processing helpers shared by both pipelines.
+//!
+//! Nothing in here touches a Discord or TeamSpeak connection, so everything can
+//! be fed with synthetic fixtures in tests.
+
+use std::collections::{ HashMap, VecDeque };
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+use std::time::Duration;
+
+use audiopus::{ coder::Encoder, Channels };
+use serde::{ Deserialize, Deserializer };
+use tsproto_packets::packets::{ AudioData, CodecType, OutAudio, OutPacket };
+
+use crate::{ MAX_OPUS_FRAME_SIZE, SAMPLE_RATE };
+
+/// Frame durations Opus and TeamSpeak both accept.
+pub const FRAME_DURATIONS_MS: [u64; 4] = [10, 20, 40, 60];
+
+/// Duration of the frames the bridge encodes for TeamSpeak.
+///
+/// Discord always sends and expects 20 ms frames, this only changes how
+/// often the Discord mix is taken and sent to TeamSpeak.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "u64")]
+pub struct FrameDuration(u64);
+
+impl FrameDuration {
+    pub fn interval(self) -> Duration {
+        Duration::from_millis(self.0)
+    }
+
+    /// Interleaved stereo samples in one frame.
+    pub fn stereo_samples(self) -> usize {
+        (SAMPLE_RATE * 2 * (self.0 as usize)) / 1000
+    }
+}
+
+impl Default for FrameDuration {
+    fn default() -> Self {
+        Self(20)
+    }
+}
+
+impl TryFrom<u64> for FrameDuration {
+    type Error = String;
+
+    fn try_from(ms: u64) -> Result<Self, Self::Error> {
+        if FRAME_DURATIONS_MS.contains(&ms) {
+            Ok(Self(ms))
+        } else {
+            Err(format!("frame size must be one of {:?} ms, got {}", FRAME_DURATIONS_MS, ms))
+        }
+    }
+}
+
+/// Samples as the little endian bytes songbird reads raw audio from.
+pub fn to_le_bytes(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// Highest volume the bridge plays at, as a factor.
+pub const MAX_VOLUME: f32 = 2.0;
+
+/// Gain of a volume written in decibels like `-6dB`, `None` if it is not.
+pub fn parse_db(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let db = text.get(..text.len().checked_sub(2)?)?;
+    if !text[db.len()..].eq_ignore_ascii_case("db") {
+        return None;
+    }
+    let db = db.trim().parse::<f32>().ok().filter(|db| !db.is_nan())?;
+    Some(db_to_gain(db))
+}
+
+/// Parse a volume given as a factor like `0.5` or in decibels like `-6dB`.
+pub fn parse_volume(text: &str) -> Result<f32, String> {
+    let gain = match parse_db(text) {
+        Some(gain) => gain,
+        None => text.trim().parse::<f32>().map_err(|_| format!("invalid volume {:?}, use a factor like 0.5 or decibels like -6dB", text))?,
+    };
+    if (0.0..=MAX_VOLUME).contains(&gain) {
+        Ok(gain)
+    } else {
+        Err(format!("volume must be 0 to {} (+{:.1} dB), got {}", MAX_VOLUME, gain_to_db(MAX_VOLUME), text.trim()))
+    }
+}
+
+/// A volume for people, like `50% (-6.0 dB)`.
+pub fn format_volume(gain: f32) -> String {
+    format!("{:.0}% ({:+.1} dB)", gain * 100.0, gain_to_db(gain))
+}
+
+/// Read a volume from the config as a number or a string like `"-6dB"`.
+pub fn deserialize_volume<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Volume {
+        Factor(f32),
+        Text(String),
+    }
+
+    let text = match Volume::deserialize(deserializer)? {
+        Volume::Factor(factor) => factor.to_string(),
+        Volume::Text(text) => text,
+    };
+    parse_volume(&text).map_err(serde::de::Error::custom)
+}
+
+/// Multiply every sample by `gain` and clamp the result to `-1.0..=1.0`.
+pub fn apply_gain_clamped(samples: &mut [f32], gain: f32) {
+    for sample in samples {
+        *sample = (*sample * gain).clamp(-1.0, 1.0);
+    }
+}
+
+/// The highest absolute sample value.
+pub fn peak(samples: &[f32]) -> f32 {
+    samples
+        .iter()
+        .map(|s| s.abs())
+        .fold(0.0f32, f32::max)
+}
+
+/// `[ducking]` section of the config file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DuckingConfig {
+    /// Voice level in dBFS above which music is pushed down.
+    pub threshold_db: f32,
+    /// How strongly voice above the threshold pushes the music down.
+    pub ratio: f32,
+    /// The most the music is pushed down, `0` turns ducking off.
+    pub max_reduction_db: f32,
+    /// How fast the music goes down once somebody talks.
+    pub attack_ms: f32,
+    /// How fast it comes back after they stop.
+    pub release_ms: f32,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -45.0,
+            ratio: 4.0,
+            max_reduction_db: 15.0,
+            attack_ms: 10.0,
+            release_ms: 400.0,
+        }
+    }
+}
+
+/// Sidechain compression of music, keyed on the voice mix.
+///
+/// Voices push the music down, so both playing at once stays intelligible.
+#[derive(Debug)]
+pub struct Ducker {
+    config: DuckingConfig,
+    /// Current reduction in dB, positive.
+    reduction_db: f32,
+}
+
+impl Ducker {
+    pub fn new(config: DuckingConfig) -> Self {
+        Self { config, reduction_db: 0.0 }
+    }
+
+    /// Duck by `config` from now on, starting from the current reduction.
+    pub fn set_config(&mut self, config: DuckingConfig) {
+        self.config = config;
+    }
+
+    /// Music gain at the start and end of the frame `voice` was mixed for.
+    pub fn process(&mut self, voice: &[f32], frame: FrameDuration) -> (f32, f32) {
+        let start = db_to_gain(-self.reduction_db);
+        let over = rms_db(voice) - self.config.threshold_db;
+        let target = if over > 0.0 && self.config.ratio > 0.0 {
+            (over * (1.0 - 1.0 / self.config.ratio)).min(self.config.max_reduction_db.max(0.0))
+        } else {
+            0.0
+        };
+        let time_ms = if target > self.reduction_db { self.config.attack_ms } else { self.config.release_ms };
+        let frame_ms = frame.interval().as_secs_f32() * 1000.0;
+        let step = if time_ms > 0.0 { 1.0 - (-frame_ms / time_ms).exp() } else { 1.0 };
+        self.reduction_db += (target - self.reduction_db) * step;
+        (start, db_to_gain(-self.reduction_db))
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    (10.0f32).powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+/// Level of `samples` in dBFS, very low for silence.
+fn rms_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / (samples.len() as f32);
+    10.0 * mean_square.max(1e-12).log10()
+}
+
+/// How far above the measured background noise a noise gate opens.
+const NOISE_GATE_MARGIN_DB: f32 = 6.0;
+/// Noise gates open no higher than this, so a noisy room doesn't cut speech.
+const MAX_NOISE_GATE_DB: f32 = -30.0;
+/// Interleaved samples a noise gate stays open after the voice went below it, 200 ms.
+const NOISE_GATE_HOLD: usize = 2 * 48_000 / 5;
+/// Gain kept per frame while a noise gate closes.
+const NOISE_GATE_RELEASE: f32 = 0.5;
+
+/// Where to gate a speaker whose microphone picked up `noise` while they were quiet, `None` without any.
+pub fn noise_gate_threshold(noise: &[f32]) -> Option<f32> {
+    (!noise.is_empty()).then(|| (rms_db(noise) + NOISE_GATE_MARGIN_DB).min(MAX_NOISE_GATE_DB))
+}
+
+/// Mutes a speaker between words, while their level stays below a threshold.
+#[derive(Clone, Debug)]
+pub struct NoiseGate {
+    threshold_db: f32,
+    /// Interleaved samples left until it starts closing.
+    hold: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+    /// A closed gate opening above `threshold_db`.
+    pub fn new(threshold_db: f32) -> Self {
+        Self { threshold_db, hold: 0, gain: 0.0 }
+    }
+
+    pub fn threshold_db(&self) -> f32 {
+        self.threshold_db
+    }
+
+    /// Gain for the frame `samples`, opening at once and closing over a few frames.
+    pub fn gain(&mut self, samples: &[f32]) -> f32 {
+        if rms_db(samples) >= self.threshold_db {
+            self.hold = NOISE_GATE_HOLD;
+            self.gain = 1.0;
+        } else if self.hold > 0 {
+            self.hold = self.hold.saturating_sub(samples.len());
+        } else {
+            self.gain *= NOISE_GATE_RELEASE;
+        }
+        self.gain
+    }
+}
+
+/// Interleaved stereo from `channels` interleaved channels.
+///
+/// Mono is duplicated to both sides, further channels beyond the first two
+/// are dropped.
+pub fn to_stereo(samples: &[f32], channels: usize, out: &mut Vec<f32>) {
+    match channels {
+        0 => {}
+        1 => out.extend(samples.iter().flat_map(|&s| std::iter::once(s).chain(std::iter::once(s)))),
+        2 => out.extend_from_slice(samples),
+        _ => out.extend(samples.chunks_exact(channels).flat_map(|frame| frame[..2].iter().copied())),
+    }
+}
+
+/// Channels an Opus packet was encoded with, from its TOC byte.
+///
+/// Decoding always gives stereo, mono is duplicated to both sides.
+pub fn opus_channels(packet: &[u8]) -> Option<usize> {
+    match audiopus::packet::nb_channels(packet).ok()? {
+        Channels::Mono => Some(1),
+        Channels::Stereo => Some(2),
+        _ => None,
+    }
+}
+
+/// Converts interleaved stereo to [`SAMPLE_RATE`] by linear interpolation.
+///
+/// Not hi-fi, but cheap and good enough for music under voice chat.
+#[derive(Debug)]
+pub struct LinearResampler {
+    /// Input frames per output frame.
+    step: f64,
+    /// Position of the next output frame, 0 being `last`.
+    position: f64,
+    /// The final frame of the previous input.
+    last: [f32; 2],
+}
+
+impl LinearResampler {
+    pub fn new(input_rate: u32) -> Self {
+        Self {
+            step: f64::from(input_rate) / (SAMPLE_RATE as f64),
+            position: 1.0,
+            last: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let frames = input.len() / 2;
+        if frames == 0 {
+            return;
+        }
+        // Interpolate between frame i and i + 1 of `last` followed by `input`
+        while self.position < (frames as f64) {
+            let i = self.position as usize;
+            let t = (self.position - (i as f64)) as f32;
+            let a = if i == 0 { self.last } else { [input[2 * i - 2], input[2 * i - 1]] };
+            let b = [input[2 * i], input[2 * i + 1]];
+            out.push(a[0] + (b[0] - a[0]) * t);
+            out.push(a[1] + (b[1] - a[1]) * t);
+            self.position += self.step;
+        }
+        self.position -= frames as f64;
+        self.last = [input[2 * frames - 2], input[2 * frames - 1]];
+    }
+}
+
+/// Encode one frame of interleaved stereo samples into an Opus packet for TeamSpeak.
+///
+/// `None` for silence the encoder left out with DTX, a one byte packet would
+/// end the stream for TeamSpeak.
+pub fn encode_ts_packet(encoder: &Encoder, pcm: &[f32], codec: CodecType) -> audiopus::Result<Option<OutPacket>> {
+    let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
+    let length = encoder.encode_float(pcm, &mut encoded)?;
+    if length <= 1 {
+        return Ok(None);
+    }
+    Ok(Some(
+        OutAudio::new(
+            &(AudioData::C2S {
+                id: 0,
+                codec,
+                data: &encoded[..length],
+            })
+        )
+    ))
+}
+
+/// Bytes of one interleaved stereo `f32` sample, audio is only dropped in whole ones.
+const PCM_FRAME_BYTES: usize = 8;
+/// Bytes of 48 kHz stereo `f32` audio per millisecond.
+pub const PCM_BYTES_PER_MS: usize = 48 * PCM_FRAME_BYTES;
+
+/// What a full queue between pipeline stages gives up.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued audio, so the delay stays bounded.
+    #[default]
+    DropOldest,
+    /// Drop what doesn't fit anymore, so queued audio plays without a jump.
+    DropNewest,
+    /// Stop taking audio at the high watermark until drained to the low one.
+    Pause,
+}
+
+/// Limits of the buffer of one direction, `[buffers.<direction>]` in the config.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferLimits {
+    /// Most audio held, in ms.
+    pub max_ms: u32,
+    /// With [`OverflowPolicy::Pause`], audio is no longer taken once this much is held.
+    pub high_watermark_ms: Option<u32>,
+    /// With [`OverflowPolicy::Pause`], audio is taken again once drained to this.
+    pub low_watermark_ms: u32,
+    /// The direction's own default if unset.
+    pub overflow: Option<OverflowPolicy>,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self { max_ms: 1000, high_watermark_ms: None, low_watermark_ms: 500, overflow: None }
+    }
+}
+
+impl BufferLimits {
+    /// The high watermark, `max_ms` if unset.
+    pub fn high_watermark_ms(&self) -> u32 {
+        self.high_watermark_ms.unwrap_or(self.max_ms)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_ms < 20 {
+            return Err(format!("max_ms must be at least one frame (20), not {}", self.max_ms));
+        }
+        if self.low_watermark_ms > self.high_watermark_ms() || self.high_watermark_ms() > self.max_ms {
+            return Err(
+                format!(
+                    "watermarks must be low_watermark_ms <= high_watermark_ms <= max_ms, not {} <= {} <= {}",
+                    self.low_watermark_ms,
+                    self.high_watermark_ms(),
+                    self.max_ms
+                )
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `[buffers]` of the config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    /// Per speaker jitter buffers of Discord audio, drop the newest packets by default.
+    pub discord_to_ts: BufferLimits,
+    /// Mixed TeamSpeak audio waiting for songbird, drops the oldest audio by default.
+    pub ts_to_discord: BufferLimits,
+}
+
+/// Counts what a full queue dropped. Cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// FIFO of raw PCM bytes with an upper bound.
+///
+/// At most `max_bytes` are queued, what is dropped beyond that depends on the
+/// [`OverflowPolicy`] and is counted in bytes.
+#[derive(Debug)]
+pub struct PcmFifo {
+    buffer: VecDeque<u8>,
+    max_bytes: usize,
+    policy: OverflowPolicy,
+    dropped: DropCounter,
+    /// Fill at which [`OverflowPolicy::Pause`] stops and resumes taking audio.
+    watermarks: (usize, usize),
+    paused: bool,
+}
+
+impl PcmFifo {
+    pub fn new(max_bytes: usize, policy: OverflowPolicy, dropped: DropCounter) -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(32768),
+            max_bytes,
+            policy,
+            dropped,
+            watermarks: (max_bytes, 0),
+            paused: false,
+        }
+    }
+
+    /// The fifo `limits` ask for, `policy` unless they set one.
+    pub fn with_limits(limits: &BufferLimits, policy: OverflowPolicy, dropped: DropCounter) -> Self {
+        let bytes = |ms: u32| (ms as usize) * PCM_BYTES_PER_MS;
+        let mut fifo = Self::new(bytes(limits.max_ms), limits.overflow.unwrap_or(policy), dropped);
+        fifo.watermarks = (bytes(limits.high_watermark_ms()), bytes(limits.low_watermark_ms));
+        fifo
+    }
+
+    /// Whether more audio should be pushed, only paused by [`OverflowPolicy::Pause`].
+    pub fn accepts(&mut self) -> bool {
+        if self.policy != OverflowPolicy::Pause {
+            return true;
+        }
+        let (high, low) = self.watermarks;
+        if self.buffer.len() >= high {
+            self.paused = true;
+        } else if self.buffer.len() <= low {
+            self.paused = false;
+        }
+        !self.paused
+    }
+
+    /// Append data, dropping audio by the policy if the bound is exceeded.
+    pub fn push(&mut self, data: &[u8]) {
+        let over = (self.buffer.len() + data.len()).saturating_sub(self.max_bytes);
+        if over == 0 {
+            self.buffer.extend(data);
+            return;
+        }
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                self.buffer.extend(data);
+                let n = over.next_multiple_of(PCM_FRAME_BYTES).min(self.buffer.len());
+                self.buffer.drain(..n);
+                self.dropped.add(n as u64);
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::Pause => {
+                let keep = (data.len() - over) / PCM_FRAME_BYTES * PCM_FRAME_BYTES;
+                self.buffer.extend(&data[..keep]);
+                self.dropped.add((data.len() - keep) as u64);
+            }
+        }
+    }
+
+    /// Bytes queued.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Read into `buf`.
+    ///
+    /// If nothing is queued, `buf` is filled with silence and its full length
+    /// is returned, so the consumer never sees an end of stream.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let available = self.buffer.len().min(buf.len());
+        if available == 0 {
+            buf.fill(0);
+            return buf.len();
+        }
+        for (dst, src) in buf.iter_mut().zip(self.buffer.drain(..available)) {
+            *dst = src;
+        }
+        available
+    }
+}
+
+/// Longest delay `/delay-user` adds to a source.
+pub const MAX_SOURCE_DELAY: Duration = Duration::from_secs(2);
+
+/// Delay line of one source, always exactly the delay long.
+#[derive(Debug)]
+struct DelayLine {
+    samples: VecDeque<f32>,
+    /// What the source said this frame, at its volume.
+    input: Vec<f32>,
+    delay: Duration,
+}
+
+/// Extra delay for some sources of a mix, e.g. somebody connected on both sides.
+///
+/// A mixer [`push`](Self::push)es delayed sources here instead of mixing them
+/// itself and adds the [`mix_into`](Self::mix_into) output once per frame.
+#[derive(Debug)]
+pub struct SourceDelays<Id> {
+    lines: HashMap<Id, DelayLine>,
+}
+
+impl<Id> Default for SourceDelays<Id> {
+    fn default() -> Self {
+        Self { lines: HashMap::new() }
+    }
+}
+
+impl<Id: Eq + Hash> SourceDelays<Id> {
+    /// Delay `id` by `delay`, at most [`MAX_SOURCE_DELAY`]. No delay drops what is held back.
+    pub fn set(&mut self, id: Id, delay: Duration) {
+        let delay = delay.min(MAX_SOURCE_DELAY);
+        if delay.is_zero() {
+            self.lines.remove(&id);
+            return;
+        }
+        if self.lines.get(&id).is_some_and(|line| line.delay == delay) {
+            return;
+        }
+        let len = (delay.as_secs_f32() * ((SAMPLE_RATE * 2) as f32)) as usize & !1;
+        self.lines.insert(id, DelayLine { samples: std::iter::repeat_n(0.0, len).collect(), input: Vec::new(), delay });
+    }
+
+    pub fn is_delayed(&self, id: &Id) -> bool {
+        self.lines.contains_key(id)
+    }
+
+    /// Keep only the delays of sources `keep` is true for.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Id) -> bool) {
+        self.lines.retain(|id, _| keep(id));
+    }
+
+    /// Hold `samples` of `id` back at `volume`, `false` if `id` is not delayed and should be mixed now.
+    pub fn push(&mut self, id: &Id, samples: &[f32], volume: f32) -> bool {
+        let Some(line) = self.lines.get_mut(id) else {
+            return false;
+        };
+        if line.input.len() < samples.len() {
+            line.input.resize(samples.len(), 0.0);
+        }
+        for (input, sample) in line.input.iter_mut().zip(samples) {
+            *input += sample * volume;
+        }
+        true
+    }
+
+    /// Add what comes out of the delay lines for this frame to `buf`.
+    ///
+    /// Called once per frame, sources that said nothing in it are held back as silence.
+    pub fn mix_into(&mut self, buf: &mut [f32]) {
+        let len = buf.len();
+        for line in self.lines.values_mut() {
+            line.input.resize(len, 0.0);
+            line.samples.extend(line.input.drain(..));
+            for (out, delayed) in buf.iter_mut().zip(line.samples.drain(..len)) {
+                *out += delayed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use audiopus::coder::Decoder;
+    use audiopus::{ Application, Channels, SampleRate };
+    use slog::{ o, Discard, Logger };
+
+    use crate::discord_audiohandler::AudioHandler;
+    use crate::STEREO_20MS;
+
+    pub fn logger() -> Logger {
+        Logger::root(Discard, o!())
+    }
+
+    pub fn encoder() -> Encoder {
+        Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Voip).unwrap()
+    }
+
+    /// 20 ms of an interleaved stereo sine, continuing at sample `offset`.
+    pub fn sine_frame(freq: f32, amplitude: f32, offset: usize) -> Vec<f32> {
+        (0..STEREO_20MS)
+            .map(|i| {
+                let t = ((offset + i / 2) as f32) / 48000.0;
+                amplitude * (2.0 * std::f32::consts::PI * freq * t).sin()
+            })
+            .collect()
+    }
+
+    /// Encode `frames` consecutive 20 ms frames of a sine into Opus packets.
+    pub fn opus_sine(freq: f32, frames: usize) -> Vec<Vec<u8>> {
+        let encoder = encoder();
+        (0..frames)
+            .map(|n| {
+                let pcm = sine_frame(freq, 0.5, n * (STEREO_20MS / 2));
+                let mut out = [0; MAX_OPUS_FRAME_SIZE];
+                let len = encoder.encode_float(&pcm, &mut out).unwrap();
+                out[..len].to_vec()
+            })
+            .collect()
+    }
+
+    /// Decode packets with a fresh decoder, as reference output.
+    fn reference_decode(packets: &[Vec<u8>]) -> Vec<Vec<f32>> {
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        packets
+            .iter()
+            .map(|p| {
+                let mut out = vec![0.0; STEREO_20MS];
+                let len = decoder.decode_float(Some(p), &mut out, false).unwrap();
+                assert_eq!(len * 2, STEREO_20MS);
+                out
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gain_is_applied_and_clamped() {
+        let mut samples = [0.1, -0.2, 0.5, -0.9, 0.0];
+        apply_gain_clamped(&mut samples, 3.0);
+        assert_eq!(samples, [0.1 * 3.0, -0.2 * 3.0, 1.0, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn peak_uses_absolute_values() {
+        assert_eq!(peak(&[0.1, -0.7, 0.3]), 0.7);
+        assert_eq!(peak(&[]), 0.0);
+    }
+
+    #[test]
+    fn frame_duration_accepts_opus_sizes_only() {
+        assert_eq!(FrameDuration::default().stereo_samples(), STEREO_20MS);
+        assert_eq!(FrameDuration::try_from(10).unwrap().stereo_samples(), 960);
+        assert_eq!(FrameDuration::try_from(60).unwrap().interval(), Duration::from_millis(60));
+        assert!(FrameDuration::try_from(30).is_err());
+        assert!(FrameDuration::try_from(0).is_err());
+    }
+
+    #[test]
+    fn volumes_are_factors_or_decibels() {
+        assert_eq!(parse_volume("0.5"), Ok(0.5));
+        assert!((parse_volume("-6dB").unwrap() - 0.501).abs() < 0.001);
+        assert!((parse_volume("+6 db").unwrap() - 1.995).abs() < 0.001);
+        assert_eq!(parse_volume("0dB"), Ok(1.0));
+        assert_eq!(parse_volume("-inf dB"), Ok(0.0));
+        assert!(parse_volume("10dB").is_err());
+        assert!(parse_volume("-1").is_err());
+        assert!(parse_volume("loud").is_err());
+        assert!(parse_volume("NaN dB").is_err());
+        assert_eq!(format_volume(0.5), "50% (-6.0 dB)");
+        assert_eq!(format_volume(1.0), "100% (+0.0 dB)");
+    }
+
+    #[test]
+    fn fifo_reads_in_order_and_pads_silence() {
+        let mut fifo = PcmFifo::new(16, OverflowPolicy::DropOldest, DropCounter::default());
+        fifo.push(&[1, 2, 3]);
+        let mut buf = [9; 2];
+        assert_eq!(fifo.read(&mut buf), 2);
+        assert_eq!(buf, [1, 2]);
+        let mut buf = [9; 4];
+        assert_eq!(fifo.read(&mut buf), 1);
+        assert_eq!(buf[0], 3);
+        assert_eq!(fifo.read(&mut buf), 4);
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test]
+    fn fifo_drops_oldest_when_full() {
+        let dropped = DropCounter::default();
+        let mut fifo = PcmFifo::new(16, OverflowPolicy::DropOldest, dropped.clone());
+        fifo.push(&(0..20).collect::<Vec<u8>>());
+        let mut buf = [0; 16];
+        assert_eq!(fifo.read(&mut buf), 12);
+        assert_eq!(buf[..12], (8..20).collect::<Vec<u8>>()[..]);
+        assert_eq!(dropped.get(), 8);
+    }
+
+    #[test]
+    fn fifo_drops_newest_when_full() {
+        let dropped = DropCounter::default();
+        let mut fifo = PcmFifo::new(16, OverflowPolicy::DropNewest, dropped.clone());
+        fifo.push(&(0..12).collect::<Vec<u8>>());
+        fifo.push(&(12..24).collect::<Vec<u8>>());
+        let mut buf = [0; 24];
+        assert_eq!(fifo.read(&mut buf), 12);
+        assert_eq!(buf[..12], (0..12).collect::<Vec<u8>>()[..]);
+        assert_eq!(dropped.get(), 12);
+    }
+
+    #[test]
+    fn fifo_pauses_between_watermarks() {
+        let limits = BufferLimits {
+            max_ms: 100,
+            high_watermark_ms: Some(60),
+            low_watermark_ms: 20,
+            overflow: Some(OverflowPolicy::Pause),
+        };
+        assert!(limits.validate().is_ok());
+        let mut fifo = PcmFifo::with_limits(&limits, OverflowPolicy::DropOldest, DropCounter::default());
+        let frame = [0; 20 * PCM_BYTES_PER_MS];
+        for _ in 0..3 {
+            assert!(fifo.accepts());
+            fifo.push(&frame);
+        }
+        assert!(!fifo.accepts());
+
+        let mut buf = [0; 20 * PCM_BYTES_PER_MS];
+        fifo.read(&mut buf);
+        assert!(!fifo.accepts());
+        fifo.read(&mut buf);
+        assert!(fifo.accepts());
+
+        let inverted = BufferLimits { low_watermark_ms: 80, ..limits };
+        assert!(inverted.validate().is_err());
+    }
+
+    #[test]
+    fn voice_ducks_music_and_releases_it() {
+        let frame = FrameDuration::default();
+        let mut ducker = Ducker::new(DuckingConfig::default());
+        let silence = vec![0.0; STEREO_20MS];
+        assert_eq!(ducker.process(&silence, frame), (1.0, 1.0));
+
+        // -6 dBFS speech, 39 dB over the threshold, reduction capped at 15 dB
+        let voice = sine_frame(440.0, 0.7, 0);
+        let (start, end) = ducker.process(&voice, frame);
+        assert_eq!(start, 1.0);
+        assert!(end < 0.3, "attack too slow: {}", end);
+        for _ in 0..5 {
+            ducker.process(&voice, frame);
+        }
+        let (_, ducked) = ducker.process(&voice, frame);
+        assert!((ducked - db_to_gain(-15.0)).abs() < 0.01, "{}", ducked);
+
+        // Comes back gradually, not at once
+        let (_, released) = ducker.process(&silence, frame);
+        assert!(released > ducked && released < 0.5, "{}", released);
+        for _ in 0..200 {
+            ducker.process(&silence, frame);
+        }
+        assert!(ducker.process(&silence, frame).1 > 0.99);
+    }
+
+    #[test]
+    fn ducking_can_be_turned_off() {
+        let config = DuckingConfig { max_reduction_db: 0.0, ..Default::default() };
+        let mut ducker = Ducker::new(config);
+        let voice = sine_frame(440.0, 0.7, 0);
+        assert_eq!(ducker.process(&voice, FrameDuration::default()), (1.0, 1.0));
+    }
+
+    #[test]
+    fn channels_are_mapped_to_stereo() {
+        let mut out = Vec::new();
+        to_stereo(&[0.1, 0.2], 1, &mut out);
+        assert_eq!(out, [0.1, 0.1, 0.2, 0.2]);
+        out.clear();
+        to_stereo(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 3, &mut out);
+        assert_eq!(out, [0.1, 0.2, 0.4, 0.5]);
+    }
+
+    #[test]
+    fn resampler_keeps_rate_and_interpolates() {
+        let input: Vec<f32> = (0..100).flat_map(|i| vec![i as f32, -(i as f32)]).collect();
+
+        let mut out = Vec::new();
+        let mut same = LinearResampler::new(48000);
+        same.process(&input[..50], &mut out);
+        same.process(&input[50..], &mut out);
+        // The final frame comes out with the next input
+        assert_eq!(out, input[..input.len() - 2]);
+
+        out.clear();
+        let mut up = LinearResampler::new(24000);
+        up.process(&input[..62], &mut out);
+        up.process(&input[62..], &mut out);
+        assert_eq!(out.len(), 2 * input.len() - 4);
+        assert_eq!(out[..8], [0.0, 0.0, 0.5, -0.5, 1.0, -1.0, 1.5, -1.5]);
+    }
+
+    #[test]
+    fn encodes_ts_packet() {
+        let encoder = encoder();
+        let packet = encode_ts_packet(&encoder, &sine_frame(440.0, 0.5, 0), CodecType::OpusMusic).unwrap().unwrap();
+        // C2S audio content: packet id (2 bytes), codec (1 byte), opus data
+        let content = packet.content();
+        assert_eq!(content[2], CodecType::OpusMusic as u8);
+        let decoded = reference_decode(&[content[3..].to_vec()]);
+        assert!(peak(&decoded[0]) > 0.1);
+    }
+
+    #[test]
+    fn jitter_buffer_output_matches_reference_decode() {
+        let packets = opus_sine(440.0, 10);
+        let reference = reference_decode(&packets);
+        let mut handler = AudioHandler::<u32>::new(logger());
+
+        for (i, (packet, expected)) in packets.iter().zip(&reference).enumerate() {
+            handler.handle_packet(1, i as u16, packet.clone()).unwrap();
+            let mut buf = vec![0.0; STEREO_20MS];
+            handler.fill_buffer(&mut buf);
+            assert_eq!(&buf, expected, "frame {} differs", i);
+        }
+    }
+
+    #[test]
+    fn jitter_buffer_reorders_packets() {
+        let packets = opus_sine(440.0, 4);
+        let reference = reference_decode(&packets);
+        let mut handler = AudioHandler::<u32>::new(logger());
+
+        handler.handle_packet(1, 0, packets[0].clone()).unwrap();
+        handler.handle_packet(1, 2, packets[2].clone()).unwrap();
+        handler.handle_packet(1, 1, packets[1].clone()).unwrap();
+        handler.handle_packet(1, 3, packets[3].clone()).unwrap();
+
+        let mut decoded = Vec::new();
+        while decoded.len() < reference.concat().len() {
+            let mut buf = vec![0.0; STEREO_20MS];
+            handler.fill_buffer(&mut buf);
+            decoded.extend(buf);
+        }
+        // Speed-up may shorten the output, but the first frame is always untouched
+        assert_eq!(&decoded[..STEREO_20MS], &reference[0][..]);
+        assert!(peak(&decoded) > 0.1);
+    }
+
+    #[test]
+    fn mixer_sums_sources_and_applies_global_volume() {
+        let a = opus_sine(440.0, 10);
+        let b = opus_sine(660.0, 10);
+
+        let mut handler = AudioHandler::<u32>::new(logger());
+        handler.set_global_volume_instantly(0.5);
+        for i in 0..10 {
+            handler.handle_packet(1, i as u16, a[i].clone()).unwrap();
+            handler.handle_packet(2, i as u16, b[i].clone()).unwrap();
+
+            let mut sources = vec![0.0; STEREO_20MS];
+            let mut buf = vec![0.0; STEREO_20MS];
+            handler.fill_buffer_with_proc(&mut buf, |_, data| {
+                for (s, d) in sources.iter_mut().zip(data) {
+                    *s += d;
+                }
+            });
+            handler.apply_global_volume(&mut buf);
+
+            for (out, expected) in buf.iter().zip(&sources) {
+                assert!((out - expected * 0.5).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn volume_changes_are_ramped() {
+        let mut handler = AudioHandler::<u32>::new(logger());
+        handler.set_global_volume(0.0);
+        assert_eq!(handler.get_global_volume(), 0.0);
+
+        let mut buf = vec![1.0; STEREO_20MS];
+        handler.apply_global_volume(&mut buf);
+        assert!(buf[0] > 0.99);
+        assert!(buf.windows(2).all(|w| w[1] <= w[0]));
+        assert!(buf[STEREO_20MS - 1] > 0.0);
+
+        let mut buf = vec![1.0; 2 * STEREO_20MS];
+        handler.apply_global_volume(&mut buf);
+        assert_eq!(buf[2 * STEREO_20MS - 1], 0.0);
+    }
+
+    #[test]
+    fn delayed_sources_play_late_and_keep_their_volume() {
+        let mut delays = SourceDelays::default();
+        delays.set(7, Duration::from_millis(20));
+        assert!(!delays.push(&8, &[1.0; 4], 1.0));
+        assert!(delays.push(&7, &[1.0; 4], 0.5));
+
+        let mut buf = vec![0.0; STEREO_20MS];
+        delays.mix_into(&mut buf);
+        assert_eq!(buf, vec![0.0; STEREO_20MS]);
+        let mut buf = vec![0.0; STEREO_20MS];
+        delays.mix_into(&mut buf);
+        assert_eq!(&buf[..5], &[0.5, 0.5, 0.5, 0.5, 0.0]);
+
+        delays.set(7, Duration::ZERO);
+        assert!(!delays.is_delayed(&7));
+    }
+
+    #[test]
+    fn noise_gate_lets_speech_through_and_closes_on_noise() {
+        let noise = vec![0.003; STEREO_20MS];
+        let threshold = noise_gate_threshold(&noise).unwrap();
+        assert!((threshold - (rms_db(&noise) + NOISE_GATE_MARGIN_DB)).abs() < 0.01);
+        assert_eq!(noise_gate_threshold(&[0.5; 100]), Some(MAX_NOISE_GATE_DB));
+        assert_eq!(noise_gate_threshold(&[]), None);
+
+        let mut gate = NoiseGate::new(threshold);
+        assert_eq!(gate.gain(&noise), 0.0);
+        assert_eq!(gate.gain(&[0.1; STEREO_20MS]), 1.0);
+        // Held for 200 ms, then closing
+        for _ in 0..10 {
+            assert_eq!(gate.gain(&noise), 1.0);
+        }
+        assert_eq!(gate.gain(&noise), 0.5);
+        assert_eq!(gate.gain(&noise), 0.25);
+    }
+
+    #[test]
+    fn end_of_stream_removes_talker() {
+        let packets = opus_sine(440.0, 1);
+        let mut handler = AudioHandler::<u32>::new(logger());
+        handler.handle_packet(7, 0, packets[0].clone()).unwrap();
+        handler.handle_packet(7, 1, vec![]).unwrap();
+
+        let mut buf = vec![0.0; STEREO_20MS];
+        handler.fill_buffer(&mut buf);
+        let mut buf = vec![0.0; STEREO_20MS];
+        let removed = handler.fill_buffer(&mut buf);
+        assert_eq!(removed, vec![7]);
+    }
+}
@@ -0,0 +1,262 @@
+//! Audit log of the Discord commands run on the bridge.
+//!
+//! Lets admins of shared bridges see who changed the volume or made the bot
+//! leave. Entries go to a file that is rotated by size, and optionally to a
+//! Discord channel.
+
+use std::fmt;
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Write };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use serde::Deserialize;
+
+/// `[audit]` section of the config file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// File entries are appended to, none is written if unset.
+    pub file: Option<String>,
+    /// Size in KiB after which the file is rotated.
+    pub max_size_kb: u64,
+    /// Rotated files kept, as `<file>.1` (newest) to `<file>.<keep>`.
+    pub keep: usize,
+    /// Discord channel every entry is also posted to.
+    pub channel_id: Option<u64>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { file: None, max_size_kb: 1024, keep: 3, channel_id: None }
+    }
+}
+
+/// One command that ran.
+#[derive(Debug)]
+pub struct AuditEntry {
+    pub user: String,
+    pub user_id: u64,
+    pub guild_id: Option<u64>,
+    pub channel_id: u64,
+    /// The command as typed, with its arguments.
+    pub invocation: String,
+    pub outcome: Result<(), String>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.user, self.user_id)?;
+        if let Some(guild_id) = self.guild_id {
+            write!(f, " in guild {}", guild_id)?;
+        }
+        write!(f, " channel {}: {} → ", self.channel_id, self.invocation)?;
+        match &self.outcome {
+            Ok(()) => write!(f, "ok"),
+            Err(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Option<Arc<StdMutex<RotatingFile>>>,
+    channel_id: Option<u64>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Self {
+        let file = config.file.as_ref().map(|path| {
+            Arc::new(
+                StdMutex::new(RotatingFile {
+                    path: PathBuf::from(path),
+                    max_bytes: config.max_size_kb * 1024,
+                    keep: config.keep,
+                })
+            )
+        });
+        Self { file, channel_id: config.channel_id }
+    }
+
+    /// Channel entries are posted to, if any.
+    pub fn channel_id(&self) -> Option<u64> {
+        self.channel_id
+    }
+
+    /// Append `entry` to the audit file.
+    pub fn record(&self, entry: &AuditEntry) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let line = format!("{} {}\n", utc_timestamp(SystemTime::now()), entry);
+        if let Err(e) = file.lock().expect("Can't lock audit log!").append(&line) {
+            tracing::warn!("Failed to write audit log: {}", e);
+        }
+    }
+
+    /// Remove the entries of `user_id` from the audit file and its rotations, returns how many.
+    ///
+    /// Entries already posted to the audit channel stay there.
+    pub fn forget(&self, user_id: u64) -> io::Result<usize> {
+        match &self.file {
+            Some(file) => file.lock().expect("Can't lock audit log!").remove_user(user_id),
+            None => Ok(0),
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn append(&self, line: &str) -> io::Result<()> {
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or_default();
+        if size > 0 && size + (line.len() as u64) > self.max_bytes {
+            self.rotate()?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(line.as_bytes())
+    }
+
+    /// Rewrite the file and its rotations without the entries of `user_id`.
+    fn remove_user(&self, user_id: u64) -> io::Result<usize> {
+        // Their id follows their name, before where the command ran
+        let markers = [format!(" ({}) in guild ", user_id), format!(" ({}) channel ", user_id)];
+        let is_theirs = |line: &&str| markers.iter().any(|marker| line.contains(marker.as_str()));
+        let mut removed = 0;
+        for path in std::iter::once(self.path.clone()).chain((1..=self.keep).map(|n| self.rotated(n))) {
+            let data = match fs::read_to_string(&path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let (theirs, kept): (Vec<&str>, Vec<&str>) = data.split_inclusive('\n').partition(is_theirs);
+            if !theirs.is_empty() {
+                removed += theirs.len();
+                fs::write(&path, kept.concat())?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Shift `<file>.n` to `<file>.n+1`, dropping the oldest, and the file to `<file>.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+/// `time` as `YYYY-MM-DDTHH:MM:SSZ`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let (days, rest) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        (rest / 60) % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(outcome: Result<(), String>) -> AuditEntry {
+        AuditEntry {
+            user: "ann".to_string(),
+            user_id: 1,
+            guild_id: Some(2),
+            channel_id: 3,
+            invocation: "/volume volume:80".to_string(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn entries_name_who_where_what_and_how_it_went() {
+        assert_eq!(entry(Ok(())).to_string(), "ann (1) in guild 2 channel 3: /volume volume:80 → ok");
+        assert_eq!(
+            entry(Err("Not in a guild".to_string())).to_string(),
+            "ann (1) in guild 2 channel 3: /volume volume:80 → failed: Not in a guild"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3661)), "2000-02-29T01:01:01Z");
+        assert_eq!(utc_timestamp(UNIX_EPOCH + Duration::from_secs(1_792_195_199)), "2026-10-16T23:59:59Z");
+    }
+
+    #[test]
+    fn files_are_rotated_by_size() {
+        let dir = std::env::temp_dir().join(format!("voice_bridge_audit_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = RotatingFile { path: dir.join("audit.log"), max_bytes: 10, keep: 2 };
+
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            file.append(line).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("audit.log"), "six\n");
+        assert_eq!(read("audit.log.1"), "four\nfive\n");
+        assert_eq!(read("audit.log.2"), "three\n");
+        assert!(!dir.join("audit.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forgetting_a_user_removes_their_entries_everywhere() {
+        let dir = std::env::temp_dir().join(format!("voice_bridge_audit_forget_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = RotatingFile { path: dir.join("audit.log"), max_bytes: 1024, keep: 1 };
+
+        let mut other = entry(Ok(()));
+        other.user_id = 11;
+        fs::write(file.rotated(1), format!("t {}\nt {}\n", entry(Ok(())), other)).unwrap();
+        file.append(&format!("t {}\n", entry(Ok(())))).unwrap();
+
+        assert_eq!(file.remove_user(1).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), format!("t {}\n", other));
+        assert_eq!(file.remove_user(1).unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
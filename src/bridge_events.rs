@@ -0,0 +1,201 @@
+//! Typed events of what happens to the bridge, for whoever follows along.
+//!
+//! Subsystems publish to one [`EventBus`]; the main loop logs the events and
+//! posts admin notices, and the control interface forwards them to a
+//! supervising process. Nobody listening is fine, events are then dropped.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events a slow subscriber may fall behind by before missing some.
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Discord,
+    Teamspeak,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BridgeEvent {
+    /// Connected to TeamSpeak, at start or with another identity.
+    TsConnected,
+    /// Lost the TeamSpeak connection, tsclientlib is reconnecting.
+    TsReconnecting,
+    /// Left TeamSpeak on purpose.
+    TsDisconnected,
+    DiscordJoined {
+        #[serde(serialize_with = "snowflake")]
+        guild_id: u64,
+        #[serde(serialize_with = "snowflake")]
+        channel_id: u64,
+    },
+    DiscordLeft {
+        #[serde(serialize_with = "snowflake")]
+        guild_id: u64,
+    },
+    SpeakerStarted {
+        side: Side,
+        name: String,
+    },
+    SpeakerStopped {
+        side: Side,
+        name: String,
+    },
+    /// The watchdog reset the Discord audio queues.
+    AudioReset {
+        reason: String,
+    },
+    /// Told to both sides.
+    Announcement {
+        text: String,
+    },
+    /// For the admin channel.
+    AdminNotice {
+        text: String,
+    },
+}
+
+/// Names events are told apart by, as in their `event` field.
+pub const EVENT_NAMES: [&str; 10] = [
+    "ts_connected",
+    "ts_reconnecting",
+    "ts_disconnected",
+    "discord_joined",
+    "discord_left",
+    "speaker_started",
+    "speaker_stopped",
+    "audio_reset",
+    "announcement",
+    "admin_notice",
+];
+
+impl BridgeEvent {
+    /// Its `event` field, one of [`EVENT_NAMES`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TsConnected => "ts_connected",
+            Self::TsReconnecting => "ts_reconnecting",
+            Self::TsDisconnected => "ts_disconnected",
+            Self::DiscordJoined { .. } => "discord_joined",
+            Self::DiscordLeft { .. } => "discord_left",
+            Self::SpeakerStarted { .. } => "speaker_started",
+            Self::SpeakerStopped { .. } => "speaker_stopped",
+            Self::AudioReset { .. } => "audio_reset",
+            Self::Announcement { .. } => "announcement",
+            Self::AdminNotice { .. } => "admin_notice",
+        }
+    }
+}
+
+/// Discord ids as strings, they don't fit a JavaScript number.
+fn snowflake<S: serde::Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+/// Where events are published, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<BridgeEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self { sender: broadcast::channel(CAPACITY).0 }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: BridgeEvent) {
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Events published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription { receiver: self.sender.subscribe() }
+    }
+}
+
+pub struct Subscription {
+    receiver: broadcast::Receiver<BridgeEvent>,
+}
+
+impl Subscription {
+    /// The next event, skipping those missed by falling behind; `None` once the bus is gone.
+    pub async fn next(&mut self) -> Option<BridgeEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("A bridge event subscriber fell behind and missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Who speaks, to tell when somebody starts or stops.
+#[derive(Debug, Default)]
+pub struct Speakers {
+    speaking: HashSet<(Side, String)>,
+}
+
+impl Speakers {
+    /// Events for the difference to the last call, with `now` speaking.
+    pub fn update(&mut self, now: HashSet<(Side, String)>) -> Vec<BridgeEvent> {
+        let mut started: Vec<_> = now.difference(&self.speaking).cloned().collect();
+        let mut stopped: Vec<_> = self.speaking.difference(&now).cloned().collect();
+        started.sort();
+        stopped.sort();
+        self.speaking = now;
+        stopped
+            .into_iter()
+            .map(|(side, name)| BridgeEvent::SpeakerStopped { side, name })
+            .chain(started.into_iter().map(|(side, name)| BridgeEvent::SpeakerStarted { side, name }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_get_events_and_speaker_changes() {
+        let bus = EventBus::new();
+        bus.publish(BridgeEvent::TsConnected);
+        let mut subscription = bus.subscribe();
+        bus.publish(BridgeEvent::DiscordJoined { guild_id: 1, channel_id: 2 });
+        let event = subscription.next().await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({ "event": "discord_joined", "guild_id": "1", "channel_id": "2" })
+        );
+
+        let mut speakers = Speakers::default();
+        let ann = (Side::Discord, "Ann".to_string());
+        let bob = (Side::Teamspeak, "Bob".to_string());
+        assert_eq!(
+            speakers.update([ann.clone()].into()),
+            [BridgeEvent::SpeakerStarted { side: Side::Discord, name: "Ann".to_string() }]
+        );
+        assert!(speakers.update([ann.clone()].into()).is_empty());
+        assert_eq!(
+            speakers.update([bob].into()),
+            [
+                BridgeEvent::SpeakerStopped { side: Side::Discord, name: "Ann".to_string() },
+                BridgeEvent::SpeakerStarted { side: Side::Teamspeak, name: "Bob".to_string() },
+            ]
+        );
+    }
+}
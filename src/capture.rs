@@ -0,0 +1,161 @@
+//! `--capture <file>`, metadata of every received voice packet as JSON lines.
+//!
+//! For debugging timing and loss offline: one line per Discord RTP packet and
+//! TeamSpeak audio packet with its arrival time, source, sequence number and
+//! size. Payloads are only written with `--capture-payloads`, they are what
+//! people said. Lines are written on their own thread; if it falls behind,
+//! packets go uncaptured rather than holding up the audio.
+
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ self, SyncSender, TryRecvError, TrySendError };
+use std::sync::Arc;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use serde::Serialize;
+
+use crate::bridge_events::Side;
+
+pub const CAPTURE_FLAG: &str = "--capture";
+pub const CAPTURE_PAYLOADS_FLAG: &str = "--capture-payloads";
+
+/// Lines waiting for the writer thread before packets go uncaptured.
+const BACKLOG: usize = 4096;
+
+/// One received packet.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Packet {
+    /// Arrival in µs since the epoch.
+    pub at_us: u64,
+    pub side: Side,
+    /// SSRC on Discord, client id on TeamSpeak.
+    pub source: u32,
+    pub sequence: u16,
+    /// RTP timestamp, Discord only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u32>,
+    /// TeamSpeak only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Bytes of payload.
+    pub len: usize,
+    /// Hex, only with `--capture-payloads`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+}
+
+impl Packet {
+    pub fn new(side: Side, source: u32, sequence: u16, payload: &[u8]) -> Self {
+        let at_us = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_micros() as u64);
+        Self { at_us, side, source, sequence, timestamp: None, codec: None, len: payload.len(), payload: None }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_codec(mut self, codec: impl std::fmt::Debug) -> Self {
+        self.codec = Some(format!("{:?}", codec));
+        self
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where packets are captured to, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    lines: SyncSender<Packet>,
+    payloads: bool,
+    /// Set once a packet went uncaptured as the writer fell behind.
+    behind: Arc<AtomicBool>,
+}
+
+impl Capture {
+    /// Capture to a new file at `path`, with the payloads if `payloads`.
+    pub fn create(path: &str, payloads: bool) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let (lines, received) = mpsc::sync_channel::<Packet>(BACKLOG);
+        let path = path.to_string();
+        std::thread::spawn(move || {
+            let mut next = received.recv().ok();
+            while let Some(packet) = next {
+                let written = serde_json::to_writer(&mut out, &packet)
+                    .map_err(std::io::Error::from)
+                    .and_then(|()| out.write_all(b"\n"));
+                if let Err(e) = written {
+                    tracing::error!("Stopped capturing packets to {}: {}", path, e);
+                    return;
+                }
+                next = match received.try_recv() {
+                    Ok(packet) => Some(packet),
+                    Err(TryRecvError::Empty) => {
+                        let _ = out.flush();
+                        received.recv().ok()
+                    }
+                    Err(TryRecvError::Disconnected) => None,
+                };
+            }
+            let _ = out.flush();
+        });
+        Ok(Self { lines, payloads, behind: Default::default() })
+    }
+
+    /// Capture `packet`, whose payload is `payload`.
+    pub fn record(&self, mut packet: Packet, payload: &[u8]) {
+        if self.payloads {
+            packet.payload = Some(hex(payload));
+        }
+        match self.lines.try_send(packet) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                if !self.behind.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("Capturing packets falls behind, some go uncaptured");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tsproto_packets::packets::CodecType;
+
+    use super::*;
+
+    #[test]
+    fn packets_are_written_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("voice-bridge-capture-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let capture = Capture::create(path, false).unwrap();
+            capture.record(Packet::new(Side::Discord, 7, 1, &[1, 2, 3]).with_timestamp(960), &[1, 2, 3]);
+            let with_payloads = Capture { payloads: true, ..capture.clone() };
+            with_payloads.record(Packet::new(Side::Teamspeak, 2, 5, &[0xab]).with_codec(CodecType::OpusVoice), &[0xab]);
+        }
+        // The writer thread ends once every sender is gone
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_file(path).unwrap();
+
+        let discord: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(discord["side"], "discord");
+        assert_eq!(discord["source"], 7);
+        assert_eq!(discord["timestamp"], 960);
+        assert_eq!(discord["len"], 3);
+        assert!(discord.get("payload").is_none());
+        let ts: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(ts["codec"], "OpusVoice");
+        assert_eq!(ts["payload"], "ab");
+    }
+}
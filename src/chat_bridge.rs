@@ -0,0 +1,391 @@
+//! Text relayed from Discord into TeamSpeak chat.
+//!
+//! TeamSpeak chat understands BBCode, so links are wrapped in `[URL]` tags
+//! to be clickable there. TeamSpeak messages can't be edited or deleted, so
+//! changes to forwarded Discord messages follow up as new messages.
+//! Mentions and custom emoji are written out, as TeamSpeak users can't look
+//! up the ids Discord sends.
+//!
+//! TeamSpeak kicks clients that send too much too fast, so messages are
+//! rate limited per author and in total, see [`FloodGuard`].
+
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// Forwarded messages remembered for edits and deletions, oldest are forgotten.
+const MAX_RELAYED_MESSAGES: usize = 200;
+
+/// Characters of a deleted message quoted to tell which one it was.
+const DELETED_QUOTE_CHARS: usize = 60;
+
+/// `url` as a clickable TeamSpeak link showing `label`.
+pub fn ts_link(url: &str, label: &str) -> String {
+    if label.is_empty() || label == url {
+        format!("[URL]{}[/URL]", url)
+    } else {
+        format!("[URL={}]{}[/URL]", url, label)
+    }
+}
+
+/// Discord message `content` for TeamSpeak, followed by one line per link.
+///
+/// `links` are `(url, label)` pairs of attachments and embeds. Links already
+/// written in the content, like the page an embed previews, are left out.
+pub fn relay_text<'a>(content: &str, links: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut lines = vec![link_urls(content)];
+    lines.extend(
+        links
+            .into_iter()
+            .filter(|(url, _)| !content.contains(url))
+            .map(|(url, label)| ts_link(url, label))
+    );
+    lines.retain(|line| !line.trim().is_empty());
+    lines.join("\n")
+}
+
+/// A token bucket: `burst` messages at once, refilled at `per_minute`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// `[chat_rate_limit]` section of the config file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ChatRateLimits {
+    /// Messages from one Discord member.
+    pub user: RateLimit,
+    /// Messages from everybody together, stays below TeamSpeak's flood protection.
+    pub total: RateLimit,
+}
+
+impl Default for ChatRateLimits {
+    fn default() -> Self {
+        Self {
+            user: RateLimit { burst: 3, per_minute: 12 },
+            total: RateLimit { burst: 5, per_minute: 40 },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f32,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self { tokens: limit.burst as f32, updated: now }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let minutes = now.saturating_duration_since(self.updated).as_secs_f32() / 60.0;
+        self.tokens = (self.tokens + minutes * limit.per_minute as f32).min(limit.burst as f32);
+        self.updated = now;
+    }
+}
+
+/// Decides which messages may go to TeamSpeak, counting the ones that may not.
+#[derive(Debug)]
+pub struct FloodGuard {
+    limits: ChatRateLimits,
+    total: Bucket,
+    users: HashMap<String, Bucket>,
+    /// Messages held back since the last [`take_suppressed`](Self::take_suppressed).
+    suppressed: usize,
+}
+
+impl FloodGuard {
+    pub fn new(limits: ChatRateLimits, now: Instant) -> Self {
+        Self { limits, total: Bucket::new(limits.total, now), users: HashMap::new(), suppressed: 0 }
+    }
+
+    /// Whether `user` may send a message now, counts it as held back if not.
+    pub fn allow(&mut self, user: &str, now: Instant) -> bool {
+        let limits = self.limits;
+        self.total.refill(limits.total, now);
+        for bucket in self.users.values_mut() {
+            bucket.refill(limits.user, now);
+        }
+        // Full buckets are the same as new ones
+        self.users.retain(|_, bucket| bucket.tokens < limits.user.burst as f32);
+
+        let user_bucket = self.users.entry(user.to_string()).or_insert_with(|| Bucket::new(limits.user, now));
+        if self.total.tokens < 1.0 || user_bucket.tokens < 1.0 {
+            self.suppressed += 1;
+            return false;
+        }
+        self.total.tokens -= 1.0;
+        user_bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Number of held back messages, once there is room to say so.
+    ///
+    /// Sending the note uses up a message of the total limit.
+    pub fn take_suppressed(&mut self, now: Instant) -> Option<usize> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        self.total.refill(self.limits.total, now);
+        if self.total.tokens < 1.0 {
+            return None;
+        }
+        self.total.tokens -= 1.0;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// What a Discord `<@id>`, `<#id>` or `<@&id>` mention points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mention {
+    User(u64),
+    Channel(u64),
+    Role(u64),
+}
+
+/// Replace mentions and custom emoji in Discord `text` by readable names.
+///
+/// `name` looks up what a mention points to, unknown ones read like
+/// `@unknown-user`. Emoji become their `:name:`.
+pub fn readable_mentions(text: &str, name: impl Fn(Mention) -> Option<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tag = &rest[start + 1..];
+        let readable = tag
+            .find('>')
+            .and_then(|end| Some((readable_tag(&tag[..end], &name)?, end)));
+        match readable {
+            Some((readable, end)) => {
+                out.push_str(&readable);
+                rest = &tag[end + 1..];
+            }
+            None => {
+                out.push('<');
+                rest = tag;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Readable form of what is between `<` and `>`, `None` if it is no mention.
+fn readable_tag(tag: &str, name: &impl Fn(Mention) -> Option<String>) -> Option<String> {
+    let id = |digits: &str| digits.parse::<u64>().ok();
+    if let Some(role) = tag.strip_prefix("@&") {
+        let role = Mention::Role(id(role)?);
+        return Some(format!("@{}", name(role).unwrap_or_else(|| "unknown-role".to_string())));
+    }
+    if let Some(user) = tag.strip_prefix('@') {
+        let user = Mention::User(id(user.strip_prefix('!').unwrap_or(user))?);
+        return Some(format!("@{}", name(user).unwrap_or_else(|| "unknown-user".to_string())));
+    }
+    if let Some(channel) = tag.strip_prefix('#') {
+        let channel = Mention::Channel(id(channel)?);
+        return Some(format!("#{}", name(channel).unwrap_or_else(|| "unknown-channel".to_string())));
+    }
+    let emoji = tag.strip_prefix('a').unwrap_or(tag).strip_prefix(':')?;
+    let (emoji, emoji_id) = emoji.split_once(':')?;
+    id(emoji_id)?;
+    Some(format!(":{}:", emoji))
+}
+
+struct Relayed {
+    id: u64,
+    author: String,
+    text: String,
+}
+
+/// Discord messages forwarded to TeamSpeak, by message id.
+#[derive(Clone, Default)]
+pub struct RelayedMessages {
+    messages: Arc<StdMutex<VecDeque<Relayed>>>,
+}
+
+impl RelayedMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember that message `id` by `author` was forwarded as `text`.
+    pub fn record(&self, id: u64, author: String, text: String) {
+        let mut messages = self.messages.lock().expect("Can't lock relayed messages!");
+        if messages.len() >= MAX_RELAYED_MESSAGES {
+            messages.pop_front();
+        }
+        messages.push_back(Relayed { id, author, text });
+    }
+
+    /// Author and follow-up for TeamSpeak if forwarded message `id` now reads `text`.
+    pub fn edited(&self, id: u64, text: String) -> Option<(String, String)> {
+        let mut messages = self.messages.lock().expect("Can't lock relayed messages!");
+        let relayed = messages.iter_mut().find(|m| m.id == id)?;
+        if relayed.text == text {
+            // Discord also sends updates when embeds are loaded
+            return None;
+        }
+        let follow_up = format!("edited: {}", text);
+        relayed.text = text;
+        Some((relayed.author.clone(), follow_up))
+    }
+
+    /// Author and follow-up for TeamSpeak if message `id` was forwarded.
+    pub fn deleted(&self, id: u64) -> Option<(String, String)> {
+        let mut messages = self.messages.lock().expect("Can't lock relayed messages!");
+        let index = messages.iter().position(|m| m.id == id)?;
+        let relayed = messages.remove(index)?;
+        let mut quote: String = relayed.text.chars().take(DELETED_QUOTE_CHARS).collect();
+        if quote.len() < relayed.text.len() {
+            quote.push('…');
+        }
+        Some((relayed.author, format!("deleted: \"{}\"", quote)))
+    }
+}
+
+/// Make the bare `http(s)://` links in Discord `text` clickable in TeamSpeak.
+///
+/// `<url>`, which stops Discord from embedding a link, is unwrapped.
+pub fn link_urls(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for part in text.split_inclusive(char::is_whitespace) {
+        let word = part.trim_end_matches(char::is_whitespace);
+        let space = &part[word.len()..];
+        match split_url(word) {
+            Some((url, rest)) => {
+                out.push_str(&ts_link(url, url));
+                out.push_str(rest);
+                out.push_str(space);
+            }
+            None => out.push_str(part),
+        }
+    }
+    out
+}
+
+/// The `http(s)://` links in Discord `text`, in order.
+pub fn urls(text: &str) -> Vec<&str> {
+    text.split_whitespace().filter_map(split_url).map(|(url, _)| url).collect()
+}
+
+/// `word` as a link and what follows it, `None` if it isn't one.
+fn split_url(word: &str) -> Option<(&str, &str)> {
+    let unwrapped = word
+        .strip_prefix('<')
+        .and_then(|w| w.strip_suffix('>'))
+        .filter(|w| is_url(w));
+    match unwrapped {
+        Some(url) => Some((url, "")),
+        None if is_url(word) => {
+            // Punctuation after a link most likely ends the sentence
+            let url = word.trim_end_matches(|c: char| ".,;:!?)'\"".contains(c));
+            Some((url, &word[url.len()..]))
+        }
+        None => None,
+    }
+}
+
+fn is_url(word: &str) -> bool {
+    ["https://", "http://"].iter().any(|scheme| word.len() > scheme.len() && word.starts_with(scheme))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_links_become_clickable() {
+        assert_eq!(
+            link_urls("see https://example.com/a?b=1, or <http://x.org>\nbye"),
+            "see [URL]https://example.com/a?b=1[/URL], or [URL]http://x.org[/URL]\nbye"
+        );
+        assert_eq!(link_urls("https:// is not a link"), "https:// is not a link");
+        assert_eq!(urls("play https://a.org/x. and <http://b.org/y> too"), ["https://a.org/x", "http://b.org/y"]);
+    }
+
+    #[test]
+    fn flood_guard_limits_users_and_total() {
+        use std::time::Duration;
+
+        let start = Instant::now();
+        let limits = ChatRateLimits {
+            user: RateLimit { burst: 2, per_minute: 60 },
+            total: RateLimit { burst: 3, per_minute: 60 },
+        };
+        let mut guard = FloodGuard::new(limits, start);
+
+        assert!(guard.allow("ann", start));
+        assert!(guard.allow("ann", start));
+        assert!(!guard.allow("ann", start));
+        assert!(guard.allow("bob", start));
+        assert!(!guard.allow("cid", start));
+        assert_eq!(guard.take_suppressed(start), None);
+
+        // One message a second comes back
+        let later = start + Duration::from_secs(1);
+        assert_eq!(guard.take_suppressed(later), Some(2));
+        assert_eq!(guard.take_suppressed(later), None);
+        assert!(!guard.allow("cid", later));
+        assert!(guard.allow("cid", later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn mentions_and_emoji_become_readable() {
+        let name = |mention| match mention {
+            Mention::User(1) => Some("Ann".to_string()),
+            Mention::Channel(2) => Some("general".to_string()),
+            Mention::Role(3) => Some("Admins".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            readable_mentions("<@1> <@!1> in <#2>, ping <@&3> and <@9> <:pog:123><a:wave:45>", name),
+            "@Ann @Ann in #general, ping @Admins and @unknown-user :pog::wave:"
+        );
+        assert_eq!(readable_mentions("a < b, <tag> <@x> <https://x.org>", name), "a < b, <tag> <@x> <https://x.org>");
+    }
+
+    #[test]
+    fn edits_and_deletions_of_forwarded_messages_follow_up() {
+        let relayed = RelayedMessages::new();
+        relayed.record(1, "Ann".to_string(), "helo".to_string());
+
+        assert_eq!(relayed.edited(1, "helo".to_string()), None);
+        assert_eq!(relayed.edited(1, "hello".to_string()), Some(("Ann".to_string(), "edited: hello".to_string())));
+        assert_eq!(relayed.edited(2, "other".to_string()), None);
+        assert_eq!(relayed.deleted(1), Some(("Ann".to_string(), "deleted: \"hello\"".to_string())));
+        assert_eq!(relayed.deleted(1), None);
+
+        for id in 0..=MAX_RELAYED_MESSAGES as u64 {
+            relayed.record(id, "Bob".to_string(), "x".repeat(100));
+        }
+        assert_eq!(relayed.deleted(0), None);
+        let (_, follow_up) = relayed.deleted(1).unwrap();
+        assert!(follow_up.ends_with("x…\""), "{}", follow_up);
+    }
+
+    #[test]
+    fn attachments_and_new_embeds_are_appended() {
+        let text = relay_text(
+            "look https://example.com/page",
+            [
+                ("https://cdn.discordapp.com/cat.png", "cat.png"),
+                ("https://example.com/page", "Example page"),
+                ("https://other.org/", "https://other.org/"),
+            ]
+        );
+        assert_eq!(
+            text,
+            "look [URL]https://example.com/page[/URL]\n\
+             [URL=https://cdn.discordapp.com/cat.png]cat.png[/URL]\n\
+             [URL]https://other.org/[/URL]"
+        );
+        assert_eq!(relay_text("", [("https://a.b/c.png", "c.png")]), "[URL=https://a.b/c.png]c.png[/URL]");
+    }
+}
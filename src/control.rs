@@ -0,0 +1,370 @@
+//! Newline-delimited JSON-RPC 2.0 control interface over stdin/stdout.
+//!
+//! Enabled with `--control-stdio`. Every line on stdin is one request, every
+//! response is written as one line to stdout. This allows a supervising process
+//! to drive the bridge without opening any network listener.
+//!
+//! Supported methods:
+//! - `join` `{ "guild_id", "channel_id" }`
+//! - `leave` `{ "guild_id" }`
+//! - `set_volume` `{ "volume" }`
+//! - `status`
+//! - `subscribe`, after which every bridge event is written as an `event`
+//!   notification
+//! - `shutdown`
+//!
+//! Closing stdin shuts the bridge down as well.
+
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use serenity::all::{ ChannelId, GuildId, Http };
+use serenity::prelude::{ RwLock, TypeMap };
+use songbird::Songbird;
+use tokio::io::{ AsyncBufReadExt, AsyncWriteExt, BufReader };
+use tokio::sync::Notify;
+
+use crate::audio::parse_volume;
+use crate::bridge_events::{ BridgeEvent, EventBus, Subscription };
+use crate::levels::Direction;
+use crate::stats::CodecStage;
+use crate::ListenerHolder;
+
+/// Command line flag enabling the control interface.
+pub const CONTROL_STDIO_FLAG: &str = "--control-stdio";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+/// A message without id that is never answered, here a bridge event.
+#[derive(Debug, Serialize)]
+struct Notification {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: BridgeEvent,
+}
+
+impl Notification {
+    fn event(event: BridgeEvent) -> Self {
+        Self { jsonrpc: "2.0", method: "event", params: event }
+    }
+}
+
+/// The next event once subscribed, never before.
+async fn next_event(events: &mut Option<Subscription>) -> Option<BridgeEvent> {
+    match events {
+        Some(events) => events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Shared handles required to execute control requests.
+pub struct Controller {
+    data: Arc<RwLock<TypeMap>>,
+    http: Arc<Http>,
+    songbird: Arc<Songbird>,
+    ts_connected: Arc<AtomicBool>,
+    shutdown: Arc<Notify>,
+    /// Set by `subscribe`, bridge events are written as notifications from then on.
+    subscribed: AtomicBool,
+}
+
+impl Controller {
+    pub fn new(
+        data: Arc<RwLock<TypeMap>>,
+        http: Arc<Http>,
+        songbird: Arc<Songbird>,
+        ts_connected: Arc<AtomicBool>,
+        shutdown: Arc<Notify>
+    ) -> Self {
+        Self { data, http, songbird, ts_connected, shutdown, subscribed: AtomicBool::new(false) }
+    }
+
+    /// Read requests from stdin until it is closed, answering on stdout.
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            let mut stdout = tokio::io::stdout();
+            let mut events = None;
+            loop {
+                let mut out = tokio::select! {
+                    line = lines.next_line() => {
+                        let line = match line {
+                            Ok(Some(line)) => line,
+                            Ok(None) => {
+                                tracing::info!("Control stdin closed, shutting down");
+                                break;
+                            }
+                            Err(e) => {
+                                tracing::error!("Failed to read control input: {}", e);
+                                break;
+                            }
+                        };
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let response = self.handle_line(&line).await;
+                        if events.is_none() && self.subscribed.load(Ordering::Relaxed) {
+                            events = self.data.read().await.get::<crate::EventBusHolder>().map(EventBus::subscribe);
+                        }
+                        match response {
+                            Some(response) => serde_json::to_vec(&response).expect("Can't serialize response"),
+                            None => continue,
+                        }
+                    }
+                    Some(event) = next_event(&mut events) => {
+                        serde_json::to_vec(&Notification::event(event)).expect("Can't serialize event")
+                    }
+                };
+                out.push(b'\n');
+                if let Err(e) = stdout.write_all(&out).await {
+                    tracing::error!("Failed to write control response: {}", e);
+                    break;
+                }
+                let _ = stdout.flush().await;
+            }
+            self.shutdown.notify_one();
+        });
+    }
+
+    async fn handle_line(&self, line: &str) -> Option<Response> {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(r) => r,
+            Err(e) => {
+                return Some(Response::error(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())));
+            }
+        };
+        let id = request.id.clone();
+        let result = if request.jsonrpc.as_deref() != Some("2.0") {
+            Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+        } else {
+            self.dispatch(&request.method, &request.params).await
+        };
+        // Requests without id are notifications and never get an answer
+        let id = id?;
+        Some(match result {
+            Ok(v) => Response::result(id, v),
+            Err(e) => Response::error(id, e),
+        })
+    }
+
+    async fn dispatch(&self, method: &str, params: &Value) -> RpcResult {
+        match method {
+            "join" => {
+                let guild_id = GuildId::new(snowflake_param(params, "guild_id")?);
+                let channel_id = ChannelId::new(snowflake_param(params, "channel_id")?);
+                crate::discord
+                    ::join_channel(&self.data, &self.http, &self.songbird, guild_id, channel_id).await
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+                Ok(json!(true))
+            }
+            "leave" => {
+                let guild_id = GuildId::new(snowflake_param(params, "guild_id")?);
+                let left = crate::discord
+                    ::leave_channel(&self.data, &self.http, &self.songbird, guild_id).await
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+                if !left {
+                    return Err(RpcError::new(INTERNAL_ERROR, "Not in a voice channel"));
+                }
+                Ok(json!(true))
+            }
+            "set_volume" => {
+                let volume = match params.get("volume") {
+                    Some(Value::Number(factor)) => factor.to_string(),
+                    Some(Value::String(text)) => text.clone(),
+                    _ => return Err(RpcError::new(INVALID_PARAMS, "missing \"volume\"")),
+                };
+                let volume = parse_volume(&volume).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+                let buffer = self.discord_buffer().await?;
+                let mut lock = buffer.lock().await;
+                lock.set_global_volume(volume);
+                Ok(json!(lock.get_global_volume()))
+            }
+            "status" => self.status().await,
+            "subscribe" => {
+                self.subscribed.store(true, Ordering::Relaxed);
+                Ok(json!(true))
+            }
+            "shutdown" => {
+                self.shutdown.notify_one();
+                Ok(json!(true))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+
+    async fn status(&self) -> RpcResult {
+        let (volume, discord_dropped) = {
+            let discord = self.discord_buffer().await?;
+            let discord = discord.lock().await;
+            (discord.get_global_volume(), discord.dropped_packets().get())
+        };
+        let (ts_dropped, ts_packets_dropped, quarantined, stereo_clients) = self.data
+            .read().await
+            .get::<ListenerHolder>()
+            .map_or((0, 0, Vec::new(), Vec::new()), |(ts, _)| {
+                (ts.dropped.get(), ts.ingest_dropped.get(), ts.quarantined(), ts.stereo_clients())
+            });
+        let late_encodes = self.data
+            .read().await
+            .get::<crate::LateEncodesHolder>()
+            .map_or(0, |late| late.get());
+        let deadline_misses = self.data
+            .read().await
+            .get::<crate::DeadlineMissesHolder>()
+            .map_or(0, |missed| missed.get());
+        let nickname = self.data.read().await.get::<crate::TsNicknameHolder>().cloned();
+        let live = self.data
+            .read().await
+            .get::<crate::ScheduleHolder>()
+            .is_none_or(|schedule| !schedule.is_dormant());
+        let active = self.data
+            .read().await
+            .get::<crate::ActivationHolder>()
+            .is_none_or(|activation| !activation.is_idle());
+        let paused = self.data.read().await.get::<crate::BridgePauseHolder>().map(|pause| {
+            json!({
+                "discord_to_ts": pause.is_paused(Direction::DiscordToTs),
+                "ts_to_discord": pause.is_paused(Direction::TsToDiscord),
+            })
+        });
+        let levels = self.data
+            .read().await
+            .get::<crate::AudioLevelsHolder>()
+            .map(|levels| levels.report())
+            .unwrap_or_default();
+        let codec = self.data.read().await.get::<crate::StatsHolder>().map(|stats| {
+            let time = |direction, stage| {
+                json!({
+                    "ms": stats.codec_time(direction, stage).as_millis() as u64,
+                    "load_percent": stats.codec_load(direction, stage),
+                })
+            };
+            // Songbird encodes for Discord, its time isn't known
+            json!({
+                "discord_to_ts": {
+                    "decode": time(Direction::DiscordToTs, CodecStage::Decode),
+                    "encode": time(Direction::DiscordToTs, CodecStage::Encode),
+                },
+                "ts_to_discord": { "decode": time(Direction::TsToDiscord, CodecStage::Decode) },
+            })
+        });
+        let link = crate::discord::ts_link_quality(&self.data).await;
+        let book = self.data.read().await.get::<crate::TsBookHolder>().map(|book| json!(&*book.snapshot()));
+        let mut calls = Vec::new();
+        for (guild_id, call) in self.songbird.iter() {
+            let channel = call
+                .lock().await
+                .current_channel()
+                .map(|c| c.0.to_string());
+            calls.push(json!({ "guild_id": guild_id.0.to_string(), "channel_id": channel }));
+        }
+        let mut shards = Vec::new();
+        let shard_manager = self.data.read().await.get::<crate::ShardManagerHolder>().cloned();
+        if let Some(shard_manager) = shard_manager {
+            let mut runners: Vec<_> = shard_manager.runners
+                .lock().await
+                .iter()
+                .map(|(id, runner)| (id.0, runner.stage, runner.latency))
+                .collect();
+            runners.sort_by_key(|&(id, _, _)| id);
+            for (id, stage, latency) in runners {
+                shards.push(
+                    json!({ "id": id, "stage": stage.to_string(), "latency_ms": latency.map(|l| l.as_millis() as u64) })
+                );
+            }
+        }
+        Ok(
+            json!({
+            "volume": volume,
+            "live": live,
+            "active": active,
+            "paused": paused,
+            "discord": { "calls": calls, "shards": shards },
+            "teamspeak": {
+                "connected": self.ts_connected.load(Ordering::Relaxed),
+                "nickname": nickname,
+                "link": link,
+                "quarantined": quarantined,
+                "stereo_clients": stereo_clients,
+                "book": book,
+            },
+            "levels": levels,
+            "dropped": {
+                "discord_to_ts_packets": discord_dropped,
+                "ts_to_discord_ms": ts_dropped / (crate::audio::PCM_BYTES_PER_MS as u64),
+                "ts_to_discord_packets": ts_packets_dropped,
+            },
+            "late_encodes": late_encodes,
+            "deadline_misses": deadline_misses,
+            "codec_cpu": codec,
+        })
+        )
+    }
+
+    async fn discord_buffer(&self) -> std::result::Result<crate::AudioBufferDiscord, RpcError> {
+        let data_read = self.data.read().await;
+        data_read
+            .get::<ListenerHolder>()
+            .map(|(_, discord)| discord.clone())
+            .ok_or_else(|| RpcError::new(INTERNAL_ERROR, "Audio handlers not found"))
+    }
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Self { jsonrpc: "2.0", id, result: Some(result), error: None }
+    }
+
+    fn error(id: Value, error: RpcError) -> Self {
+        Self { jsonrpc: "2.0", id, result: None, error: Some(error) }
+    }
+}
+
+/// Discord ids exceed the safe integer range of JSON numbers in most languages,
+/// so accept them as numbers or strings.
+fn snowflake_param(params: &Value, name: &str) -> std::result::Result<u64, RpcError> {
+    params
+        .get(name)
+        .and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())))
+        .filter(|id| *id != 0)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing id \"{}\"", name)))
+}
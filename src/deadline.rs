@@ -0,0 +1,116 @@
+//! Watching the send ticks keep up with the frames they send.
+//!
+//! A tick misses its deadline when the frame it sends is finished after the
+//! next one is due, from a late start or a slow mix and encode. The share of
+//! ticks missing it is taken over a window, and exceeding the budget raises
+//! one alert until the rate is back within it.
+
+use std::time::{ Duration, Instant };
+
+use serde::Deserialize;
+
+use crate::audio::DropCounter;
+
+/// `[deadlines]` section of the config file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DeadlineConfig {
+    /// Ticks that may miss their deadline in a window, in percent.
+    pub miss_budget_percent: f32,
+    /// Seconds the miss rate is taken over.
+    pub window_s: u64,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self { miss_budget_percent: 5.0, window_s: 10 }
+    }
+}
+
+/// Change of the miss rate against the budget.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alert {
+    /// More ticks than the budget missed their deadline in the last window.
+    Exceeded { missed: u32, ticks: u32, rate: f32 },
+    /// Back within the budget.
+    Recovered { missed: u32, ticks: u32, rate: f32 },
+}
+
+#[derive(Debug)]
+pub struct DeadlineMonitor {
+    config: DeadlineConfig,
+    window_start: Option<Instant>,
+    ticks: u32,
+    missed: u32,
+    exceeded: bool,
+    /// Every tick that missed its deadline, for `status`.
+    total_missed: DropCounter,
+}
+
+impl DeadlineMonitor {
+    pub fn new(config: DeadlineConfig) -> Self {
+        Self {
+            config,
+            window_start: None,
+            ticks: 0,
+            missed: 0,
+            exceeded: false,
+            total_missed: DropCounter::default(),
+        }
+    }
+
+    pub fn total_missed(&self) -> DropCounter {
+        self.total_missed.clone()
+    }
+
+    /// A tick due at `scheduled` sending a frame of `interval` was done at `finished`.
+    pub fn record(&mut self, scheduled: Instant, finished: Instant, interval: Duration) -> Option<Alert> {
+        let window_start = *self.window_start.get_or_insert(scheduled);
+        self.ticks += 1;
+        if finished.saturating_duration_since(scheduled) > interval {
+            self.missed += 1;
+            self.total_missed.add(1);
+        }
+        if finished.saturating_duration_since(window_start) < Duration::from_secs(self.config.window_s) {
+            return None;
+        }
+
+        let (missed, ticks) = (self.missed, self.ticks);
+        let rate = ((missed as f32) / (ticks as f32)) * 100.0;
+        self.window_start = Some(finished);
+        self.ticks = 0;
+        self.missed = 0;
+        let over = rate > self.config.miss_budget_percent;
+        if over == self.exceeded {
+            return None;
+        }
+        self.exceeded = over;
+        Some(if over { Alert::Exceeded { missed, ticks, rate } } else { Alert::Recovered { missed, ticks, rate } })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_when_misses_exceed_the_budget() {
+        let mut monitor = DeadlineMonitor::new(DeadlineConfig { miss_budget_percent: 5.0, window_s: 1 });
+        let interval = Duration::from_millis(20);
+        let start = Instant::now();
+        let mut alerts = Vec::new();
+        for tick in 0..200u32 {
+            let scheduled = start + interval * tick;
+            // One in ten ticks takes 30 ms during the second second
+            let slow = (51..100).contains(&tick) && tick % 10 == 5;
+            let took = if slow { Duration::from_millis(30) } else { Duration::from_millis(2) };
+            if let Some(alert) = monitor.record(scheduled, scheduled + took, interval) {
+                alerts.push((tick, alert));
+            }
+        }
+        assert_eq!(monitor.total_missed().get(), 5);
+        assert_eq!(alerts.len(), 2);
+        assert!(matches!(alerts[0], (100, Alert::Exceeded { missed: 5, ticks: 50, .. })));
+        assert!(matches!(alerts[1], (150, Alert::Recovered { missed: 0, ticks: 50, .. })));
+    }
+}
@@ -0,0 +1,84 @@
+//! Broadcast delay, for bridging a public stage.
+//!
+//! One direction is played a few seconds late, so a moderator can `/dump`
+//! something before anybody on the other side hears it.
+
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::levels::Direction;
+use crate::SAMPLE_RATE;
+
+/// `[broadcast_delay]` section, holding back one direction like a radio station's profanity delay.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct BroadcastDelayConfig {
+    pub direction: Direction,
+    pub seconds: f32,
+}
+
+/// Plays audio a fixed time late, so a moderator can `/dump` what shouldn't go out.
+///
+/// Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct BroadcastDelay {
+    /// Always exactly the delay long, starting as silence.
+    samples: Arc<StdMutex<VecDeque<f32>>>,
+    delay: Duration,
+}
+
+impl BroadcastDelay {
+    pub fn new(delay: Duration) -> Self {
+        let len = (delay.as_secs_f32() * ((SAMPLE_RATE * 2) as f32)) as usize;
+        Self {
+            samples: Arc::new(StdMutex::new(std::iter::repeat_n(0.0, len & !1).collect())),
+            delay,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Put `buf` at the end of the delay and replace it with what comes out the front.
+    pub fn process(&self, buf: &mut [f32]) {
+        let mut samples = self.samples.lock().expect("Can't lock broadcast delay!");
+        samples.extend(buf.iter());
+        let len = buf.len();
+        for (out, delayed) in buf.iter_mut().zip(samples.drain(..len)) {
+            *out = delayed;
+        }
+    }
+
+    /// Drop everything held back, silence plays for the delay instead.
+    pub fn dump(&self) {
+        self.samples.lock().expect("Can't lock broadcast delay!").iter_mut().for_each(|s| *s = 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STEREO_20MS;
+
+    #[test]
+    fn broadcast_delay_holds_audio_back_until_dumped() {
+        let delay = BroadcastDelay::new(Duration::from_millis(20));
+        let mut first = vec![0.5; STEREO_20MS];
+        delay.process(&mut first);
+        assert_eq!(first, vec![0.0; STEREO_20MS]);
+        let mut second = vec![0.25; STEREO_20MS / 2];
+        delay.process(&mut second);
+        assert_eq!(second, vec![0.5; STEREO_20MS / 2]);
+
+        delay.dump();
+        let mut third = vec![1.0; STEREO_20MS];
+        delay.process(&mut third);
+        assert_eq!(third, vec![0.0; STEREO_20MS]);
+        let mut fourth = vec![0.0; 2];
+        delay.process(&mut fourth);
+        assert_eq!(fourth, [1.0, 1.0]);
+    }
+}
@@ -1,15 +1,61 @@
 use serenity::async_trait;
-use serenity::all::{ Context as SerenityContext, Ready };
+use serenity::all::{
+    Attachment,
+    ChannelId,
+    Context as SerenityContext,
+    CurrentUser,
+    Embed,
+    GuildId,
+    Message,
+    MessageId,
+    MessageUpdateEvent,
+    ReactionType,
+    Ready,
+    VoiceState,
+};
+use serenity::prelude::{ RwLock, TypeMap };
 
 // Poise imports
 use poise::serenity_prelude as serenity;
+use poise::ChoiceParameter;
 
 // Songbird imports
 use songbird::input::{ Input, RawAdapter };
 use songbird::events::EventContext;
-use songbird::{ Event, EventHandler as VoiceEventHandler };
+use songbird::{ Event, EventHandler as VoiceEventHandler, Songbird };
 use songbird::events::CoreEvent;
 
+use std::collections::{ HashMap, HashSet };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use crate::audio::{ format_volume, noise_gate_threshold, parse_volume, to_le_bytes, MAX_SOURCE_DELAY };
+use crate::audit::AuditEntry;
+use crate::bridge_events::{ BridgeEvent, Side };
+use crate::capture::{ Capture, Packet as CapturedPacket };
+use crate::chat_bridge::{ link_urls, readable_mentions, relay_text, urls, Mention };
+use crate::echo::{ EchoRecorder, ECHO_DURATION, NOISE_CALIBRATION };
+use crate::hands::{ Hand, HandQueue, Speaker };
+use crate::impair::{ Fate, Impairer };
+use crate::levels::{ AudioLevels, Direction };
+use crate::media::FILE_PREFIX;
+use crate::music::{ format_duration, parse_timestamp, LoopMode, MusicQueues };
+use crate::ping::Hops;
+use crate::pool::FramePool;
+use crate::pause::PauseDirection;
+use crate::postmortem::PacketTimes;
+use crate::profile::DEFAULT_PROFILE;
+use crate::routes::Route;
+use crate::schedule::{ BridgeSchedule, Window };
+use crate::senders::{ Sender, Senders };
+use crate::solo::Solo;
+use crate::settings::{ SharedSettings, UserPrefs };
+use crate::soundboard::{ self, SoundFeed, SoundPlayed };
+use crate::stats::BridgeStats;
+use crate::talk_time::{ leaderboard, TalkTime, Talker, LEADERBOARD_PLACES };
+use crate::teamspeak::{ LinkQuality, TsCommand };
+use crate::tone::{ self, ToneDirection };
+use crate::voice_states::{ CachedVoiceState, VoiceStates };
 use crate::ListenerHolder;
 use crate::BufferedPipeline;
 
@@ -24,13 +70,380 @@ pub struct Handler;
 
 #[async_trait]
 impl serenity::EventHandler for Handler {
-    async fn ready(&self, _ctx: SerenityContext, ready: Ready) {
-        println!("{} is connected!", ready.user.name);
+    async fn ready(&self, ctx: SerenityContext, ready: Ready) {
+        eprintln!("{} is connected!", ready.user.name);
+        sync_ts_avatar(&ctx, &ready.user).await;
+    }
+
+    async fn guild_create(&self, ctx: SerenityContext, guild: serenity::Guild, _is_new: Option<bool>) {
+        if let Some(presence) = ctx.data.read().await.get::<crate::VoicePresenceHolder>() {
+            presence.track_guild(&guild);
+        }
+    }
+
+    async fn voice_state_update(&self, ctx: SerenityContext, old: Option<VoiceState>, new: VoiceState) {
+        if let Some(presence) = ctx.data.read().await.get::<crate::VoicePresenceHolder>() {
+            presence.track(&new);
+        }
+        let (guild_id, channel_id) = match (new.guild_id, new.channel_id) {
+            (Some(guild_id), Some(channel_id)) => (guild_id, channel_id),
+            _ => {
+                return;
+            }
+        };
+        if old.is_some_and(|old| old.channel_id == Some(channel_id)) {
+            return;
+        }
+        if let Err(e) = send_ts_roster(&ctx, guild_id, channel_id, new.user_id).await {
+            tracing::warn!("Can't send TeamSpeak roster to {}: {}", new.user_id, e);
+        }
+    }
+
+    async fn user_update(&self, ctx: SerenityContext, old: Option<CurrentUser>, new: CurrentUser) {
+        if old.is_none_or(|old| old.avatar != new.avatar) {
+            sync_ts_avatar(&ctx, &new).await;
+        }
+    }
+
+    async fn message(&self, ctx: SerenityContext, message: Message) {
+        let guild_id = match message.guild_id {
+            Some(guild_id) if !message.author.bot => guild_id,
+            _ => return,
+        };
+        let channel = ctx.data.read().await.get::<crate::SongRequestChannelHolder>().copied();
+        if channel != Some(message.channel_id.get()) {
+            return;
+        }
+        let (mut accepted, mut rejected) = (false, false);
+        for url in urls(&message.content) {
+            match request_song(&ctx, guild_id, url.to_string(), message.author.name.clone()).await {
+                Ok(()) => accepted = true,
+                Err(e) => {
+                    tracing::info!("Song request {} from {} rejected: {}", url, message.author.name, e);
+                    rejected = true;
+                }
+            }
+        }
+        let reactions = [(accepted, SONG_ACCEPTED), (rejected, SONG_REJECTED)];
+        for (_, reaction) in reactions.iter().filter(|(shown, _)| *shown) {
+            if let Err(e) = message.react(&ctx.http, ReactionType::Unicode(reaction.to_string())).await {
+                tracing::warn!("Can't react to song request: {}", e);
+            }
+        }
+    }
+
+    async fn message_update(
+        &self,
+        ctx: SerenityContext,
+        _old: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent
+    ) {
+        let content = match &event.content {
+            Some(content) => content,
+            None => return,
+        };
+        let text = message_text(
+            &ctx.cache,
+            event.guild_id,
+            content,
+            event.attachments.as_deref().unwrap_or_default(),
+            event.embeds.as_deref().unwrap_or_default()
+        );
+        let follow_up = match ctx.data.read().await.get::<crate::RelayedHolder>() {
+            Some(relayed) => relayed.edited(event.id.get(), text),
+            None => return,
+        };
+        if let Some((author, text)) = follow_up {
+            relay_follow_up(&ctx, author, text).await;
+        }
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: SerenityContext,
+        _channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>
+    ) {
+        let follow_up = match ctx.data.read().await.get::<crate::RelayedHolder>() {
+            Some(relayed) => relayed.deleted(deleted_message_id.get()),
+            None => return,
+        };
+        if let Some((author, text)) = follow_up {
+            relay_follow_up(&ctx, author, text).await;
+        }
+    }
+}
+
+/// Plays soundboard sounds of the bridged channel to TeamSpeak.
+///
+/// Serenity has no event for them yet, they arrive as unknown raw events.
+pub struct SoundboardHandler {
+    feed: SoundFeed,
+    http: reqwest::Client,
+}
+
+impl SoundboardHandler {
+    pub fn new(feed: SoundFeed) -> Self {
+        Self { feed, http: reqwest::Client::new() }
+    }
+
+    async fn play(&self, ctx: &SerenityContext, sound: SoundPlayed) -> Result<(), Error> {
+        let call = songbird::get(ctx).await.and_then(|manager| manager.get(GuildId::new(sound.guild_id)));
+        let in_channel = match call {
+            Some(call) => call.lock().await.current_channel().map(|c| c.0.get()) == Some(sound.channel_id),
+            None => false,
+        };
+        let muted = match ctx.data.read().await.get::<crate::SettingsHolder>() {
+            Some(settings) => settings.lock().expect("Can't lock settings!").get().bridge_muted.contains(&sound.user_id),
+            None => false,
+        };
+        if !in_channel || muted {
+            return Ok(());
+        }
+
+        let data = self.http.get(sound.url()).send().await?.error_for_status()?.bytes().await?.to_vec();
+        let volume = sound.volume;
+        let samples = tokio::task::spawn_blocking(move || soundboard::decode(data, volume)).await??;
+        tracing::debug!("Playing soundboard sound {} to TeamSpeak", sound.sound_id);
+        self.feed.play(&samples);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl serenity::RawEventHandler for SoundboardHandler {
+    async fn raw_event(&self, ctx: SerenityContext, event: serenity::Event) {
+        let sound = match &event {
+            serenity::Event::Unknown(unknown) if unknown.kind == soundboard::EFFECT_EVENT => {
+                SoundPlayed::from_event(&unknown.value)
+            }
+            _ => None,
+        };
+        if let Some(sound) = sound {
+            if let Err(e) = self.play(&ctx, sound).await {
+                tracing::warn!("Failed to play soundboard sound: {}", e);
+            }
+        }
+    }
+}
+
+/// Reaction on a song request whose links were queued.
+const SONG_ACCEPTED: &str = "✅";
+/// Reaction on a song request with links that couldn't be queued.
+const SONG_REJECTED: &str = "❌";
+
+/// Queue `url` from the song request channel, like `/play` does.
+async fn request_song(ctx: &SerenityContext, guild_id: GuildId, url: String, requested_by: String) -> Result<(), Error> {
+    let call = songbird
+        ::get(ctx).await
+        .and_then(|manager| manager.get(guild_id))
+        .ok_or("The bridge isn't in a voice channel")?;
+    let music = ctx.data
+        .read().await
+        .get::<crate::MusicHolder>()
+        .ok_or("Music queues not found")?
+        .clone();
+    let mut handler = call.lock().await;
+    music.play(guild_id.get(), &mut handler, url, requested_by).await?;
+    Ok(())
+}
+
+/// Tell TeamSpeak that a forwarded message changed.
+async fn relay_follow_up(ctx: &SerenityContext, author: String, text: String) {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    match ctx.data.read().await.get::<crate::TsCommandHolder>() {
+        Some(commands) => {
+            let _ = commands.send(TsCommand::SendMessage { author, text, reply });
+        }
+        None => return,
+    }
+    if let Ok(Err(e)) = response.await {
+        tracing::warn!("Failed to forward message change to TeamSpeak: {}", e);
+    }
+}
+
+/// Give the bridge's TeamSpeak client the bot's Discord avatar.
+async fn sync_ts_avatar(ctx: &SerenityContext, user: &CurrentUser) {
+    // TeamSpeak can't show webp, so ask the CDN for a small PNG
+    let url = match &user.avatar {
+        Some(hash) => format!("https://cdn.discordapp.com/avatars/{}/{}.png?size=256", user.id, hash),
+        None => user.default_avatar_url(),
+    };
+    let image = match download(&url).await {
+        Ok(image) => image,
+        Err(e) => {
+            tracing::warn!("Failed to download Discord avatar {}: {}", url, e);
+            return;
+        }
+    };
+    if let Some(commands) = ctx.data.read().await.get::<crate::TsCommandHolder>() {
+        let _ = commands.send(TsCommand::SetAvatar { image });
+    }
+}
+
+async fn download(url: &str) -> reqwest::Result<Vec<u8>> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Record a command that ran in the audit log.
+pub async fn audit(ctx: Context<'_>, outcome: Result<(), String>) {
+    // Logging it would keep what it just deleted
+    if ctx.command().name == "forget-me" {
+        return;
+    }
+    let log = match ctx.serenity_context().data.read().await.get::<crate::AuditHolder>() {
+        Some(log) => log.clone(),
+        None => return,
+    };
+    let entry = AuditEntry {
+        user: ctx.author().name.clone(),
+        user_id: ctx.author().id.get(),
+        guild_id: ctx.guild_id().map(|id| id.get()),
+        channel_id: ctx.channel_id().get(),
+        invocation: ctx.invocation_string(),
+        outcome,
+    };
+    log.record(&entry);
+
+    if let Some(channel_id) = log.channel_id() {
+        // Arguments are user input, they must not ping anybody
+        let message = serenity::CreateMessage
+            ::new()
+            .content(entry.to_string())
+            .allowed_mentions(serenity::CreateAllowedMentions::new());
+        if let Err(e) = ChannelId::new(channel_id).send_message(ctx.http(), message).await {
+            tracing::warn!("Failed to post to the audit channel: {}", e);
+        }
+    }
+}
+
+/// Audit failed commands, then answer like poise does by default.
+pub async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
+    if let poise::FrameworkError::Command { error, ctx, .. } = &error {
+        audit(*ctx, Err(error.to_string())).await;
+    }
+    if let Err(e) = poise::builtins::on_error(error).await {
+        tracing::error!("Failed to handle command error: {}", e);
+    }
+}
+
+/// Register the slash commands, globally or only in `guilds`.
+///
+/// Global commands can take a while to show up everywhere, commands of a
+/// server are there at once, which helps when testing. Whatever the other
+/// mode left behind is removed, so no command shows up twice and stale ones
+/// disappear.
+pub async fn register_commands(
+    ctx: &SerenityContext,
+    ready: &Ready,
+    commands: &[poise::Command<Data, Error>],
+    guilds: Option<&[u64]>
+) -> Result<(), Error> {
+    match guilds {
+        Some(guilds) => {
+            serenity::Command::set_global_commands(ctx, Vec::new()).await?;
+            for guild in guilds {
+                poise::builtins::register_in_guild(ctx, commands, serenity::GuildId::new(*guild)).await?;
+            }
+            eprintln!("Registered commands in {} server(s)", guilds.len());
+        }
+        None => {
+            poise::builtins::register_globally(ctx, commands).await?;
+            for guild in &ready.guilds {
+                if let Err(e) = guild.id.set_commands(ctx, Vec::new()).await {
+                    tracing::warn!("Failed to remove server commands of {}: {}", guild.id, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set the per-server cooldowns of `commands` by name, in seconds, `0` turns one off.
+///
+/// Returns the names no command has, they are warned about.
+pub fn apply_cooldowns<'a>(commands: &[poise::Command<Data, Error>], cooldowns: &'a HashMap<String, u64>) -> Vec<&'a str> {
+    let mut unknown = Vec::new();
+    for (name, seconds) in cooldowns {
+        match commands.iter().find(|command| &command.name == name) {
+            Some(command) => {
+                let cooldown = (*seconds > 0).then(|| std::time::Duration::from_secs(*seconds));
+                command.cooldown_config.write().expect("Can't lock cooldowns!").guild = cooldown;
+            }
+            None => {
+                tracing::warn!("Cooldown configured for unknown command {}", name);
+                unknown.push(name.as_str());
+            }
+        }
+    }
+    unknown
+}
+
+/// Servers with a join or leave in progress.
+///
+/// Songbird doesn't expect two of them racing for the same server, that can
+/// leave the call half set up.
+#[derive(Clone, Default)]
+pub struct VoiceBusy(Arc<StdMutex<HashSet<serenity::GuildId>>>);
+
+impl VoiceBusy {
+    /// Mark `guild_id` busy until the guard is dropped, fails if it already is.
+    fn claim(&self, guild_id: serenity::GuildId) -> Result<VoiceBusyGuard, Error> {
+        if !self.0.lock().expect("Can't lock busy guilds!").insert(guild_id) {
+            return Err("Already joining or leaving, try again in a moment".into());
+        }
+        Ok(VoiceBusyGuard { busy: self.clone(), guild_id })
+    }
+}
+
+struct VoiceBusyGuard {
+    busy: VoiceBusy,
+    guild_id: serenity::GuildId,
+}
+
+impl Drop for VoiceBusyGuard {
+    fn drop(&mut self) {
+        self.busy.0.lock().expect("Can't lock busy guilds!").remove(&self.guild_id);
     }
 }
 
+async fn claim_voice(data: &RwLock<TypeMap>, guild_id: serenity::GuildId) -> Result<VoiceBusyGuard, Error> {
+    data.read().await
+        .get::<crate::VoiceBusyHolder>()
+        .expect("Expected busy guilds in TypeMap.")
+        .claim(guild_id)
+}
+
+/// The songbird manager placed in the client at startup.
+async fn voice_manager(ctx: Context<'_>) -> Arc<Songbird> {
+    songbird
+        ::get(ctx.serenity_context()).await
+        .expect("Songbird Voice client placed in at initialisation.")
+}
+
+/// Voice call in the server the command came from.
+async fn call(ctx: Context<'_>) -> Result<Arc<tokio::sync::Mutex<songbird::Call>>, Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+    Ok(voice_manager(ctx).await.get(guild_id).ok_or("Not in a voice channel, use /join first")?)
+}
+
+/// 48 kHz interleaved stereo samples as something a call can play.
+fn pcm_input(samples: &[f32]) -> Input {
+    Input::from(RawAdapter::new(std::io::Cursor::new(to_le_bytes(samples)), 48000, 2))
+}
+
+/// Buffer of the audio going from Discord to TeamSpeak.
+async fn discord_buffer(ctx: Context<'_>) -> Result<crate::AudioBufferDiscord, Error> {
+    let data_read = ctx.serenity_context().data.read().await;
+    let (_, discord_buffer) = data_read.get::<crate::ListenerHolder>().ok_or("Audio handlers not found")?;
+    Ok(discord_buffer.clone())
+}
+
 /// Join a voice channel
-#[poise::command(slash_command, guild_only)]
+#[poise::command(slash_command, guild_only, guild_cooldown = 5)]
 pub async fn join(
     ctx: Context<'_>,
     #[description = "Voice channel to join"] channel: serenity::Channel
@@ -52,18 +465,57 @@ pub async fn join(
 
     ctx.defer_ephemeral().await?;
 
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let manager = voice_manager(ctx).await;
+    join_channel(&ctx.serenity_context().data, ctx.http(), &manager, guild_id, connect_to).await?;
+
+    ctx.send(poise::CreateReply::default().content("Joined voice channel!").ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Join the voice channel of a member
+#[poise::command(context_menu_command = "Join their voice channel", rename = "join-user", guild_only, guild_cooldown = 5)]
+pub async fn join_user(ctx: Context<'_>, user: serenity::User) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+    let connect_to = ctx.guild().and_then(|guild| guild.voice_states.get(&user.id).and_then(|state| state.channel_id));
+
+    let Some(connect_to) = connect_to else {
+        ctx.send(
+            poise::CreateReply
+                ::default()
+                .content(format!("{} is not in a voice channel", user.name))
+                .ephemeral(true)
+        ).await?;
+        return Ok(());
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let manager = voice_manager(ctx).await;
+    join_channel(&ctx.serenity_context().data, ctx.http(), &manager, guild_id, connect_to).await?;
+
+    ctx.send(poise::CreateReply::default().content(format!("Joined <#{}>!", connect_to)).ephemeral(true)).await?;
+    Ok(())
+}
 
-    let handler_lock = manager.join(guild_id, connect_to).await?;
+/// Join `channel_id` in `guild_id` and wire up both audio directions.
+///
+/// Shared by the `/join` command and the stdio control interface.
+pub async fn join_channel(
+    data: &RwLock<TypeMap>,
+    http: &serenity::Http,
+    manager: &Songbird,
+    guild_id: serenity::GuildId,
+    channel_id: serenity::ChannelId
+) -> Result<(), Error> {
+    let _busy = claim_voice(data, guild_id).await?;
+    prefer_voice_region(data, http, channel_id).await;
+    let handler_lock = manager.join(guild_id, channel_id).await?;
 
     // Get audio handlers
     let channel: crate::AudioBufferDiscord;
     let ts_buffer: crate::TsToDiscordPipeline;
     {
-        let data_read = ctx.serenity_context().data.read().await;
+        let data_read = data.read().await;
         let (ts_buf, chan) = data_read
             .get::<ListenerHolder>()
             .expect("Expected audio handlers in TypeMap.")
@@ -72,7 +524,48 @@ pub async fn join(
         ts_buffer = ts_buf;
     }
 
+    let (impairer, settings, presence, music, echo, idle, voice_roles, solo, hands) = {
+        let data_read = data.read().await;
+        (
+            data_read.get::<crate::DiscordImpairment>().cloned().flatten(),
+            data_read.get::<crate::SettingsHolder>().expect("Expected settings in TypeMap.").clone(),
+            data_read.get::<crate::VoicePresenceHolder>().expect("Expected voice presence in TypeMap.").clone(),
+            data_read.get::<crate::MusicHolder>().expect("Expected music queues in TypeMap.").clone(),
+            data_read.get::<crate::EchoHolder>().expect("Expected echo recorder in TypeMap.").clone(),
+            data_read.get::<crate::ActivationHolder>().expect("Expected activation in TypeMap.").idle_flag(),
+            data_read.get::<crate::DiscordVoiceRoles>().cloned().flatten(),
+            data_read.get::<crate::SoloHolder>().cloned().unwrap_or_default(),
+            data_read.get::<crate::HandsHolder>().cloned().unwrap_or_default(),
+        )
+    };
+
+    let pool = channel.lock().await.frame_pool();
+    let settings_changes = settings.lock().expect("Can't lock settings!").changes();
+    let receiver = Receiver {
+        sink: channel,
+        pool,
+        impairer,
+        settings,
+        presence,
+        echo,
+        idle,
+        guild_id,
+        voice_roles,
+        solo,
+        hands,
+        senders: Default::default(),
+        settings_changes,
+        packet_times: ts_buffer.packet_times.clone(),
+        stats: ts_buffer.stats.clone(),
+        levels: ts_buffer.levels.clone(),
+        talk: ts_buffer.talk.clone(),
+        capture: ts_buffer.capture.clone(),
+    };
+
     let mut handler = handler_lock.lock().await;
+    if let Some(&bitrate) = data.read().await.get::<crate::DiscordBitrateHolder>() {
+        handler.set_bitrate(songbird::driver::Bitrate::BitsPerSecond(bitrate));
+    }
 
     let buffered = BufferedPipeline::new(ts_buffer.clone());
     buffered.start_filler();
@@ -80,30 +573,83 @@ pub async fn join(
     let discord_input = Input::from(RawAdapter::new(buffered, 48000, 2));
     let _track = handler.play_input(discord_input);
 
-    handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), Receiver::new(channel.clone()));
-    handler.add_global_event(CoreEvent::VoiceTick.into(), Receiver::new(channel.clone()));
-    handler.add_global_event(CoreEvent::RtcpPacket.into(), Receiver::new(channel.clone()));
-    handler.add_global_event(CoreEvent::ClientDisconnect.into(), Receiver::new(channel.clone()));
-    handler.add_global_event(CoreEvent::RtpPacket.into(), Receiver::new(channel.clone()));
+    handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), receiver.clone());
+    handler.add_global_event(CoreEvent::VoiceTick.into(), receiver.clone());
+    handler.add_global_event(CoreEvent::RtcpPacket.into(), receiver.clone());
+    handler.add_global_event(CoreEvent::ClientDisconnect.into(), receiver.clone());
+    handler.add_global_event(CoreEvent::RtpPacket.into(), receiver);
+
+    music.restore(guild_id.get(), &mut handler);
+    if let Some(events) = data.read().await.get::<crate::EventBusHolder>() {
+        events.publish(BridgeEvent::DiscordJoined { guild_id: guild_id.get(), channel_id: channel_id.get() });
+    }
 
-    ctx.send(poise::CreateReply::default().content("Joined voice channel!").ephemeral(true)).await?;
     Ok(())
 }
 
+/// Set `channel_id` to the configured voice region, if one is.
+///
+/// Discord picks the voice server by the channel's region, one close to the
+/// TeamSpeak server keeps the bridge's latency low. Needs the *Manage
+/// Channels* permission, the channel is joined anyway without it.
+async fn prefer_voice_region(data: &RwLock<TypeMap>, http: &serenity::Http, channel_id: ChannelId) {
+    let region = match data.read().await.get::<crate::VoiceRegionHolder>() {
+        Some(region) => region.clone(),
+        None => return,
+    };
+    let current = match http.get_channel(channel_id).await {
+        Ok(serenity::Channel::Guild(channel)) => channel.rtc_region,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::warn!("Can't look up the voice region of channel {}: {}", channel_id, e);
+            return;
+        }
+    };
+    if current.as_deref() == Some(region.as_str()) {
+        return;
+    }
+
+    let edit = serenity::EditChannel::new().voice_region(Some(region.clone()));
+    match channel_id.edit(http, edit).await {
+        Ok(_) => tracing::info!("Set voice region of channel {} to {}", channel_id, region),
+        Err(e) => tracing::warn!("Can't set voice region of channel {} to {}: {}", channel_id, region, e),
+    }
+}
+
+/// Leave the voice channel of `guild_id`, whether the bot was in one.
+///
+/// Shared by the `/leave` command and the stdio control interface.
+pub async fn leave_channel(
+    data: &RwLock<TypeMap>,
+    http: &serenity::Http,
+    manager: &Songbird,
+    guild_id: serenity::GuildId
+) -> Result<bool, Error> {
+    let _busy = claim_voice(data, guild_id).await?;
+    if manager.get(guild_id).is_none() {
+        return Ok(false);
+    }
+    manager.remove(guild_id).await?;
+    let summary = {
+        let data = data.read().await;
+        if let Some(events) = data.get::<crate::EventBusHolder>() {
+            events.publish(BridgeEvent::DiscordLeft { guild_id: guild_id.get() });
+        }
+        data.get::<crate::AdminChannelHolder>().copied().zip(data.get::<crate::StatsHolder>().map(BridgeStats::summary))
+    };
+    if let Some((channel_id, summary)) = summary {
+        post_to_admins(http, channel_id, &summary).await;
+    }
+    Ok(true)
+}
+
 /// Leave the voice channel
-#[poise::command(slash_command, guild_only)]
+#[poise::command(slash_command, guild_only, guild_cooldown = 5)]
 pub async fn leave(ctx: Context<'_>) -> Result<(), Error> {
     let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
 
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let has_handler = manager.get(guild_id).is_some();
-
-    if has_handler {
-        manager.remove(guild_id).await?;
+    let manager = voice_manager(ctx).await;
+    if leave_channel(&ctx.serenity_context().data, ctx.http(), &manager, guild_id).await? {
         ctx.send(
             poise::CreateReply::default().content("Left voice channel").ephemeral(true)
         ).await?;
@@ -119,14 +665,7 @@ pub async fn leave(ctx: Context<'_>) -> Result<(), Error> {
 /// Deafen the bot
 #[poise::command(slash_command, guild_only)]
 pub async fn deafen(ctx: Context<'_>) -> Result<(), Error> {
-    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
-
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let handler_lock = manager.get(guild_id).ok_or("Not in a voice channel")?;
+    let handler_lock = call(ctx).await?;
     let mut handler = handler_lock.lock().await;
 
     if handler.is_deaf() {
@@ -142,14 +681,7 @@ pub async fn deafen(ctx: Context<'_>) -> Result<(), Error> {
 /// Undeafen the bot
 #[poise::command(slash_command, guild_only)]
 pub async fn undeafen(ctx: Context<'_>) -> Result<(), Error> {
-    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
-
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let handler_lock = manager.get(guild_id).ok_or("Not in a voice channel")?;
+    let handler_lock = call(ctx).await?;
     let mut handler = handler_lock.lock().await;
 
     handler.deafen(false).await?;
@@ -161,14 +693,7 @@ pub async fn undeafen(ctx: Context<'_>) -> Result<(), Error> {
 /// Mute the bot
 #[poise::command(slash_command, guild_only)]
 pub async fn mute(ctx: Context<'_>) -> Result<(), Error> {
-    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
-
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let handler_lock = manager.get(guild_id).ok_or("Not in a voice channel")?;
+    let handler_lock = call(ctx).await?;
     let mut handler = handler_lock.lock().await;
 
     if handler.is_mute() {
@@ -184,14 +709,7 @@ pub async fn mute(ctx: Context<'_>) -> Result<(), Error> {
 /// Unmute the bot
 #[poise::command(slash_command, guild_only)]
 pub async fn unmute(ctx: Context<'_>) -> Result<(), Error> {
-    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
-
-    let manager = songbird
-        ::get(ctx.serenity_context()).await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let handler_lock = manager.get(guild_id).ok_or("Not in a voice channel")?;
+    let handler_lock = call(ctx).await?;
     let mut handler = handler_lock.lock().await;
 
     handler.mute(false).await?;
@@ -200,88 +718,1751 @@ pub async fn unmute(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Ping the bot
+/// How long to wait for the TeamSpeak connection to report its link quality.
+const LINK_QUALITY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Ping and packet loss to the TeamSpeak server, `None` while not connected.
+pub async fn ts_link_quality(data: &RwLock<TypeMap>) -> Option<LinkQuality> {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    data.read().await.get::<crate::TsCommandHolder>()?.send(TsCommand::LinkQuality { reply }).ok()?;
+    tokio::time::timeout(LINK_QUALITY_TIMEOUT, response).await.ok()?.ok().flatten()
+}
+
+/// Ping the bot and show the latency of every hop across the bridge
 #[poise::command(slash_command)]
 pub async fn ping(ctx: Context<'_>) -> Result<(), Error> {
-    ctx.send(poise::CreateReply::default().content("Pong!").ephemeral(true)).await?;
+    let data = &ctx.serenity_context().data;
+    let start = std::time::Instant::now();
+    let rest = ctx.http().get_current_user().await.ok().map(|_| start.elapsed());
+    let hops = Hops {
+        gateway: Some(ctx.ping().await).filter(|latency| !latency.is_zero()),
+        rest,
+        teamspeak: ts_link_quality(data).await,
+        discord_to_ts: discord_to_ts_latency(data).await,
+        ts_to_discord: ts_to_discord_latency(data).await,
+    };
+    ctx.send(poise::CreateReply::default().content(format!("Pong!\n{}", hops)).ephemeral(true)).await?;
     Ok(())
 }
 
-/// Set the bot's output volume
-#[poise::command(slash_command, guild_only)]
-pub async fn volume(
-    ctx: Context<'_>,
-    #[description = "Volume level (0.0 to 2.0, default 1.0)"] #[min = 0.0] #[max = 2.0] level: f32
-) -> Result<(), Error> {
-    let data_read = ctx.serenity_context().data.read().await;
-    let (_, discord_buffer) = data_read
-        .get::<crate::ListenerHolder>()
-        .ok_or("Audio handlers not found")?
-        .clone();
+/// Audio held for the deepest Discord speaker, plus the frame being sent.
+async fn discord_to_ts_latency(data: &RwLock<TypeMap>) -> Option<std::time::Duration> {
+    let (buffer, frame) = {
+        let data_read = data.read().await;
+        let (_, buffer) = data_read.get::<ListenerHolder>()?.clone();
+        (buffer, data_read.get::<crate::AudioProfilesHolder>()?.format().frame.interval())
+    };
+    let packets = buffer.lock().await.buffered_packets().map(|(_, packets)| packets).max().unwrap_or(0);
+    Some(std::time::Duration::from_millis((packets * crate::FRAME_SIZE_MS) as u64) + frame)
+}
 
-    let mut lock = discord_buffer.lock().await;
-    lock.set_global_volume(level);
+/// Audio waiting for songbird, plus the frame it mixes.
+async fn ts_to_discord_latency(data: &RwLock<TypeMap>) -> Option<std::time::Duration> {
+    let (pipeline, _) = data.read().await.get::<ListenerHolder>()?.clone();
+    let buffered_ms = pipeline.buffered.load(Ordering::Relaxed) / crate::audio::PCM_BYTES_PER_MS;
+    Some(std::time::Duration::from_millis((buffered_ms + crate::FRAME_SIZE_MS) as u64))
+}
 
-    ctx.send(
-        poise::CreateReply
-            ::default()
-            .content(format!("🔊 Volume set to: {:.0}%", level * 100.0))
-            .ephemeral(true)
-    ).await?;
+/// Show how long the bridge is up and what it passed on since
+#[poise::command(slash_command)]
+pub async fn uptime(ctx: Context<'_>) -> Result<(), Error> {
+    let stats = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::StatsHolder>()
+        .ok_or("Bridge statistics not available")?
+        .clone();
+    ctx.send(poise::CreateReply::default().content(stats.to_string()).ephemeral(true)).await?;
+    Ok(())
+}
 
+/// Show statistics of the bridged channels
+#[poise::command(slash_command, guild_only, subcommands("stats_talk"), subcommand_required)]
+pub async fn stats(_ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Reset all audio queues (use if audio gets stuck)
-#[poise::command(slash_command, guild_only)]
-pub async fn reset_audio(ctx: Context<'_>) -> Result<(), Error> {
-    let data_read = ctx.serenity_context().data.read().await;
-    let (_, discord_buffer) = data_read
-        .get::<crate::ListenerHolder>()
-        .ok_or("Audio handlers not found")?
-        .clone();
+/// Show who talked most this session and all time, on both sides
+#[poise::command(slash_command, guild_only, rename = "talk")]
+pub async fn stats_talk(
+    ctx: Context<'_>,
+    #[description = "Show the totals of this Discord user, yours if not given"] user: Option<serenity::User>
+) -> Result<(), Error> {
+    let (talk, settings) = {
+        let data = ctx.serenity_context().data.read().await;
+        (
+            data.get::<crate::TalkTimeHolder>().ok_or("Speaking time not available")?.clone(),
+            data.get::<crate::SettingsHolder>().ok_or("Settings not found")?.clone(),
+        )
+    };
+    let settings = settings.lock().expect("Can't lock settings!").get().clone();
+    let (session, all_time) = (talk.session(), talk.all_time(&settings));
+    let name = |talker: &Talker| match talker {
+        Talker::Discord(user) => format!("<@{}>", user),
+        Talker::Ts(uid) => format!("{} (TeamSpeak)", talk.ts_name(&settings, uid)),
+    };
+    let board = |totals| {
+        let ranked = leaderboard(totals, LEADERBOARD_PLACES);
+        if ranked.is_empty() {
+            return "Nobody talked yet".to_string();
+        }
+        ranked
+            .iter()
+            .enumerate()
+            .map(|(i, (talker, length))| format!("{}. {} {}", i + 1, name(talker), format_duration(*length)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let user = user.as_ref().unwrap_or_else(|| ctx.author());
+    let talker = Talker::Discord(user.id.get());
+    let total = |totals: &HashMap<Talker, std::time::Duration>| format_duration(totals.get(&talker).copied().unwrap_or_default());
+    let content = format!(
+        "**Speaking time this session**\n{}\n\n**All time**\n{}\n\n{}: {} this session, {} all time",
+        board(&session),
+        board(&all_time),
+        user.name,
+        total(&session),
+        total(&all_time)
+    );
+    let reply = poise::CreateReply::default()
+        .content(content)
+        .allowed_mentions(serenity::CreateAllowedMentions::new())
+        .ephemeral(true);
+    ctx.send(reply).await?;
+    Ok(())
+}
 
-    let mut lock = discord_buffer.lock().await;
-    lock.reset();
+/// Discord cuts embed descriptions longer than this.
+const MAX_EMBED_DESCRIPTION_CHARS: usize = 4096;
+
+/// Suggest command names for `/help`.
+async fn autocomplete_command(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let partial = partial.to_lowercase();
+    ctx.framework()
+        .options()
+        .commands.iter()
+        .filter(|c| !c.hide_in_help && c.slash_action.is_some() && c.name.contains(&partial))
+        .map(|c| c.name.clone())
+        .take(MAX_AUTOCOMPLETE_CHOICES)
+        .collect()
+}
 
-    ctx.send(
-        poise::CreateReply::default().content("🔄 Audio queues reset!").ephemeral(true)
-    ).await?;
+/// List what the bridge can do, who may use it and the cooldowns
+#[poise::command(slash_command)]
+pub async fn help(
+    ctx: Context<'_>,
+    #[description = "Show the options of this command"]
+    #[autocomplete = "autocomplete_command"]
+    command: Option<String>
+) -> Result<(), Error> {
+    let commands = &ctx.framework().options().commands;
+    let (title, mut text) = match command {
+        Some(name) => {
+            let command = commands
+                .iter()
+                .find(|c| c.name == name.trim_start_matches('/'))
+                .ok_or_else(|| format!("No command {}", name))?;
+            let mut lines = vec![help_line(command)];
+            lines.extend(
+                command.parameters.iter().map(|p| {
+                    let optional = if p.required { "" } else { " (optional)" };
+                    format!("• `{}`{} {}", p.name, optional, p.description.as_deref().unwrap_or_default())
+                })
+            );
+            (format!("/{}", command.name), lines.join("\n"))
+        }
+        None => {
+            let lines: Vec<_> = commands
+                .iter()
+                .filter(|c| !c.hide_in_help)
+                .map(help_line)
+                .collect();
+            ("Bridge commands".to_string(), lines.join("\n"))
+        }
+    };
+    if text.chars().count() > MAX_EMBED_DESCRIPTION_CHARS {
+        text = text.chars().take(MAX_EMBED_DESCRIPTION_CHARS - 1).collect();
+        text.push('…');
+    }
+
+    let embed = serenity::CreateEmbed::new().title(title).description(text);
+    ctx.send(poise::CreateReply::default().embed(embed).ephemeral(true)).await?;
     Ok(())
 }
 
-/// Check the current bot output volume
-#[poise::command(slash_command, guild_only)]
-pub async fn volume_check(ctx: Context<'_>) -> Result<(), Error> {
-    let data_read = ctx.serenity_context().data.read().await;
-    let (_, discord_buffer) = data_read
-        .get::<crate::ListenerHolder>()
-        .ok_or("Audio handlers not found")?
-        .clone();
+/// Name, description, needed permissions and cooldowns of `command`, on one line.
+fn help_line(command: &poise::Command<Data, Error>) -> String {
+    let name = match &command.context_menu_name {
+        Some(name) if command.context_menu_action.is_some() => format!("*Apps → {}*", name),
+        _ => format!("`/{}`", command.name),
+    };
+    let mut line = format!("{} {}", name, command.description.as_deref().unwrap_or_default());
 
-    let lock = discord_buffer.lock().await;
-    let current = lock.get_global_volume();
+    let permissions = command.default_member_permissions | command.required_permissions;
+    if !permissions.is_empty() {
+        line += &format!(" · needs {}", permissions.get_permission_names().join(", "));
+    }
+    let cooldowns = command.cooldown_config.read().expect("Can't lock cooldowns!").clone();
+    let scopes = [
+        (cooldowns.global, "in total"),
+        (cooldowns.guild, "per server"),
+        (cooldowns.channel, "per channel"),
+        (cooldowns.user, "per user"),
+        (cooldowns.member, "per member"),
+    ];
+    for (cooldown, scope) in scopes {
+        if let Some(cooldown) = cooldown {
+            line += &format!(" · {}s cooldown {}", cooldown.as_secs(), scope);
+        }
+    }
+    line
+}
+
+/// Set the bot's output volume
+#[poise::command(slash_command, guild_only, default_member_permissions = "MANAGE_GUILD")]
+pub async fn volume(
+    ctx: Context<'_>,
+    #[description = "Volume as a factor (0.0 to 2.0, default 1.0) or in decibels like -6dB"] level: String
+) -> Result<(), Error> {
+    let level = parse_volume(&level)?;
+    let discord_buffer = discord_buffer(ctx).await?;
+    let mut lock = discord_buffer.lock().await;
+    lock.set_global_volume(level);
 
     ctx.send(
         poise::CreateReply
             ::default()
-            .content(format!("🔊 Current volume: {:.0}%", current * 100.0))
+            .content(format!("🔊 Volume set to: {}", format_volume(level)))
             .ephemeral(true)
     ).await?;
 
     Ok(())
 }
 
-struct Receiver {
-    sink: crate::AudioBufferDiscord,
+/// Set the volume of /play music, without changing the bridged voices
+#[poise::command(slash_command, guild_only, rename = "music-volume", default_member_permissions = "MANAGE_GUILD")]
+pub async fn music_volume(
+    ctx: Context<'_>,
+    #[description = "Music volume as a factor (0.0 to 2.0, default 1.0) or in decibels like -6dB"] level: Option<String>
+) -> Result<(), Error> {
+    let music = music(ctx).await?;
+    let content = match level {
+        Some(level) => {
+            let level = parse_volume(&level)?;
+            music.set_volume(level)?;
+            format!("🎵 Music volume set to: {}", format_volume(level))
+        }
+        None => format!("🎵 Music volume: {}", format_volume(music.volume())),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Show or set how loud each source is on each side
+#[poise::command(slash_command, guild_only, default_member_permissions = "MANAGE_GUILD")]
+pub async fn route(
+    ctx: Context<'_>,
+    #[description = "Source and the side hearing it"] route: Option<Route>,
+    #[description = "Volume as a factor (0.0 to 2.0, default 1.0) or in decibels like -3dB"] level: Option<String>
+) -> Result<(), Error> {
+    let routes = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::RoutesHolder>()
+        .ok_or("Routes not available")?
+        .clone();
+    let discord_buffer = discord_buffer(ctx).await?;
+    let content = match (route, level) {
+        (Some(route), Some(level)) => {
+            let level = parse_volume(&level)?;
+            match route {
+                Route::DiscordToTs => discord_buffer.lock().await.set_global_volume(level),
+                Route::MusicToDiscord => {
+                    routes.set(route, level);
+                    music(ctx).await?.refresh_track_volume();
+                }
+                Route::TsToDiscord | Route::MusicToTs => routes.set(route, level),
+            }
+            format!("🎚️ {} set to: {}", route.name(), format_volume(level))
+        }
+        (None, Some(_)) => return Err("Pick the route to set".into()),
+        (route, None) => {
+            let summary = routes.summary(discord_buffer.lock().await.get_global_volume());
+            match route {
+                Some(route) => summary.lines().find(|line| line.starts_with(route.name())).unwrap_or_default().to_string(),
+                None => summary,
+            }
+        }
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Play a test tone to TeamSpeak or Discord, to check each side on its own
+#[poise::command(slash_command, guild_only, default_member_permissions = "MANAGE_GUILD")]
+pub async fn tone(
+    ctx: Context<'_>,
+    #[description = "Who should hear the tone"] direction: ToneDirection,
+    #[description = "Frequency in Hz (default 1000)"] #[min = 20.0] #[max = 20000.0] frequency: Option<f32>,
+    #[description = "Length in seconds (default 2)"] #[min = 0.1] #[max = 10.0] seconds: Option<f32>,
+    #[description = "Level in dBFS (default -20)"] #[min = -60.0] #[max = 0.0] level: Option<f32>
+) -> Result<(), Error> {
+    let frequency = frequency.unwrap_or(tone::DEFAULT_FREQUENCY);
+    let seconds = seconds.unwrap_or(tone::DEFAULT_SECONDS).min(tone::MAX_SECONDS);
+    let level = level.unwrap_or(tone::DEFAULT_LEVEL_DB);
+    let samples = tone::sine(frequency, seconds, level);
+
+    match direction {
+        ToneDirection::TeamSpeak => {
+            let sounds = ctx
+                .serenity_context()
+                .data.read().await
+                .get::<crate::SoundFeedHolder>()
+                .ok_or("Sound feed not found")?
+                .clone();
+            sounds.play(&samples);
+        }
+        ToneDirection::Discord => {
+            call(ctx).await?.lock().await.play_input(pcm_input(&samples));
+        }
+    }
+
+    ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(format!("🔔 Playing {:.0} Hz at {:.0} dBFS for {:.1} s to {}", frequency, level, seconds, direction.name()))
+            .ephemeral(true)
+    ).await?;
+    Ok(())
+}
+
+/// Record yourself for a few seconds and hear it played back, TeamSpeak doesn't hear it
+#[poise::command(slash_command, guild_only, rename = "echo-test", user_cooldown = 10)]
+pub async fn echo_test(ctx: Context<'_>) -> Result<(), Error> {
+    let call = call(ctx).await?;
+    let echo = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::EchoHolder>()
+        .ok_or("Echo recorder not found")?
+        .clone();
+    let user = ctx.author().id.get();
+    if !echo.start(user) {
+        return Err("Already recording you".into());
+    }
+
+    let seconds = ECHO_DURATION.as_secs();
+    ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(format!("🎙️ Recording you for {} s, talk now", seconds))
+            .ephemeral(true)
+    ).await?;
+    tokio::time::sleep(ECHO_DURATION).await;
+
+    let samples = echo.finish(&user)?;
+    let content = if samples.is_empty() {
+        "🔇 Didn't hear anything from you".to_string()
+    } else {
+        call.lock().await.play_input(pcm_input(&samples));
+        format!("🔁 Playing back {:.1} s", (samples.len() as f32) / 96_000.0)
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Measure your microphone's background noise and mute it between your words in TeamSpeak
+#[poise::command(slash_command, guild_only, rename = "calibrate-noise", user_cooldown = 10)]
+pub async fn calibrate_noise(
+    ctx: Context<'_>,
+    #[description = "Turn your noise gate off instead"] off: Option<bool>
+) -> Result<(), Error> {
+    let settings = settings(ctx).await?;
+    let user = ctx.author().id.get();
+    let set_gate = |gate: Option<f32>| {
+        settings
+            .lock()
+            .expect("Can't lock settings!")
+            .update(|s| {
+                let mut prefs = s.user_prefs.remove(&user).unwrap_or_default();
+                prefs.noise_gate_db = gate;
+                if prefs != UserPrefs::default() {
+                    s.user_prefs.insert(user, prefs);
+                }
+            })
+    };
+    if off.unwrap_or(false) {
+        set_gate(None)?;
+        ctx.send(poise::CreateReply::default().content("🎙️ Your noise gate is off").ephemeral(true)).await?;
+        return Ok(());
+    }
+
+    call(ctx).await?;
+    let echo = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::EchoHolder>()
+        .ok_or("Echo recorder not found")?
+        .clone();
+    if !echo.start(user) {
+        return Err("Already recording you".into());
+    }
+    ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(format!("🤫 Listening to your background noise for {} s, stay quiet", NOISE_CALIBRATION.as_secs()))
+            .ephemeral(true)
+    ).await?;
+    tokio::time::sleep(NOISE_CALIBRATION).await;
+
+    let content = match noise_gate_threshold(&echo.finish(&user)?) {
+        Some(threshold_db) => {
+            set_gate(Some(threshold_db))?;
+            format!("🎙️ TeamSpeak hears you only above {:.0} dBFS from now on, `/calibrate-noise off:True` undoes it", threshold_db)
+        }
+        None => {
+            "🔇 Didn't hear anything from you, Discord's voice activity already keeps your background noise out".to_string()
+        }
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Drop the audio held back by the broadcast delay, before anybody hears it
+#[poise::command(slash_command, guild_only, default_member_permissions = "MUTE_MEMBERS")]
+pub async fn dump(ctx: Context<'_>) -> Result<(), Error> {
+    let delay = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::BroadcastDelayHolder>()
+        .ok_or("No broadcast delay is set up")?
+        .clone();
+    delay.dump();
+    ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(format!("⏏️ Dumped the last {:.1} s, nobody will hear them", delay.delay().as_secs_f32()))
+            .ephemeral(true)
+    ).await?;
+    Ok(())
+}
+
+/// Stop bridging audio for now, e.g. for a private talk
+#[poise::command(slash_command, guild_only, rename = "pause-bridge", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn pause_bridge(
+    ctx: Context<'_>,
+    #[description = "Direction to pause (default both)"] direction: Option<PauseDirection>
+) -> Result<(), Error> {
+    set_bridge_paused(ctx, direction.unwrap_or(PauseDirection::Both), true).await
+}
+
+/// Bridge audio again after /pause-bridge
+#[poise::command(slash_command, guild_only, rename = "resume-bridge", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn resume_bridge(
+    ctx: Context<'_>,
+    #[description = "Direction to resume (default both)"] direction: Option<PauseDirection>
+) -> Result<(), Error> {
+    set_bridge_paused(ctx, direction.unwrap_or(PauseDirection::Both), false).await
+}
+
+async fn set_bridge_paused(ctx: Context<'_>, direction: PauseDirection, paused: bool) -> Result<(), Error> {
+    let pause = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::BridgePauseHolder>()
+        .ok_or("Bridge pause not available")?
+        .clone();
+    if !pause.set(direction, paused) {
+        ctx.send(poise::CreateReply::default().content(pause.summary()).ephemeral(true)).await?;
+        return Ok(());
+    }
+    let summary = pause.summary();
+    let icon = if paused { "⏸️" } else { "▶️" };
+    // Everybody in the call should know what is still heard on the other side
+    ctx.say(format!("{} {} ({})", icon, summary, ctx.author().name)).await?;
+    send_ts_command(ctx, TsCommand::Announce { text: summary }).await
+}
+
+/// Reset all audio queues (use if audio gets stuck)
+#[poise::command(slash_command, guild_only, guild_cooldown = 10)]
+pub async fn reset_audio(ctx: Context<'_>) -> Result<(), Error> {
+    let discord_buffer = discord_buffer(ctx).await?;
+    let mut lock = discord_buffer.lock().await;
+    lock.reset();
+
+    ctx.send(
+        poise::CreateReply::default().content("🔄 Audio queues reset!").ephemeral(true)
+    ).await?;
+    Ok(())
+}
+
+/// Check the current bot output volume
+#[poise::command(slash_command, guild_only)]
+pub async fn volume_check(ctx: Context<'_>) -> Result<(), Error> {
+    let discord_buffer = discord_buffer(ctx).await?;
+    let lock = discord_buffer.lock().await;
+    let current = lock.get_global_volume();
+
+    ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(format!("🔊 Current volume: {}", format_volume(current)))
+            .ephemeral(true)
+    ).await?;
+
+    Ok(())
+}
+
+/// Leave a TeamSpeak client out of what Discord hears
+#[poise::command(slash_command, guild_only, rename = "ts-mute", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn ts_mute(
+    ctx: Context<'_>,
+    #[description = "TeamSpeak client name or unique id"] client: String
+) -> Result<(), Error> {
+    set_ts_muted(ctx, client, true).await
+}
+
+/// Let Discord hear a muted TeamSpeak client again
+#[poise::command(slash_command, guild_only, rename = "ts-unmute", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn ts_unmute(
+    ctx: Context<'_>,
+    #[description = "TeamSpeak client name or unique id"] client: String
+) -> Result<(), Error> {
+    set_ts_muted(ctx, client, false).await
+}
+
+/// Mix only TeamSpeak speakers at full volume, e.g. for a moderated event
+#[poise::command(slash_command, guild_only, default_member_permissions = "MUTE_MEMBERS")]
+pub async fn podium(
+    ctx: Context<'_>,
+    #[description = "Turn podium mode on or off"] on: bool
+) -> Result<(), Error> {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::SetPodium { enabled: on, reply }).await?;
+    let speakers = response.await?;
+    let summary = if on {
+        format!("Podium mode on: {} TeamSpeak speakers at full volume in Discord, everybody else turned down", speakers)
+    } else {
+        "Podium mode off: every TeamSpeak client at full volume in Discord".to_string()
+    };
+    ctx.say(format!("🎤 {} ({})", summary, ctx.author().name)).await?;
+    send_ts_command(ctx, TsCommand::Announce { text: summary }).await
+}
+
+/// Bridge only picked speakers, e.g. for an interview or announcement
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("solo_user", "solo_ts", "solo_off"),
+    subcommand_required,
+    default_member_permissions = "MUTE_MEMBERS"
+)]
+pub async fn solo(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Bridge this Discord user, and only the other picked speakers
+#[poise::command(slash_command, guild_only, rename = "user", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn solo_user(
+    ctx: Context<'_>,
+    #[description = "Discord user to bridge"] user: serenity::User
+) -> Result<(), Error> {
+    let name = match ctx.guild_id() {
+        Some(guild_id) => user.nick_in(ctx, guild_id).await.unwrap_or_else(|| user.display_name().to_string()),
+        None => user.display_name().to_string(),
+    };
+    let solo = bridge_solo(ctx).await?;
+    let added = solo.add_discord(user.id.get(), name);
+    announce_solo(ctx, &solo, added).await
+}
+
+/// Bridge this TeamSpeak client, and only the other picked speakers
+#[poise::command(slash_command, guild_only, rename = "ts", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn solo_ts(
+    ctx: Context<'_>,
+    #[description = "TeamSpeak client name or unique id"] client: String
+) -> Result<(), Error> {
+    let book = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::TsBookHolder>()
+        .ok_or("TeamSpeak connection not available")?
+        .snapshot();
+    let found = book.find_client(&client).ok_or_else(|| format!("No TeamSpeak client {} connected", client))?;
+    let (id, name) = (found.id, found.name.clone());
+    let solo = bridge_solo(ctx).await?;
+    let added = solo.add_ts(id, name);
+    announce_solo(ctx, &solo, added).await
+}
+
+/// Bridge everybody again
+#[poise::command(slash_command, guild_only, rename = "off", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn solo_off(ctx: Context<'_>) -> Result<(), Error> {
+    let solo = bridge_solo(ctx).await?;
+    let cleared = solo.clear();
+    announce_solo(ctx, &solo, cleared).await
+}
+
+async fn bridge_solo(ctx: Context<'_>) -> Result<Solo, Error> {
+    Ok(ctx.serenity_context().data.read().await.get::<crate::SoloHolder>().ok_or("Solo not available")?.clone())
+}
+
+/// Tell both sides who is bridged now, or only the caller if nothing `changed`.
+async fn announce_solo(ctx: Context<'_>, solo: &Solo, changed: bool) -> Result<(), Error> {
+    let summary = solo.summary();
+    if !changed {
+        ctx.send(poise::CreateReply::default().content(summary).ephemeral(true)).await?;
+        return Ok(());
+    }
+    ctx.say(format!("🎙️ {} ({})", summary, ctx.author().name)).await?;
+    send_ts_command(ctx, TsCommand::Announce { text: summary }).await
+}
+
+/// Ask to speak in a moderated session
+#[poise::command(slash_command, guild_only, rename = "hand-raise")]
+pub async fn hand_raise(ctx: Context<'_>) -> Result<(), Error> {
+    let name = match ctx.author_member().await {
+        Some(member) => member.display_name().to_string(),
+        None => ctx.author().name.clone(),
+    };
+    let hand = Hand { speaker: Speaker::Discord(ctx.author().id.get()), name };
+    let place = raised_hands(ctx).await?.raise(hand);
+    let content = format!("✋ Your hand is raised, you are number {} in line", place);
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Take back your raised hand
+#[poise::command(slash_command, guild_only, rename = "hand-lower")]
+pub async fn hand_lower(ctx: Context<'_>) -> Result<(), Error> {
+    let lowered = raised_hands(ctx).await?.lower(Speaker::Discord(ctx.author().id.get()));
+    let content = if lowered { "Your hand is down" } else { "Your hand wasn't raised" };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Show who raised their hand and who has the floor
+#[poise::command(slash_command, guild_only)]
+pub async fn hands(ctx: Context<'_>) -> Result<(), Error> {
+    let hands = raised_hands(ctx).await?;
+    let mut lines = Vec::new();
+    if let Some(hand) = hands.floor() {
+        lines.push(format!("🎤 {} has the floor", hand.label()));
+    }
+    let queued = hands.hands();
+    if queued.is_empty() {
+        lines.push("Nobody raised their hand".to_string());
+    }
+    for (place, hand) in queued.iter().enumerate() {
+        lines.push(format!("{}. {}", place + 1, hand.label()));
+    }
+    ctx.send(poise::CreateReply::default().content(lines.join("\n")).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Give the floor to the next raised hand, bridging them even if muted
+#[poise::command(slash_command, guild_only, default_member_permissions = "MUTE_MEMBERS")]
+pub async fn next(
+    ctx: Context<'_>,
+    #[description = "Seconds they may speak (default from the config)"] #[min = 10] #[max = 3600] seconds: Option<u64>
+) -> Result<(), Error> {
+    let hands = raised_hands(ctx).await?;
+    let time = seconds.map_or(hands.floor_time(), std::time::Duration::from_secs);
+    let Some(hand) = hands.next(time) else {
+        ctx.say("Nobody raised their hand, the floor is closed").await?;
+        return Ok(());
+    };
+    let text = format!("{} has the floor for {}.", hand.label(), format_duration(time));
+    ctx.say(format!("🎤 {} ({})", text, ctx.author().name)).await?;
+    send_ts_command(ctx, TsCommand::Announce { text }).await
+}
+
+async fn raised_hands(ctx: Context<'_>) -> Result<HandQueue, Error> {
+    Ok(ctx.serenity_context().data.read().await.get::<crate::HandsHolder>().ok_or("Raised hands not available")?.clone())
+}
+
+async fn set_ts_muted(ctx: Context<'_>, client: String, muted: bool) -> Result<(), Error> {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::SetMuted { client, muted, reply }).await?;
+
+    let content = match response.await? {
+        Ok(name) if muted => format!("🔇 {} is no longer bridged to Discord", name),
+        Ok(name) => format!("🔊 {} is bridged to Discord again", name),
+        Err(e) => e,
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Hand a command to the main loop, which owns the TeamSpeak connection.
+async fn send_ts_command(ctx: Context<'_>, command: TsCommand) -> Result<(), Error> {
+    ctx.serenity_context()
+        .data.read().await
+        .get::<crate::TsCommandHolder>()
+        .ok_or("TeamSpeak connection not available")?
+        .send(command)
+        .map_err(|_| "TeamSpeak connection is shutting down")?;
+    Ok(())
+}
+
+/// Send a message to the TeamSpeak channel
+#[poise::command(slash_command, guild_only, rename = "ts-message")]
+pub async fn ts_message(
+    ctx: Context<'_>,
+    #[description = "Text to send"] text: String
+) -> Result<(), Error> {
+    let author = ctx.author().name.clone();
+    let text = readable_mentions(&text, |mention| mention_name(ctx.cache(), ctx.guild_id(), mention));
+    send_ts_message(ctx, author, link_urls(&text)).await?;
+    Ok(())
+}
+
+/// Forward a message to the TeamSpeak channel
+#[poise::command(context_menu_command = "Send to TeamSpeak", guild_only)]
+pub async fn send_to_ts(ctx: Context<'_>, message: serenity::Message) -> Result<(), Error> {
+    let text = message_text(ctx.cache(), message.guild_id, &message.content, &message.attachments, &message.embeds);
+    let author = message.author.name.clone();
+    if send_ts_message(ctx, author.clone(), text.clone()).await? {
+        // Edits and deletions are followed up on
+        if let Some(relayed) = ctx.serenity_context().data.read().await.get::<crate::RelayedHolder>() {
+            relayed.record(message.id.get(), author, text);
+        }
+    }
+    Ok(())
+}
+
+/// Text of a Discord message for TeamSpeak, with attachments and embeds as links.
+fn message_text(
+    cache: &serenity::Cache,
+    guild_id: Option<GuildId>,
+    content: &str,
+    attachments: &[Attachment],
+    embeds: &[Embed]
+) -> String {
+    let attachments = attachments.iter().map(|a| (a.url.as_str(), a.filename.as_str()));
+    let embeds = embeds.iter().filter_map(|e| {
+        let url = e.url.as_deref()?;
+        Some((url, e.title.as_deref().unwrap_or(url)))
+    });
+    let content = readable_mentions(content, |mention| mention_name(cache, guild_id, mention));
+    relay_text(&content, attachments.chain(embeds))
+}
+
+/// Name of the member, channel or role `mention` points to, as far as it is cached.
+fn mention_name(cache: &serenity::Cache, guild_id: Option<GuildId>, mention: Mention) -> Option<String> {
+    if let Mention::User(id) = mention {
+        let id = serenity::UserId::new(id);
+        let member = guild_id
+            .and_then(|guild_id| cache.guild(guild_id))
+            .and_then(|guild| guild.members.get(&id).map(|m| m.display_name().to_string()));
+        return member.or_else(|| cache.user(id).map(|user| user.display_name().to_string()));
+    }
+    let guild = cache.guild(guild_id?)?;
+    match mention {
+        Mention::Channel(id) => guild.channels.get(&ChannelId::new(id)).map(|c| c.name.clone()),
+        Mention::Role(id) => guild.roles.get(&serenity::RoleId::new(id)).map(|r| r.name.clone()),
+        Mention::User(_) => None,
+    }
+}
+
+/// Send `text` to the TeamSpeak channel, whether it arrived.
+async fn send_ts_message(ctx: Context<'_>, author: String, text: String) -> Result<bool, Error> {
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::SendMessage { author, text, reply }).await?;
+
+    let (sent, content) = match response.await? {
+        Ok(()) => (true, "💬 Sent to TeamSpeak".to_string()),
+        Err(e) => (false, e),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(sent)
+}
+
+/// Discord shows at most this many autocomplete choices.
+const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
+
+/// Suggest connected TeamSpeak clients.
+async fn autocomplete_ts_client(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Some(book) = ctx.serenity_context().data.read().await.get::<crate::TsBookHolder>().cloned() else {
+        return Vec::new();
+    };
+    let partial = partial.to_lowercase();
+    book.snapshot()
+        .client_names()
+        .into_iter()
+        .filter(|name| name.to_lowercase().contains(&partial))
+        .take(MAX_AUTOCOMPLETE_CHOICES)
+        .collect()
+}
+
+/// Poke a TeamSpeak client with a message, e.g. to call someone who is AFK
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "ts-poke",
+    default_member_permissions = "MOVE_MEMBERS"
+)]
+pub async fn ts_poke(
+    ctx: Context<'_>,
+    #[description = "TeamSpeak client name or unique id"]
+    #[autocomplete = "autocomplete_ts_client"]
+    client: String,
+    #[description = "Text of the poke"] text: String
+) -> Result<(), Error> {
+    let author = ctx.author().name.clone();
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::Poke { client, author, text, reply }).await?;
+
+    let content = match response.await? {
+        Ok(name) => format!("👉 Poked {}", name),
+        Err(e) => e,
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+#[derive(Debug, poise::Modal)]
+#[name = "TeamSpeak channel password"]
+struct ChannelPasswordModal {
+    #[name = "Password"]
+    #[placeholder = "Password of the bridge's TeamSpeak channel"]
+    password: String,
+}
+
+/// Give the password of the bridge's TeamSpeak channel and join it again
+///
+/// Asked for in a form, so the password doesn't show up in the command or
+/// the audit log. It is kept for the next start once TeamSpeak accepted it.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "ts-channel-password",
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn ts_channel_password(ctx: poise::ApplicationContext<'_, Data, Error>) -> Result<(), Error> {
+    use poise::Modal as _;
+
+    let password = match ChannelPasswordModal::execute(ctx).await? {
+        Some(modal) => modal.password,
+        None => return Ok(()),
+    };
+    let ctx = poise::Context::Application(ctx);
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::JoinHomeChannel { password: password.clone(), reply }).await?;
+
+    let content = match response.await? {
+        Ok(name) => {
+            let settings = ctx
+                .serenity_context()
+                .data.read().await
+                .get::<crate::SettingsHolder>()
+                .ok_or("Settings not found")?
+                .clone();
+            settings
+                .lock()
+                .expect("Can't lock settings!")
+                .update(|s| s.ts_channel_password = Some(password))?;
+            format!("🔑 The bridge joined {}", name)
+        }
+        Err(e) => e,
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Reconnect to TeamSpeak with the next configured identity
+///
+/// For when the current identity got banned, or a spare with a higher
+/// security level was computed offline. Kept for the next start.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "rotate-identity",
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn rotate_identity(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let (reply, response) = tokio::sync::oneshot::channel();
+    send_ts_command(ctx, TsCommand::RotateIdentity { reply }).await?;
+
+    let content = match response.await? {
+        Ok(identity) => format!("🪪 {}", identity),
+        Err(e) => e,
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Post `text` to the admin channel, without waiting for it.
+pub fn notify_admins(http: Arc<serenity::Http>, channel_id: u64, text: String) {
+    tokio::spawn(async move {
+        post_to_admins(&http, channel_id, &text).await;
+    });
+}
+
+/// Post `text` to the admin channel `channel_id` and wait for it, e.g. while shutting down.
+pub async fn post_to_admins(http: &serenity::Http, channel_id: u64, text: &str) {
+    if let Err(e) = ChannelId::new(channel_id).say(http, text).await {
+        tracing::warn!("Failed to post to the admin channel: {}", e);
+    }
+}
+
+/// Post `text` in the chat of every voice channel the bridge is in.
+pub fn announce_in_calls(http: Arc<serenity::Http>, manager: Arc<Songbird>, text: String) {
+    tokio::spawn(async move {
+        let calls: Vec<_> = manager.iter().collect();
+        for (_, call) in calls {
+            let channel_id = match call.lock().await.current_channel() {
+                Some(channel_id) => ChannelId::new(channel_id.0.get()),
+                None => continue,
+            };
+            if let Err(e) = channel_id.say(&http, &text).await {
+                tracing::warn!("Failed to announce in {}: {}", channel_id, e);
+            }
+        }
+    });
+}
+
+/// Show or change the hours the bridge is live
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("schedule_show", "schedule_add", "schedule_remove", "schedule_reset"),
+    subcommand_required
+)]
+pub async fn schedule(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Show when the bridge is live
+#[poise::command(slash_command, guild_only, rename = "show")]
+pub async fn schedule_show(ctx: Context<'_>) -> Result<(), Error> {
+    let schedule = bridge_schedule(ctx).await?;
+    ctx.send(poise::CreateReply::default().content(schedule_summary(&schedule)).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Add hours the bridge is live, like "Mon-Fri 19:00-23:30"
+#[poise::command(slash_command, guild_only, rename = "add", default_member_permissions = "MANAGE_GUILD")]
+pub async fn schedule_add(
+    ctx: Context<'_>,
+    #[description = "Days and local hours, like \"Sat 20:00-02:00\" or \"daily 19:00-23:00\""] window: String
+) -> Result<(), Error> {
+    let window: Window = window.parse()?;
+    change_schedule(ctx, |windows| {
+        if !windows.contains(&window) {
+            windows.push(window);
+        }
+    }).await
+}
+
+/// Remove hours the bridge is live, without any it is always live
+#[poise::command(slash_command, guild_only, rename = "remove", default_member_permissions = "MANAGE_GUILD")]
+pub async fn schedule_remove(
+    ctx: Context<'_>,
+    #[description = "Window as shown by /schedule show"]
+    #[autocomplete = "autocomplete_schedule_window"]
+    window: String
+) -> Result<(), Error> {
+    let schedule = bridge_schedule(ctx).await?;
+    if !schedule.windows().iter().any(|w| w.to_string() == window) {
+        return Err(format!("No window {:?} in the schedule", window).into());
+    }
+    change_schedule(ctx, |windows| windows.retain(|w| w.to_string() != window)).await
+}
+
+/// Go back to the schedule from the config file
+#[poise::command(slash_command, guild_only, rename = "reset", default_member_permissions = "MANAGE_GUILD")]
+pub async fn schedule_reset(ctx: Context<'_>) -> Result<(), Error> {
+    let schedule = bridge_schedule(ctx).await?;
+    settings(ctx).await?.lock().expect("Can't lock settings!").update(|s| s.schedule = None)?;
+    schedule.notify_changed();
+    ctx.send(poise::CreateReply::default().content(schedule_summary(&schedule)).ephemeral(true)).await?;
+    Ok(())
+}
+
+async fn autocomplete_schedule_window(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let windows = match bridge_schedule(ctx).await {
+        Ok(schedule) => schedule.windows(),
+        Err(_) => return Vec::new(),
+    };
+    windows
+        .iter()
+        .map(Window::to_string)
+        .filter(|w| w.to_lowercase().contains(&partial.to_lowercase()))
+        .take(MAX_AUTOCOMPLETE_CHOICES)
+        .collect()
+}
+
+async fn change_schedule(ctx: Context<'_>, change: impl FnOnce(&mut Vec<Window>)) -> Result<(), Error> {
+    let schedule = bridge_schedule(ctx).await?;
+    let mut windows = schedule.windows();
+    change(&mut windows);
+    settings(ctx).await?.lock().expect("Can't lock settings!").update(|s| s.schedule = Some(windows))?;
+    schedule.notify_changed();
+    ctx.send(poise::CreateReply::default().content(schedule_summary(&schedule)).ephemeral(true)).await?;
+    Ok(())
+}
+
+fn schedule_summary(schedule: &BridgeSchedule) -> String {
+    let windows = schedule.windows();
+    let source = if schedule.is_overridden() { "set with /schedule" } else { "from the config" };
+    if windows.is_empty() {
+        return format!("🟢 The bridge is always live ({})", source);
+    }
+    let list: Vec<_> = windows.iter().map(|w| format!("- {}", w)).collect();
+    format!("🗓️ The bridge is live ({}, local time of the bridge):\n{}", source, list.join("\n"))
+}
+
+/// Switch how audio is sent to TeamSpeak, or show the profiles
+#[poise::command(slash_command, guild_only, default_member_permissions = "MANAGE_GUILD")]
+pub async fn profile(
+    ctx: Context<'_>,
+    #[description = "Profile to switch to, \"default\" for the plain config"]
+    #[autocomplete = "autocomplete_profile"]
+    name: Option<String>
+) -> Result<(), Error> {
+    let profiles = ctx
+        .serenity_context()
+        .data.read().await
+        .get::<crate::AudioProfilesHolder>()
+        .ok_or("Audio profiles not found")?
+        .clone();
+    if let Some(name) = &name {
+        profiles.select(Some(name.as_str()).filter(|name| *name != DEFAULT_PROFILE))?;
+    }
+    let active = profiles.active().unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    let others: Vec<_> = profiles
+        .names()
+        .chain(std::iter::once(DEFAULT_PROFILE))
+        .filter(|name| *name != active)
+        .collect();
+    let content = match name {
+        Some(_) => format!("🎚️ Switched to the {} audio profile", active),
+        None if others.is_empty() => format!("🎚️ Using the {} audio profile, no others are configured", active),
+        None => format!("🎚️ Using the {} audio profile, also available: {}", active, others.join(", ")),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+async fn autocomplete_profile(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let data = ctx.serenity_context().data.read().await;
+    let Some(profiles) = data.get::<crate::AudioProfilesHolder>() else {
+        return Vec::new();
+    };
+    profiles
+        .names()
+        .chain(std::iter::once(DEFAULT_PROFILE))
+        .filter(|name| name.contains(&partial.to_lowercase()))
+        .take(MAX_AUTOCOMPLETE_CHOICES)
+        .map(str::to_string)
+        .collect()
+}
+
+async fn bridge_schedule(ctx: Context<'_>) -> Result<BridgeSchedule, Error> {
+    Ok(
+        ctx.serenity_context()
+            .data.read().await
+            .get::<crate::ScheduleHolder>()
+            .ok_or("Schedule not found")?
+            .clone()
+    )
+}
+
+async fn settings(ctx: Context<'_>) -> Result<SharedSettings, Error> {
+    Ok(
+        ctx.serenity_context()
+            .data.read().await
+            .get::<crate::SettingsHolder>()
+            .ok_or("Settings not found")?
+            .clone()
+    )
+}
+
+/// Keep a Discord member out of what TeamSpeak hears
+#[poise::command(slash_command, guild_only, rename = "bridge-mute", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn bridge_mute(
+    ctx: Context<'_>,
+    #[description = "Member to stop bridging"] user: serenity::User
+) -> Result<(), Error> {
+    set_bridge_muted(ctx, user, true).await
+}
+
+/// Bridge a Discord member to TeamSpeak again
+#[poise::command(slash_command, guild_only, rename = "bridge-unmute", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn bridge_unmute(
+    ctx: Context<'_>,
+    #[description = "Member to bridge again"] user: serenity::User
+) -> Result<(), Error> {
+    set_bridge_muted(ctx, user, false).await
+}
+
+async fn set_bridge_muted(ctx: Context<'_>, user: serenity::User, muted: bool) -> Result<(), Error> {
+    let settings = settings(ctx).await?;
+
+    let changed = settings
+        .lock()
+        .expect("Can't lock settings!")
+        .update(|s| {
+            if muted { s.bridge_muted.insert(user.id.get()) } else { s.bridge_muted.remove(&user.id.get()) }
+        })?;
+
+    let content = match (changed, muted) {
+        (true, true) => format!("🔇 {} is no longer bridged to TeamSpeak", user.name),
+        (true, false) => format!("🔊 {} is bridged to TeamSpeak again", user.name),
+        (false, true) => format!("{} is already excluded", user.name),
+        (false, false) => format!("{} was not excluded", user.name),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Play a Discord member or TeamSpeak client later than everybody else, e.g. if on both sides
+#[poise::command(slash_command, guild_only, rename = "delay-user", default_member_permissions = "MUTE_MEMBERS")]
+pub async fn delay_user(
+    ctx: Context<'_>,
+    #[description = "Extra delay in ms, 0 to remove it"] #[max = 2000] ms: u32,
+    #[description = "Discord member to delay"] discord_user: Option<serenity::User>,
+    #[description = "TeamSpeak client name or unique id to delay"] ts_client: Option<String>
+) -> Result<(), Error> {
+    let delay = std::time::Duration::from_millis(ms.into()).min(MAX_SOURCE_DELAY);
+    let name = match (discord_user, ts_client) {
+        (Some(user), None) => {
+            settings(ctx).await?
+                .lock()
+                .expect("Can't lock settings!")
+                .update(|s| {
+                    if delay.is_zero() {
+                        s.discord_delays_ms.remove(&user.id.get());
+                    } else {
+                        s.discord_delays_ms.insert(user.id.get(), delay.as_millis() as u32);
+                    }
+                })?;
+            user.name
+        }
+        (None, Some(client)) => {
+            let (reply, response) = tokio::sync::oneshot::channel();
+            send_ts_command(ctx, TsCommand::SetDelay { client, delay, reply }).await?;
+            response.await??
+        }
+        _ => return Err("Give either a Discord member or a TeamSpeak client".into()),
+    };
+
+    let content = if delay.is_zero() {
+        format!("⏱️ {} plays on time again", name)
+    } else {
+        format!("⏱️ {} plays {} ms later than everybody else", name, delay.as_millis())
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Tell `user` who is in TeamSpeak if they joined a bridged channel and asked for it.
+async fn send_ts_roster(
+    ctx: &SerenityContext,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    user: serenity::UserId
+) -> Result<(), Error> {
+    let (settings, book) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<crate::SettingsHolder>().ok_or("Settings not found")?.clone(),
+            data.get::<crate::TsBookHolder>().ok_or("TeamSpeak connection not available")?.clone(),
+        )
+    };
+    if !settings.lock().expect("Can't lock settings!").get().wants_ts_roster(user.get()) {
+        return Ok(());
+    }
+    let call = match songbird::get(ctx).await.and_then(|manager| manager.get(guild_id)) {
+        Some(call) => call,
+        None => {
+            return Ok(());
+        }
+    };
+    if call.lock().await.current_channel() != Some(channel_id.into()) {
+        return Ok(());
+    }
+
+    let names = book.snapshot().client_names();
+    let text = if names.is_empty() {
+        "Nobody is in TeamSpeak.".to_string()
+    } else {
+        format!("In TeamSpeak ({}): {}", names.len(), names.join(", "))
+    };
+    user.direct_message(&ctx.http, serenity::CreateMessage::new().content(text)).await?;
+    Ok(())
+}
+
+/// Set your own defaults, applied whenever you are in a bridged channel
+#[poise::command(slash_command, guild_only, rename = "my-settings")]
+pub async fn my_settings(
+    ctx: Context<'_>,
+    #[description = "How loud you are in TeamSpeak, a factor (default 1.0) or decibels like -6dB"] mic_gain: Option<String>,
+    #[description = "Get a message with who is in TeamSpeak when you join"] ts_roster_on_join: Option<bool>
+) -> Result<(), Error> {
+    let mic_gain = mic_gain.as_deref().map(parse_volume).transpose()?;
+    let settings = settings(ctx).await?;
+
+    let user = ctx.author().id.get();
+    let prefs = settings
+        .lock()
+        .expect("Can't lock settings!")
+        .update(|s| {
+            let mut prefs = s.user_prefs.remove(&user).unwrap_or_default();
+            if let Some(gain) = mic_gain {
+                prefs.mic_gain = Some(gain).filter(|&gain| gain != 1.0);
+            }
+            if let Some(roster) = ts_roster_on_join {
+                prefs.ts_roster_on_join = roster;
+            }
+            if prefs != UserPrefs::default() {
+                s.user_prefs.insert(user, prefs.clone());
+            }
+            prefs
+        })?;
+
+    let content = format!(
+        "🎙️ Your voice in TeamSpeak: {}\n📋 Who is in TeamSpeak when you join: {}\n🤫 Noise gate: {}",
+        format_volume(prefs.mic_gain.unwrap_or(1.0)),
+        if prefs.ts_roster_on_join { "sent to you" } else { "not sent" },
+        prefs.noise_gate_db.map_or("off".to_string(), |db| format!("below {:.0} dBFS", db))
+    );
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// How long `/forget-me` and `/purge-user` wait to be confirmed.
+const CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Delete everything the bridge stores about you
+#[poise::command(slash_command, rename = "forget-me")]
+pub async fn forget_me(ctx: Context<'_>) -> Result<(), Error> {
+    let user = ctx.author().clone();
+    purge(ctx, &user, "Delete everything the bridge stores about you? This can't be undone.".to_string()).await
+}
+
+/// Delete everything the bridge stores about a Discord user
+#[poise::command(slash_command, guild_only, rename = "purge-user", default_member_permissions = "MANAGE_GUILD")]
+pub async fn purge_user(
+    ctx: Context<'_>,
+    #[description = "Whose data to delete"] user: serenity::User
+) -> Result<(), Error> {
+    let question = format!("Delete everything the bridge stores about {}? This can't be undone.", user.name);
+    purge(ctx, &user, question).await
+}
+
+async fn purge(ctx: Context<'_>, user: &serenity::User, question: String) -> Result<(), Error> {
+    let Some(reply) = confirm(ctx, question).await? else {
+        return Ok(());
+    };
+    let forgotten = forget_user(&ctx.serenity_context().data, user.id.get()).await?;
+    let content = if forgotten.is_empty() {
+        format!("Nothing was stored about {}.", user.name)
+    } else {
+        format!("Deleted about {}: {}.", user.name, forgotten.join(", "))
+    };
+    reply.edit(ctx, poise::CreateReply::default().content(content).components(vec![])).await?;
+    Ok(())
+}
+
+/// Ask `question` with Delete and Cancel buttons, the reply to edit once the author pressed Delete.
+async fn confirm(ctx: Context<'_>, question: String) -> Result<Option<poise::ReplyHandle<'_>>, Error> {
+    let prefix = ctx.id().to_string();
+    let (delete, cancel) = (format!("{}-delete", prefix), format!("{}-cancel", prefix));
+    let buttons = serenity::CreateActionRow::Buttons(
+        vec![
+            serenity::CreateButton::new(&delete).label("Delete").style(serenity::ButtonStyle::Danger),
+            serenity::CreateButton::new(&cancel).label("Cancel").style(serenity::ButtonStyle::Secondary)
+        ]
+    );
+    let reply = ctx.send(
+        poise::CreateReply
+            ::default()
+            .content(question)
+            .components(vec![buttons])
+            .ephemeral(true)
+    ).await?;
+
+    let press = serenity::ComponentInteractionCollector
+        ::new(ctx.serenity_context())
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .timeout(CONFIRM_TIMEOUT)
+        .filter(move |press| press.data.custom_id.starts_with(&prefix))
+        .await;
+    if let Some(press) = &press {
+        press.create_response(ctx, serenity::CreateInteractionResponse::Acknowledge).await?;
+    }
+    if press.is_some_and(|press| press.data.custom_id == delete) {
+        return Ok(Some(reply));
+    }
+    reply.edit(ctx, poise::CreateReply::default().content("Nothing was deleted.").components(vec![])).await?;
+    Ok(None)
+}
+
+/// Delete what the bridge stores about the Discord user `user`, naming what there was.
+pub async fn forget_user(data: &RwLock<TypeMap>, user: u64) -> Result<Vec<String>, Error> {
+    let data = data.read().await;
+    let mut forgotten = Vec::new();
+    if let Some(settings) = data.get::<crate::SettingsHolder>() {
+        let removed = settings.lock().expect("Can't lock settings!").update(|s| s.forget_user(user))?;
+        forgotten.extend(removed.into_iter().map(String::from));
+    }
+    if let Some(talk) = data.get::<crate::TalkTimeHolder>() {
+        talk.forget_discord(user);
+    }
+    if data.get::<crate::EchoHolder>().is_some_and(|echo| echo.discard(&user)) {
+        forgotten.push("echo test recording".to_string());
+    }
+    if let Some(log) = data.get::<crate::AuditHolder>() {
+        match log.forget(user)? {
+            0 => {}
+            entries => forgotten.push(format!("{} audit log entries", entries)),
+        }
+    }
+    Ok(forgotten)
+}
+
+async fn music(ctx: Context<'_>) -> Result<MusicQueues, Error> {
+    Ok(
+        ctx.serenity_context()
+            .data.read().await
+            .get::<crate::MusicHolder>()
+            .ok_or("Music queues not found")?
+            .clone()
+    )
+}
+
+/// Suggest files from the media directory for `/play`.
+async fn autocomplete_play(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    if partial.contains("://") {
+        return Vec::new();
+    }
+    let music = match music(ctx).await {
+        Ok(music) => music,
+        Err(_) => return Vec::new(),
+    };
+    let library = match music.library() {
+        Some(library) => library,
+        None => return Vec::new(),
+    };
+    library
+        .suggest(partial.strip_prefix(FILE_PREFIX).unwrap_or(partial))
+        .into_iter()
+        .map(|name| format!("{}{}", FILE_PREFIX, name))
+        .collect()
+}
+
+/// Queue a track from a URL or the media directory
+#[poise::command(slash_command, guild_only)]
+pub async fn play(
+    ctx: Context<'_>,
+    #[description = "Link to an audio file, stream, video or playlist, or file:<name>"]
+    #[autocomplete = "autocomplete_play"]
+    url: String
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+    let handler_lock = call(ctx).await?;
+
+    ctx.defer_ephemeral().await?;
+
+    let music = music(ctx).await?;
+    let requested_by = ctx.author().name.clone();
+    let queued = {
+        let mut handler = handler_lock.lock().await;
+        music.play(guild_id.get(), &mut handler, url, requested_by).await?
+    };
+
+    let length = music.tracks(guild_id.get()).len();
+    let content = match queued.tracks.as_slice() {
+        [track] if length == 1 => format!("▶️ Playing {}", track),
+        [track] => format!("➕ Queued {} at position {}", track, length - 1),
+        tracks if queued.cut_off => format!("➕ Queued the first {} tracks, the playlist is longer", tracks.len()),
+        tracks => format!("➕ Queued {} tracks", tracks.len()),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Show the music queue
+#[poise::command(slash_command, guild_only)]
+pub async fn queue(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    let tracks = music(ctx).await?.tracks(guild_id.get());
+    let content = if tracks.is_empty() {
+        "The queue is empty".to_string()
+    } else {
+        let mut lines = vec![format!("▶️ {} (requested by {})", tracks[0], tracks[0].requested_by)];
+        lines.extend(
+            tracks
+                .iter()
+                .enumerate()
+                .skip(1)
+                .take(MAX_LISTED_TRACKS)
+                .map(|(i, track)| format!("{}. {} (requested by {})", i, track, track.requested_by))
+        );
+        if tracks.len() > MAX_LISTED_TRACKS + 1 {
+            lines.push(format!("… and {} more", tracks.len() - MAX_LISTED_TRACKS - 1));
+        }
+        lines.join("\n")
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Tracks listed by `/queue` after the playing one.
+const MAX_LISTED_TRACKS: usize = 10;
+
+/// Skip the playing track
+#[poise::command(slash_command, guild_only)]
+pub async fn skip(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    let content = match music(ctx).await?.skip(guild_id.get())? {
+        Some(track) => format!("⏭️ Skipped {}", track),
+        None => "Nothing is playing".to_string(),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Shuffle the queued tracks
+#[poise::command(slash_command, guild_only)]
+pub async fn shuffle(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    let content = match music(ctx).await?.shuffle(guild_id.get()) {
+        0 | 1 => "Nothing to shuffle".to_string(),
+        n => format!("🔀 Shuffled {} tracks", n),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Pause the music
+#[poise::command(slash_command, guild_only)]
+pub async fn pause(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    music(ctx).await?.pause(guild_id.get())?;
+    ctx.send(poise::CreateReply::default().content("⏸️ Paused").ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Resume the music
+#[poise::command(slash_command, guild_only)]
+pub async fn resume(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    music(ctx).await?.resume(guild_id.get())?;
+    ctx.send(poise::CreateReply::default().content("▶️ Resumed").ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Jump to a position in the playing track
+#[poise::command(slash_command, guild_only)]
+pub async fn seek(
+    ctx: Context<'_>,
+    #[description = "Position like 1:30, 1:02:03 or 90 seconds"] timestamp: String
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+    let position = parse_timestamp(&timestamp).ok_or("Give the position like 1:30, 1:02:03 or 90")?;
+
+    let track = music(ctx).await?.seek(guild_id.get(), position).await?;
+    let content = format!("⏩ {} at {}", track.name(), format_duration(position));
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Repeat the playing track or the whole queue
+#[poise::command(slash_command, guild_only, rename = "loop")]
+pub async fn loop_(ctx: Context<'_>, #[description = "What to repeat"] mode: LoopMode) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    music(ctx).await?.set_loop(guild_id.get(), mode)?;
+    let content = match mode {
+        LoopMode::Off => "➡️ Not looping anymore",
+        LoopMode::Track => "🔂 Looping the playing track",
+        LoopMode::Queue => "🔁 Looping the queue, finished tracks go to the back",
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Show the playing track
+#[poise::command(slash_command, guild_only)]
+pub async fn np(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or("Not in a guild")?;
+
+    let content = match music(ctx).await?.now_playing(guild_id.get()).await {
+        Some(now) => {
+            let position = match now.track.duration() {
+                Some(duration) => format!("{} / {}", format_duration(now.position), format_duration(duration)),
+                None => format_duration(now.position),
+            };
+            let looping = match now.looping {
+                LoopMode::Off => "",
+                LoopMode::Track => " 🔂 looping the track",
+                LoopMode::Queue => " 🔁 looping the queue",
+            };
+            format!(
+                "{} {} [{}] (requested by {}){}",
+                if now.paused { "⏸️" } else { "▶️" },
+                now.track.name(),
+                position,
+                now.track.requested_by,
+                looping
+            )
+        }
+        None => "Nothing is playing".to_string(),
+    };
+    ctx.send(poise::CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// A member of the bridged voice channel, as listed by TeamSpeak's `!who`.
+#[derive(Debug, PartialEq)]
+pub struct VoiceMember {
+    pub id: u64,
+    pub name: String,
+    pub speaking: bool,
+    pub muted: bool,
+    pub deafened: bool,
+    pub bot: bool,
+}
+
+/// Who is in the voice channels the bridge joined, from the gateway cache.
+#[derive(Clone)]
+pub struct VoicePresence {
+    cache: Arc<serenity::Cache>,
+    states: VoiceStates,
+    /// Users heard in the last voice tick.
+    speaking: Arc<StdMutex<HashSet<u64>>>,
+}
+
+impl VoicePresence {
+    pub fn new(cache: Arc<serenity::Cache>) -> Self {
+        Self { cache, states: VoiceStates::new(), speaking: Default::default() }
+    }
+
+    /// Keep `state` from a voice state update.
+    fn track(&self, state: &VoiceState) {
+        let Some(guild_id) = state.guild_id else {
+            return;
+        };
+        let cached = state.channel_id.map(|channel_id| {
+            let guild = self.cache.guild(guild_id);
+            let member = state.member.as_ref().or_else(|| guild.as_ref()?.members.get(&state.user_id));
+            cached_voice_state(channel_id, state, member)
+        });
+        self.states.update(guild_id.get(), state.user_id.get(), cached);
+    }
+
+    /// Start over with the voice states of `guild`, sent whole when the bridge connects.
+    fn track_guild(&self, guild: &serenity::Guild) {
+        let users = guild.voice_states.values().filter_map(|state| {
+            let member = state.member.as_ref().or_else(|| guild.members.get(&state.user_id));
+            Some((state.user_id.get(), cached_voice_state(state.channel_id?, state, member)))
+        });
+        self.states.replace_guild(guild.id.get(), users);
+    }
+
+    fn set_speaking(&self, users: HashSet<u64>) {
+        *self.speaking.lock().expect("Can't lock speaking users!") = users;
+    }
+
+    /// Whether `user` has one of `roles` in `guild_id`, as of their last voice state.
+    fn has_any_role(&self, guild_id: serenity::GuildId, user: u64, roles: &HashSet<serenity::RoleId>) -> bool {
+        let user = serenity::UserId::new(user);
+        self.cache.guild(guild_id).is_some_and(|guild| {
+            let member = guild.voice_states
+                .get(&user)
+                .and_then(|state| state.member.as_ref())
+                .or_else(|| guild.members.get(&user));
+            member.is_some_and(|member| member.roles.iter().any(|role| roles.contains(role)))
+        })
+    }
+
+    /// Whether `user` may speak over the others in the voice channel they are in.
+    fn is_priority_speaker(&self, guild_id: serenity::GuildId, user: u64) -> bool {
+        let user = serenity::UserId::new(user);
+        self.cache.guild(guild_id).is_some_and(|guild| {
+            let Some(state) = guild.voice_states.get(&user) else {
+                return false;
+            };
+            let channel = state.channel_id.and_then(|channel_id| guild.channels.get(&channel_id));
+            let member = state.member.as_ref().or_else(|| guild.members.get(&user));
+            match (channel, member) {
+                (Some(channel), Some(member)) => guild.user_permissions_in(channel, member).priority_speaker(),
+                _ => false,
+            }
+        })
+    }
+
+    /// Everybody in the bridged channels except the bridge itself, sorted by name.
+    pub fn members(&self) -> Vec<VoiceMember> {
+        let own_id = self.cache.current_user().id.get();
+        let speaking = self.speaking.lock().expect("Can't lock speaking users!").clone();
+        let mut members: Vec<_> = self.states
+            .around(own_id)
+            .into_iter()
+            .map(|(id, state)| VoiceMember {
+                id,
+                name: state.name,
+                speaking: speaking.contains(&id),
+                muted: state.muted,
+                deafened: state.deafened,
+                bot: state.bot,
+            })
+            .collect();
+        members.sort_by_key(|member| member.name.to_lowercase());
+        members
+    }
+}
+
+/// What the voice state cache keeps of `state` in `channel_id`.
+fn cached_voice_state(
+    channel_id: serenity::ChannelId,
+    state: &VoiceState,
+    member: Option<&serenity::Member>
+) -> CachedVoiceState {
+    CachedVoiceState {
+        channel: channel_id.get(),
+        name: member.map_or_else(|| state.user_id.to_string(), |member| member.display_name().to_string()),
+        muted: state.mute || state.self_mute,
+        deafened: state.deaf || state.self_deaf,
+        bot: member.is_some_and(|member| member.user.bot),
+    }
+}
+
+#[derive(Clone)]
+struct Receiver {
+    sink: crate::AudioBufferDiscord,
+    pool: FramePool,
+    impairer: Option<Arc<Impairer>>,
+    settings: SharedSettings,
+    presence: VoicePresence,
+    /// Records users running `/echo-test`.
+    echo: EchoRecorder<u64>,
+    /// Set while one side is empty, packets are dropped then.
+    idle: Arc<AtomicBool>,
+    guild_id: serenity::GuildId,
+    /// Roles whose members are bridged, everybody if unset.
+    voice_roles: Option<Arc<HashSet<serenity::RoleId>>>,
+    /// Only the picked users are bridged while it is on.
+    solo: Solo,
+    /// Whoever has the floor is bridged despite bridge mutes and `/solo`.
+    hands: HandQueue,
+    /// Sender of each SSRC, learned from speaking state updates.
+    senders: Arc<StdMutex<Senders>>,
+    /// Counts settings changes, the senders are resolved again after one.
+    settings_changes: Arc<AtomicU64>,
+    /// Arrivals of the last packets, for panic snapshots.
+    packet_times: PacketTimes,
+    stats: BridgeStats,
+    /// Tells which senders feed back.
+    levels: AudioLevels,
+    talk: TalkTime,
+    /// Where received packets are captured, if asked to.
+    capture: Option<Capture>,
 }
 
 impl Receiver {
-    pub fn new(voice_receiver: crate::AudioBufferDiscord) -> Self {
-        Self {
-            sink: voice_receiver,
+    /// The sender of `ssrc`, if known yet.
+    fn sender(&self, ssrc: u32) -> Option<Sender> {
+        self.senders.lock().expect("Can't lock senders!").get(ssrc)
+    }
+
+    /// `user` sends as `ssrc` from now on, returns the gains to play everybody at.
+    fn map_sender(&self, ssrc: u32, user: u64) -> Vec<(u32, f32)> {
+        let changes = self.settings_changes.load(Ordering::Relaxed);
+        let mut senders = self.senders.lock().expect("Can't lock senders!");
+        // Once per SSRC, its packets must not get through before a bridge mute is known
+        let settings = self.settings.lock().expect("Can't lock settings!");
+        if senders.is_stale(changes) {
+            senders.resolve(settings.get(), changes);
+        }
+        senders.map(ssrc, user, settings.get());
+        senders.gains()
+    }
+
+    /// Resolve the senders again if the settings changed, unless they are being saved right now.
+    ///
+    /// Returns the gains to play everybody at once resolved.
+    fn refresh_senders(&self) -> Option<Vec<(u32, f32)>> {
+        let changes = self.settings_changes.load(Ordering::Relaxed);
+        let mut senders = self.senders.lock().expect("Can't lock senders!");
+        if !senders.is_stale(changes) {
+            return None;
+        }
+        // Tried again next tick rather than waiting for the disk
+        let settings = self.settings.try_lock().ok()?;
+        senders.resolve(settings.get(), changes);
+        Some(senders.gains())
+    }
+
+    /// Play each SSRC at its gain in the Discord→TS mix.
+    async fn apply_gains(&self, gains: Vec<(u32, f32)>) {
+        let mut sink = self.sink.lock().await;
+        for (ssrc, gain) in gains {
+            sink.set_gain(ssrc, gain);
         }
     }
+
+    /// Whether `sender` is excluded from the TeamSpeak feed.
+    fn is_bridge_muted(&self, sender: Option<Sender>) -> bool {
+        sender.is_some_and(|sender| sender.muted && !self.hands.has_floor(Speaker::Discord(sender.user)))
+    }
+
+    /// Whether `user` has one of the voice roles, unknown senders don't.
+    fn has_voice_role(&self, user: Option<u64>) -> bool {
+        let Some(roles) = &self.voice_roles else {
+            return true;
+        };
+        user.is_some_and(|user| self.presence.has_any_role(self.guild_id, user, roles))
+    }
+
+    /// Whether `user` may be heard while `/solo` is on, unknown senders can't.
+    fn is_solo_allowed(&self, user: Option<u64>) -> bool {
+        self.solo.allows_discord(user) || user.is_some_and(|user| self.hands.has_floor(Speaker::Discord(user)))
+    }
+
+    /// Whether `user` has priority speaker in their voice channel.
+    fn is_priority_speaker(&self, user: Option<u64>) -> bool {
+        user.is_some_and(|user| self.presence.is_priority_speaker(self.guild_id, user))
+    }
+
+    /// Whether the packet was recorded for an echo test, and should not be bridged.
+    fn record_echo(&self, user: Option<u64>, payload: &[u8]) -> bool {
+        user.is_some_and(|user| self.echo.record(&user, payload))
+    }
+}
+
+/// How the sender of a voice packet is mixed for TeamSpeak.
+#[derive(Clone, Copy, Debug)]
+struct SenderMix {
+    delay: std::time::Duration,
+    priority: bool,
+    noise_gate_db: Option<f32>,
+}
+
+/// Hand a received voice packet to the Discord→TS jitter buffer, its sender mixed as `mix` says.
+async fn deliver(sink: &crate::AudioBufferDiscord, ssrc: u32, sequence: u16, payload: Vec<u8>, mix: SenderMix) {
+    let time = std::time::Instant::now();
+    let mut lock = sink.lock().await;
+    let dur = time.elapsed();
+    if let Err(e) = lock.handle_packet(ssrc, sequence, payload) {
+        tracing::error!("Failed to handle Discord voice packet: {}", e);
+    }
+    lock.set_delay(ssrc, mix.delay);
+    lock.set_priority(ssrc, mix.priority);
+    lock.set_noise_gate(ssrc, mix.noise_gate_db);
+    if dur.as_millis() > 1 {
+        tracing::debug!("Acquiring lock took {}ms", dur.as_millis());
+    }
 }
 
 #[async_trait]
@@ -289,54 +2470,67 @@ impl VoiceEventHandler for Receiver {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
         match ctx {
             EventContext::SpeakingStateUpdate(speaking) => {
-                println!("Speaking state: ssrc={}, user_id={:?}", speaking.ssrc, speaking.user_id);
+                eprintln!("Speaking state: ssrc={}, user_id={:?}", speaking.ssrc, speaking.user_id);
+                if let Some(user) = speaking.user_id {
+                    let gains = self.map_sender(speaking.ssrc, user.0);
+                    self.apply_gains(gains).await;
+                }
             }
             EventContext::RtpPacket(rtp_data) => {
-                let packet_bytes = &rtp_data.packet;
-
-                if packet_bytes.len() < 12 {
+                let rtp = crate::rtp::parse(&rtp_data.packet)?;
+                self.packet_times.record(&format!("ssrc {}", rtp.ssrc));
+                if let Some(capture) = &self.capture {
+                    let captured = CapturedPacket
+                        ::new(Side::Discord, rtp.ssrc, rtp.sequence, rtp.payload)
+                        .with_timestamp(rtp.timestamp);
+                    capture.record(captured, rtp.payload);
+                }
+                self.stats.received(Direction::DiscordToTs, rtp_data.packet.len());
+                let sender = self.sender(rtp.ssrc);
+                let user = sender.map(|sender| sender.user);
+                if self.record_echo(user, rtp.payload) || self.idle.load(Ordering::Relaxed) {
                     return None;
                 }
-
-                let ssrc = u32::from_be_bytes([
-                    packet_bytes[8],
-                    packet_bytes[9],
-                    packet_bytes[10],
-                    packet_bytes[11],
-                ]);
-
-                let sequence = u16::from_be_bytes([packet_bytes[2], packet_bytes[3]]);
-
-                let has_extension = (packet_bytes[0] & 0x10) != 0;
-                let mut payload_offset = 12;
-
-                if has_extension && packet_bytes.len() >= 16 {
-                    let ext_len =
-                        (u16::from_be_bytes([packet_bytes[14], packet_bytes[15]]) as usize) * 4;
-                    payload_offset = 16 + ext_len;
+                if self.is_bridge_muted(sender) || !self.has_voice_role(user) || !self.is_solo_allowed(user) {
+                    return None;
                 }
-
-                if payload_offset < packet_bytes.len() {
-                    let opus_data = &packet_bytes[payload_offset..];
-
-                    let dur;
-                    {
-                        let time = std::time::Instant::now();
-                        let mut lock = self.sink.lock().await;
-                        dur = time.elapsed();
-                        if let Err(e) = lock.handle_packet(ssrc, sequence, opus_data.to_vec()) {
-                            tracing::error!("Failed to handle Discord voice packet: {}", e);
-                        }
-                        if dur.as_millis() > 1 {
-                            tracing::debug!("Acquiring lock took {}ms", dur.as_millis());
-                        }
+                if self.levels.feedback().is_suppressed(Direction::DiscordToTs, &format!("ssrc {}", rtp.ssrc)) {
+                    return None;
+                }
+                let (ssrc, sequence, payload) = (rtp.ssrc, rtp.sequence, self.pool.frame_from(rtp.payload));
+                let mix = SenderMix {
+                    delay: sender.map_or(std::time::Duration::ZERO, |sender| sender.delay),
+                    priority: self.is_priority_speaker(user),
+                    noise_gate_db: sender.and_then(|sender| sender.noise_gate_db),
+                };
+
+                match self.impairer.as_deref().map_or(Fate::Now, Impairer::fate) {
+                    Fate::Drop => {}
+                    Fate::Now => deliver(&self.sink, ssrc, sequence, payload, mix).await,
+                    Fate::Later(delay) => {
+                        let sink = self.sink.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            deliver(&sink, ssrc, sequence, payload, mix).await;
+                        });
                     }
                 }
             }
             EventContext::VoiceTick(tick) => {
+                if let Some(gains) = self.refresh_senders() {
+                    self.apply_gains(gains).await;
+                }
+                let speaking: HashSet<u64> = {
+                    let senders = self.senders.lock().expect("Can't lock senders!");
+                    tick.speaking.keys().filter_map(|&ssrc| senders.get(ssrc)).map(|sender| sender.user).collect()
+                };
+                for &user in &speaking {
+                    self.talk.discord(user, std::time::Duration::from_millis(crate::FRAME_SIZE_MS as u64));
+                }
+                self.presence.set_speaking(speaking);
                 for (&ssrc, voice_data) in &tick.speaking {
                     if let Some(audio) = &voice_data.decoded_voice {
-                        if audio.len() > 0 {
+                        if !audio.is_empty() {
                             tracing::debug!(
                                 "Voice tick for SSRC {}: {} samples",
                                 ssrc,
@@ -348,10 +2542,44 @@ impl VoiceEventHandler for Receiver {
             }
             EventContext::RtcpPacket(_rtcp_data) => {}
             EventContext::ClientDisconnect(disconnect) => {
-                println!("Client disconnected: user {:?}", disconnect.user_id);
+                eprintln!("Client disconnected: user {:?}", disconnect.user_id);
+                self.senders.lock().expect("Can't lock senders!").forget_user(disconnect.user_id.0);
             }
             _ => {}
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voice_claims_last_until_dropped() {
+        let busy = VoiceBusy::default();
+        let (first, second) = (serenity::GuildId::new(1), serenity::GuildId::new(2));
+        let guard = busy.claim(first).unwrap();
+        assert!(busy.claim(first).is_err());
+        let other = busy.claim(second).unwrap();
+
+        drop(guard);
+        assert!(busy.claim(first).is_ok());
+        drop(other);
+        assert!(busy.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cooldowns_are_set_by_command_name() {
+        let commands = vec![ping(), leave()];
+        *commands[1].cooldown_config.write().unwrap() = poise::CooldownConfig {
+            guild: Some(std::time::Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let cooldowns = HashMap::from([("ping".to_string(), 30), ("leave".to_string(), 0), ("pong".to_string(), 10)]);
+
+        assert_eq!(apply_cooldowns(&commands, &cooldowns), ["pong"]);
+        assert_eq!(commands[0].cooldown_config.read().unwrap().guild, Some(std::time::Duration::from_secs(30)));
+        assert_eq!(commands[1].cooldown_config.read().unwrap().guild, None);
+    }
+}
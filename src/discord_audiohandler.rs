@@ -11,16 +11,19 @@
 //! incoming packets.
 
 use std::cmp::Reverse;
-use std::collections::{ HashMap, VecDeque };
+use std::collections::{ HashMap, HashSet, VecDeque };
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::time::{ Duration, Instant };
 
 use audiopus::coder::Decoder;
 use audiopus::{ packet, Channels, SampleRate };
 use slog::{ Logger, debug, info, o, trace, warn };
 use tsclientlib::audio::Error;
 
+use crate::audio::{ peak, BufferLimits, DropCounter, NoiseGate, OverflowPolicy, SourceDelays, MAX_VOLUME };
+use crate::pool::FramePool;
 use crate::ClientId;
 
 const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
@@ -32,10 +35,18 @@ const MAX_PACKET_LOSSES: usize = 3;
 const LAST_BUFFER_SIZE_COUNT: u8 = 255;
 /// The amount of samples to maximally buffer. Equivalent to 0.5 s.
 const MAX_BUFFER_SIZE: usize = 48_000 / 2;
-/// Maximum number of packets in the queue.
+/// Priority speakers louder than this, about -60 dBFS, turn the others down.
+const PRIORITY_PEAK: f32 = 0.001;
+/// Interleaved samples the others stay down after a priority speaker was heard, 300 ms.
+const PRIORITY_HOLD: usize = 48_000 * 2 * 3 / 10;
+/// Maximum number of packets in the queue, unless configured otherwise.
 const MAX_BUFFER_PACKETS: usize = 50;
+/// Milliseconds of audio in one packet of [`USUAL_FRAME_SIZE`] samples.
+const USUAL_FRAME_MS: u32 = 20;
 /// Buffer for maximal 0.5 s without playing anything.
 const MAX_BUFFER_TIME: usize = 48_000 / 2;
+/// Volume changes are spread over this amount of samples. Equivalent to 50 ms.
+const VOLUME_RAMP_SAMPLES: f32 = 48_000.0 / 20.0;
 /// Duplicate or remove every `step` sample when speeding-up.
 const SPEED_CHANGE_STEPS: usize = 100;
 /// The usual amount of samples in a frame.
@@ -64,6 +75,40 @@ struct SlidingWindowMinimum<T: Copy + Default + Ord> {
     cur_time: u8,
 }
 
+/// How many packets a queue holds and what it does when full.
+#[derive(Clone, Copy, Debug)]
+struct QueueLimits {
+    max: usize,
+    /// With [`OverflowPolicy::Pause`], packets are dropped from this many on.
+    high: usize,
+    /// With [`OverflowPolicy::Pause`], packets are taken again from this many on.
+    low: usize,
+    policy: OverflowPolicy,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self { max: MAX_BUFFER_PACKETS, high: MAX_BUFFER_PACKETS, low: 0, policy: OverflowPolicy::DropNewest }
+    }
+}
+
+impl QueueLimits {
+    fn new(limits: &BufferLimits) -> Self {
+        let packets = |ms: u32| ((ms / USUAL_FRAME_MS) as usize).max(1);
+        Self {
+            max: packets(limits.max_ms),
+            high: packets(limits.high_watermark_ms()),
+            low: (limits.low_watermark_ms / USUAL_FRAME_MS) as usize,
+            policy: limits.overflow.unwrap_or(OverflowPolicy::DropNewest),
+        }
+    }
+
+    /// How far ahead of the next expected packet ids are accepted.
+    fn window(&self) -> u16 {
+        self.max.max(MAX_BUFFER_PACKETS) as u16
+    }
+}
+
 #[derive(Debug)]
 struct QueuePacket {
     packet: Vec<u8>,
@@ -75,6 +120,13 @@ struct QueuePacket {
 pub struct AudioQueue {
     logger: Logger,
     decoder: Decoder,
+    /// Receives the buffers of consumed packets.
+    pool: FramePool,
+    limits: QueueLimits,
+    /// Counts packets dropped because the queue was full.
+    dropped: DropCounter,
+    /// Set by [`OverflowPolicy::Pause`] between the watermarks.
+    paused: bool,
     pub volume: f32,
     /// The id of the next packet that should be decoded.
     ///
@@ -113,8 +165,32 @@ pub struct AudioHandler<Id: Clone + Debug + Eq + Hash + PartialEq = ClientId> {
     ///
     /// Updated when a new queue gets added.
     avg_buffer_samples: usize,
-    /// Global volume multiplier (0.0 to 2.0)
+    /// Global volume multiplier (0.0 to 2.0) currently applied
     pub global_volume: f32,
+    /// Volume [`global_volume`](Self::global_volume) is ramping to
+    target_volume: f32,
+    /// Change of the volume per stereo sample while ramping
+    volume_step: f32,
+    pool: FramePool,
+    /// Packets dropped because their queue was full.
+    dropped: DropCounter,
+    limits: QueueLimits,
+    /// Clients played later than the others.
+    delays: SourceDelays<Id>,
+    /// Packets that failed to decode.
+    decode_errors: DropCounter,
+    /// Time spent decoding, in µs.
+    decode_time: DropCounter,
+    /// Clients allowed to speak over the others.
+    priority: HashSet<Id>,
+    /// Gain of everybody else while a priority speaker talks.
+    priority_gain: f32,
+    /// Interleaved samples the others stay turned down for.
+    priority_hold: usize,
+    /// Clients muted between words, below their background noise.
+    gates: HashMap<Id, NoiseGate>,
+    /// Volume of clients not played at 1.0, also for their queues to come.
+    gains: HashMap<Id, f32>,
 }
 
 impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
@@ -153,7 +229,14 @@ impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
 }
 
 impl AudioQueue {
-    fn new(logger: Logger, sequence: u16, packet: Vec<u8>) -> Result<Self> {
+    fn new(
+        logger: Logger,
+        pool: FramePool,
+        limits: QueueLimits,
+        dropped: DropCounter,
+        sequence: u16,
+        packet: Vec<u8>
+    ) -> Result<Self> {
         let last_packet_samples = packet
             ::nb_samples(&packet, SAMPLE_RATE)
             .map_err(Error::GetPacketSample)?;
@@ -165,6 +248,10 @@ impl AudioQueue {
         let mut res = Self {
             logger,
             decoder: Decoder::new(SAMPLE_RATE, CHANNELS).map_err(Error::CreateDecoder)?,
+            pool,
+            limits,
+            dropped,
+            paused: false,
             volume: 1.0,
             next_id: sequence,
             whispering: false,
@@ -201,8 +288,37 @@ impl AudioQueue {
         max.0 - min
     }
 
+    /// Make room for a packet as the overflow policy says, `false` if it has to be dropped.
+    fn make_room(&mut self) -> bool {
+        let held = self.packet_buffer.len();
+        if self.limits.policy == OverflowPolicy::Pause {
+            if held >= self.limits.high {
+                self.paused = true;
+            } else if held <= self.limits.low {
+                self.paused = false;
+            }
+            if self.paused {
+                return false;
+            }
+        }
+        if held < self.limits.max {
+            return true;
+        }
+        if self.limits.policy != OverflowPolicy::DropOldest {
+            return false;
+        }
+        if let Some(oldest) = self.packet_buffer.pop_front() {
+            self.packet_buffer_samples -= oldest.samples;
+            self.next_id = oldest.id.wrapping_add(1);
+            self.pool.recycle(oldest.packet);
+            self.dropped.add(1);
+        }
+        true
+    }
+
     fn add_packet(&mut self, sequence: u16, packet: Vec<u8>) -> Result<()> {
-        if self.packet_buffer.len() >= MAX_BUFFER_PACKETS {
+        if !self.make_room() {
+            self.dropped.add(1);
             return Err(Error::QueueFull);
         }
         let samples;
@@ -218,7 +334,7 @@ impl AudioQueue {
 
         let id = sequence;
         let packet = QueuePacket { packet, samples, id };
-        if id.wrapping_sub(self.next_id) > (MAX_BUFFER_PACKETS as u16) {
+        if id.wrapping_sub(self.next_id) >= self.limits.window() {
             return Err(Error::TooLate { wanted: self.next_id, got: id });
         }
 
@@ -229,7 +345,7 @@ impl AudioQueue {
                 .iter()
                 .enumerate()
                 .rev()
-                .take_while(|(_, p)| p.id.wrapping_sub(id) <= (MAX_BUFFER_PACKETS as u16))
+                .take_while(|(_, p)| p.id.wrapping_sub(id) <= self.limits.window())
                 .count();
         // Check for duplicate packet
         if let Some(p) = self.packet_buffer.get(i) {
@@ -273,7 +389,7 @@ impl AudioQueue {
 
         self.decoded_buffer.resize(self.decoded_pos + len * CHANNEL_NUM, 0.0);
         let len = self.decoder
-            .decode_float(packet_data.as_deref(), &mut self.decoded_buffer[self.decoded_pos..], fec)
+            .decode_float(packet_data, &mut self.decoded_buffer[self.decoded_pos..], fec)
             .map_err(|e| Error::Decode {
                 error: e,
                 packet: packet.map(|p| p.packet.to_owned()),
@@ -346,6 +462,7 @@ impl AudioQueue {
             if let Some(packet) = self.packet_buffer.pop_front() {
                 if packet.packet.len() <= 1 {
                     // End of stream
+                    self.pool.recycle(packet.packet);
                     return Ok((&self.decoded_buffer, true));
                 }
 
@@ -354,7 +471,7 @@ impl AudioQueue {
                 self.next_id = self.next_id.wrapping_add(1);
                 if packet.id != cur_id {
                     debug_assert!(
-                        packet.id.wrapping_sub(cur_id) < (MAX_BUFFER_PACKETS as u16),
+                        packet.id.wrapping_sub(cur_id) < self.limits.window(),
                         "Invalid packet queue state: {} < {}",
                         packet.id,
                         cur_id
@@ -371,6 +488,7 @@ impl AudioQueue {
                     self.packet_buffer.push_front(packet);
                 } else {
                     self.decode_packet(Some(&packet), false)?;
+                    self.pool.recycle(packet.packet);
                 }
             } else {
                 debug!(self.logger, "No packets in queue");
@@ -398,7 +516,9 @@ impl AudioQueue {
                     })
                     .count();
                 let len = self.packet_buffer.len() - keep;
-                self.packet_buffer.drain(..len);
+                for p in self.packet_buffer.drain(..len) {
+                    self.pool.recycle(p.packet);
+                }
                 self.packet_buffer_samples = self.packet_buffer
                     .iter()
                     .map(|p| p.samples)
@@ -432,26 +552,145 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
             queues: Default::default(),
             avg_buffer_samples: 0,
             global_volume: 1.0,
+            target_volume: 1.0,
+            volume_step: 0.0,
+            pool: FramePool::new(),
+            dropped: DropCounter::default(),
+            limits: QueueLimits::default(),
+            delays: SourceDelays::default(),
+            decode_errors: DropCounter::default(),
+            decode_time: DropCounter::default(),
+            priority: HashSet::new(),
+            priority_gain: 1.0,
+            priority_hold: 0,
+            gates: HashMap::new(),
+            gains: HashMap::new(),
+        }
+    }
+
+    /// Turn everybody else down to `gain` while a priority speaker talks.
+    pub fn with_priority_gain(mut self, gain: f32) -> Self {
+        self.priority_gain = gain;
+        self
+    }
+
+    /// Count the time spent decoding in `counter`, in µs.
+    pub fn with_decode_time(mut self, counter: DropCounter) -> Self {
+        self.decode_time = counter;
+        self
+    }
+
+    /// Let `id` speak over the others or not.
+    pub fn set_priority(&mut self, id: Id, priority: bool) {
+        if priority {
+            self.priority.insert(id);
+        } else {
+            self.priority.remove(&id);
         }
     }
 
+    /// Play `id` at `gain`, now and whenever it starts talking again.
+    pub fn set_gain(&mut self, id: Id, gain: f32) {
+        if let Some(queue) = self.queues.get_mut(&id) {
+            queue.volume = gain;
+        }
+        if gain == 1.0 {
+            self.gains.remove(&id);
+        } else {
+            self.gains.insert(id, gain);
+        }
+    }
+
+    /// Gate `id` below `threshold_db`, not at all if `None`.
+    pub fn set_noise_gate(&mut self, id: Id, threshold_db: Option<f32>) {
+        match threshold_db {
+            Some(threshold_db) => {
+                if self.gates.get(&id).is_none_or(|gate| gate.threshold_db() != threshold_db) {
+                    self.gates.insert(id, NoiseGate::new(threshold_db));
+                }
+            }
+            None => {
+                self.gates.remove(&id);
+            }
+        }
+    }
+
+    /// Hold and drop packets of each client as `limits` say.
+    pub fn with_limits(mut self, limits: &BufferLimits) -> Self {
+        self.limits = QueueLimits::new(limits);
+        self
+    }
+
+    /// Counts packets dropped because their client's queue was full.
+    pub fn dropped_packets(&self) -> DropCounter {
+        self.dropped.clone()
+    }
+
+    /// Counts packets that failed to decode.
+    pub fn decode_errors(&self) -> DropCounter {
+        self.decode_errors.clone()
+    }
+
+    /// Clients whose queue is as full as it may get.
+    pub fn full_queues(&self) -> usize {
+        self.queues.values().filter(|queue| queue.packet_buffer.len() >= queue.limits.max).count()
+    }
+
+    /// Pool to take packet buffers for [`handle_packet`](Self::handle_packet) from.
+    ///
+    /// Buffers are returned to it once their packet was decoded.
+    pub fn frame_pool(&self) -> FramePool {
+        self.pool.clone()
+    }
+
+    /// Packets waiting in the queue of every client.
+    pub fn buffered_packets(&self) -> impl Iterator<Item = (&Id, usize)> {
+        self.queues.iter().map(|(id, queue)| (id, queue.packet_buffer.len()))
+    }
+
+    /// Play `id` later than the others by `delay`, not at all held back if zero.
+    pub fn set_delay(&mut self, id: Id, delay: Duration) {
+        self.delays.set(id, delay);
+    }
+
     /// Delete all queues
     pub fn reset(&mut self) {
         self.queues.clear();
+        self.priority.clear();
+        self.priority_hold = 0;
+        self.gates.clear();
     }
 
     /// `buf` is not cleared before filling it.
     ///
     /// Returns the clients that are not talking anymore.
+    #[cfg(test)]
     pub fn fill_buffer(&mut self, buf: &mut [f32]) -> Vec<Id> {
         let removed = self.fill_buffer_with_proc(buf, |_, _| {});
+        self.apply_global_volume(buf);
+        removed
+    }
 
-        // Apply global volume to the entire buffer
-        for sample in buf.iter_mut() {
-            *sample *= self.global_volume;
+    /// Apply the global volume to an already mixed buffer.
+    ///
+    /// Moves the volume towards the last one set on the way.
+    pub fn apply_global_volume(&mut self, buf: &mut [f32]) {
+        if self.global_volume == self.target_volume {
+            for sample in buf.iter_mut() {
+                *sample *= self.global_volume;
+            }
+            return;
+        }
+        for frame in buf.chunks_mut(2) {
+            if (self.target_volume - self.global_volume).abs() <= self.volume_step.abs() {
+                self.global_volume = self.target_volume;
+            } else {
+                self.global_volume += self.volume_step;
+            }
+            for sample in frame {
+                *sample *= self.global_volume;
+            }
         }
-
-        removed
     }
 
     /// `buf` is not cleared before filling it.
@@ -467,6 +706,9 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
     ) -> Vec<Id> {
         trace!(self.logger, "Filling audio buffer"; "len" => buf.len());
         let mut to_remove = Vec::new();
+        // Decided by the last frames, a priority speaker is only known once mixed
+        let ducked = self.priority_hold > 0;
+        let mut priority_heard = false;
         for (id, queue) in self.queues.iter_mut() {
             if queue.packet_loss_num >= MAX_PACKET_LOSSES {
                 debug!(self.logger, "Removing talker"; "packet_loss_num" => queue.packet_loss_num);
@@ -474,15 +716,26 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
                 continue;
             }
 
-            let vol = queue.volume;
-            match queue.get_next_data(buf.len()) {
+            let is_priority = self.priority.contains(id);
+            let mut vol = if ducked && !is_priority { queue.volume * self.priority_gain } else { queue.volume };
+            let start = Instant::now();
+            let next = queue.get_next_data(buf.len());
+            self.decode_time.add(start.elapsed().as_micros() as u64);
+            match next {
                 Err(e) => {
                     warn!(self.logger, "Failed to decode audio packet"; "error" => %e);
+                    self.decode_errors.add(1);
                 }
                 Ok((r, is_end)) => {
-                    handle(id, &r);
-                    for i in 0..r.len() {
-                        buf[i] += r[i] * vol;
+                    handle(id, r);
+                    if let Some(gate) = self.gates.get_mut(id) {
+                        vol *= gate.gain(r);
+                    }
+                    priority_heard |= is_priority && peak(r) >= PRIORITY_PEAK;
+                    if !self.delays.push(id, r, vol) {
+                        for i in 0..r.len() {
+                            buf[i] += r[i] * vol;
+                        }
                     }
                     if is_end {
                         to_remove.push(id.clone());
@@ -491,8 +744,12 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
             }
         }
 
+        self.delays.mix_into(buf);
+        self.priority_hold = if priority_heard { PRIORITY_HOLD } else { self.priority_hold.saturating_sub(buf.len()) };
+
         for id in &to_remove {
-            self.queues.remove(&id);
+            self.queues.remove(id);
+            self.priority.remove(id);
         }
         to_remove
     }
@@ -508,6 +765,9 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
             trace!(self.logger, "Adding talker");
             let mut queue = AudioQueue::new(
                 self.logger.new(o!("client" => format!("{:?}", id))),
+                self.pool.clone(),
+                self.limits,
+                self.dropped.clone(),
                 sequence,
                 packet
             )?;
@@ -522,18 +782,217 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
                         self.queues.len();
             }
             queue.buffering_samples = self.avg_buffer_samples;
+            queue.volume = self.gains.get(&id).copied().unwrap_or(1.0);
             self.queues.insert(id.clone(), queue);
             Ok(Some(id))
         }
     }
 
-    /// Set the global output volume (0.0 to 2.0)
+    /// Set the global output volume (0.0 to 2.0), ramped to over 50 ms.
     pub fn set_global_volume(&mut self, volume: f32) {
-        self.global_volume = volume.clamp(0.0, 2.0);
+        self.target_volume = volume.clamp(0.0, MAX_VOLUME);
+        self.volume_step = (self.target_volume - self.global_volume) / VOLUME_RAMP_SAMPLES;
     }
 
-    /// Get the current global volume
+    /// Set the global output volume (0.0 to 2.0) without a ramp.
+    pub fn set_global_volume_instantly(&mut self, volume: f32) {
+        self.set_global_volume(volume);
+        self.global_volume = self.target_volume;
+    }
+
+    /// Get the global volume, the one ramped to if it is changing
     pub fn get_global_volume(&self) -> f32 {
-        self.global_volume
+        self.target_volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::audio::tests::{ encoder, logger };
+    use crate::{ MAX_OPUS_FRAME_SIZE, STEREO_20MS };
+
+    fn silent_packet() -> Vec<u8> {
+        let mut out = [0; MAX_OPUS_FRAME_SIZE];
+        let len = encoder().encode_float(&[0.0; STEREO_20MS], &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    /// Feed packets with ids `base + offset` in the given order, reading one frame
+    /// after every `read_every` arrivals, and check the invariants after each step.
+    fn run_arrivals(
+        base: u16,
+        arrivals: &[u16],
+        read_every: usize,
+        mut check: impl FnMut(&AudioHandler<u32>, &[f32]) -> std::result::Result<(), TestCaseError>
+    ) -> std::result::Result<(), TestCaseError> {
+        let packet = silent_packet();
+        let mut handler = AudioHandler::<u32>::new(logger());
+        let mut buf = vec![0.0; STEREO_20MS];
+        for (i, offset) in arrivals.iter().enumerate() {
+            let _ = handler.handle_packet(1, base.wrapping_add(*offset), packet.clone());
+            if i % read_every == 0 {
+                buf.fill(0.0);
+                handler.fill_buffer(&mut buf);
+            }
+            check(&handler, &buf)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn priority_speakers_turn_the_others_down() {
+        let packets = crate::audio::tests::opus_sine(440.0, 10);
+        let mut handler = AudioHandler::<u32>::new(logger()).with_priority_gain(0.25);
+        handler.set_priority(2, true);
+        for (sequence, packet) in packets.iter().enumerate() {
+            handler.handle_packet(1, sequence as u16, packet.clone()).unwrap();
+            handler.handle_packet(2, sequence as u16, packet.clone()).unwrap();
+        }
+
+        // The same sine from both, the mix is how loud client 1 was added
+        let mut buf = vec![0.0; STEREO_20MS];
+        let mut ratios = Vec::new();
+        for _ in 0..6 {
+            buf.fill(0.0);
+            let mut source_peak = 0.0f32;
+            handler.fill_buffer_with_proc(&mut buf, |_, samples| source_peak = source_peak.max(peak(samples)));
+            if source_peak > 0.1 {
+                ratios.push(peak(&buf) / source_peak);
+            }
+        }
+        assert!(ratios.len() >= 3, "{:?}", ratios);
+        assert!((ratios[0] - 2.0).abs() < 0.1, "{:?}", ratios);
+        assert!(ratios[1..].iter().all(|ratio| (ratio - 1.25).abs() < 0.1), "{:?}", ratios);
+
+        // Full volume again once the priority speaker has been quiet for long enough
+        for _ in 0..(4 + MAX_PACKET_LOSSES + PRIORITY_HOLD / STEREO_20MS + 1) {
+            handler.fill_buffer(&mut buf);
+        }
+        assert!(handler.queues.is_empty());
+        assert!(handler.priority.is_empty());
+        assert_eq!(handler.priority_hold, 0);
+    }
+
+    #[test]
+    fn gains_outlive_the_queue() {
+        let packet = silent_packet();
+        let mut handler = AudioHandler::<u32>::new(logger());
+        handler.set_gain(1, 0.5);
+        handler.handle_packet(1, 0, packet.clone()).unwrap();
+        assert_eq!(handler.queues[&1].volume, 0.5);
+
+        handler.reset();
+        handler.handle_packet(1, 10, packet.clone()).unwrap();
+        assert_eq!(handler.queues[&1].volume, 0.5);
+        handler.set_gain(1, 1.0);
+        assert_eq!(handler.queues[&1].volume, 1.0);
+        assert!(handler.gains.is_empty());
+    }
+
+    #[test]
+    fn full_queues_count_dropped_packets() {
+        let packet = silent_packet();
+        let mut handler = AudioHandler::<u32>::new(logger());
+        for sequence in 0..(MAX_BUFFER_PACKETS as u16) {
+            handler.handle_packet(1, sequence, packet.clone()).unwrap();
+        }
+        assert_eq!(handler.dropped_packets().get(), 0);
+        assert!(matches!(handler.handle_packet(1, MAX_BUFFER_PACKETS as u16, packet), Err(Error::QueueFull)));
+        assert_eq!(handler.dropped_packets().get(), 1);
+    }
+
+    #[test]
+    fn configured_queues_drop_the_oldest_packets() {
+        let packet = silent_packet();
+        let limits = BufferLimits { max_ms: 100, overflow: Some(OverflowPolicy::DropOldest), ..Default::default() };
+        let mut handler = AudioHandler::<u32>::new(logger()).with_limits(&limits);
+        for sequence in 0..8 {
+            handler.handle_packet(1, sequence, packet.clone()).unwrap();
+        }
+        let queue = &handler.queues[&1];
+        assert_eq!(queue.packet_buffer.len(), 5);
+        assert_eq!(queue.packet_buffer.front().unwrap().id, 3);
+        assert_eq!(handler.dropped_packets().get(), 3);
+    }
+
+    #[test]
+    fn decoded_packets_return_to_the_pool() {
+        let packet = silent_packet();
+        let mut handler = AudioHandler::<u32>::new(logger());
+        let pool = handler.frame_pool();
+        let mut buf = vec![0.0; STEREO_20MS];
+        for i in 0..3 {
+            handler.handle_packet(1, i, pool.frame_from(&packet)).unwrap();
+            handler.fill_buffer(&mut buf);
+        }
+        assert_eq!(pool.idle(), 1);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn queue_stays_ordered(
+            base in any::<u16>(),
+            arrivals in vec(0u16..80, 1..150),
+            read_every in 1usize..5
+        ) {
+            run_arrivals(base, &arrivals, read_every, |handler, _| {
+                if let Some(queue) = handler.queues.get(&1) {
+                    let ids: Vec<u16> = queue.packet_buffer
+                        .iter()
+                        .map(|p| p.id.wrapping_sub(queue.next_id))
+                        .collect();
+                    prop_assert!(ids.windows(2).all(|w| w[0] < w[1]), "unordered: {:?}", ids);
+                }
+                Ok(())
+            })?;
+        }
+
+        #[test]
+        fn queue_occupancy_is_bounded(
+            base in any::<u16>(),
+            arrivals in vec(0u16..200, 1..300),
+            read_every in 1usize..20
+        ) {
+            run_arrivals(base, &arrivals, read_every, |handler, _| {
+                if let Some(queue) = handler.queues.get(&1) {
+                    prop_assert!(queue.packet_buffer.len() <= MAX_BUFFER_PACKETS);
+                    prop_assert!(queue.packet_buffer_samples <= MAX_BUFFER_PACKETS * MAX_BUFFER_SIZE);
+                    prop_assert!(queue.decoded_buffer.len() <= MAX_BUFFER_SIZE * CHANNEL_NUM * 2);
+                }
+                Ok(())
+            })?;
+        }
+
+        #[test]
+        fn silence_in_is_silence_out(
+            base in any::<u16>(),
+            arrivals in vec(0u16..60, 1..100),
+            read_every in 1usize..4
+        ) {
+            run_arrivals(base, &arrivals, read_every, |_, buf| {
+                prop_assert!(buf.iter().all(|s| s.abs() < 1e-3));
+                Ok(())
+            })?;
+        }
+
+        #[test]
+        fn mixer_output_is_finite(volume in -1.0f32..4.0, sources in 1u32..6) {
+            let packet = silent_packet();
+            let mut handler = AudioHandler::<u32>::new(logger());
+            handler.set_global_volume(volume);
+            prop_assert!((0.0..=2.0).contains(&handler.get_global_volume()));
+            for id in 0..sources {
+                handler.handle_packet(id, 0, packet.clone()).unwrap();
+            }
+            let mut buf = vec![0.0; STEREO_20MS];
+            handler.fill_buffer(&mut buf);
+            prop_assert!(buf.iter().all(|s| s.is_finite()));
+        }
     }
 }
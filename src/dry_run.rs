@@ -0,0 +1,263 @@
+//! `--dry-run`, checking the config against Discord and TeamSpeak without bridging.
+//!
+//! Nothing is joined and no audio flows. Every check is printed, and the exit
+//! code is non-zero if one failed, so a deployment can run it before going live.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+use futures::prelude::*;
+use serenity::http::Http;
+use serenity::model::channel::Channel;
+use serenity::model::id::{ ChannelId, GuildId, RoleId, UserId };
+use serenity::model::permissions::Permissions;
+use tsclientlib::{ DisconnectOptions, ServerGroupId };
+
+use crate::identity::Identities;
+use crate::{ teamspeak, Config };
+
+pub const DRY_RUN_FLAG: &str = "--dry-run";
+
+/// How long connecting to TeamSpeak may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long the TeamSpeak server gets to confirm the disconnect.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Needed in the voice channels the bridge is asked to join.
+const VOICE_PERMISSIONS: Permissions = Permissions::VIEW_CHANNEL.union(Permissions::CONNECT).union(Permissions::SPEAK);
+/// Needed in `admin_channel_id`.
+const ADMIN_PERMISSIONS: Permissions = Permissions::VIEW_CHANNEL.union(Permissions::SEND_MESSAGES);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Passed,
+    /// Might be a problem, e.g. permissions a channel may still grant.
+    Warning,
+    Failed,
+}
+
+/// Outcome of every check, by what was checked.
+#[derive(Debug, Default)]
+pub struct Report {
+    checks: Vec<(Outcome, String, String)>,
+}
+
+impl Report {
+    fn record(&mut self, outcome: Outcome, what: &str, detail: impl fmt::Display) {
+        self.checks.push((outcome, what.to_string(), detail.to_string()));
+    }
+
+    fn pass(&mut self, what: &str, detail: impl fmt::Display) {
+        self.record(Outcome::Passed, what, detail);
+    }
+
+    fn warn(&mut self, what: &str, detail: impl fmt::Display) {
+        self.record(Outcome::Warning, what, detail);
+    }
+
+    fn fail(&mut self, what: &str, detail: impl fmt::Display) {
+        self.record(Outcome::Failed, what, detail);
+    }
+
+    /// Whether no check failed, warnings are fine.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|(outcome, _, _)| *outcome != Outcome::Failed)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |wanted| self.checks.iter().filter(|(outcome, _, _)| *outcome == wanted).count();
+        for (outcome, what, detail) in &self.checks {
+            let label = match outcome {
+                Outcome::Passed => "ok",
+                Outcome::Warning => "warn",
+                Outcome::Failed => "FAIL",
+            };
+            writeln!(f, "{:<4} {}: {}", label, what, detail)?;
+        }
+        write!(
+            f,
+            "{} passed, {} warnings, {} failed",
+            count(Outcome::Passed),
+            count(Outcome::Warning),
+            count(Outcome::Failed)
+        )
+    }
+}
+
+/// Check everything and print the report to stdout, `true` if nothing failed.
+pub async fn run(config: &Config, channel_password: Option<String>) -> bool {
+    let mut report = Report::default();
+    report.pass("Config", "loaded");
+    check_discord(config, &mut report).await;
+    check_teamspeak(config, channel_password, &mut report).await;
+    println!("{}", report);
+    report.passed()
+}
+
+fn permission_names(permissions: Permissions) -> String {
+    permissions.get_permission_names().join(", ")
+}
+
+async fn check_discord(config: &Config, report: &mut Report) {
+    let http = Http::new(&config.discord_token);
+    let me = match http.get_current_user().await {
+        Ok(me) => {
+            report.pass("Discord login", me.tag());
+            me
+        }
+        Err(e) => {
+            report.fail("Discord login", e);
+            return;
+        }
+    };
+    let guilds = match http.get_guilds(None, None).await {
+        Ok(guilds) if guilds.is_empty() => {
+            report.warn("Discord servers", "in none yet, invite the bot first");
+            guilds
+        }
+        Ok(guilds) => {
+            report.pass("Discord servers", format!("in {}", guilds.len()));
+            guilds
+        }
+        Err(e) => {
+            report.fail("Discord servers", e);
+            return;
+        }
+    };
+
+    let joined: HashSet<GuildId> = guilds.iter().map(|guild| guild.id).collect();
+    for &id in config.command_guilds.iter().flatten() {
+        if !joined.contains(&GuildId::new(id)) {
+            report.fail("Command servers", format!("not in server {}", id));
+        }
+    }
+
+    let mut roles = HashSet::new();
+    for info in &guilds {
+        let what = format!("Discord server {}", info.name);
+        let (guild, member) = match (http.get_guild(info.id).await, http.get_member(info.id, me.id).await) {
+            (Ok(guild), Ok(member)) => (guild, member),
+            (Err(e), _) | (_, Err(e)) => {
+                report.warn(&what, e);
+                continue;
+            }
+        };
+        let missing = VOICE_PERMISSIONS - guild.member_permissions(&member);
+        if missing.is_empty() {
+            report.pass(&what, "can join voice channels");
+        } else {
+            report.warn(&what, format!("missing {} unless channels grant them", permission_names(missing)));
+        }
+        roles.extend(guild.roles.keys().copied());
+    }
+    for &id in config.discord_voice_roles.iter().flatten() {
+        if !roles.contains(&RoleId::new(id)) {
+            report.fail("Discord voice roles", format!("no server has role {}", id));
+        }
+    }
+
+    if let Some(id) = config.admin_channel_id {
+        check_admin_channel(&http, ChannelId::new(id), me.id, report).await;
+    }
+}
+
+async fn check_admin_channel(http: &Http, id: ChannelId, me: UserId, report: &mut Report) {
+    let what = "Admin channel";
+    let channel = match http.get_channel(id).await {
+        Ok(Channel::Guild(channel)) => channel,
+        Ok(_) => {
+            report.fail(what, format!("{} is not a server channel", id));
+            return;
+        }
+        Err(e) => {
+            report.fail(what, e);
+            return;
+        }
+    };
+    match (http.get_guild(channel.guild_id).await, http.get_member(channel.guild_id, me).await) {
+        (Ok(guild), Ok(member)) => {
+            let missing = ADMIN_PERMISSIONS - guild.user_permissions_in(&channel, &member);
+            if missing.is_empty() {
+                report.pass(what, format!("#{}", channel.name));
+            } else {
+                report.fail(what, format!("missing {} in #{}", permission_names(missing), channel.name));
+            }
+        }
+        (Err(e), _) | (_, Err(e)) => report.fail(what, e),
+    }
+}
+
+async fn check_teamspeak(config: &Config, channel_password: Option<String>, report: &mut Report) {
+    let more = config.teamspeak_identities.as_deref().unwrap_or_default();
+    let identities = match Identities::parse(&config.teamspeak_identity, more) {
+        Ok(identities) => identities,
+        Err(e) => {
+            report.fail("TeamSpeak identity", e);
+            return;
+        }
+    };
+    let options = crate::ts_connect_options(config, channel_password, identities.get(0).clone());
+    let mut con = match tokio::time::timeout(CONNECT_TIMEOUT, crate::connect_teamspeak(config, &options)).await {
+        Ok(Ok(con)) => con,
+        Ok(Err(e)) => {
+            report.fail("TeamSpeak connection", e);
+            return;
+        }
+        Err(_) => {
+            report.fail("TeamSpeak connection", format!("no answer within {} s", CONNECT_TIMEOUT.as_secs()));
+            return;
+        }
+    };
+
+    if let Ok(state) = con.get_state() {
+        let nickname = state.clients.get(&state.own_client).map_or("", |own| own.name.as_str());
+        report.pass("TeamSpeak connection", format!("on {} as {}", state.server.name, nickname));
+        match crate::home_channel(config) {
+            Some(home) => match teamspeak::home_channel_notice(&home, state) {
+                None => report.pass("TeamSpeak channel", home),
+                Some(notice) => report.fail("TeamSpeak channel", notice),
+            },
+            None => report.pass("TeamSpeak channel", "the server's default"),
+        }
+        let groups = [
+            ("TeamSpeak command groups", &config.teamspeak_command_groups),
+            ("TeamSpeak voice groups", &config.teamspeak_voice_groups),
+        ];
+        for (what, groups) in groups {
+            for &id in groups.iter().flatten() {
+                if !state.server_groups.contains_key(&ServerGroupId(id)) {
+                    report.fail(what, format!("no server group {}", id));
+                }
+            }
+        }
+    }
+
+    if con.disconnect(DisconnectOptions::new()).is_ok() {
+        let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, con.events().for_each(|_| future::ready(()))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_failures_fail_the_report() {
+        let mut report = Report::default();
+        report.pass("Discord login", "bridge#0001");
+        report.warn("Discord server Raid", "missing Speak unless channels grant them");
+        assert!(report.passed());
+
+        report.fail("TeamSpeak channel", "The TeamSpeak channel Raid doesn't exist");
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "ok   Discord login: bridge#0001\n\
+             warn Discord server Raid: missing Speak unless channels grant them\n\
+             FAIL TeamSpeak channel: The TeamSpeak channel Raid doesn't exist\n\
+             1 passed, 1 warnings, 1 failed"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Echo tests, recording a speaker and playing them back on their own side.
+//!
+//! The Opus packets are kept as they arrive and only decoded at the end, so
+//! recording costs nothing for everybody else.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Duration;
+
+use anyhow::Result;
+use audiopus::coder::Decoder;
+use audiopus::{ Channels, SampleRate };
+
+use crate::SAMPLE_RATE;
+
+/// How long a speaker is recorded.
+pub const ECHO_DURATION: Duration = Duration::from_secs(5);
+/// How long a quiet speaker is recorded for `/calibrate-noise`, within what a recording keeps.
+pub const NOISE_CALIBRATION: Duration = Duration::from_secs(5);
+/// Packets kept per recording, a bit more than [`ECHO_DURATION`] of 20 ms packets.
+const MAX_PACKETS: usize = 300;
+/// Interleaved stereo samples in the longest Opus packet (120 ms).
+const MAX_PACKET_SAMPLES: usize = (SAMPLE_RATE * 2 * 120) / 1000;
+
+/// Recordings in progress, by speaker. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct EchoRecorder<Id> {
+    recordings: Arc<StdMutex<HashMap<Id, Vec<Vec<u8>>>>>,
+}
+
+impl<Id> Default for EchoRecorder<Id> {
+    fn default() -> Self {
+        Self { recordings: Default::default() }
+    }
+}
+
+impl<Id: Eq + Hash> EchoRecorder<Id> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start recording `id`, `false` if they are recorded already.
+    pub fn start(&self, id: Id) -> bool {
+        let mut recordings = self.recordings.lock().expect("Can't lock echo recordings!");
+        if recordings.contains_key(&id) {
+            return false;
+        }
+        recordings.insert(id, Vec::new());
+        true
+    }
+
+    /// Keep an Opus packet of `id`, `false` if they are not being recorded.
+    pub fn record(&self, id: &Id, packet: &[u8]) -> bool {
+        let mut recordings = self.recordings.lock().expect("Can't lock echo recordings!");
+        match recordings.get_mut(id) {
+            Some(packets) => {
+                if packets.len() < MAX_PACKETS {
+                    packets.push(packet.to_vec());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop recording `id` and throw away what was recorded, `false` if they were not being recorded.
+    pub fn discard(&self, id: &Id) -> bool {
+        self.recordings.lock().expect("Can't lock echo recordings!").remove(id).is_some()
+    }
+
+    /// Stop recording `id` and decode what they said to 48 kHz stereo.
+    pub fn finish(&self, id: &Id) -> Result<Vec<f32>> {
+        let packets = self.recordings
+            .lock()
+            .expect("Can't lock echo recordings!")
+            .remove(id)
+            .unwrap_or_default();
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo)?;
+        let mut samples = Vec::new();
+        let mut frame = vec![0.0; MAX_PACKET_SAMPLES];
+        for packet in packets.iter().filter(|p| !p.is_empty()) {
+            // A broken packet is a gap in the recording, not the end of it
+            if let Ok(len) = decoder.decode_float(Some(packet), &mut frame[..], false) {
+                samples.extend_from_slice(&frame[..len * 2]);
+            }
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::peak;
+    use crate::audio::tests::opus_sine;
+    use crate::STEREO_20MS;
+
+    #[test]
+    fn only_started_recordings_are_kept() {
+        let echo = EchoRecorder::new();
+        let packets = opus_sine(440.0, 10);
+        assert!(!echo.record(&1, &packets[0]));
+        assert!(echo.start(1));
+        assert!(!echo.start(1));
+        for packet in &packets {
+            assert!(echo.record(&1, packet));
+            assert!(!echo.record(&2, packet));
+        }
+
+        let samples = echo.finish(&1).unwrap();
+        assert_eq!(samples.len(), 10 * STEREO_20MS);
+        assert!(peak(&samples) > 0.1);
+        assert!(echo.finish(&1).unwrap().is_empty());
+        assert!(echo.start(1));
+    }
+}
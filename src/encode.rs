@@ -0,0 +1,162 @@
+//! Encoding the Discord mix for every output at once.
+//!
+//! Each output has its own Opus encoder, they run side by side on the blocking
+//! pool so adding one does not add its encoding time to every tick. Ticks whose
+//! encoding runs past the frame are counted.
+
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use audiopus::coder::Encoder;
+use audiopus::Channels;
+use futures::future::join_all;
+use tsproto_packets::packets::{ CodecType, OutPacket };
+
+use crate::audio::{ self, DropCounter };
+
+/// `OPUS_SET_DTX_REQUEST`, audiopus has no setter for it.
+const OPUS_SET_DTX_REQUEST: i32 = 4016;
+
+struct Output {
+    name: String,
+    encoder: StdMutex<Encoder>,
+}
+
+/// Encoders of all outputs, cheap to clone.
+#[derive(Clone, Default)]
+pub struct EncoderPool {
+    outputs: Vec<Arc<Output>>,
+    late: DropCounter,
+    /// Time all outputs spent encoding, in µs.
+    time: DropCounter,
+}
+
+impl EncoderPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also encode for the output `name`, packets are returned in the order outputs were added.
+    pub fn with_output(mut self, name: &str, encoder: Encoder) -> Self {
+        self.outputs.push(Arc::new(Output { name: name.to_string(), encoder: StdMutex::new(encoder) }));
+        self
+    }
+
+    /// Count the time spent encoding in `counter`, in µs.
+    pub fn with_encode_time(mut self, counter: DropCounter) -> Self {
+        self.time = counter;
+        self
+    }
+
+    /// Ticks whose encoding took longer than their frame.
+    pub fn late_frames(&self) -> DropCounter {
+        self.late.clone()
+    }
+
+    /// Let Opus pick the bitrate of all outputs if `bitrate` is unset.
+    pub fn set_bitrate(&self, bitrate: Option<i32>) {
+        let bitrate = bitrate.map_or(audiopus::Bitrate::Auto, audiopus::Bitrate::BitsPerSecond);
+        for output in &self.outputs {
+            let mut encoder = output.encoder.lock().expect("Can't lock encoder!");
+            if let Err(e) = encoder.set_bitrate(bitrate) {
+                tracing::warn!("Can't set the bitrate of {} to {:?}: {}", output.name, bitrate, e);
+            }
+        }
+    }
+
+    /// Downmix every output to mono, or let Opus pick the channels.
+    pub fn set_mono(&self, mono: bool) {
+        let channels = if mono { Channels::Mono } else { Channels::Auto };
+        for output in &self.outputs {
+            let mut encoder = output.encoder.lock().expect("Can't lock encoder!");
+            if let Err(e) = encoder.set_force_channels(channels) {
+                tracing::warn!("Can't set the channels of {} to {:?}: {}", output.name, channels, e);
+            }
+        }
+    }
+
+    /// Let every output leave out silence, with a packet every 400 ms to keep the stream up.
+    pub fn set_dtx(&self, dtx: bool) {
+        for output in &self.outputs {
+            let mut encoder = output.encoder.lock().expect("Can't lock encoder!");
+            if let Err(e) = encoder.set_encoder_ctl_request(OPUS_SET_DTX_REQUEST, dtx.into()) {
+                tracing::warn!("Can't turn DTX of {} {}: {}", output.name, if dtx { "on" } else { "off" }, e);
+            }
+        }
+    }
+
+    /// Encode `pcm` for every output in parallel, `None` for outputs that failed or have nothing to send.
+    ///
+    /// `deadline` is the length of the frame, encoding should be well done by then.
+    pub async fn encode(&self, pcm: &[f32], codec: CodecType, deadline: Duration) -> Vec<Option<OutPacket>> {
+        let start = Instant::now();
+        let pcm: Arc<[f32]> = pcm.into();
+        let jobs = self.outputs.iter().map(|output| {
+            let (output, pcm, time) = (output.clone(), pcm.clone(), self.time.clone());
+            tokio::task::spawn_blocking(move || {
+                let encoder = output.encoder.lock().expect("Can't lock encoder!");
+                let start = Instant::now();
+                let encoded = audio::encode_ts_packet(&encoder, &pcm, codec);
+                time.add(start.elapsed().as_micros() as u64);
+                match encoded {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        tracing::error!("Failed to encode voice for {}: {}", output.name, e);
+                        None
+                    }
+                }
+            })
+        });
+        let packets = join_all(jobs).await
+            .into_iter()
+            .map(|job| job.expect("Join error for audio processing thread!"))
+            .collect();
+
+        let took = start.elapsed();
+        if took > deadline {
+            self.late.add(1);
+            tracing::warn!(
+                "Encoding {} outputs took {}ms, longer than the {}ms frame!",
+                self.outputs.len(),
+                took.as_millis(),
+                deadline.as_millis()
+            );
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::tests::encoder;
+    use crate::STEREO_20MS;
+
+    #[tokio::test]
+    async fn every_output_gets_a_packet() {
+        let time = DropCounter::default();
+        let pool = EncoderPool::new()
+            .with_encode_time(time.clone())
+            .with_output("teamspeak", encoder())
+            .with_output("recording", encoder());
+        pool.set_bitrate(Some(64_000));
+        let packets = pool.encode(&[0.1; STEREO_20MS], CodecType::OpusMusic, Duration::from_secs(1)).await;
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(Option::is_some));
+        assert_eq!(pool.late_frames().get(), 0);
+        assert!(time.get() > 0);
+
+        pool.encode(&[0.1; STEREO_20MS], CodecType::OpusMusic, Duration::ZERO).await;
+        assert_eq!(pool.late_frames().get(), 1);
+
+        // Silence is left out once DTX has seen enough of it
+        pool.set_mono(true);
+        pool.set_dtx(true);
+        let mut sent = 0;
+        for _ in 0..50 {
+            let packets = pool.encode(&[0.0; STEREO_20MS], CodecType::OpusVoice, Duration::from_secs(1)).await;
+            sent += packets.iter().flatten().count();
+        }
+        assert!(sent < 50, "{} of 100 silent packets sent", sent);
+    }
+}
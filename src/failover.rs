@@ -0,0 +1,188 @@
+//! Moving to a standby TeamSpeak server while the configured one is down.
+//!
+//! tsclientlib keeps trying to reconnect on its own, but a server that is
+//! down for long leaves Discord talking to nobody. Once the primary server
+//! has been unreachable for `after_s`, the bridge connects to the
+//! `[fallback_teamspeak]` server, and from there tries the primary every
+//! `retry_primary_s` to move back as soon as it answers.
+//!
+//! Connecting and leaving happen off the main loop, so audio keeps flowing
+//! meanwhile.
+
+use std::time::{ Duration, Instant };
+
+use futures::prelude::*;
+use serde::Deserialize;
+use tsclientlib::{ Connection, DisconnectOptions };
+
+use crate::teamspeak::HomeChannel;
+
+/// How long connecting to either server may take.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a server gets to confirm the disconnect when leaving it.
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the connection is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the primary may be unreachable unless configured.
+const DEFAULT_AFTER: Duration = Duration::from_secs(60);
+/// How often the primary is tried from the fallback unless configured.
+const DEFAULT_RETRY_PRIMARY: Duration = Duration::from_secs(60);
+
+/// `[fallback_teamspeak]` section.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FallbackConfig {
+    pub server: String,
+    pub server_password: Option<String>,
+    pub channel_id: Option<u64>,
+    pub channel_name: Option<String>,
+    pub channel_password: Option<String>,
+    /// Seconds the primary may be unreachable before moving, 60 by default.
+    pub after_s: Option<u64>,
+    /// Seconds between tries of the primary while on the fallback, 60 by default.
+    pub retry_primary_s: Option<u64>,
+}
+
+impl FallbackConfig {
+    /// The channel to be in on the standby server.
+    pub fn home_channel(&self) -> Option<HomeChannel> {
+        self.channel_id.map(HomeChannel::Id).or(self.channel_name.clone().map(HomeChannel::Path))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Server {
+    Primary,
+    Fallback,
+}
+
+/// When to connect where.
+#[derive(Debug)]
+pub struct Failover {
+    after: Duration,
+    retry_primary: Duration,
+    on: Server,
+    /// Since when the primary is unreachable.
+    lost_since: Option<Instant>,
+    /// When the primary was last tried from the fallback.
+    last_try: Option<Instant>,
+    /// Whether a connection attempt is under way.
+    connecting: bool,
+}
+
+impl Failover {
+    pub fn new(config: &FallbackConfig) -> Self {
+        Self {
+            after: config.after_s.map_or(DEFAULT_AFTER, Duration::from_secs),
+            retry_primary: config.retry_primary_s.map_or(DEFAULT_RETRY_PRIMARY, Duration::from_secs),
+            on: Server::Primary,
+            lost_since: None,
+            last_try: None,
+            connecting: false,
+        }
+    }
+
+    /// The server the bridge is on.
+    pub fn on(&self) -> Server {
+        self.on
+    }
+
+    /// Where to connect to now, given whether the current connection is `connected`.
+    pub fn check(&mut self, connected: bool, now: Instant) -> Option<Server> {
+        if self.connecting {
+            return None;
+        }
+        let target = match self.on {
+            Server::Primary if connected => {
+                self.lost_since = None;
+                return None;
+            }
+            Server::Primary => {
+                let since = *self.lost_since.get_or_insert(now);
+                if now.duration_since(since) < self.after {
+                    return None;
+                }
+                Server::Fallback
+            }
+            Server::Fallback => {
+                if self.last_try.is_some_and(|last| now.duration_since(last) < self.retry_primary) {
+                    return None;
+                }
+                self.last_try = Some(now);
+                Server::Primary
+            }
+        };
+        self.connecting = true;
+        Some(target)
+    }
+
+    /// Connected to `server` and left the other one.
+    pub fn switched(&mut self, server: Server, now: Instant) {
+        self.on = server;
+        self.connecting = false;
+        self.lost_since = None;
+        self.last_try = Some(now);
+    }
+
+    /// The attempt to connect failed or wasn't needed anymore, try again after the usual wait.
+    pub fn gave_up(&mut self, now: Instant) {
+        self.connecting = false;
+        if self.on == Server::Primary {
+            self.lost_since = Some(now);
+        }
+    }
+}
+
+/// Leave the server of `con` in the background, giving it a moment to confirm.
+pub fn leave(mut con: Connection) {
+    tokio::spawn(async move {
+        if con.get_state().is_err() || con.disconnect(DisconnectOptions::new()).is_err() {
+            return;
+        }
+        let drained = con.events().for_each(|_| future::ready(()));
+        let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, drained).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_after_the_threshold_and_back() {
+        let config = FallbackConfig {
+            server: "standby".to_string(),
+            server_password: None,
+            channel_id: None,
+            channel_name: None,
+            channel_password: None,
+            after_s: Some(30),
+            retry_primary_s: Some(60),
+        };
+        let mut failover = Failover::new(&config);
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+
+        assert_eq!(failover.check(true, at(0)), None);
+        assert_eq!(failover.check(false, at(1)), None);
+        assert_eq!(failover.check(true, at(20)), None);
+        assert_eq!(failover.check(false, at(21)), None);
+        assert_eq!(failover.check(false, at(50)), None);
+        assert_eq!(failover.check(false, at(51)), Some(Server::Fallback));
+        assert_eq!(failover.check(false, at(52)), None, "one attempt at a time");
+        failover.gave_up(at(60));
+        assert_eq!(failover.check(false, at(80)), None);
+        assert_eq!(failover.check(false, at(90)), Some(Server::Fallback));
+
+        failover.switched(Server::Fallback, at(95));
+        assert_eq!(failover.on(), Server::Fallback);
+        assert_eq!(failover.check(true, at(100)), None);
+        assert_eq!(failover.check(true, at(155)), Some(Server::Primary));
+        failover.gave_up(at(156));
+        assert_eq!(failover.check(true, at(157)), None);
+        assert_eq!(failover.check(true, at(215)), Some(Server::Primary));
+        failover.switched(Server::Primary, at(220));
+        assert_eq!(failover.on(), Server::Primary);
+        assert_eq!(failover.check(false, at(249)), None);
+        assert_eq!(failover.check(false, at(280)), Some(Server::Fallback));
+    }
+}
@@ -0,0 +1,240 @@
+//! Feedback detection, for people connected on both sides at once.
+//!
+//! Somebody in Discord and TeamSpeak with their speakers on plays what the
+//! bridge sends to one side into their microphone on the other, and round it
+//! goes. The loudness of every source is compared with what the bridge put out
+//! on the source's side a moment before; a source that follows it closely is
+//! not bridged for a while.
+
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use crate::levels::Direction;
+
+/// Loudness is compared in blocks this long, a few frames each.
+const BLOCK: Duration = Duration::from_millis(50);
+/// Blocks compared, 4 s.
+const WINDOW_BLOCKS: u64 = 80;
+/// Longest round trip through the other side looked for, 1.5 s.
+const MAX_LAG_BLOCKS: u64 = 30;
+/// Blocks louder than about -40 dBFS count as sound.
+const SOUND_RMS: f32 = 0.01;
+/// Both the source and the output need this many blocks of sound in the window.
+const MIN_SOUND_BLOCKS: usize = 20;
+/// Correlation from which a source counts as feedback.
+const THRESHOLD: f32 = 0.85;
+/// How often sources are compared with the output.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a feeding back source is not bridged.
+pub const SUPPRESS_FOR: Duration = Duration::from_secs(30);
+/// Source named like this in [`AudioLevels`](crate::levels::AudioLevels) is what the bridge put out.
+const OUTPUT: &str = "mix";
+
+/// Loudest RMS of each block, contiguous up to the last block heard.
+#[derive(Debug, Default)]
+struct Envelope {
+    first: u64,
+    blocks: VecDeque<f32>,
+}
+
+impl Envelope {
+    fn add(&mut self, block: u64, rms: f32) {
+        if self.blocks.is_empty() {
+            self.first = block;
+        }
+        let Some(offset) = block.checked_sub(self.first) else {
+            return;
+        };
+        let offset = offset as usize;
+        if offset >= self.blocks.len() {
+            self.blocks.resize(offset + 1, 0.0);
+        }
+        self.blocks[offset] = self.blocks[offset].max(rms);
+        let kept = (WINDOW_BLOCKS + MAX_LAG_BLOCKS) as usize;
+        while self.blocks.len() > kept {
+            self.blocks.pop_front();
+            self.first += 1;
+        }
+    }
+
+    fn get(&self, block: u64) -> f32 {
+        block
+            .checked_sub(self.first)
+            .and_then(|offset| self.blocks.get(offset as usize))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn last(&self) -> u64 {
+        self.first + (self.blocks.len() as u64)
+    }
+
+    /// The `WINDOW_BLOCKS` ending `lag` blocks before `end`.
+    fn window(&self, end: u64, lag: u64) -> Vec<f32> {
+        let end = end.saturating_sub(lag);
+        (end.saturating_sub(WINDOW_BLOCKS)..end).map(|block| self.get(block)).collect()
+    }
+}
+
+/// Pearson correlation of `a` and `b`, 0 if either is flat.
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len()) as f32;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let (mean_a, mean_b) = (a.iter().sum::<f32>() / n, b.iter().sum::<f32>() / n);
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (dx, dy) = (x - mean_a, y - mean_b);
+        cov += dx * dy;
+        var_a += dx * dx;
+        var_b += dy * dy;
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+fn has_sound(window: &[f32]) -> bool {
+    window.iter().filter(|&&rms| rms >= SOUND_RMS).count() >= MIN_SOUND_BLOCKS
+}
+
+#[derive(Debug)]
+struct Detector {
+    start: Instant,
+    envelopes: HashMap<(Direction, String), Envelope>,
+    /// Sources not bridged, until when.
+    suppressed: HashMap<(Direction, String), Instant>,
+}
+
+/// Loudness of every source and output, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct FeedbackDetector {
+    detector: Arc<StdMutex<Detector>>,
+}
+
+impl Default for FeedbackDetector {
+    fn default() -> Self {
+        Self {
+            detector: Arc::new(
+                StdMutex::new(Detector {
+                    start: Instant::now(),
+                    envelopes: HashMap::new(),
+                    suppressed: HashMap::new(),
+                })
+            ),
+        }
+    }
+}
+
+impl FeedbackDetector {
+    /// Feed one frame of `source` going in `direction`, the output of the direction if `source` is `mix`.
+    pub fn record(&self, direction: Direction, source: &str, samples: &[f32]) {
+        self.record_at(direction, source, samples, Instant::now());
+    }
+
+    fn record_at(&self, direction: Direction, source: &str, samples: &[f32], now: Instant) {
+        if samples.is_empty() {
+            return;
+        }
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / (samples.len() as f32)).sqrt();
+        let mut detector = self.detector.lock().expect("Can't lock feedback detector!");
+        let block = (now.saturating_duration_since(detector.start).as_millis() / BLOCK.as_millis()) as u64;
+        match detector.envelopes.get_mut(&(direction, source.to_string())) {
+            Some(envelope) => envelope.add(block, rms),
+            None => {
+                let mut envelope = Envelope::default();
+                envelope.add(block, rms);
+                detector.envelopes.insert((direction, source.to_string()), envelope);
+            }
+        }
+    }
+
+    /// Whether `source` going in `direction` is not bridged for feeding back.
+    pub fn is_suppressed(&self, direction: Direction, source: &str) -> bool {
+        let detector = self.detector.lock().expect("Can't lock feedback detector!");
+        detector.suppressed.get(&(direction, source.to_string())).is_some_and(|until| Instant::now() < *until)
+    }
+
+    /// Sources that started feeding back, they are suppressed for [`SUPPRESS_FOR`] from now on.
+    pub fn check(&self) -> Vec<(Direction, String)> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Vec<(Direction, String)> {
+        let mut detector = self.detector.lock().expect("Can't lock feedback detector!");
+        let end = (now.saturating_duration_since(detector.start).as_millis() / BLOCK.as_millis()) as u64;
+        detector.suppressed.retain(|_, until| now < *until);
+        detector.envelopes.retain(|_, envelope| end.saturating_sub(envelope.last()) < WINDOW_BLOCKS + MAX_LAG_BLOCKS);
+
+        let mut feeding_back = Vec::new();
+        for ((direction, source), envelope) in &detector.envelopes {
+            let key = (*direction, source.clone());
+            if source == OUTPUT || detector.suppressed.contains_key(&key) {
+                continue;
+            }
+            // What the source's side heard from the bridge
+            let opposite = match direction {
+                Direction::DiscordToTs => Direction::TsToDiscord,
+                Direction::TsToDiscord => Direction::DiscordToTs,
+            };
+            let Some(output) = detector.envelopes.get(&(opposite, OUTPUT.to_string())) else {
+                continue;
+            };
+            let heard = envelope.window(end, 0);
+            if !has_sound(&heard) {
+                continue;
+            }
+            let follows = (0..=MAX_LAG_BLOCKS).any(|lag| {
+                let said = output.window(end, lag);
+                has_sound(&said) && correlation(&heard, &said) >= THRESHOLD
+            });
+            if follows {
+                feeding_back.push(key);
+            }
+        }
+        for key in &feeding_back {
+            detector.suppressed.insert(key.clone(), now + SUPPRESS_FOR);
+        }
+        feeding_back
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loudness of made up speech of `speaker`, changing every block.
+    fn speech(speaker: u64, block: u64) -> f32 {
+        let mut x = (block + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ speaker;
+        x ^= x >> 29;
+        x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x ^= x >> 32;
+        (x % 500) as f32 / 1000.0
+    }
+
+    #[test]
+    fn sources_echoing_the_other_side_are_suppressed() {
+        let detector = FeedbackDetector::default();
+        let start = detector.detector.lock().unwrap().start;
+        for block in 0..WINDOW_BLOCKS + 20 {
+            let now = start + BLOCK * (block as u32);
+            let level = speech(1, block);
+            detector.record_at(Direction::DiscordToTs, OUTPUT, &[level; 4], now);
+            // Back from TeamSpeak 300 ms later, quieter
+            let echo = if block >= 6 { speech(1, block - 6) * 0.3 } else { 0.0 };
+            detector.record_at(Direction::TsToDiscord, "client 5", &[echo; 4], now);
+            // Somebody just talking
+            detector.record_at(Direction::TsToDiscord, "client 6", &[speech(2, block); 4], now);
+        }
+
+        let now = start + BLOCK * ((WINDOW_BLOCKS + 20) as u32);
+        assert_eq!(detector.check_at(now), vec![(Direction::TsToDiscord, "client 5".to_string())]);
+        assert!(detector.is_suppressed(Direction::TsToDiscord, "client 5"));
+        assert!(!detector.is_suppressed(Direction::TsToDiscord, "client 6"));
+        // Reported once
+        assert!(detector.check_at(now).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+//! Raised hands for moderated sessions.
+//!
+//! `/hand-raise` and `!hand` queue people on either side, `/next` gives the
+//! first of them the floor for a while: they are bridged even if bridge-muted
+//! or left out by `/solo`, until their time is up.
+
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex as StdMutex, MutexGuard };
+use std::time::{ Duration, Instant };
+
+use tsclientlib::ClientId;
+
+/// How long `/next` gives the floor, unless configured otherwise.
+pub const DEFAULT_FLOOR: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    Discord(u64),
+    Ts(ClientId),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hand {
+    pub speaker: Speaker,
+    pub name: String,
+}
+
+impl Hand {
+    /// Name with the side they are on.
+    pub fn label(&self) -> String {
+        match self.speaker {
+            Speaker::Discord(_) => format!("{} (Discord)", self.name),
+            Speaker::Ts(_) => format!("{} (TeamSpeak)", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    hands: VecDeque<Hand>,
+    /// Who has the floor, until when.
+    floor: Option<(Hand, Instant)>,
+}
+
+/// Raised hands and who has the floor, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct HandQueue {
+    queue: Arc<StdMutex<Queue>>,
+    /// How long the floor is given by default.
+    floor_time: Duration,
+}
+
+impl Default for HandQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_FLOOR)
+    }
+}
+
+impl HandQueue {
+    pub fn new(floor_time: Duration) -> Self {
+        Self { queue: Default::default(), floor_time }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().expect("Can't lock raised hands!")
+    }
+
+    pub fn floor_time(&self) -> Duration {
+        self.floor_time
+    }
+
+    /// Queue `hand`, returns their place counting from 1, the old one if already queued.
+    pub fn raise(&self, hand: Hand) -> usize {
+        let mut queue = self.queue();
+        if let Some(place) = queue.hands.iter().position(|queued| queued.speaker == hand.speaker) {
+            return place + 1;
+        }
+        queue.hands.push_back(hand);
+        queue.hands.len()
+    }
+
+    /// Take `speaker` out of the queue, false if they weren't in it.
+    pub fn lower(&self, speaker: Speaker) -> bool {
+        let mut queue = self.queue();
+        let before = queue.hands.len();
+        queue.hands.retain(|hand| hand.speaker != speaker);
+        queue.hands.len() != before
+    }
+
+    pub fn hands(&self) -> Vec<Hand> {
+        self.queue().hands.iter().cloned().collect()
+    }
+
+    /// Give the floor to the first hand for `time`, taking it from whoever had it.
+    pub fn next(&self, time: Duration) -> Option<Hand> {
+        self.next_at(time, Instant::now())
+    }
+
+    fn next_at(&self, time: Duration, now: Instant) -> Option<Hand> {
+        let mut queue = self.queue();
+        let hand = queue.hands.pop_front();
+        queue.floor = hand.clone().map(|hand| (hand, now + time));
+        hand
+    }
+
+    /// Who has the floor now.
+    pub fn floor(&self) -> Option<Hand> {
+        self.floor_at(Instant::now())
+    }
+
+    fn floor_at(&self, now: Instant) -> Option<Hand> {
+        match &self.queue().floor {
+            Some((hand, until)) if now < *until => Some(hand.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn has_floor(&self, speaker: Speaker) -> bool {
+        self.floor().is_some_and(|hand| hand.speaker == speaker)
+    }
+
+    /// Who had the floor until their time ran out, once.
+    pub fn take_expired(&self) -> Option<Hand> {
+        self.take_expired_at(Instant::now())
+    }
+
+    fn take_expired_at(&self, now: Instant) -> Option<Hand> {
+        let mut queue = self.queue();
+        match &queue.floor {
+            Some((_, until)) if now >= *until => queue.floor.take().map(|(hand, _)| hand),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hand(speaker: Speaker, name: &str) -> Hand {
+        Hand { speaker, name: name.to_string() }
+    }
+
+    #[test]
+    fn hands_get_the_floor_in_turn() {
+        let hands = HandQueue::default();
+        assert_eq!(hands.raise(hand(Speaker::Discord(1), "Ann")), 1);
+        assert_eq!(hands.raise(hand(Speaker::Ts(ClientId(5)), "Bob")), 2);
+        assert_eq!(hands.raise(hand(Speaker::Discord(1), "Ann")), 1);
+        assert_eq!(hands.raise(hand(Speaker::Discord(2), "Cid")), 3);
+        assert!(hands.lower(Speaker::Discord(2)));
+        assert!(!hands.lower(Speaker::Discord(2)));
+
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+        assert_eq!(hands.next_at(minute, now).unwrap().label(), "Ann (Discord)");
+        assert_eq!(hands.floor_at(now).unwrap().speaker, Speaker::Discord(1));
+        assert_eq!(hands.next_at(minute, now + minute / 2).unwrap().label(), "Bob (TeamSpeak)");
+        assert_eq!(hands.floor_at(now + minute).unwrap().speaker, Speaker::Ts(ClientId(5)));
+        assert_eq!(hands.take_expired_at(now + minute), None);
+
+        let later = now + minute * 2;
+        assert_eq!(hands.floor_at(later), None);
+        assert_eq!(hands.take_expired_at(later).unwrap().name, "Bob");
+        assert_eq!(hands.take_expired_at(later), None);
+        assert_eq!(hands.next_at(minute, later), None);
+        assert!(hands.hands().is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+//! Discord audio held while TeamSpeak reconnects, sent once it is back.
+//!
+//! A short blip would otherwise cut whole sentences. Frames wait here up to
+//! `reconnect_buffer_ms`, the oldest going first past that, and afterwards go
+//! out a few per tick alongside the new ones until the bridge has caught up.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How long held audio may be unless configured, a blip of a few seconds.
+pub const DEFAULT_HOLDOVER: Duration = Duration::from_secs(5);
+/// Frames sent per tick while catching up, one new and one held.
+pub const CATCH_UP_FRAMES: usize = 2;
+
+/// Frames waiting for TeamSpeak, with how long each lasts.
+#[derive(Debug)]
+pub struct Holdover<T> {
+    frames: VecDeque<(T, Duration)>,
+    held: Duration,
+    max: Duration,
+}
+
+impl<T> Holdover<T> {
+    pub fn new(max: Duration) -> Self {
+        Self { frames: VecDeque::new(), held: Duration::ZERO, max }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// How much audio is waiting.
+    pub fn held(&self) -> Duration {
+        self.held
+    }
+
+    /// Queue `frame` after the ones held, returns how many of the oldest were dropped for it.
+    pub fn push(&mut self, frame: T, length: Duration) -> usize {
+        self.frames.push_back((frame, length));
+        self.held += length;
+        let mut dropped = 0;
+        while self.held > self.max {
+            match self.frames.pop_front() {
+                Some((_, length)) => {
+                    self.held -= length;
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    /// The next frame to send, oldest first.
+    pub fn pop(&mut self) -> Option<T> {
+        let (frame, length) = self.frames.pop_front()?;
+        self.held -= length;
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_frames_go_past_the_cap() {
+        let frame = Duration::from_millis(20);
+        let mut holdover = Holdover::new(Duration::from_millis(60));
+        assert_eq!(holdover.push(1, frame), 0);
+        assert_eq!(holdover.push(2, frame), 0);
+        assert_eq!(holdover.push(3, frame), 0);
+        assert_eq!(holdover.push(4, frame), 1);
+        assert_eq!(holdover.held(), Duration::from_millis(60));
+
+        assert_eq!(holdover.pop(), Some(2));
+        assert_eq!(holdover.pop(), Some(3));
+        assert_eq!(holdover.pop(), Some(4));
+        assert_eq!(holdover.pop(), None);
+        assert_eq!(holdover.held(), Duration::ZERO);
+
+        let mut off = Holdover::new(Duration::ZERO);
+        assert_eq!(off.push(1, frame), 1);
+        assert_eq!(off.pop(), None);
+    }
+}
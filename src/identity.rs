@@ -0,0 +1,78 @@
+//! TeamSpeak identities to take turns with, switched by `/rotate-identity`.
+//!
+//! `teamspeak_identity` comes first, then `teamspeak_identities` in order.
+//! Rotating reconnects with the next one, for when the current identity got
+//! banned or a spare with a higher security level was computed offline.
+
+use anyhow::Result;
+use tsclientlib::Identity;
+
+#[derive(Clone, Debug)]
+pub struct Identities {
+    identities: Vec<Identity>,
+}
+
+impl Identities {
+    /// Parse `first` and `more`, failing on the first identity that isn't valid.
+    pub fn parse(first: &str, more: &[String]) -> Result<Self> {
+        let identities = std::iter::once(first)
+            .chain(more.iter().map(String::as_str))
+            .enumerate()
+            .map(|(i, id)| Identity::new_from_str(id).map_err(|e| anyhow::anyhow!("TeamSpeak identity {}: {}", i + 1, e)))
+            .collect::<Result<_>>()?;
+        Ok(Self { identities })
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len()
+    }
+
+    /// Identity at `index`, the first one if there are fewer.
+    pub fn get(&self, index: usize) -> &Identity {
+        self.identities.get(index).unwrap_or(&self.identities[0])
+    }
+
+    /// Where to start from `saved`, the first identity if it is gone from the config.
+    pub fn start(&self, saved: Option<usize>) -> usize {
+        saved.filter(|&index| index < self.len()).unwrap_or(0)
+    }
+
+    /// The identity after `index`, `None` if there is only one.
+    pub fn next(&self, index: usize) -> Option<usize> {
+        (self.len() > 1).then(|| (index + 1) % self.len())
+    }
+
+    /// Identity at `index` in words, with its unique id and security level.
+    pub fn describe(&self, index: usize) -> String {
+        let id = self.get(index);
+        format!(
+            "identity {} of {} ({}, security level {})",
+            index + 1,
+            self.len(),
+            id.key().to_pub().get_uid(),
+            id.level()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_take_turns() {
+        let first = Identity::create().key().to_ts();
+        let second = Identity::create().key().to_ts();
+        assert!(Identities::parse(&first, &["not an identity".to_string()]).is_err());
+
+        let single = Identities::parse(&first, &[]).unwrap();
+        assert_eq!(single.next(0), None);
+
+        let identities = Identities::parse(&first, &[second]).unwrap();
+        assert_eq!(identities.start(Some(1)), 1);
+        assert_eq!(identities.start(Some(2)), 0);
+        assert_eq!(identities.next(0), Some(1));
+        assert_eq!(identities.next(1), Some(0));
+        assert!(identities.describe(1).starts_with("identity 2 of 2 ("));
+    }
+}
@@ -0,0 +1,130 @@
+//! Artificial network impairment on the receive paths.
+//!
+//! Drops, delays and reorders incoming voice packets at configurable rates, so
+//! the jitter buffers and packet loss concealment can be exercised and demoed
+//! without an actually bad network. Only meant for testing and diagnostics.
+
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::TICK_TIME;
+
+/// Impairment settings for one receive path.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ImpairmentConfig {
+    /// Fraction of packets to drop, `0.0..=1.0`.
+    pub drop: f32,
+    /// Fraction of packets held back two frames, so later ones overtake them.
+    pub reorder: f32,
+    /// Fixed delay added to every packet.
+    pub delay_ms: u64,
+    /// Random extra delay of up to this much per packet.
+    pub jitter_ms: u64,
+    /// Seed for reproducible runs.
+    pub seed: Option<u64>,
+}
+
+/// `[impairment]` section of the config file.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Impairments {
+    /// Packets received from Discord users.
+    pub discord: Option<ImpairmentConfig>,
+    /// Packets received from TeamSpeak clients.
+    pub teamspeak: Option<ImpairmentConfig>,
+}
+
+/// What happens to a received packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fate {
+    Drop,
+    Now,
+    Later(Duration),
+}
+
+pub struct Impairer {
+    config: ImpairmentConfig,
+    rng: StdMutex<u64>,
+}
+
+impl Impairer {
+    pub fn new(config: ImpairmentConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            std::time::SystemTime
+                ::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        // xorshift gets stuck on zero
+        Self { config, rng: StdMutex::new(seed | 1) }
+    }
+
+    /// Decide the fate of the next packet.
+    pub fn fate(&self) -> Fate {
+        let mut rng = self.rng.lock().expect("Can't lock impairment rng!");
+        let mut next = || {
+            // xorshift64*
+            *rng ^= *rng >> 12;
+            *rng ^= *rng << 25;
+            *rng ^= *rng >> 27;
+            ((rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40) as f32) / ((1u64 << 24) as f32)
+        };
+
+        if next() < self.config.drop {
+            return Fate::Drop;
+        }
+        let mut delay = self.config.delay_ms as f32 + next() * (self.config.jitter_ms as f32);
+        if next() < self.config.reorder {
+            delay += (2 * TICK_TIME) as f32;
+        }
+        match delay as u64 {
+            0 => Fate::Now,
+            ms => Fate::Later(Duration::from_millis(ms)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fates(config: ImpairmentConfig, n: usize) -> Vec<Fate> {
+        let impairer = Impairer::new(ImpairmentConfig { seed: Some(42), ..config });
+        (0..n).map(|_| impairer.fate()).collect()
+    }
+
+    #[test]
+    fn default_config_passes_everything_through() {
+        assert!(fates(ImpairmentConfig::default(), 1000).iter().all(|f| *f == Fate::Now));
+    }
+
+    #[test]
+    fn drop_rate_is_roughly_applied() {
+        let config = ImpairmentConfig { drop: 0.1, ..Default::default() };
+        let dropped = fates(config, 10_000).iter().filter(|f| **f == Fate::Drop).count();
+        assert!((800..1200).contains(&dropped), "{} dropped", dropped);
+    }
+
+    #[test]
+    fn delays_stay_in_range() {
+        let config = ImpairmentConfig { delay_ms: 30, jitter_ms: 20, reorder: 0.5, ..Default::default() };
+        let fates = fates(config, 1000);
+        for fate in &fates {
+            match fate {
+                Fate::Later(d) => assert!(*d >= Duration::from_millis(30) && *d <= Duration::from_millis(90)),
+                f => panic!("unexpected {:?}", f),
+            }
+        }
+        // Reordered packets are held back two frames on top of the delay
+        assert!(fates.iter().any(|f| matches!(f, Fate::Later(d) if *d >= Duration::from_millis(70))));
+    }
+
+    #[test]
+    fn same_seed_same_fates() {
+        let config = ImpairmentConfig { drop: 0.3, jitter_ms: 50, ..Default::default() };
+        assert_eq!(fates(config, 100), fates(config, 100));
+    }
+}
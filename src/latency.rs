@@ -0,0 +1,57 @@
+//! The `low_latency = true` preset, for groups that want the least delay across the bridge.
+//!
+//! It trades headroom for delay: 10 ms frames to TeamSpeak, buffers of a few
+//! frames that drop the oldest audio, the restricted low delay Opus mode and
+//! songbird only decrypting the packets the bridge decodes itself anyway.
+//! Stages that add delay on purpose, like the broadcast delay, are skipped.
+
+use std::convert::TryFrom;
+
+use audiopus::Application;
+use songbird::driver::DecodeMode;
+
+use crate::audio::{ BufferConfig, BufferLimits, FrameDuration, OverflowPolicy };
+
+/// Length of the frames sent to TeamSpeak.
+const FRAME_MS: u64 = 10;
+/// Most audio each buffer holds, three frames of 20 ms.
+const BUFFER_MS: u32 = 60;
+
+pub fn frame() -> FrameDuration {
+    FrameDuration::try_from(FRAME_MS).expect("10 ms frames are supported")
+}
+
+/// Buffers cut short, so what plays is never more than a few frames old.
+pub fn buffers() -> BufferConfig {
+    let limits = BufferLimits {
+        max_ms: BUFFER_MS,
+        low_watermark_ms: 0,
+        overflow: Some(OverflowPolicy::DropOldest),
+        ..BufferLimits::default()
+    };
+    BufferConfig { discord_to_ts: limits, ts_to_discord: limits }
+}
+
+/// Opus mode of the TeamSpeak encoder, restricted low delay saves 4 ms of lookahead.
+pub fn application(low_latency: bool) -> Application {
+    if low_latency { Application::LowDelay } else { Application::Voip }
+}
+
+/// What songbird does with received packets, decoded only for `VoiceTick` logging.
+pub fn decode_mode(low_latency: bool) -> DecodeMode {
+    if low_latency { DecodeMode::Decrypt } else { DecodeMode::Decode }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_buffers_hold_a_few_frames() {
+        let buffers = buffers();
+        assert_eq!(buffers.discord_to_ts.validate(), Ok(()));
+        assert_eq!(buffers.ts_to_discord.high_watermark_ms(), BUFFER_MS);
+        assert_eq!(frame().stereo_samples(), 960);
+        assert_eq!(application(true), Application::LowDelay);
+    }
+}
@@ -0,0 +1,211 @@
+//! Short-term audio levels of the bridged streams.
+//!
+//! Kept per direction and per source, so the status report can answer "is
+//! audio even flowing, and from whom?" without debug logging every frame.
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::audio::peak;
+use crate::feedback::FeedbackDetector;
+use crate::SAMPLE_RATE;
+
+/// Time constant of the RMS average.
+const RMS_WINDOW_SECS: f32 = 0.3;
+/// How fast the held peak falls back.
+const PEAK_DECAY_DB_PER_SEC: f32 = 20.0;
+/// Levels are reported no lower than this.
+const MIN_DB: f32 = -90.0;
+/// Sources not heard from for this long are left out of the report.
+const STALE_AFTER: Duration = Duration::from_secs(5);
+/// Characters of a level bar, covering -60 to 0 dBFS.
+const BAR_WIDTH: usize = 20;
+const BAR_RANGE_DB: f32 = 60.0;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    DiscordToTs,
+    TsToDiscord,
+}
+
+/// Peak and RMS of one source right now, in dBFS.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Level {
+    pub peak_db: f32,
+    pub rms_db: f32,
+    /// RMS as a bar, the held peak marked with `|`.
+    pub bar: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SourceLevel {
+    pub direction: Direction,
+    pub source: String,
+    #[serde(flatten)]
+    pub level: Level,
+}
+
+/// Held peak and moving RMS of a stream of 48 kHz stereo frames.
+#[derive(Clone, Copy, Debug)]
+struct LevelMeter {
+    peak: f32,
+    mean_square: f32,
+    updated: Instant,
+}
+
+impl LevelMeter {
+    fn new(now: Instant) -> Self {
+        Self { peak: 0.0, mean_square: 0.0, updated: now }
+    }
+
+    fn update(&mut self, samples: &[f32], now: Instant) {
+        let frame_secs = (samples.len() as f32) / ((SAMPLE_RATE * 2) as f32);
+        // The frame itself covers the time up to now, only a gap before it decays
+        self.decay(now.checked_sub(Duration::from_secs_f32(frame_secs)).unwrap_or(now));
+        self.updated = now;
+        if samples.is_empty() {
+            return;
+        }
+        let weight = 1.0 - (-frame_secs / RMS_WINDOW_SECS).exp();
+        let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / (samples.len() as f32);
+        self.mean_square += (mean_square - self.mean_square) * weight;
+        self.peak = self.peak.max(peak(samples));
+    }
+
+    /// Let the levels fall for the time since the last update.
+    fn decay(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.updated).as_secs_f32();
+        self.peak *= (10.0f32).powf((-PEAK_DECAY_DB_PER_SEC * secs) / 20.0);
+        self.mean_square *= (-secs / RMS_WINDOW_SECS).exp();
+        self.updated = now;
+    }
+
+    fn level(&self, now: Instant) -> Level {
+        let mut meter = *self;
+        meter.decay(now);
+        let peak_db = to_db(meter.peak);
+        let rms_db = to_db(meter.mean_square.sqrt());
+        Level { peak_db, rms_db, bar: bar(rms_db, peak_db) }
+    }
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.max(1e-9).log10()).max(MIN_DB)
+}
+
+/// `rms_db` as a row of `#`, with `|` where `peak_db` is.
+fn bar(rms_db: f32, peak_db: f32) -> String {
+    let position = |db: f32| {
+        let filled = ((db + BAR_RANGE_DB) / BAR_RANGE_DB) * (BAR_WIDTH as f32);
+        (filled.round().max(0.0) as usize).min(BAR_WIDTH)
+    };
+    let rms = position(rms_db);
+    let peak = position(peak_db);
+    (1..=BAR_WIDTH)
+        .map(|i| {
+            if i == peak && peak > rms { '|' } else if i <= rms { '#' } else { '-' }
+        })
+        .collect()
+}
+
+/// Meters of all sources, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct AudioLevels {
+    meters: Arc<StdMutex<HashMap<Direction, HashMap<String, LevelMeter>>>>,
+    /// Fed the same frames, to find sources echoing the other side.
+    feedback: FeedbackDetector,
+}
+
+impl AudioLevels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feedback(&self) -> &FeedbackDetector {
+        &self.feedback
+    }
+
+    /// Feed one frame of `source` going in `direction`.
+    pub fn record(&self, direction: Direction, source: &str, samples: &[f32]) {
+        self.feedback.record(direction, source, samples);
+        let now = Instant::now();
+        let mut meters = self.meters.lock().expect("Can't lock audio levels!");
+        let sources = meters.entry(direction).or_default();
+        match sources.get_mut(source) {
+            Some(meter) => meter.update(samples, now),
+            None => {
+                let mut meter = LevelMeter::new(now);
+                meter.update(samples, now);
+                sources.insert(source.to_string(), meter);
+            }
+        }
+    }
+
+    /// Levels of the sources heard from recently, by direction and source.
+    pub fn report(&self) -> Vec<SourceLevel> {
+        let now = Instant::now();
+        let mut meters = self.meters.lock().expect("Can't lock audio levels!");
+        let mut report = Vec::new();
+        for (&direction, sources) in meters.iter_mut() {
+            sources.retain(|_, meter| now.saturating_duration_since(meter.updated) < STALE_AFTER);
+            report.extend(
+                sources.iter().map(|(source, meter)| SourceLevel {
+                    direction,
+                    source: source.clone(),
+                    level: meter.level(now),
+                })
+            );
+        }
+        report.sort_by(|a, b| (a.direction, &a.source).cmp(&(b.direction, &b.source)));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_follow_the_signal_and_fall_back() {
+        let start = Instant::now();
+        let mut meter = LevelMeter::new(start);
+        let frame = [0.5f32; 1920];
+        for i in 0..50 {
+            meter.update(&frame, start + Duration::from_millis(20 * i));
+        }
+        let loud = meter.level(start + Duration::from_millis(980));
+        assert!((loud.peak_db - to_db(0.5)).abs() < 0.5, "{:?}", loud);
+        assert!((loud.rms_db - to_db(0.5)).abs() < 0.5, "{:?}", loud);
+
+        let later = meter.level(start + Duration::from_secs(3));
+        assert!(later.peak_db < loud.peak_db - 30.0, "{:?}", later);
+        assert_eq!(LevelMeter::new(start).level(start).rms_db, MIN_DB);
+    }
+
+    #[test]
+    fn bars_show_rms_and_peak() {
+        assert_eq!(bar(-30.0, -15.0), "##########----|-----");
+        assert_eq!(bar(0.0, 0.0), "#".repeat(BAR_WIDTH));
+        assert_eq!(bar(MIN_DB, MIN_DB), "-".repeat(BAR_WIDTH));
+    }
+
+    #[test]
+    fn quiet_sources_drop_out_of_the_report() {
+        let levels = AudioLevels::new();
+        levels.record(Direction::TsToDiscord, "client 5", &[0.1; 960]);
+        levels.record(Direction::DiscordToTs, "mix", &[0.1; 960]);
+        let report = levels.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].direction, Direction::DiscordToTs);
+        assert_eq!(report[1].source, "client 5");
+
+        for meter in levels.meters.lock().unwrap().values_mut().flat_map(|s| s.values_mut()) {
+            meter.updated -= STALE_AFTER;
+        }
+        assert!(levels.report().is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Evening out the loudness of `/play` tracks, so the queue doesn't swing against the voices.
+//!
+//! Tracks with a ReplayGain track gain are played at it, never louder than
+//! their peak allows. Untagged tracks are measured while they play: the level
+//! of what isn't silence is taken for the first half minute, and the gain
+//! glides towards what brings that to the target.
+
+use std::time::Duration;
+
+use crate::audio::{ apply_gain_clamped, db_to_gain };
+
+/// Level untagged tracks are brought to, about where ReplayGain puts tagged ones.
+const TARGET_DB: f32 = -20.0;
+/// Quieter blocks are silence and don't count.
+const GATE_DB: f32 = -60.0;
+/// Most an untagged track is turned up or down.
+const MAX_ADJUST_DB: f32 = 12.0;
+/// How much of an untagged track is measured before its gain stays put.
+const ANALYSIS: Duration = Duration::from_secs(30);
+/// How long the gain takes to follow the measurement.
+const GLIDE: Duration = Duration::from_secs(1);
+
+/// `"-6.48 dB"` from a ReplayGain tag as decibels.
+pub fn parse_gain_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value)
+        .trim();
+    number.parse().ok().filter(|db: &f32| db.is_finite())
+}
+
+/// Gain of one track, from its tags or measured as it plays.
+#[derive(Clone, Debug)]
+pub struct Normalizer {
+    sample_rate: u32,
+    /// From the ReplayGain tags, no measuring then.
+    tagged: Option<f32>,
+    gain: f32,
+    /// Sum of squares and count of the samples above the gate.
+    sum_squares: f64,
+    counted: u64,
+    /// Stereo frames seen so far.
+    frames: u64,
+}
+
+impl Normalizer {
+    /// A normalizer for stereo audio at `sample_rate`, played at `gain_db` and below `peak` if tagged.
+    pub fn new(sample_rate: u32, gain_db: Option<f32>, peak: Option<f32>) -> Self {
+        let tagged = gain_db.map(|db| {
+            let gain = db_to_gain(db);
+            match peak.filter(|peak| *peak > 0.0) {
+                Some(peak) => gain.min(1.0 / peak),
+                None => gain,
+            }
+        });
+        Self { sample_rate, tagged, gain: tagged.unwrap_or(1.0), sum_squares: 0.0, counted: 0, frames: 0 }
+    }
+
+    /// Apply the gain to interleaved stereo `samples`, measuring them first if untagged.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.tagged.is_none() {
+            self.measure(samples);
+        }
+        apply_gain_clamped(samples, self.gain);
+    }
+
+    fn measure(&mut self, samples: &[f32]) {
+        let frames = (samples.len() / 2) as u64;
+        let analysed = self.frames < ANALYSIS.as_secs() * u64::from(self.sample_rate);
+        self.frames += frames;
+        if !analysed || samples.is_empty() {
+            return;
+        }
+        let squares: f64 = samples.iter().map(|&s| f64::from(s) * f64::from(s)).sum();
+        let block_db = 10.0 * (squares / samples.len() as f64).log10();
+        if block_db > f64::from(GATE_DB) {
+            self.sum_squares += squares;
+            self.counted += samples.len() as u64;
+        }
+        if self.counted == 0 {
+            return;
+        }
+        let level_db = 10.0 * (self.sum_squares / self.counted as f64).log10() as f32;
+        let target = db_to_gain((TARGET_DB - level_db).clamp(-MAX_ADJUST_DB, MAX_ADJUST_DB));
+        let glide = (frames as f32 / (GLIDE.as_secs_f32() * self.sample_rate as f32)).min(1.0);
+        self.gain += (target - self.gain) * glide;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_used_and_quiet_tracks_turned_up() {
+        assert_eq!(parse_gain_db(" -6.48 dB"), Some(-6.48));
+        assert_eq!(parse_gain_db("+2.5"), Some(2.5));
+        assert_eq!(parse_gain_db("loud"), None);
+
+        let tagged = Normalizer::new(48000, Some(6.0), Some(0.8));
+        assert_eq!(tagged.gain, 1.25);
+
+        // A sine at -30 dB RMS, measured for five seconds
+        let mut quiet = Normalizer::new(48000, None, None);
+        let amplitude = db_to_gain(-30.0) * std::f32::consts::SQRT_2;
+        for block in 0..250 {
+            let mut samples: Vec<f32> = (0..1920)
+                .map(|i| amplitude * ((block * 960 + i / 2) as f32 * 0.05).sin())
+                .collect();
+            quiet.process(&mut samples);
+        }
+        assert!((quiet.gain - db_to_gain(10.0)).abs() < 0.1, "gain {}", quiet.gain);
+
+        let mut silent = Normalizer::new(48000, None, None);
+        silent.process(&mut [0.0; 1920]);
+        assert_eq!(silent.gain, 1.0);
+    }
+}
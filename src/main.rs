@@ -1,23 +1,73 @@
 use std::io::Seek;
+use std::collections::{ BTreeMap, HashMap, HashSet };
 use std::{ io::Read, mem::size_of, sync::Arc, time::Duration };
 use byte_slice_cast::AsByteSlice;
 use serde::Deserialize;
 use serenity::prelude::GatewayIntents;
 use tsclientlib::{ ClientId, Connection, DisconnectOptions, Identity, StreamItem };
-use tsproto_packets::packets::{ AudioData, CodecType, OutAudio, OutPacket };
-use audiopus::coder::Encoder;
+use tsproto_packets::packets::{ AudioData, InAudioBuf, OutPacket };
 use futures::prelude::*;
-use slog::{ debug, o, Drain, Logger };
-use tokio::task;
-use tokio::sync::Mutex;
+use slog::{ o, Drain, Logger };
+use tokio::sync::{ mpsc, Mutex, Notify };
 use anyhow::{ bail, Result };
 use symphonia::core::io::MediaSource;
 
-use std::collections::VecDeque;
-use std::sync::Mutex as StdMutex;
-
+use std::sync::{ Mutex as StdMutex, MutexGuard };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+mod activation;
+mod audio;
+mod audit;
+mod capture;
+mod bridge_events;
+mod chat_bridge;
+mod control;
+mod deadline;
+mod delay;
+mod encode;
 mod discord;
 mod discord_audiohandler;
+mod dry_run;
+mod echo;
+mod failover;
+mod feedback;
+mod hands;
+mod holdover;
+mod identity;
+mod impair;
+mod latency;
+mod levels;
+mod loudness;
+mod media;
+#[cfg(test)]
+mod mock_ts;
+mod music;
+mod podium;
+mod pool;
+mod pause;
+mod ping;
+mod postmortem;
+mod profile;
+mod quarantine;
+mod routes;
+mod rtp;
+mod schedule;
+mod senders;
+mod settings;
+mod solo;
+mod soundboard;
+#[cfg(test)]
+mod sim;
+mod stats;
+mod talk_time;
+mod teamspeak;
+mod ts_avatar;
+mod ts_book;
+mod tone;
+mod ts_chat;
+mod voice_states;
+mod watchdog;
+mod webhooks;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct ConnectionId(u64);
@@ -33,30 +83,410 @@ struct Config {
     discord_token: String,
     teamspeak_server: String,
     teamspeak_identity: String,
+    /// More identities to switch to with `/rotate-identity`, after `teamspeak_identity`.
+    teamspeak_identities: Option<Vec<String>>,
     teamspeak_server_password: Option<String>,
     teamspeak_channel_id: Option<u64>,
     teamspeak_channel_name: Option<String>,
     teamspeak_channel_password: Option<String>,
+    /// Standby server the bridge moves to while the configured one is down.
+    fallback_teamspeak: Option<failover::FallbackConfig>,
     teamspeak_name: Option<String>,
+    /// Nickname tried when `teamspeak_name` is taken, `{name}` and `{n}` are replaced.
+    teamspeak_name_pattern: Option<String>,
     verbose: i32,
+    /// Factor like `0.5` or decibels like `"-6dB"`.
+    #[serde(deserialize_with = "audio::deserialize_volume")]
     volume: f32,
+    /// Server groups allowed to use TeamSpeak chat commands, everybody if unset.
+    teamspeak_command_groups: Option<Vec<u64>>,
+    /// Server groups whose members are heard in Discord, everybody if unset.
+    teamspeak_voice_groups: Option<Vec<u64>>,
+    /// Roles whose members are heard in TeamSpeak, everybody if unset.
+    discord_voice_roles: Option<Vec<u64>>,
+    /// Directory `/play file:<name>` plays from.
+    media_dir: Option<String>,
+    /// yt-dlp program, lets `/play` take pages and playlists instead of only direct links.
+    yt_dlp: Option<String>,
+    /// Text channel where every posted link is queued like `/play`.
+    song_request_channel_id: Option<u64>,
+    /// What TeamSpeak is told when the music queue advances.
+    #[serde(default)]
+    now_playing: music::NowPlayingConfig,
+    /// Entries queued at most from one playlist.
+    max_playlist_length: Option<usize>,
+    /// Even out the loudness of `/play` tracks, on by default.
+    normalize_music: Option<bool>,
+    /// Gain of `/play` music in TeamSpeak.
+    music_gain: Option<f32>,
+    /// How voices push the music down in TeamSpeak.
+    #[serde(default)]
+    ducking: audio::DuckingConfig,
+    /// How many messages and pokes from Discord may go to TeamSpeak.
+    #[serde(default)]
+    chat_rate_limit: chat_bridge::ChatRateLimits,
+    /// Register commands only in these servers instead of globally, shows them at once when testing.
+    command_guilds: Option<Vec<u64>>,
+    /// Per-server cooldowns in seconds by command name, `0` turns one off.
+    #[serde(default)]
+    cooldowns: HashMap<String, u64>,
+    /// Discord voice region set on joined channels, e.g. `rotterdam`, to be close to the TeamSpeak server.
+    discord_voice_region: Option<String>,
+    /// Play Discord soundboard sounds to TeamSpeak, on by default.
+    bridge_soundboard: Option<bool>,
+    /// Where the bridge says when it couldn't join its TeamSpeak channel.
+    admin_channel_id: Option<u64>,
+    /// Where the commands run are recorded.
+    #[serde(default)]
+    audit: audit::AuditConfig,
+    /// URLs bridge events are POSTed to.
+    #[serde(default)]
+    webhooks: Vec<webhooks::WebhookConfig>,
+    /// Where settings changed by commands are saved.
+    settings_file: Option<String>,
+    #[serde(default)]
+    frame_size_ms: audio::FrameDuration,
+    /// Least delay over robustness: 10 ms frames, short buffers, no broadcast delay.
+    #[serde(default)]
+    low_latency: bool,
+    /// Least bandwidth: mono 24 kbps voice with DTX to TeamSpeak, mono 32 kbps to Discord.
+    #[serde(default)]
+    bandwidth_saver: bool,
+    /// How long `/next` gives the floor, 120 seconds by default.
+    floor_seconds: Option<u64>,
+    /// How far other Discord speakers are turned down while a priority speaker talks, 12 dB by default.
+    priority_speaker_reduction_db: Option<f32>,
+    /// Who is mixed at full volume while `/podium` is on.
+    #[serde(default)]
+    podium: podium::PodiumConfig,
+    /// How loud each source is on each side, `/route` changes it.
+    #[serde(default)]
+    routes: routes::RouteConfig,
+    /// Share of send ticks that may finish late before the bridge warns.
+    #[serde(default)]
+    deadlines: deadline::DeadlineConfig,
+    /// Size of the buffers between the pipeline stages and what they drop when full.
+    #[serde(default)]
+    buffers: audio::BufferConfig,
+    /// Discord audio held while TeamSpeak reconnects, 5000 ms by default, `0` drops it.
+    reconnect_buffer_ms: Option<u64>,
+    /// Bits per second of the audio sent to TeamSpeak, chosen by Opus if unset.
+    bitrate: Option<i32>,
+    /// Named settings `/profile` switches between, by name.
+    #[serde(default)]
+    profiles: BTreeMap<String, profile::AudioProfile>,
+    #[serde(default)]
+    impairment: impair::Impairments,
+    /// Gateway shards to run, a single one if unset.
+    shards: Option<ShardConfig>,
+    /// Hold one direction back, so `/dump` can keep things from going out.
+    broadcast_delay: Option<delay::BroadcastDelayConfig>,
+    /// Where the state is saved when the bridge panics, the working directory if unset.
+    panic_snapshot_dir: Option<String>,
+    /// Idle until people are on both sides, off by default.
+    auto_activate: Option<bool>,
+    /// Stop bridging sources that echo the other side for a while, on by default.
+    feedback_suppression: Option<bool>,
+    /// Reset stuck Discord audio queues like `/reset_audio`, on by default.
+    auto_reset_audio: Option<bool>,
+    /// Local hours the bridge is live, like `"Mon-Fri 19:00-23:30"`, always if unset.
+    schedule: Option<Vec<schedule::Window>>,
+}
+
+/// `[shards]` section, for bridges in many servers.
+#[derive(Debug, Deserialize)]
+struct ShardConfig {
+    /// Shards across all processes, as many as Discord recommends if unset.
+    total: Option<u32>,
+    /// First and last shard run by this process, all if unset.
+    ids: Option<[u32; 2]>,
+}
+
+impl ShardConfig {
+    async fn start(&self, client: &mut Client) -> serenity::Result<()> {
+        match (self.total, self.ids) {
+            (Some(total), Some([first, last])) => client.start_shard_range(first..last + 1, total).await,
+            (Some(total), None) => client.start_shards(total).await,
+            (None, ids) => {
+                if ids.is_some() {
+                    tracing::warn!("Shard ids need a total shard count, starting all shards");
+                }
+                client.start_autosharded().await
+            }
+        }
+    }
 }
 
 struct ListenerHolder;
 
+struct SettingsHolder;
+
+impl TypeMapKey for SettingsHolder {
+    type Value = settings::SharedSettings;
+}
+
+/// Channel to the main loop for commands that need the TeamSpeak connection.
+struct TsCommandHolder;
+
+impl TypeMapKey for TsCommandHolder {
+    type Value = teamspeak::TsCommandSender;
+}
+
+/// Nickname the bridge got on the TeamSpeak server.
+struct TsNicknameHolder;
+
+impl TypeMapKey for TsNicknameHolder {
+    type Value = String;
+}
+
+struct MusicHolder;
+
+impl TypeMapKey for MusicHolder {
+    type Value = music::MusicQueues;
+}
+
+/// Text channel the session summary is posted to.
+struct AdminChannelHolder;
+
+impl TypeMapKey for AdminChannelHolder {
+    type Value = u64;
+}
+
+/// Text channel whose links are queued as music.
+struct SongRequestChannelHolder;
+
+impl TypeMapKey for SongRequestChannelHolder {
+    type Value = u64;
+}
+
+/// Rotating log of the commands run on the bridge.
+struct AuditHolder;
+
+impl TypeMapKey for AuditHolder {
+    type Value = audit::AuditLog;
+}
+
+struct RelayedHolder;
+
+impl TypeMapKey for RelayedHolder {
+    type Value = chat_bridge::RelayedMessages;
+}
+
+/// Voice region joined channels are set to.
+struct VoiceRegionHolder;
+
+impl TypeMapKey for VoiceRegionHolder {
+    type Value = String;
+}
+
+/// Bits per second sent to joined calls, songbird's default if unset.
+struct DiscordBitrateHolder;
+
+impl TypeMapKey for DiscordBitrateHolder {
+    type Value = i32;
+}
+
+struct ShardManagerHolder;
+
+impl TypeMapKey for ShardManagerHolder {
+    type Value = Arc<serenity::gateway::ShardManager>;
+}
+
+struct AudioLevelsHolder;
+
+impl TypeMapKey for AudioLevelsHolder {
+    type Value = levels::AudioLevels;
+}
+
+/// Delay `/dump` flushes, if one is configured.
+struct BroadcastDelayHolder;
+
+impl TypeMapKey for BroadcastDelayHolder {
+    type Value = delay::BroadcastDelay;
+}
+
+struct ActivationHolder;
+
+impl TypeMapKey for ActivationHolder {
+    type Value = activation::Activation;
+}
+
+struct AudioProfilesHolder;
+
+impl TypeMapKey for AudioProfilesHolder {
+    type Value = profile::AudioProfiles;
+}
+
+/// Ticks the encoders ran past their frame.
+struct LateEncodesHolder;
+
+impl TypeMapKey for LateEncodesHolder {
+    type Value = audio::DropCounter;
+}
+
+/// Send ticks that finished after the next was due.
+struct DeadlineMissesHolder;
+
+impl TypeMapKey for DeadlineMissesHolder {
+    type Value = audio::DropCounter;
+}
+
+struct BridgePauseHolder;
+
+impl TypeMapKey for BridgePauseHolder {
+    type Value = pause::BridgePause;
+}
+
+/// Speakers picked with `/solo`.
+struct SoloHolder;
+
+impl TypeMapKey for SoloHolder {
+    type Value = solo::Solo;
+}
+
+/// Raised hands and who has the floor.
+struct HandsHolder;
+
+impl TypeMapKey for HandsHolder {
+    type Value = hands::HandQueue;
+}
+
+/// Gains of the routes through the bridge, for `/route`.
+struct RoutesHolder;
+
+impl TypeMapKey for RoutesHolder {
+    type Value = routes::RouteGains;
+}
+
+/// Uptime and traffic since start, for `/uptime`.
+struct StatsHolder;
+
+impl TypeMapKey for StatsHolder {
+    type Value = stats::BridgeStats;
+}
+
+struct TalkTimeHolder;
+
+impl TypeMapKey for TalkTimeHolder {
+    type Value = talk_time::TalkTime;
+}
+
+struct EventBusHolder;
+
+impl TypeMapKey for EventBusHolder {
+    type Value = bridge_events::EventBus;
+}
+
+struct TsBookHolder;
+
+impl TypeMapKey for TsBookHolder {
+    type Value = ts_book::TsBook;
+}
+
+struct ScheduleHolder;
+
+impl TypeMapKey for ScheduleHolder {
+    type Value = schedule::BridgeSchedule;
+}
+
+/// Discord users running `/echo-test`.
+struct EchoHolder;
+
+impl TypeMapKey for EchoHolder {
+    type Value = echo::EchoRecorder<u64>;
+}
+
+/// Sounds mixed into the audio sent to TeamSpeak.
+struct SoundFeedHolder;
+
+impl TypeMapKey for SoundFeedHolder {
+    type Value = soundboard::SoundFeed;
+}
+
+struct VoiceBusyHolder;
+
+impl TypeMapKey for VoiceBusyHolder {
+    type Value = discord::VoiceBusy;
+}
+
+/// Who is in the bridged voice channel, kept up to date by the receiver.
+struct VoicePresenceHolder;
+
+impl TypeMapKey for VoicePresenceHolder {
+    type Value = discord::VoicePresence;
+}
+
+/// Impairment of packets received from Discord, if configured.
+struct DiscordImpairment;
+
+impl TypeMapKey for DiscordImpairment {
+    type Value = Option<Arc<impair::Impairer>>;
+}
+
+/// Roles whose members are bridged to TeamSpeak, everybody if unset.
+struct DiscordVoiceRoles;
+
+impl TypeMapKey for DiscordVoiceRoles {
+    type Value = Option<Arc<HashSet<serenity::model::id::RoleId>>>;
+}
+
 type AudioBufferDiscord = Arc<Mutex<discord_audiohandler::AudioHandler<u32>>>;
 
 type TsVoiceId = (ConnectionId, ClientId);
 type TsAudioHandler = tsclientlib::audio::AudioHandler<TsVoiceId>;
 
+/// Received TeamSpeak packets queued across all clients, about a second of five talking.
+const TS_INGEST_PACKETS: usize = 256;
+
+/// TeamSpeak audio on its way to Discord.
+///
+/// The TeamSpeak event loop only queues packets with [`ingest`](Self::ingest),
+/// they are handed to the jitter buffers by whoever mixes next, so a slow
+/// decode never holds up packet handling.
 #[derive(Clone)]
 struct TsToDiscordPipeline {
     data: Arc<std::sync::Mutex<TsAudioHandler>>,
+    ingest: mpsc::Sender<(TsVoiceId, InAudioBuf)>,
+    /// Only taken while holding `data`.
+    pending: Arc<StdMutex<mpsc::Receiver<(TsVoiceId, InAudioBuf)>>>,
+    /// Packets dropped because the queue was full.
+    ingest_dropped: audio::DropCounter,
+    /// Set once something mixes, until then nothing would take packets off the queue.
+    mixing: Arc<AtomicBool>,
+    /// Clients whose last packet was stereo Opus.
+    stereo: Arc<StdMutex<HashSet<ClientId>>>,
+    /// Clients whose packets keep failing, dropped before the jitter buffers.
+    quarantine: Arc<StdMutex<quarantine::DecodeQuarantine<ClientId>>>,
+    /// Clients whose jitter buffers play silent.
+    muted: Arc<StdMutex<HashSet<ClientId>>>,
+    /// Clients played later than the others, their jitter buffers play silent too.
+    delays: Arc<StdMutex<audio::SourceDelays<ClientId>>>,
+    /// Turns down clients not on the podium.
+    podium: podium::Podium,
+    /// How loud TeamSpeak is in Discord.
+    routes: routes::RouteGains,
+    /// Reused for every read, so reading does not allocate.
+    scratch: Vec<f32>,
+    levels: levels::AudioLevels,
+    /// Arrivals of the last packets from both sides, for panic snapshots.
+    packet_times: postmortem::PacketTimes,
+    delay: Option<delay::BroadcastDelay>,
+    /// Size of the buffer in front of songbird and what it drops when full.
+    limits: audio::BufferLimits,
+    /// Bytes the buffer in front of songbird dropped.
+    dropped: audio::DropCounter,
+    /// Bytes in the buffer in front of songbird.
+    buffered: Arc<AtomicUsize>,
+    stats: stats::BridgeStats,
+    /// Speaking time of both sides, the Discord receivers count theirs in it too.
+    talk: talk_time::TalkTime,
+    /// Where received packets of both sides are captured, if asked to.
+    capture: Option<capture::Capture>,
 }
 
 impl Seek for TsToDiscordPipeline {
     fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "source does not support seeking"))
+        Err(std::io::Error::other("source does not support seeking"))
     }
 }
 
@@ -72,42 +502,220 @@ impl MediaSource for TsToDiscordPipeline {
 
 impl TsToDiscordPipeline {
     pub fn new(logger: Logger) -> Self {
+        let (ingest, pending) = mpsc::channel(TS_INGEST_PACKETS);
         Self {
             data: Arc::new(std::sync::Mutex::new(TsAudioHandler::new(logger))),
+            ingest,
+            pending: Arc::new(StdMutex::new(pending)),
+            ingest_dropped: audio::DropCounter::default(),
+            mixing: Default::default(),
+            stereo: Default::default(),
+            quarantine: Default::default(),
+            muted: Default::default(),
+            delays: Default::default(),
+            podium: podium::Podium::default(),
+            routes: routes::RouteGains::default(),
+            scratch: Vec::new(),
+            levels: levels::AudioLevels::new(),
+            packet_times: postmortem::PacketTimes::new(),
+            delay: None,
+            limits: audio::BufferLimits::default(),
+            dropped: audio::DropCounter::default(),
+            buffered: Default::default(),
+            stats: stats::BridgeStats::new(),
+            talk: talk_time::TalkTime::new(),
+            capture: None,
+        }
+    }
+
+    pub fn with_buffer_limits(mut self, limits: audio::BufferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Turn down clients not on the podium while podium mode is on.
+    pub fn with_podium(mut self, podium: podium::Podium) -> Self {
+        self.podium = podium;
+        self
+    }
+
+    pub fn with_routes(mut self, routes: routes::RouteGains) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Count what is handed to Discord in `stats`.
+    pub fn with_stats(mut self, stats: stats::BridgeStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Count who talks in `talk`.
+    pub fn with_talk_time(mut self, talk: talk_time::TalkTime) -> Self {
+        self.talk = talk;
+        self
+    }
+
+    /// Capture the received packets of both sides to `capture`.
+    pub fn with_capture(mut self, capture: capture::Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Hold what Discord hears back by `delay`.
+    pub fn with_broadcast_delay(mut self, delay: delay::BroadcastDelay) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Queue a received packet of `id` without waiting for the mixer.
+    pub fn ingest(&self, id: TsVoiceId, packet: InAudioBuf) {
+        self.packet_times.record(&format!("client {}", id.1.0));
+        let audio = packet.data().data();
+        let data = match audio {
+            AudioData::S2C { data, .. } | AudioData::S2CWhisper { data, .. } => Some(*data),
+            _ => None,
+        };
+        if let (Some(capture), Some(data)) = (&self.capture, data) {
+            let captured = capture::Packet
+                ::new(bridge_events::Side::Teamspeak, id.1.0.into(), audio.id(), data)
+                .with_codec(audio.codec());
+            capture.record(captured, data);
+        }
+        let channels = data.and_then(audio::opus_channels);
+        if let Some(channels) = channels {
+            let mut stereo = self.stereo.lock().expect("Can't lock stereo clients!");
+            let changed = if channels == 2 { stereo.insert(id.1) } else { stereo.remove(&id.1) };
+            if changed {
+                tracing::debug!("TeamSpeak client {} sends {} channel audio", id.1.0, channels);
+            }
+        }
+        // Before the first Discord call, they would go stale and be counted as dropped
+        if !self.mixing.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.ingest.try_send((id, packet)).is_err() {
+            self.ingest_dropped.add(1);
+        }
+    }
+
+    /// Lock the jitter buffers, after handing them the queued packets.
+    pub fn lock_handler(&self) -> MutexGuard<'_, TsAudioHandler> {
+        let mut handler = self.data.lock().expect("Can't lock ts audio buffer!");
+        let mut pending = self.pending.lock().expect("Can't lock ts packet queue!");
+        let mut quarantine = self.quarantine.lock().expect("Can't lock decode quarantine!");
+        let now = std::time::Instant::now();
+        while let Ok((id, packet)) = pending.try_recv() {
+            if quarantine.is_quarantined(&id.1, now) {
+                continue;
+            }
+            let result = handler.handle_packet(id, packet);
+            if quarantine.record(&id.1, result.is_err(), now) {
+                tracing::warn!(
+                    "Packets of TeamSpeak client {} keep failing, ignoring them for {} seconds",
+                    id.1.0,
+                    quarantine::QUARANTINE_FOR.as_secs()
+                );
+            }
+            match result {
+                Ok(Some(new_talker)) => {
+                    let volume = self.live_volume(new_talker.1);
+                    if let Some(queue) = handler.get_mut_queues().get_mut(&new_talker) {
+                        queue.volume = volume;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => tracing::debug!("Failed to handle TS_Voice packet: {}", e),
+            }
         }
+        handler
+    }
+}
+
+impl TsToDiscordPipeline {
+    /// Volume the jitter buffer of `client` is mixed at, silent if muted or delayed.
+    fn live_volume(&self, client: ClientId) -> f32 {
+        let muted = self.muted.lock().expect("Can't lock muted clients!").contains(&client);
+        if muted || self.delays.lock().expect("Can't lock source delays!").is_delayed(&client) {
+            0.0
+        } else {
+            self.podium.gain(client)
+        }
+    }
+
+    /// Clients sending stereo Opus, for `status`.
+    fn stereo_clients(&self) -> Vec<u16> {
+        let mut clients: Vec<_> = self.stereo
+            .lock().expect("Can't lock stereo clients!")
+            .iter()
+            .map(|client| client.0)
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Whether the last packet of `client` was stereo Opus.
+    #[cfg(test)]
+    fn is_stereo(&self, client: ClientId) -> bool {
+        self.stereo.lock().expect("Can't lock stereo clients!").contains(&client)
+    }
+
+    /// Quarantined clients for `status`.
+    fn quarantined(&self) -> Vec<serde_json::Value> {
+        self.quarantine
+            .lock().expect("Can't lock decode quarantine!")
+            .quarantined(std::time::Instant::now())
+            .into_iter()
+            .map(|client| {
+                serde_json::json!({
+                    "client_id": client.id.0,
+                    "seconds_left": client.remaining.as_secs(),
+                    "dropped_packets": client.dropped,
+                })
+            })
+            .collect()
     }
 }
 
 impl Read for TsToDiscordPipeline {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.mixing.store(true, Ordering::Relaxed);
         let samples_requested = buf.len() / size_of::<f32>();
-        let mut audio_buffer: Vec<f32> = vec![0.0; samples_requested];
+        // Taken for the call, the handler lock borrows `self`
+        let mut audio_buffer = std::mem::take(&mut self.scratch);
+        audio_buffer.clear();
+        audio_buffer.resize(samples_requested, 0.0);
 
         {
-            let mut lock = self.data.lock().expect("Can't lock ts voice buffer!");
-            lock.fill_buffer(&mut audio_buffer);
-        }
-
-        let max_sample = audio_buffer
-            .iter()
-            .map(|s| s.abs())
-            .fold(0.0f32, f32::max);
-        if max_sample > 0.001 {
-            tracing::debug!(
-                "TS→Discord: max sample: {:.4}, samples requested: {}",
-                max_sample,
-                samples_requested
-            );
+            let mut lock = self.lock_handler();
+            let levels = &self.levels;
+            let talk = &self.talk;
+            let podium = &self.podium;
+            let muted = self.muted.lock().expect("Can't lock muted clients!");
+            let mut delays = self.delays.lock().expect("Can't lock source delays!");
+            let start = std::time::Instant::now();
+            lock.fill_buffer_with_proc(&mut audio_buffer, |&(_, client), samples| {
+                levels.record(levels::Direction::TsToDiscord, &format!("client {}", client.0), samples);
+                talk.ts(client, samples);
+                // Mixed silent by the handler, the delay line plays them
+                delays.push(&client, samples, if muted.contains(&client) { 0.0 } else { podium.gain(client) });
+            });
+            // tsclientlib decodes while mixing, the two are timed together
+            self.stats.add_codec_time(levels::Direction::TsToDiscord, stats::CodecStage::Decode, start.elapsed());
+            delays.mix_into(&mut audio_buffer);
         }
 
         const GAIN: f32 = 3.0;
-        for sample in &mut audio_buffer {
-            *sample *= GAIN;
-            *sample = sample.clamp(-1.0, 1.0);
+        audio::apply_gain_clamped(&mut audio_buffer, GAIN * self.routes.gain(routes::Route::TsToDiscord));
+        if let Some(delay) = &self.delay {
+            delay.process(&mut audio_buffer);
         }
+        self.levels.record(levels::Direction::TsToDiscord, "mix", &audio_buffer);
+        self.stats.bridged(levels::Direction::TsToDiscord, &audio_buffer);
+        self.stats.sent(levels::Direction::TsToDiscord, buf.len());
 
-        let slice = audio_buffer.as_byte_slice();
-        buf.copy_from_slice(slice);
+        buf.copy_from_slice(audio_buffer.as_byte_slice());
+        self.scratch = audio_buffer;
 
         Ok(buf.len())
     }
@@ -119,30 +727,36 @@ impl TypeMapKey for ListenerHolder {
 
 struct BufferedPipeline {
     inner: TsToDiscordPipeline,
-    buffer: Arc<StdMutex<VecDeque<u8>>>,
+    buffer: Arc<StdMutex<audio::PcmFifo>>,
 }
 
 impl BufferedPipeline {
     fn new(inner: TsToDiscordPipeline) -> Self {
         Self {
+            buffer: Arc::new(
+                StdMutex::new(
+                    audio::PcmFifo::with_limits(&inner.limits, audio::OverflowPolicy::DropOldest, inner.dropped.clone())
+                )
+            ),
             inner,
-            buffer: Arc::new(StdMutex::new(VecDeque::with_capacity(32768))),
         }
     }
 
     fn start_filler(&self) {
-        let inner = self.inner.clone();
+        let mut reader = self.inner.clone();
         let buffer = self.buffer.clone();
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_millis(20));
+            let mut temp_buf = vec![0u8; STEREO_20MS * size_of::<f32>()];
             loop {
                 interval.tick().await;
-
-                let mut temp_buf = vec![0u8; 1920 * 4];
+                // Paused, the audio waits in the TeamSpeak jitter buffers
+                if !buffer.lock().unwrap().accepts() {
+                    continue;
+                }
 
                 let n = {
-                    let mut reader = inner.clone();
                     match std::io::Read::read(&mut reader, &mut temp_buf) {
                         Ok(n) => n,
                         Err(e) => {
@@ -153,12 +767,9 @@ impl BufferedPipeline {
                 };
 
                 if n > 0 {
-                    let mut buf_lock = buffer.lock().unwrap();
-                    buf_lock.extend(&temp_buf[..n]);
-
-                    while buf_lock.len() > 48000 * 2 * 4 {
-                        buf_lock.drain(..1920 * 4);
-                    }
+                    let mut buffer = buffer.lock().unwrap();
+                    buffer.push(&temp_buf[..n]);
+                    reader.buffered.store(buffer.len(), Ordering::Relaxed);
                 }
             }
         });
@@ -167,25 +778,16 @@ impl BufferedPipeline {
 
 impl Read for BufferedPipeline {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut buffer_lock = self.buffer.lock().unwrap();
-        let available = buffer_lock.len().min(buf.len());
-
-        for i in 0..available {
-            buf[i] = buffer_lock.pop_front().unwrap();
-        }
-
-        if available == 0 {
-            buf.fill(0);
-            return Ok(buf.len());
-        }
-
-        Ok(available)
+        let mut buffer = self.buffer.lock().unwrap();
+        let read = buffer.read(buf);
+        self.inner.buffered.store(buffer.len(), Ordering::Relaxed);
+        Ok(read)
     }
 }
 
 impl Seek for BufferedPipeline {
     fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "source does not support seeking"))
+        Err(std::io::Error::other("source does not support seeking"))
     }
 }
 
@@ -212,9 +814,11 @@ const TICK_TIME: u64 = 20;
 const FRAME_SIZE_MS: usize = 20;
 const SAMPLE_RATE: usize = 48000;
 const STEREO_20MS: usize = (SAMPLE_RATE * 2 * FRAME_SIZE_MS) / 1000;
+/// Interleaved stereo samples in the longest supported frame (60 ms).
+const MAX_STEREO_FRAME: usize = STEREO_20MS * 3;
 const MAX_OPUS_FRAME_SIZE: usize = 1275;
 
-const RUST_LOG: &'static str = "RUST_LOG";
+const RUST_LOG: &str = "RUST_LOG";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -230,11 +834,66 @@ async fn main() -> Result<()> {
             #[cfg(not(debug_assertions))] "error,tsclientlib=error,songbird=error,voice_bridge=info"
         );
     }
-    tracing_subscriber::fmt::init();
+    // stdout is reserved for the control interface
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let control_stdio = std::env::args().any(|a| a == control::CONTROL_STDIO_FLAG);
 
-    let config: Config = toml
+    let mut config: Config = toml
         ::from_str(&std::fs::read_to_string(".credentials.toml").expect("No config file!"))
         .expect("Invalid config");
+    if config.low_latency {
+        config.frame_size_ms = latency::frame();
+        if config.buffers == audio::BufferConfig::default() {
+            config.buffers = latency::buffers();
+        }
+        if config.broadcast_delay.take().is_some() {
+            tracing::warn!("low_latency is on, ignoring [broadcast_delay]");
+        }
+    }
+    let buffers = [("discord_to_ts", &config.buffers.discord_to_ts), ("ts_to_discord", &config.buffers.ts_to_discord)];
+    for (direction, limits) in buffers {
+        if let Err(e) = limits.validate() {
+            bail!("Invalid [buffers.{}]: {}", direction, e);
+        }
+    }
+
+    let settings_path = config.settings_file
+        .as_deref()
+        .unwrap_or(settings::DEFAULT_SETTINGS_FILE);
+    let settings: settings::SharedSettings = Arc::new(
+        StdMutex::new(settings::SettingsStore::load(settings_path).expect("Invalid settings file"))
+    );
+    let channel_password = settings
+        .lock()
+        .unwrap()
+        .get()
+        .ts_channel_password.clone()
+        .or(config.teamspeak_channel_password.clone());
+    if std::env::args().any(|a| a == dry_run::DRY_RUN_FLAG) {
+        let passed = dry_run::run(&config, channel_password).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    let bridge_schedule = schedule::BridgeSchedule::new(config.schedule.clone().unwrap_or_default(), settings.clone());
+    let activation = activation::Activation::new();
+    let bridge_pause = pause::BridgePause::new();
+    let solo = solo::Solo::new();
+    let raised_hands = hands::HandQueue::new(config.floor_seconds.map_or(hands::DEFAULT_FLOOR, Duration::from_secs));
+    let mut base_format = profile::OutputFormat {
+        frame: config.frame_size_ms,
+        bitrate: config.bitrate,
+        ducking: config.ducking,
+        ..Default::default()
+    };
+    if config.bandwidth_saver {
+        base_format = base_format.bandwidth_saver();
+    }
+    let audio_profiles = profile::AudioProfiles::new(
+        base_format,
+        config.profiles.clone(),
+        settings.clone()
+    );
+    let (ts_command_tx, mut ts_command_rx) = tokio::sync::mpsc::unbounded_channel();
 
     let logger = {
         let decorator = slog_term::TermDecorator::new().build();
@@ -244,150 +903,763 @@ async fn main() -> Result<()> {
         Logger::root(drain, o!())
     };
 
+    let commands = vec![
+        discord::join(),
+        discord::join_user(),
+        discord::leave(),
+        discord::deafen(),
+        discord::undeafen(),
+        discord::mute(),
+        discord::unmute(),
+        discord::ping(),
+        discord::uptime(),
+        discord::stats(),
+        discord::help(),
+        discord::volume(),
+        discord::volume_check(),
+        discord::reset_audio(),
+        discord::dump(),
+        discord::pause_bridge(),
+        discord::resume_bridge(),
+        discord::tone(),
+        discord::echo_test(),
+        discord::calibrate_noise(),
+        discord::ts_mute(),
+        discord::ts_unmute(),
+        discord::bridge_mute(),
+        discord::bridge_unmute(),
+        discord::delay_user(),
+        discord::podium(),
+        discord::solo(),
+        discord::hand_raise(),
+        discord::hand_lower(),
+        discord::hands(),
+        discord::next(),
+        discord::my_settings(),
+        discord::forget_me(),
+        discord::purge_user(),
+        discord::schedule(),
+        discord::profile(),
+        discord::play(),
+        discord::queue(),
+        discord::skip(),
+        discord::pause(),
+        discord::resume(),
+        discord::np(),
+        discord::music_volume(),
+        discord::route(),
+        discord::shuffle(),
+        discord::seek(),
+        discord::loop_(),
+        discord::ts_message(),
+        discord::send_to_ts(),
+        discord::ts_poke(),
+        discord::ts_channel_password(),
+        discord::rotate_identity()
+    ];
+    discord::apply_cooldowns(&commands, &config.cooldowns);
+    let command_guilds = config.command_guilds.clone();
+
     // Create Poise framework
     let framework = poise::Framework
         ::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![
-                discord::join(),
-                discord::leave(),
-                discord::deafen(),
-                discord::undeafen(),
-                discord::mute(),
-                discord::unmute(),
-                discord::ping(),
-                discord::volume(),
-                discord::volume_check(),
-                discord::reset_audio()
-            ],
+            commands,
+            post_command: |ctx| Box::pin(discord::audit(ctx, Ok(()))),
+            on_error: |error| Box::pin(discord::on_error(error)),
             ..Default::default()
         })
-        .setup(|ctx, _ready, framework| {
+        .setup(move |ctx, ready, framework| {
             Box::pin(async move {
-                poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                discord::register_commands(
+                    ctx,
+                    ready,
+                    &framework.options().commands,
+                    command_guilds.as_deref()
+                ).await?;
                 Ok(discord::Data {})
             })
         })
         .build();
 
     let songbird = Songbird::serenity();
-    songbird.set_config(DriverConfig::default().decode_mode(songbird::driver::DecodeMode::Decode));
+    let mut driver_config = DriverConfig::default().decode_mode(latency::decode_mode(config.low_latency));
+    if config.bandwidth_saver {
+        driver_config = driver_config.mix_mode(songbird::driver::MixMode::Mono);
+    }
+    songbird.set_config(driver_config);
 
     // Store songbird manager for graceful shutdown
     let songbird_manager_shutdown = songbird.clone();
 
     let intents =
+        GatewayIntents::GUILDS |
         GatewayIntents::GUILD_MESSAGES |
         GatewayIntents::MESSAGE_CONTENT |
         GatewayIntents::GUILD_VOICE_STATES;
 
-    let mut client = Client::builder(&config.discord_token, intents)
+    let sound_feed = soundboard::SoundFeed::new();
+    let mut client_builder = Client::builder(&config.discord_token, intents)
         .event_handler(discord::Handler)
-        .framework(framework)
-        .register_songbird_with(songbird.into()).await
+        .framework(framework);
+    if config.bridge_soundboard.unwrap_or(true) {
+        client_builder = client_builder.raw_event_handler(discord::SoundboardHandler::new(sound_feed.clone()));
+    }
+    let mut client = client_builder
+        .register_songbird_with(songbird.clone()).await
         .expect("Err creating client");
 
     let ts_voice_logger = logger.new(o!("pipeline" => "voice-ts"));
-    let teamspeak_voice_handler = TsToDiscordPipeline::new(ts_voice_logger);
+    let broadcast_delay = config.broadcast_delay.map(|delay| {
+        (delay.direction, delay::BroadcastDelay::new(Duration::from_secs_f32(delay.seconds.max(0.0))))
+    });
+    let bridge_stats = stats::BridgeStats::new();
+    let talk_time = talk_time::TalkTime::new();
+    let ts_book = ts_book::TsBook::new();
+    let bridge_events = bridge_events::EventBus::new();
+    let route_gains = routes::RouteGains::new(config.routes, config.music_gain.unwrap_or(music::DEFAULT_MUSIC_GAIN));
+    let mut teamspeak_voice_handler = TsToDiscordPipeline
+        ::new(ts_voice_logger)
+        .with_buffer_limits(config.buffers.ts_to_discord)
+        .with_podium(podium::Podium::new(config.podium))
+        .with_routes(route_gains.clone())
+        .with_stats(bridge_stats.clone())
+        .with_talk_time(talk_time.clone());
+    if let Some(path) = std::env::args().skip_while(|a| a != capture::CAPTURE_FLAG).nth(1) {
+        let payloads = std::env::args().any(|a| a == capture::CAPTURE_PAYLOADS_FLAG);
+        let capture = match capture::Capture::create(&path, payloads) {
+            Ok(capture) => capture,
+            Err(e) => bail!("Can't capture packets to {}: {}", path, e),
+        };
+        tracing::info!("Capturing received packets to {}{}", path, if payloads { " with their payloads" } else { "" });
+        teamspeak_voice_handler = teamspeak_voice_handler.with_capture(capture);
+    }
+    let mut discord_delay = None;
+    match &broadcast_delay {
+        Some((levels::Direction::TsToDiscord, delay)) => {
+            teamspeak_voice_handler = teamspeak_voice_handler.with_broadcast_delay(delay.clone());
+        }
+        Some((levels::Direction::DiscordToTs, delay)) => discord_delay = Some(delay.clone()),
+        None => {}
+    }
+    let audio_levels = teamspeak_voice_handler.levels.clone();
 
     let discord_voice_logger = logger.new(o!("pipeline" => "voice-discord"));
-    let mut handler = discord_audiohandler::AudioHandler::new(discord_voice_logger);
-    handler.set_global_volume(config.volume);
+    let mut handler = discord_audiohandler::AudioHandler
+        ::new(discord_voice_logger)
+        .with_limits(&config.buffers.discord_to_ts)
+        .with_priority_gain(audio::db_to_gain(-config.priority_speaker_reduction_db.unwrap_or(12.0).max(0.0)))
+        .with_decode_time(bridge_stats.codec_counter(levels::Direction::DiscordToTs, stats::CodecStage::Decode));
+    handler.set_global_volume_instantly(config.routes.teamspeak.discord.unwrap_or(config.volume));
     let discord_voice_buffer: AudioBufferDiscord = Arc::new(Mutex::new(handler));
 
+    let music_feed = music::MusicFeed::new();
+    let mut music_mix = music::MusicMix::new(
+        music_feed.clone(),
+        route_gains.clone(),
+        audio_profiles.format().ducking
+    );
+    let voice_presence = discord::VoicePresence::new(client.cache.clone());
+
     {
         let mut data = client.data.write().await;
         data.insert::<ListenerHolder>((
             teamspeak_voice_handler.clone(),
             discord_voice_buffer.clone(),
         ));
+        if let Some(impairment) = config.impairment.discord {
+            tracing::warn!("Impairing packets received from Discord: {:?}", impairment);
+        }
+        data.insert::<SettingsHolder>(settings.clone());
+        data.insert::<VoicePresenceHolder>(voice_presence.clone());
+        data.insert::<AuditHolder>(audit::AuditLog::new(&config.audit));
+        data.insert::<RelayedHolder>(chat_bridge::RelayedMessages::new());
+        data.insert::<VoiceBusyHolder>(discord::VoiceBusy::default());
+        data.insert::<ShardManagerHolder>(client.shard_manager.clone());
+        data.insert::<AudioLevelsHolder>(audio_levels.clone());
+        data.insert::<SoundFeedHolder>(sound_feed.clone());
+        data.insert::<EchoHolder>(echo::EchoRecorder::new());
+        data.insert::<ScheduleHolder>(bridge_schedule.clone());
+        data.insert::<BridgePauseHolder>(bridge_pause.clone());
+        data.insert::<SoloHolder>(solo.clone());
+        data.insert::<HandsHolder>(raised_hands.clone());
+        data.insert::<RoutesHolder>(route_gains.clone());
+        data.insert::<StatsHolder>(bridge_stats.clone());
+        data.insert::<TalkTimeHolder>(talk_time.clone());
+        data.insert::<TsBookHolder>(ts_book.clone());
+        data.insert::<EventBusHolder>(bridge_events.clone());
+        data.insert::<AudioProfilesHolder>(audio_profiles.clone());
+        data.insert::<ActivationHolder>(activation.clone());
+        if let Some((_, delay)) = &broadcast_delay {
+            data.insert::<BroadcastDelayHolder>(delay.clone());
+        }
+        if let Some(region) = config.discord_voice_region.clone() {
+            data.insert::<VoiceRegionHolder>(region);
+        }
+        if config.bandwidth_saver {
+            data.insert::<DiscordBitrateHolder>(profile::BANDWIDTH_SAVER_DISCORD_BITRATE);
+        }
+        let mut music = music::MusicQueues
+            ::new(settings.clone(), music_feed.clone(), config.media_dir.as_ref().map(media::MediaLibrary::new))
+            .with_routes(route_gains.clone())
+            .with_songbird(songbird.clone())
+            .with_now_playing(ts_command_tx.clone(), config.now_playing);
+        if !config.normalize_music.unwrap_or(true) {
+            music = music.without_normalization();
+        }
+        if let Some(program) = config.yt_dlp.clone() {
+            let max_length = config.max_playlist_length.unwrap_or(music::DEFAULT_MAX_PLAYLIST_LENGTH);
+            music = music.with_yt_dlp(program, max_length);
+        }
+        data.insert::<MusicHolder>(music);
+        if let Some(channel_id) = config.admin_channel_id {
+            data.insert::<AdminChannelHolder>(channel_id);
+        }
+        if let Some(channel_id) = config.song_request_channel_id {
+            data.insert::<SongRequestChannelHolder>(channel_id);
+        }
+        data.insert::<TsCommandHolder>(ts_command_tx.clone());
+        data.insert::<DiscordImpairment>(
+            config.impairment.discord.map(|c| Arc::new(impair::Impairer::new(c)))
+        );
+        data.insert::<DiscordVoiceRoles>(
+            config.discord_voice_roles.clone().map(|roles| Arc::new(roles.into_iter().map(Into::into).collect()))
+        );
     }
 
+    let ts_connected = Arc::new(AtomicBool::new(false));
+    let shutdown = Arc::new(Notify::new());
+    post_mortem(
+        &teamspeak_voice_handler,
+        &discord_voice_buffer,
+        ts_connected.clone(),
+        songbird_manager_shutdown.clone(),
+        &activation,
+        &bridge_schedule
+    ).install(config.panic_snapshot_dir.clone().unwrap_or_else(|| ".".to_string()).into());
+    if control_stdio {
+        control::Controller
+            ::new(
+                client.data.clone(),
+                client.http.clone(),
+                songbird_manager_shutdown.clone(),
+                ts_connected.clone(),
+                shutdown.clone()
+            )
+            .spawn();
+    }
+
+    let discord_http = client.http.clone();
+    let discord_data = client.data.clone();
+    {
+        let mut events = bridge_events.subscribe();
+        let (http, admin_channel) = (discord_http.clone(), config.admin_channel_id);
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                match event {
+                    bridge_events::BridgeEvent::Announcement { text } => tracing::info!("{}", text),
+                    bridge_events::BridgeEvent::AdminNotice { text } => {
+                        tracing::warn!("{}", text);
+                        if let Some(channel_id) = admin_channel {
+                            discord::notify_admins(http.clone(), channel_id, text);
+                        }
+                    }
+                    bridge_events::BridgeEvent::AudioReset { reason } => {
+                        let text = format!("Reset the Discord audio queues: {}.", reason);
+                        tracing::warn!("{}", text);
+                        if let Some(channel_id) = admin_channel {
+                            discord::notify_admins(http.clone(), channel_id, text);
+                        }
+                    }
+                    event => tracing::debug!(?event, "Bridge event"),
+                }
+            }
+        });
+    }
+    webhooks::start(&config.webhooks, &bridge_events);
+    let shards = config.shards.take();
     let client_handle = tokio::spawn(async move {
-        let _ = client.start().await.map_err(|why| println!("Client ended: {:?}", why));
+        let result = match &shards {
+            Some(shards) => shards.start(&mut client).await,
+            None => client.start().await,
+        };
+        let _ = result.map_err(|why| eprintln!("Client ended: {:?}", why));
     });
 
     let con_id = ConnectionId(0);
 
-    let mut con_config = Connection::build(config.teamspeak_server)
-        .log_commands(config.verbose >= 1)
-        .log_packets(config.verbose >= 2)
-        .log_udp_packets(config.verbose >= 3);
-
-    if let Some(name) = config.teamspeak_name {
-        con_config = con_config.name(name);
-    }
-    if let Some(channel) = config.teamspeak_channel_id {
-        con_config = con_config.channel_id(tsclientlib::ChannelId(channel));
-    }
-    if let Some(channel) = config.teamspeak_channel_name {
-        con_config = con_config.channel(channel);
-    }
-    if let Some(password) = config.teamspeak_server_password {
-        con_config = con_config.password(password);
+    let identities = identity::Identities::parse(
+        &config.teamspeak_identity,
+        config.teamspeak_identities.as_deref().unwrap_or_default()
+    )?;
+    let mut identity_index = identities.start(settings.lock().unwrap().get().ts_identity);
+    if identity_index != 0 {
+        tracing::info!("Using TeamSpeak {}", identities.describe(identity_index));
     }
-    if let Some(password) = config.teamspeak_channel_password {
-        con_config = con_config.channel_password(password);
-    }
-
-    let id = Identity::new_from_str(&config.teamspeak_identity).expect("Can't load identity!");
-    let con_config = con_config.identity(id);
-
-    let mut con = con_config.connect()?;
-
-    let r = con
-        .events()
-        .try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))))
-        .next().await;
-    if let Some(r) = r {
-        r?;
+    let con_config = ts_connect_options(&config, channel_password.clone(), identities.get(identity_index).clone());
+    let mut con = connect_teamspeak(&config, &con_config).await?;
+    ts_connected.store(true, Ordering::Relaxed);
+    bridge_stats.ts_connected();
+    bridge_events.publish(bridge_events::BridgeEvent::TsConnected);
+    if let Some(nickname) = own_nickname(&con) {
+        tracing::info!("Connected to TeamSpeak as {:?}", nickname);
+        discord_data.write().await.insert::<TsNicknameHolder>(nickname);
     }
 
     let encoder = audiopus::coder::Encoder
         ::new(
             audiopus::SampleRate::Hz48000,
             audiopus::Channels::Stereo,
-            audiopus::Application::Voip
+            latency::application(config.low_latency)
         )
         .expect("Can't construct encoder!");
-    let encoder = Arc::new(Mutex::new(encoder));
-
-    let mut interval = tokio::time::interval(Duration::from_millis(TICK_TIME));
+    let encoders = encode::EncoderPool
+        ::new()
+        .with_encode_time(bridge_stats.codec_counter(levels::Direction::DiscordToTs, stats::CodecStage::Encode))
+        .with_output("teamspeak", encoder);
+    discord_data.write().await.insert::<LateEncodesHolder>(encoders.late_frames());
+
+    let mut format = audio_profiles.format();
+    encoders.set_bitrate(format.bitrate);
+    encoders.set_mono(format.mono);
+    encoders.set_dtx(format.dtx);
+    if format.frame != audio::FrameDuration::default() {
+        tracing::info!("Sending {:?} ms frames to TeamSpeak", format.frame.interval().as_millis());
+    }
+    let mut interval = tokio::time::interval(format.frame.interval());
+    let mut deadlines = deadline::DeadlineMonitor::new(config.deadlines);
+    let mut holdover = holdover::Holdover::new(
+        config.reconnect_buffer_ms.map_or(holdover::DEFAULT_HOLDOVER, Duration::from_millis)
+    );
+    let mut ts_down = false;
+    discord_data.write().await.insert::<DeadlineMissesHolder>(deadlines.total_missed());
+
+    let announce = {
+        let ts_commands = ts_command_tx.clone();
+        let (http, manager) = (discord_http.clone(), songbird_manager_shutdown.clone());
+        let events = bridge_events.clone();
+        move |text: &str| {
+            events.publish(bridge_events::BridgeEvent::Announcement { text: text.to_string() });
+            let _ = ts_commands.send(teamspeak::TsCommand::Announce { text: text.to_string() });
+            discord::announce_in_calls(http.clone(), manager.clone(), text.to_string());
+        }
+    };
+    {
+        let announce = announce.clone();
+        tokio::spawn(
+            bridge_schedule.clone().run(move |live| {
+                if live {
+                    announce("The bridge is live, Discord and TeamSpeak hear each other now.");
+                } else {
+                    announce("The bridge is dormant until its next scheduled hours.");
+                }
+            })
+        );
+    }
+    {
+        let music = discord_data.read().await.get::<MusicHolder>().cloned();
+        if let Some(music) = music {
+            let announce = announce.clone();
+            tokio::spawn(music.watch(move |notice| announce(notice)));
+        }
+    }
+    let auto_activate = config.auto_activate.unwrap_or(false);
+    let mut activation_check = tokio::time::interval(activation::CHECK_INTERVAL);
+    let feedback_suppression = config.feedback_suppression.unwrap_or(true);
+    let mut feedback_check = tokio::time::interval(feedback::CHECK_INTERVAL);
+    let auto_reset_audio = config.auto_reset_audio.unwrap_or(true);
+    let mut floor_check = tokio::time::interval(Duration::from_secs(1));
+    // TeamSpeak client the bridge mute was last lifted for
+    let mut ts_floor = None;
+    let mut audio_watchdog = watchdog::AudioWatchdog::default();
+    let mut watchdog_check = tokio::time::interval(watchdog::CHECK_INTERVAL);
+    let mut talk_check = tokio::time::interval(talk_time::RESOLVE_INTERVAL);
+    let mut talk_save = tokio::time::interval(talk_time::SAVE_INTERVAL);
+    let mut people_check = tokio::time::interval(stats::PEOPLE_CHECK);
+    let mut speakers = bridge_events::Speakers::default();
+    let mut failover = config.fallback_teamspeak.as_ref().map(failover::Failover::new);
+    let mut failover_check = tokio::time::interval(failover::CHECK_INTERVAL);
+    let (standby_tx, mut standby_rx) = mpsc::channel(1);
+    // Identity the bridge is reconnecting with, see `/rotate-identity`
+    let mut rotating = None;
+    let (rotated_tx, mut rotated_rx) = mpsc::channel::<(usize, teamspeak::IdentityReply, Result<Connection>)>(1);
+
+    let mut ts_events = teamspeak::TsEventHandler
+        ::new(con_id, teamspeak_voice_handler.clone(), logger.clone())
+        .with_dormant_flag(bridge_schedule.dormant_flag())
+        .with_pause_flag(bridge_pause.flag(levels::Direction::TsToDiscord))
+        .with_solo(solo.clone())
+        .with_hands(raised_hands.clone())
+        .with_stats(bridge_stats.clone())
+        .with_events(bridge_events.clone())
+        .with_idle_flag(activation.idle_flag());
+    let feed_muted = Arc::new(AtomicBool::new(false));
+    let chat = ts_chat::ChatCommands::new(
+        discord_voice_buffer.clone(),
+        feed_muted.clone(),
+        voice_presence.clone(),
+        config.teamspeak_command_groups.clone(),
+        logger.new(o!("component" => "ts-chat"))
+    )
+        .with_echo_test(sound_feed.clone())
+        .with_hands(raised_hands.clone());
+    ts_events = ts_events.with_chat(chat, ts_command_tx).with_rate_limits(config.chat_rate_limit);
+    if let Some(groups) = config.teamspeak_voice_groups.clone() {
+        ts_events = ts_events.with_voice_groups(groups);
+    }
+    if let Some(impairment) = config.impairment.teamspeak {
+        tracing::warn!("Impairing packets received from TeamSpeak: {:?}", impairment);
+        ts_events = ts_events.with_impairment(impairment);
+    }
+    if let Some(home) = home_channel(&config) {
+        ts_events = ts_events.with_home_channel(home).with_channel_password(channel_password);
+    }
 
     loop {
         let events = con.events().try_for_each(|e| async {
-            if let StreamItem::Audio(packet) = e {
-                let from = ClientId(match packet.data().data() {
-                    AudioData::S2C { from, .. } => *from,
-                    AudioData::S2CWhisper { from, .. } => *from,
-                    _ => panic!("Can only handle S2C packets but got a C2S packet"),
-                });
-
-                let mut ts_voice = teamspeak_voice_handler.data
-                    .lock()
-                    .expect("Can't lock ts audio buffer!");
-                if let Err(e) = ts_voice.handle_packet((con_id, from), packet) {
-                    debug!(logger, "Failed to handle TS_Voice packet"; "error" => %e);
-                }
-            }
+            ts_events.handle(e);
             Ok(())
         });
 
         tokio::select! {
-            _send = interval.tick() => {
+            scheduled = interval.tick() => {
                 let start = std::time::Instant::now();
-                if let Some(processed) = process_discord_audio(&discord_voice_buffer,&encoder).await {
-                    con.send_audio(processed)?;
+                if ts_events.take_roster_changed() {
+                    ts_events.restore_channel(&mut con);
+                    ts_events.follow_channel(&mut con);
+                    if let Ok(state) = con.get_state() {
+                        ts_book.update(ts_book::Snapshot::from_state(state, &ts_talking(&teamspeak_voice_handler)));
+                        ts_events.refresh_muted(state, settings.lock().unwrap().get());
+                        ts_events.refresh_delays(state, settings.lock().unwrap().get());
+                        ts_events.refresh_podium(state);
+                        ts_events.refresh_voice_gate(state);
+                        if let Some(text) = ts_events.check_home_channel(state) {
+                            bridge_events.publish(bridge_events::BridgeEvent::AdminNotice { text });
+                        }
+                    }
+                }
+                if let Some(hash) = ts_events.take_uploaded_avatar() {
+                    ts_events.set_avatar(&mut con, &hash);
+                }
+                ts_events.report_suppressed(&mut con);
+                if activation.is_idle() {
+                    // Sounds played while idle would all come at once on activation
+                    sound_feed.clear();
+                    continue;
+                }
+                if let Some((processed, audible)) = process_discord_audio(
+                    &discord_voice_buffer,
+                    &mut music_mix,
+                    &sound_feed,
+                    discord_delay.as_ref(),
+                    &audio_levels,
+                    &encoders,
+                    format
+                ).await {
+                    // Still drained while muted, so nothing stale plays on unmute
+                    let paused = bridge_pause.is_paused(levels::Direction::DiscordToTs);
+                    if !feed_muted.load(Ordering::Relaxed) && !bridge_schedule.is_dormant() && !paused {
+                        if audible {
+                            bridge_stats.bridged_frame(levels::Direction::DiscordToTs, format.frame.interval());
+                        }
+                        bridge_stats.sent(levels::Direction::DiscordToTs, processed.data().len());
+                        if con.get_state().is_err() && !ts_down {
+                            ts_down = true;
+                            tracing::info!("TeamSpeak is reconnecting, holding up to {} ms of Discord audio", holdover.max().as_millis());
+                        }
+                        if holdover.push(processed, format.frame.interval()) > 0 {
+                            tracing::debug!("Dropped held Discord audio past the reconnect buffer");
+                        }
+                    }
                     let dur = start.elapsed();
                     if dur >= Duration::from_millis(1) {
                         tracing::debug!("Audio pipeline took {}ms",dur.as_millis());
                     }
                 }
+                // Held audio goes out faster than it comes in, until caught up
+                if con.get_state().is_ok() {
+                    if ts_down {
+                        ts_down = false;
+                        tracing::info!("TeamSpeak is back, catching up on {} ms of Discord audio", holdover.held().as_millis());
+                    }
+                    for _ in 0..holdover::CATCH_UP_FRAMES {
+                        match holdover.pop() {
+                            Some(packet) => con.send_audio(packet)?,
+                            None => break,
+                        }
+                    }
+                }
+                let finished = std::time::Instant::now();
+                match deadlines.record(scheduled.into_std(), finished, format.frame.interval()) {
+                    Some(deadline::Alert::Exceeded { missed, ticks, rate }) => {
+                        tracing::warn!(
+                            missed,
+                            ticks,
+                            rate_percent = rate,
+                            budget_percent = config.deadlines.miss_budget_percent,
+                            "Send ticks missing their deadline"
+                        );
+                        let text = format!(
+                            "{} of {} frames to TeamSpeak were sent late in the last {} seconds ({:.1}%, {}% allowed), the host may be overloaded.",
+                            missed,
+                            ticks,
+                            config.deadlines.window_s,
+                            rate,
+                            config.deadlines.miss_budget_percent
+                        );
+                        bridge_events.publish(bridge_events::BridgeEvent::AdminNotice { text });
+                    }
+                    Some(deadline::Alert::Recovered { missed, ticks, rate }) => {
+                        tracing::info!(missed, ticks, rate_percent = rate, "Send ticks back within their deadline budget");
+                    }
+                    None => {}
+                }
+            }
+            _ = audio_profiles.changed() => {
+                let changed = audio_profiles.format();
+                tracing::info!("Switched audio profile to {:?}: {:?}", audio_profiles.active(), changed);
+                if changed.frame != format.frame {
+                    interval = tokio::time::interval(changed.frame.interval());
+                }
+                encoders.set_bitrate(changed.bitrate);
+                encoders.set_mono(changed.mono);
+                encoders.set_dtx(changed.dtx);
+                music_mix.set_ducking(changed.ducking);
+                format = changed;
+            }
+            _ = activation_check.tick(), if auto_activate => {
+                let ts_people = con.get_state().is_ok_and(teamspeak::has_listeners);
+                let discord_people = voice_presence.members().iter().any(|member| !member.bot);
+                match activation.update(discord_people, ts_people) {
+                    Some(true) => announce("People are on both sides, the bridge is active."),
+                    Some(false) => announce("One side is empty, the bridge idles until both have people again."),
+                    None => {}
+                }
+            }
+            _ = feedback_check.tick(), if feedback_suppression => {
+                for (direction, source) in audio_levels.feedback().check() {
+                    let name = feedback_source_name(&con, direction, &source);
+                    announce(&format!(
+                        "{} echoes what the bridge plays, probably connected on both sides with speakers on. Not bridging them for {} seconds.",
+                        name,
+                        feedback::SUPPRESS_FOR.as_secs()
+                    ));
+                }
+            }
+            _ = floor_check.tick() => {
+                let floor = raised_hands.floor().and_then(|hand| match hand.speaker {
+                    hands::Speaker::Ts(client) => Some(client),
+                    hands::Speaker::Discord(_) => None,
+                });
+                if floor != ts_floor {
+                    ts_floor = floor;
+                    if let Ok(state) = con.get_state() {
+                        ts_events.refresh_muted(state, settings.lock().unwrap().get());
+                    }
+                }
+                if let Some(hand) = raised_hands.take_expired() {
+                    announce(&format!("Time is up for {}.", hand.label()));
+                }
             }
+            _ = talk_check.tick() => {
+                if let Ok(state) = con.get_state() {
+                    talk_time.resolve_ts(|id| {
+                        let client = state.clients.get(&id)?;
+                        Some((client.uid.as_ref()?.to_string(), client.name.clone()))
+                    });
+                }
+            }
+            _ = talk_save.tick(), if talk_time.has_unsaved() => {
+                if let Err(e) = settings.lock().unwrap().update(|s| talk_time.save(s)) {
+                    tracing::warn!("Failed to save speaking time: {}", e);
+                }
+            }
+            _ = people_check.tick() => {
+                let members = voice_presence.members();
+                let people: Vec<_> = members.iter().filter(|member| !member.bot).collect();
+                let ts_people = con.get_state().map(teamspeak::listener_uids).unwrap_or_default();
+                if let Ok(state) = con.get_state() {
+                    ts_book.update(ts_book::Snapshot::from_state(state, &ts_talking(&teamspeak_voice_handler)));
+                }
+                let speaking_now = people
+                    .iter()
+                    .filter(|member| member.speaking)
+                    .map(|member| (bridge_events::Side::Discord, member.name.clone()))
+                    .chain(
+                        ts_book
+                            .snapshot()
+                            .clients.iter()
+                            .filter(|client| client.talking)
+                            .map(|client| (bridge_events::Side::Teamspeak, client.name.clone()))
+                    )
+                    .collect();
+                for event in speakers.update(speaking_now) {
+                    bridge_events.publish(event);
+                }
+                let speaking = people.iter().filter(|member| member.speaking).count()
+                    + teamspeak_voice_handler.lock_handler().get_queues().len();
+                bridge_stats.saw_people(people.iter().map(|member| member.id), ts_people, speaking);
+            }
+            _ = watchdog_check.tick(), if auto_reset_audio => {
+                let mut handler = discord_voice_buffer.lock().await;
+                let health = watchdog::Health {
+                    decode_errors: handler.decode_errors().get(),
+                    dropped_packets: handler.dropped_packets().get(),
+                    full_queues: handler.full_queues(),
+                };
+                if let Some(trigger) = audio_watchdog.check(health, std::time::Instant::now()) {
+                    handler.reset();
+                    drop(handler);
+                    bridge_stats.audio_reset();
+                    bridge_events.publish(bridge_events::BridgeEvent::AudioReset { reason: trigger.to_string() });
+                }
+            }
+            _ = failover_check.tick(), if failover.is_some() => {
+                let Some(failover) = failover.as_mut() else {
+                    continue;
+                };
+                let Some(server) = failover.check(con.get_state().is_ok(), std::time::Instant::now()) else {
+                    continue;
+                };
+                let password = settings.lock().unwrap().get().ts_channel_password.clone()
+                    .or(config.teamspeak_channel_password.clone());
+                let options = server_connect_options(&config, server, password, identities.get(identity_index).clone());
+                let name = config.teamspeak_name.clone();
+                let name_pattern = config.teamspeak_name_pattern.clone()
+                    .unwrap_or_else(|| teamspeak::DEFAULT_NICKNAME_PATTERN.to_string());
+                let standby = standby_tx.clone();
+                tracing::info!("Trying the {:?} TeamSpeak server", server);
+                tokio::spawn(async move {
+                    let connecting = connect_as(name.as_deref(), &name_pattern, &options);
+                    let result = match tokio::time::timeout(failover::CONNECT_TIMEOUT, connecting).await {
+                        Ok(result) => result,
+                        Err(_) => Err(anyhow::anyhow!("no answer within {} seconds", failover::CONNECT_TIMEOUT.as_secs())),
+                    };
+                    let _ = standby.send((server, result)).await;
+                });
+            }
+            Some((server, result)) = standby_rx.recv() => {
+                let (Some(failover), Some(fallback)) = (failover.as_mut(), config.fallback_teamspeak.as_ref()) else {
+                    continue;
+                };
+                let connected = match result {
+                    Ok(connected) => connected,
+                    Err(e) => {
+                        tracing::warn!("Can't connect to the {:?} TeamSpeak server: {}", server, e);
+                        failover.gave_up(std::time::Instant::now());
+                        continue;
+                    }
+                };
+                // The primary may have come back while the fallback was connecting
+                if server == failover::Server::Fallback && con.get_state().is_ok() {
+                    tracing::info!("The primary TeamSpeak server is back, staying on it");
+                    failover::leave(connected);
+                    failover.gave_up(std::time::Instant::now());
+                    continue;
+                }
+                failover::leave(std::mem::replace(&mut con, connected));
+                failover.switched(server, std::time::Instant::now());
+                let (home, password) = match server {
+                    failover::Server::Primary => {
+                        let password = settings.lock().unwrap().get().ts_channel_password.clone()
+                            .or(config.teamspeak_channel_password.clone());
+                        (home_channel(&config), password)
+                    }
+                    failover::Server::Fallback => (fallback.home_channel(), fallback.channel_password.clone()),
+                };
+                ts_events.switch_server(home, password);
+                ts_book.disconnected();
+                bridge_stats.ts_reconnecting();
+                bridge_events.publish(bridge_events::BridgeEvent::TsConnected);
+                if let Some(nickname) = own_nickname(&con) {
+                    discord_data.write().await.insert::<TsNicknameHolder>(nickname);
+                }
+                announce(&match server {
+                    failover::Server::Fallback => format!(
+                        "The TeamSpeak server {} is unreachable, the bridge moved to {} until it is back.",
+                        config.teamspeak_server,
+                        fallback.server
+                    ),
+                    failover::Server::Primary => format!(
+                        "The TeamSpeak server {} is back, the bridge moved back to it.",
+                        config.teamspeak_server
+                    ),
+                });
+            }
+            Some((next, reply, result)) = rotated_rx.recv() => {
+                rotating = None;
+                let rotated = match result {
+                    Ok(rotated) => rotated,
+                    Err(e) => {
+                        tracing::warn!("Can't connect to TeamSpeak with {}: {}", identities.describe(next), e);
+                        let _ = reply.send(Err(format!(
+                            "Can't connect with {}, staying on {}: {}",
+                            identities.describe(next),
+                            identities.describe(identity_index),
+                            e
+                        )));
+                        continue;
+                    }
+                };
+                failover::leave(std::mem::replace(&mut con, rotated));
+                identity_index = next;
+                let saved = settings.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = saved.lock().expect("Can't lock settings!").update(|s| s.ts_identity = Some(next)) {
+                        tracing::warn!("Failed to save the TeamSpeak identity: {}", e);
+                    }
+                });
+                ts_book.disconnected();
+                bridge_stats.ts_reconnecting();
+                bridge_events.publish(bridge_events::BridgeEvent::TsConnected);
+                ts_events.new_session();
+                if let Some(nickname) = own_nickname(&con) {
+                    discord_data.write().await.insert::<TsNicknameHolder>(nickname);
+                }
+                let _ = reply.send(Ok(format!("Reconnected to TeamSpeak with {}", identities.describe(next))));
+            }
+            Some(command) = ts_command_rx.recv() => match command {
+                teamspeak::TsCommand::RotateIdentity { reply } => {
+                    if let Some(next) = rotating {
+                        let _ = reply.send(Err(format!("Already switching to TeamSpeak {}", identities.describe(next))));
+                        continue;
+                    }
+                    let next = match identities.next(identity_index) {
+                        Some(next) => next,
+                        None => {
+                            let _ = reply.send(Err("Only one TeamSpeak identity is configured, add more in `teamspeak_identities`".to_string()));
+                            continue;
+                        }
+                    };
+                    tracing::info!("Switching to TeamSpeak {}", identities.describe(next));
+                    let password = settings.lock().expect("Can't lock settings!").get().ts_channel_password.clone()
+                        .or(config.teamspeak_channel_password.clone());
+                    let server = failover.as_ref().map_or(failover::Server::Primary, failover::Failover::on);
+                    let options = server_connect_options(&config, server, password, identities.get(next).clone());
+                    let name = config.teamspeak_name.clone();
+                    let name_pattern = config.teamspeak_name_pattern.clone()
+                        .unwrap_or_else(|| teamspeak::DEFAULT_NICKNAME_PATTERN.to_string());
+                    let rotated = rotated_tx.clone();
+                    rotating = Some(next);
+                    tokio::spawn(async move {
+                        let connecting = connect_as(name.as_deref(), &name_pattern, &options);
+                        let result = match tokio::time::timeout(failover::CONNECT_TIMEOUT, connecting).await {
+                            Ok(result) => result,
+                            Err(_) => Err(anyhow::anyhow!("no answer within {} seconds", failover::CONNECT_TIMEOUT.as_secs())),
+                        };
+                        let _ = rotated.send((next, reply, result)).await;
+                    });
+                }
+                command => ts_events.handle_command(&mut con, &settings, command).await,
+            },
             _ = tokio::signal::ctrl_c() => { 
-                println!("Received shutdown signal...");
+                eprintln!("Received shutdown signal...");
                 break; 
             }
+            _ = shutdown.notified() => {
+                eprintln!("Shutdown requested via control interface...");
+                break;
+            }
             r = events => {
                 r?;
                 bail!("Disconnected");
@@ -396,14 +1668,17 @@ async fn main() -> Result<()> {
     }
 
     // Graceful shutdown
-    println!("Disconnecting from Discord voice channels...");
+    if let Some(channel_id) = config.admin_channel_id {
+        discord::post_to_admins(&discord_http, channel_id, &bridge_stats.summary()).await;
+    }
+    eprintln!("Disconnecting from Discord voice channels...");
     let guild_ids: Vec<_> = songbird_manager_shutdown
         .iter()
         .map(|(guild_id, _)| guild_id)
         .collect();
 
     for guild_id in guild_ids {
-        println!("  Leaving guild {}...", guild_id);
+        eprintln!("  Leaving guild {}...", guild_id);
         if let Err(e) = songbird_manager_shutdown.remove(guild_id).await {
             eprintln!("  Error leaving guild {}: {:?}", guild_id, e);
         }
@@ -414,54 +1689,238 @@ async fn main() -> Result<()> {
 
     // Abort the client task
     client_handle.abort();
-    println!("Discord client stopped");
+    eprintln!("Discord client stopped");
 
-    println!("Disconnecting from TeamSpeak...");
+    eprintln!("Disconnecting from TeamSpeak...");
     con.disconnect(DisconnectOptions::new())?;
     con.events().for_each(|_| future::ready(())).await;
-    println!("Shutdown complete!");
+    ts_connected.store(false, Ordering::Relaxed);
+    ts_book.disconnected();
+    bridge_stats.ts_disconnected();
+    bridge_events.publish(bridge_events::BridgeEvent::TsDisconnected);
+    eprintln!("Shutdown complete!");
     Ok(())
 }
 
-async fn process_discord_audio(
-    voice_buffer: &AudioBufferDiscord,
-    encoder: &Arc<Mutex<Encoder>>
-) -> Option<OutPacket> {
-    let mut data = [0.0; STEREO_20MS];
-    {
-        let mut lock = voice_buffer.lock().await;
-        lock.fill_buffer(&mut data);
+/// What panic snapshots hold: buffer fills, packet arrivals and connection states.
+fn post_mortem(
+    ts_pipeline: &TsToDiscordPipeline,
+    discord_buffer: &AudioBufferDiscord,
+    ts_connected: Arc<AtomicBool>,
+    songbird: Arc<Songbird>,
+    activation: &activation::Activation,
+    schedule: &schedule::BridgeSchedule
+) -> postmortem::PostMortem {
+    let post_mortem = postmortem::PostMortem::new();
+    let pipeline = ts_pipeline.clone();
+    post_mortem.add("ts_to_discord", move || {
+        serde_json::json!({
+            "buffered_ms": pipeline.buffered.load(Ordering::Relaxed) / audio::PCM_BYTES_PER_MS,
+            "queues": pipeline.data.try_lock().ok().map(|handler| handler.get_queues().len()),
+            "ingest_free": pipeline.ingest.capacity(),
+            "dropped_ms": pipeline.dropped.get() / (audio::PCM_BYTES_PER_MS as u64),
+            "dropped_packets": pipeline.ingest_dropped.get(),
+        })
+    });
+    let buffer = discord_buffer.clone();
+    post_mortem.add("discord_to_ts", move || {
+        buffer.try_lock().map_or(serde_json::Value::Null, |handler| {
+            let queues: serde_json::Map<_, _> = handler
+                .buffered_packets()
+                .map(|(ssrc, packets)| (format!("ssrc {}", ssrc), packets.into()))
+                .collect();
+            serde_json::json!({
+                "queued_packets": queues,
+                "dropped_packets": handler.dropped_packets().get(),
+            })
+        })
+    });
+    let packet_times = ts_pipeline.packet_times.clone();
+    post_mortem.add("packets", move || packet_times.snapshot());
+    let (idle, dormant) = (activation.idle_flag(), schedule.dormant_flag());
+    post_mortem.add("connections", move || {
+        let calls: Vec<_> = songbird.iter().map(|(guild_id, _)| guild_id.0.get()).collect();
+        serde_json::json!({
+            "teamspeak": ts_connected.load(Ordering::Relaxed),
+            "discord_calls": calls,
+            "idle": idle.load(Ordering::Relaxed),
+            "dormant": dormant.load(Ordering::Relaxed),
+        })
+    });
+    post_mortem
+}
+
+/// Who `source` of the audio levels is, for telling people.
+fn feedback_source_name(con: &Connection, direction: levels::Direction, source: &str) -> String {
+    let client = source
+        .strip_prefix("client ")
+        .and_then(|id| id.parse().ok())
+        .map(ClientId);
+    match (direction, client, con.get_state()) {
+        (levels::Direction::TsToDiscord, Some(id), Ok(state)) if state.clients.contains_key(&id) => {
+            format!("TeamSpeak client {}", state.clients[&id].name)
+        }
+        (levels::Direction::TsToDiscord, _, _) => "A TeamSpeak client".to_string(),
+        (levels::Direction::DiscordToTs, _, _) => "A Discord speaker".to_string(),
     }
-    let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
-    let encoder_c = encoder.clone();
+}
 
-    let res = task
-        ::spawn_blocking(move || {
-            let start = std::time::Instant::now();
-            let lock = encoder_c.try_lock().expect("Can't reach encoder!");
-            let length = match lock.encode_float(&data, &mut encoded) {
-                Err(e) => {
-                    tracing::error!("Failed to encode voice: {}", e);
-                    return None;
-                }
-                Ok(size) => size,
-            };
+/// The TeamSpeak channel the bridge is configured to be in.
+fn home_channel(config: &Config) -> Option<teamspeak::HomeChannel> {
+    config.teamspeak_channel_id
+        .map(teamspeak::HomeChannel::Id)
+        .or(config.teamspeak_channel_name.clone().map(teamspeak::HomeChannel::Path))
+}
 
-            let duration = start.elapsed().as_millis();
-            if duration > 2 {
-                tracing::warn!("Took too {}ms for processing audio!", duration);
+/// Options to connect to TeamSpeak with, into the configured channel.
+fn ts_connect_options(config: &Config, channel_password: Option<String>, identity: Identity) -> tsclientlib::ConnectOptions {
+    let con_config = Connection::build(config.teamspeak_server.as_str());
+    let con_config = into_channel(con_config, config.teamspeak_channel_id, config.teamspeak_channel_name.clone());
+    with_passwords(con_config, config.teamspeak_server_password.clone(), channel_password)
+        .log_commands(config.verbose >= 1)
+        .log_packets(config.verbose >= 2)
+        .log_udp_packets(config.verbose >= 3)
+        .identity(identity)
+}
+
+/// Options to connect to the standby server with, into its channel.
+fn fallback_connect_options(
+    config: &Config,
+    fallback: &failover::FallbackConfig,
+    identity: Identity
+) -> tsclientlib::ConnectOptions {
+    let con_config = Connection::build(fallback.server.as_str());
+    let con_config = into_channel(con_config, fallback.channel_id, fallback.channel_name.clone());
+    with_passwords(con_config, fallback.server_password.clone(), fallback.channel_password.clone())
+        .log_commands(config.verbose >= 1)
+        .log_packets(config.verbose >= 2)
+        .log_udp_packets(config.verbose >= 3)
+        .identity(identity)
+}
+
+/// Options to connect to `server`, the configured one or the standby.
+fn server_connect_options(
+    config: &Config,
+    server: failover::Server,
+    channel_password: Option<String>,
+    identity: Identity
+) -> tsclientlib::ConnectOptions {
+    match (server, &config.fallback_teamspeak) {
+        (failover::Server::Fallback, Some(fallback)) => fallback_connect_options(config, fallback, identity),
+        _ => ts_connect_options(config, channel_password, identity),
+    }
+}
+
+fn into_channel(
+    mut con_config: tsclientlib::ConnectOptions,
+    channel_id: Option<u64>,
+    channel_name: Option<String>
+) -> tsclientlib::ConnectOptions {
+    if let Some(channel) = channel_id {
+        con_config = con_config.channel_id(tsclientlib::ChannelId(channel));
+    }
+    if let Some(channel) = channel_name {
+        con_config = con_config.channel(channel);
+    }
+    con_config
+}
+
+fn with_passwords(
+    mut con_config: tsclientlib::ConnectOptions,
+    server_password: Option<String>,
+    channel_password: Option<String>
+) -> tsclientlib::ConnectOptions {
+    if let Some(password) = server_password {
+        con_config = con_config.password(password);
+    }
+    if let Some(password) = channel_password {
+        con_config = con_config.channel_password(password);
+    }
+    con_config
+}
+
+/// TeamSpeak clients whose voice is being mixed.
+fn ts_talking(pipeline: &TsToDiscordPipeline) -> HashSet<ClientId> {
+    pipeline.lock_handler().get_queues().keys().map(|(_, id)| *id).collect()
+}
+
+/// Nickname the bridge has on the server.
+fn own_nickname(con: &Connection) -> Option<String> {
+    let state = con.get_state().ok()?;
+    state.clients.get(&state.own_client).map(|own| own.name.clone())
+}
+
+/// Connect with `con_config`, trying other nicknames while the configured one is taken.
+async fn connect_teamspeak(config: &Config, con_config: &tsclientlib::ConnectOptions) -> Result<Connection> {
+    let name_pattern = config.teamspeak_name_pattern
+        .as_deref()
+        .unwrap_or(teamspeak::DEFAULT_NICKNAME_PATTERN);
+    connect_as(config.teamspeak_name.as_deref(), name_pattern, con_config).await
+}
+
+/// Connect with `con_config` as `name`, or a nickname after `name_pattern` while that is taken.
+async fn connect_as(
+    name: Option<&str>,
+    name_pattern: &str,
+    con_config: &tsclientlib::ConnectOptions
+) -> Result<Connection> {
+    let mut attempt = 0;
+    loop {
+        let nickname = name.map(|name| teamspeak::nickname_candidate(name, name_pattern, attempt));
+        let mut options = con_config.clone();
+        if let Some(nickname) = nickname.clone() {
+            options = options.name(nickname);
+        }
+        let mut con = options.connect()?;
+
+        let r = con
+            .events()
+            .try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))))
+            .next().await;
+        match r {
+            Some(Err(tsclientlib::Error::ConnectTs(tsclientlib::TsError::ClientNicknameInuse))) if
+                nickname.is_some() && attempt < teamspeak::MAX_NICKNAME_ATTEMPTS
+            => {
+                tracing::warn!("TeamSpeak nickname {:?} is taken, trying another one", nickname.unwrap_or_default());
+                attempt += 1;
             }
+            Some(r) => {
+                r?;
+                return Ok(con);
+            }
+            None => return Ok(con),
+        }
+    }
+}
 
-            Some(
-                OutAudio::new(
-                    &(AudioData::C2S {
-                        id: 0,
-                        codec: CodecType::OpusMusic,
-                        data: &encoded[..length],
-                    })
-                )
-            )
-        }).await
-        .expect("Join error for audio processing thread!");
-    res
+/// Mix and encode a frame for TeamSpeak, with whether anything could be heard in it.
+async fn process_discord_audio(
+    voice_buffer: &AudioBufferDiscord,
+    music: &mut music::MusicMix,
+    sounds: &soundboard::SoundFeed,
+    delay: Option<&delay::BroadcastDelay>,
+    levels: &levels::AudioLevels,
+    encoders: &encode::EncoderPool,
+    format: profile::OutputFormat
+) -> Option<(OutPacket, bool)> {
+    let (frame, codec) = (format.frame, format.codec.into());
+    let len = frame.stereo_samples();
+    let mut data = [0.0; MAX_STEREO_FRAME];
+    {
+        let mut lock = voice_buffer.lock().await;
+        lock.fill_buffer_with_proc(&mut data[..len], |ssrc, samples| {
+            levels.record(levels::Direction::DiscordToTs, &format!("ssrc {}", ssrc), samples);
+        });
+        lock.apply_global_volume(&mut data[..len]);
+    }
+    music.mix_into(&mut data[..len], frame);
+    sounds.mix_into(&mut data[..len]);
+    if let Some(delay) = delay {
+        delay.process(&mut data[..len]);
+    }
+    levels.record(levels::Direction::DiscordToTs, "mix", &data[..len]);
+    let audible = stats::is_audible(&data[..len]);
+    // The first output is TeamSpeak
+    let packet = encoders.encode(&data[..len], codec, frame.interval()).await.into_iter().next().flatten()?;
+    Some((packet, audible))
 }
@@ -0,0 +1,182 @@
+//! Local files for `/play file:<name>`, like jingles and recordings.
+//!
+//! Names are paths relative to the configured media directory, using `/`
+//! on every platform. Directories end with `/` and stand for every file
+//! below them.
+
+use std::path::{ Component, Path, PathBuf };
+
+use anyhow::{ anyhow, bail, Result };
+
+/// Prefix of `/play` arguments naming something in the media directory.
+pub const FILE_PREFIX: &str = "file:";
+
+/// Discord shows at most this many autocomplete choices.
+const MAX_SUGGESTIONS: usize = 25;
+
+/// Extensions of files that are listed, all others are ignored.
+const AUDIO_EXTENSIONS: &[&str] = &["flac", "mka", "mkv", "mp3", "oga", "ogg", "opus", "wav", "webm"];
+
+#[derive(Clone, Debug)]
+pub struct MediaLibrary {
+    root: PathBuf,
+}
+
+impl MediaLibrary {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Path of `name`, which must stay inside the media directory.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        let relative = Path::new(name.trim_end_matches('/'));
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("{} is not inside the media directory", name);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Every audio file and the directories containing them, sorted.
+    pub fn entries(&self) -> Vec<String> {
+        let mut entries = Vec::new();
+        collect(&self.root, "", &mut entries);
+        entries.sort();
+        entries
+    }
+
+    /// Entries matching `query` best first, for autocompletion.
+    pub fn suggest(&self, query: &str) -> Vec<String> {
+        let mut scored: Vec<_> = self
+            .entries()
+            .into_iter()
+            .filter_map(|entry| Some((score(query, &entry)?, entry)))
+            .collect();
+        scored.sort();
+        scored.into_iter().take(MAX_SUGGESTIONS).map(|(_, entry)| entry).collect()
+    }
+
+    /// Files to play for `query`, several if it matches a directory best.
+    pub fn resolve(&self, query: &str) -> Result<Vec<String>> {
+        let entries = self.entries();
+        let best = entries
+            .iter()
+            .filter_map(|entry| Some((score(query, entry)?, entry)))
+            .min()
+            .map(|(_, entry)| entry.clone())
+            .ok_or_else(|| anyhow!("Nothing in the media directory matches {}", query))?;
+        if !best.ends_with('/') {
+            return Ok(vec![best]);
+        }
+        Ok(
+            entries
+                .into_iter()
+                .filter(|entry| entry.starts_with(&best) && !entry.ends_with('/'))
+                .collect()
+        )
+    }
+}
+
+/// Add audio files below `dir` to `entries`, and `dir` itself if it has any.
+fn collect(dir: &Path, prefix: &str, entries: &mut Vec<String>) {
+    let read = match std::fs::read_dir(dir) {
+        Ok(read) => read,
+        Err(e) => {
+            tracing::warn!("Can't list media directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    let before = entries.len();
+    for entry in read.flatten() {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, &format!("{}{}/", prefix, name), entries);
+        } else if is_audio(&path) {
+            entries.push(format!("{}{}", prefix, name));
+        }
+    }
+    if !prefix.is_empty() && entries.len() > before {
+        entries.push(prefix.to_string());
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.to_str().is_some_and(has_audio_extension)
+}
+
+/// Whether `name`, a file name or the path of a URL, ends in a known audio extension.
+pub fn has_audio_extension(name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// How well `entry` matches `query`, lower is better, `None` if not at all.
+///
+/// Exact names beat substrings, which beat the query's characters appearing
+/// in order. Shorter entries win ties, so `intro` prefers `intro.ogg` over
+/// `old/intro-long.ogg`.
+fn score(query: &str, entry: &str) -> Option<(u8, usize)> {
+    let query = query.trim().to_lowercase();
+    let entry_lower = entry.to_lowercase();
+    let file_name = entry_lower.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _)| stem);
+
+    let rank = if entry_lower.trim_end_matches('/') == query || stem == query {
+        0
+    } else if entry_lower.contains(&query) {
+        1
+    } else {
+        let mut chars = entry_lower.chars();
+        if !query.chars().all(|q| chars.any(|c| c == q)) {
+            return None;
+        }
+        2
+    };
+    Some((rank, entry.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str, files: &[&str]) -> MediaLibrary {
+        let root = std::env::temp_dir().join(format!("voice_bridge_media_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        MediaLibrary::new(root)
+    }
+
+    #[test]
+    fn lists_audio_files_and_their_directories() {
+        let library = library("list", &["intro.ogg", "notes.txt", "jingles/Horn.MP3", "empty/readme.md", ".hidden.ogg"]);
+        assert_eq!(library.entries(), ["intro.ogg", "jingles/", "jingles/Horn.MP3"]);
+        std::fs::remove_dir_all(&library.root).unwrap();
+    }
+
+    #[test]
+    fn fuzzy_matches_prefer_exact_then_substring_then_shortest() {
+        let library = library("match", &["intro.ogg", "old/intro-long.ogg", "jingles/airhorn.ogg", "jingles/tada.wav"]);
+        assert_eq!(library.resolve("intro").unwrap(), ["intro.ogg"]);
+        assert_eq!(library.resolve("INTRO-L").unwrap(), ["old/intro-long.ogg"]);
+        assert_eq!(library.resolve("arhrn").unwrap(), ["jingles/airhorn.ogg"]);
+        assert_eq!(library.resolve("jingles").unwrap(), ["jingles/airhorn.ogg", "jingles/tada.wav"]);
+        assert!(library.resolve("xyz").is_err());
+        assert_eq!(library.suggest("in")[..2], ["jingles/", "intro.ogg"]);
+        std::fs::remove_dir_all(&library.root).unwrap();
+    }
+
+    #[test]
+    fn paths_stay_inside_the_media_directory() {
+        let library = MediaLibrary::new("/srv/media");
+        assert_eq!(library.path("jingles/horn.ogg").unwrap(), Path::new("/srv/media/jingles/horn.ogg"));
+        assert!(library.path("../secret.ogg").is_err());
+        assert!(library.path("/etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Scripted in-process TeamSpeak peer for tests.
+//!
+//! Produces the same [`StreamItem`]s a live `tsclientlib::Connection` would
+//! yield, so the bridge side can be exercised end-to-end without a server.
+
+use std::collections::HashMap;
+
+use futures::prelude::*;
+use slog::{ o, Discard, Logger };
+use tsclientlib::data::Connection as ConnectionState;
+use tsclientlib::events::Event;
+use tsclientlib::{ Identity, InMessage, StreamItem, TemporaryDisconnectReason };
+use tsproto_packets::packets::{ AudioData, CodecType, Direction, Flags, InAudioBuf, OutAudio, OutPacket, PacketType };
+
+/// Builder for a scripted sequence of stream items.
+#[derive(Default)]
+pub struct MockTsPeer {
+    items: Vec<StreamItem>,
+    /// Next audio packet id per sending client.
+    sequence: HashMap<u16, u16>,
+}
+
+impl MockTsPeer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A normal voice packet from client `from`.
+    pub fn audio(self, from: u16, opus: &[u8]) -> Self {
+        self.push_audio(from, |id| AudioData::S2C { id, codec: CodecType::OpusMusic, from, data: opus })
+    }
+
+    /// A whispered voice packet from client `from`.
+    pub fn whisper(self, from: u16, opus: &[u8]) -> Self {
+        self.push_audio(from, |id| AudioData::S2CWhisper {
+            id,
+            codec: CodecType::OpusVoice,
+            from,
+            data: opus,
+        })
+    }
+
+    /// Changes of the server book, as received after connecting or on updates.
+    pub fn book(mut self, events: Vec<Event>) -> Self {
+        self.items.push(StreamItem::BookEvents(events));
+        self
+    }
+
+    /// The server stopped answering, tsclientlib reconnects on its own.
+    pub fn temporary_disconnect(mut self) -> Self {
+        self.items.push(StreamItem::DisconnectedTemporarily(TemporaryDisconnectReason::Timeout("mock")));
+        self
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<StreamItem, tsclientlib::Error>> {
+        stream::iter(self.items.into_iter().map(Ok))
+    }
+
+    fn push_audio<'a>(mut self, from: u16, data: impl FnOnce(u16) -> AudioData<'a>) -> Self {
+        let sequence = self.sequence.entry(from).or_default();
+        let id = *sequence;
+        *sequence = sequence.wrapping_add(1);
+
+        self.items.push(StreamItem::Audio(s2c_packet(&data(id))));
+        self
+    }
+}
+
+/// A server to client audio packet as tsclientlib would hand it out.
+pub fn s2c_packet(data: &AudioData) -> InAudioBuf {
+    let raw = OutAudio::new(data).into_vec();
+    InAudioBuf::try_new(Direction::S2C, raw).expect("Invalid mock audio packet")
+}
+
+/// Server book of a mock connection, kept by tsclientlib's own bookkeeping.
+///
+/// Its changes are the events to script with [`MockTsPeer::book`].
+pub struct MockBook {
+    state: ConnectionState,
+}
+
+impl MockBook {
+    /// Connected as client 1 to an empty server.
+    pub fn new() -> Self {
+        let InMessage::InitServer(init) = parse(concat!(
+            r"initserver virtualserver_name=Mock virtualserver_welcomemessage virtualserver_platform=Linux",
+            r" virtualserver_version=3.13.7\s[Build:\s1655727713] virtualserver_maxclients=32",
+            r" virtualserver_created=0 virtualserver_codec_encryption_mode=0 virtualserver_hostmessage",
+            r" virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8",
+            r" virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url",
+            r" virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000",
+            r" virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url",
+            r" virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0",
+            r" virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0",
+            r" virtualserver_channel_temp_delete_delay_default=0 virtualserver_nickname acn=bridge aclid=1 pv=7",
+            r" client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id=0",
+        )) else {
+            panic!("Mock initserver is no initserver");
+        };
+        Self { state: ConnectionState::new(Identity::create().key().to_pub(), &init) }
+    }
+
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    /// Client `id` called `name` with `talk_power` comes into view.
+    pub fn client_enters(&mut self, id: u16, name: &str, talk_power: i32) -> Vec<Event> {
+        self.apply(&format!(
+            concat!(
+                r"notifycliententerview reasonid=0 ctid=1 clid={id} client_database_id={id} client_nickname={name}",
+                r" client_type=0 cfid=0 client_unique_identifier=mock{id:04}AAAAAAAAAAAAAAAAAAA= client_flag_avatar",
+                r" client_description client_icon_id=0 client_input_muted=0 client_output_muted=0",
+                r" client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data",
+                r" client_is_recording=0 client_channel_group_id=8 client_channel_group_inherited_channel_id=1",
+                r" client_servergroups=8 client_away=0 client_away_message client_talk_power={talk_power}",
+                r" client_talk_request=0 client_talk_request_msg client_is_talker=0 client_is_priority_speaker=0",
+                r" client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=0",
+                r" client_is_channel_commander=0 client_country client_badges client_myteamspeak_id",
+                r" client_integrations",
+            ),
+            id = id,
+            name = name.replace(' ', r"\s"),
+            talk_power = talk_power,
+        ))
+    }
+
+    /// Client `id` now has `talk_power`.
+    pub fn set_talk_power(&mut self, id: u16, talk_power: i32) -> Vec<Event> {
+        self.apply(&format!("notifyclientupdated clid={} client_talk_power={}", id, talk_power))
+    }
+
+    fn apply(&mut self, command: &str) -> Vec<Event> {
+        self.state.handle_command(&parse(command)).expect("Mock book command not applied").0
+    }
+}
+
+fn parse(command: &str) -> InMessage {
+    let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+    InMessage::new(&Logger::root(Discard, o!()), &header.header(), command.as_bytes()).expect("Invalid mock command")
+}
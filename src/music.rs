@@ -0,0 +1,1296 @@
+//! Music queue for `/play`, one per guild.
+//!
+//! Tracks are streamed over HTTP, through yt-dlp if configured, or read from
+//! the media directory. They are decoded here rather than by songbird,
+//! so the same audio can be played to Discord and fed to TeamSpeak through
+//! a [`MusicFeed`]. The queued tracks are mirrored into the settings file,
+//! so a queue picks up again when the bridge rejoins, even after a restart.
+
+use std::collections::{ HashMap, VecDeque };
+use std::fmt;
+use std::io::{ Read, Seek, SeekFrom };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use anyhow::{ anyhow, bail, Result };
+use byte_slice_cast::AsByteSlice;
+use serde::{ Deserialize, Serialize };
+use serenity::async_trait;
+use songbird::events::{ Event, EventContext, EventData, EventHandler, TrackEvent };
+use songbird::input::codecs::{ get_codec_registry, get_probe };
+use songbird::input::{
+    AudioStream,
+    AudioStreamError,
+    AuxMetadata,
+    Compose,
+    File,
+    HttpRequest,
+    Input,
+    LiveInput,
+    Parsed,
+    RawAdapter,
+    YoutubeDl,
+};
+use songbird::tracks::{ PlayMode, ReadyState, Track, TrackHandle, TrackQueue };
+use songbird::{ Call, Songbird };
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{ SeekMode, SeekTo };
+use symphonia::core::io::MediaSource;
+use symphonia::core::meta::{ MetadataRevision, StandardTagKey };
+use symphonia::core::units::Time;
+
+use crate::audio::{ to_stereo, Ducker, DuckingConfig, FrameDuration, LinearResampler };
+use crate::loudness::{ parse_gain_db, Normalizer };
+use crate::media::{ has_audio_extension, MediaLibrary, FILE_PREFIX };
+use crate::routes::{ Route, RouteGains };
+use crate::settings::SharedSettings;
+use crate::teamspeak::{ TsCommand, TsCommandSender };
+use crate::SAMPLE_RATE;
+
+/// Header `RawAdapter` puts before the samples, included in the positions it seeks to.
+const RAW_HEADER_BYTES: u64 = 16;
+
+/// Songbird starts loading the next track this long before the current one ends.
+const PRELOAD: Duration = Duration::from_secs(5);
+
+/// Upper bound of music waiting for TeamSpeak, half a second.
+///
+/// Songbird reads ahead a little, more than this means TeamSpeak isn't
+/// taking any.
+const MAX_FEED_SAMPLES: usize = SAMPLE_RATE;
+
+/// How often the playing tracks are checked for stalled streams.
+const STALL_CHECK: Duration = Duration::from_secs(2);
+/// A track that didn't move on for this long is skipped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gain of the music in TeamSpeak if not configured.
+pub const DEFAULT_MUSIC_GAIN: f32 = 1.0;
+
+/// Playlists are cut off after this many entries if not configured otherwise.
+pub const DEFAULT_MAX_PLAYLIST_LENGTH: usize = 50;
+
+/// `[now_playing]` section, what TeamSpeak is told when the queue advances.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NowPlayingConfig {
+    /// Say "Now playing: …" in the channel chat, on by default.
+    pub chat: bool,
+    /// Show the playing track as the description of the bridge's channel, replacing it.
+    pub description: bool,
+}
+
+impl Default for NowPlayingConfig {
+    fn default() -> Self {
+        Self { chat: true, description: false }
+    }
+}
+
+/// What `/loop` repeats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum LoopMode {
+    #[default]
+    #[name = "off"]
+    Off,
+    /// The playing track, over and over.
+    #[name = "track"]
+    Track,
+    /// Finished tracks go to the back of the queue.
+    #[name = "queue"]
+    Queue,
+}
+
+/// A queued track, also how it is saved to the settings file.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TrackInfo {
+    pub url: String,
+    pub title: String,
+    #[serde(default)]
+    pub artist: Option<String>,
+    /// Length in seconds, unknown for streams.
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    /// Display name of who queued it.
+    pub requested_by: String,
+}
+
+impl TrackInfo {
+    /// A track known only by its URL so far.
+    fn new(url: String, requested_by: String) -> Self {
+        Self {
+            title: title_from_url(url.strip_prefix(FILE_PREFIX).unwrap_or(&url)),
+            url,
+            artist: None,
+            duration_secs: None,
+            requested_by,
+        }
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration_secs.map(Duration::from_secs)
+    }
+
+    /// Artist and title, without the length.
+    pub fn name(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.title),
+            None => self.title.clone(),
+        }
+    }
+
+    /// What TeamSpeak is told when it starts playing.
+    pub fn now_playing(&self) -> String {
+        format!("Now playing: {}, requested by {}", self, self.requested_by)
+    }
+}
+
+impl fmt::Display for TrackInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(duration) = self.duration() {
+            write!(f, " [{}]", format_duration(duration))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// The track currently playing in a guild.
+pub struct NowPlaying {
+    pub track: TrackInfo,
+    pub position: Duration,
+    pub paused: bool,
+    pub looping: LoopMode,
+}
+
+/// `1:02:03`, `2:03` or `123` as a position in a track.
+pub fn parse_timestamp(text: &str) -> Option<Duration> {
+    let parts: Vec<_> = text.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    let mut secs = 0u64;
+    for (i, part) in parts.iter().enumerate() {
+        let value: u64 = part.parse().ok()?;
+        if i > 0 && value >= 60 {
+            return None;
+        }
+        secs = secs.checked_mul(60)?.checked_add(value)?;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// Music played to Discord, on its way to TeamSpeak as 48 kHz interleaved stereo.
+#[derive(Clone, Debug)]
+pub struct MusicFeed {
+    samples: Arc<StdMutex<VecDeque<f32>>>,
+    /// `/music-volume` as `f32` bits, applied on top of the TeamSpeak gain.
+    volume: Arc<AtomicU32>,
+}
+
+impl Default for MusicFeed {
+    fn default() -> Self {
+        Self {
+            samples: Default::default(),
+            volume: Arc::new(AtomicU32::new((1.0f32).to_bits())),
+        }
+    }
+}
+
+impl MusicFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    fn push(&self, samples: &[f32]) {
+        let mut queued = self.samples.lock().expect("Can't lock music feed!");
+        queued.extend(samples);
+        if queued.len() > MAX_FEED_SAMPLES {
+            let excess = queued.len() - MAX_FEED_SAMPLES;
+            queued.drain(..excess);
+        }
+    }
+
+    /// Add the next `out.len()` samples to `out`, times the music volume and
+    /// a gain going from `start` to `end` over the frame.
+    fn mix_into(&self, out: &mut [f32], start: f32, end: f32) {
+        let volume = self.volume();
+        let mut queued = self.samples.lock().expect("Can't lock music feed!");
+        let n = out.len().min(queued.len());
+        let step = (end - start) / (out.len().max(1) as f32);
+        for (i, (sample, music)) in out.iter_mut().zip(queued.drain(..n)).enumerate() {
+            *sample += music * volume * (start + step * (i as f32));
+        }
+    }
+}
+
+/// Mixes the music into the Discord voices sent to TeamSpeak.
+pub struct MusicMix {
+    feed: MusicFeed,
+    /// Music is mixed at the music to TeamSpeak route.
+    routes: RouteGains,
+    ducker: Ducker,
+}
+
+impl MusicMix {
+    pub fn new(feed: MusicFeed, routes: RouteGains, ducking: DuckingConfig) -> Self {
+        Self { feed, routes, ducker: Ducker::new(ducking) }
+    }
+
+    pub fn set_ducking(&mut self, ducking: DuckingConfig) {
+        self.ducker.set_config(ducking);
+    }
+
+    /// Add one frame of music to `voice`, ducked while somebody talks.
+    pub fn mix_into(&mut self, voice: &mut [f32], frame: FrameDuration) {
+        let (start, end) = self.ducker.process(voice, frame);
+        let gain = self.routes.gain(Route::MusicToTs);
+        self.feed.mix_into(voice, start * gain, end * gain);
+    }
+}
+
+/// What `/play` added to a queue.
+pub struct Queued {
+    pub tracks: Vec<TrackInfo>,
+    /// A playlist had more entries than allowed.
+    pub cut_off: bool,
+}
+
+#[derive(Clone, Copy)]
+struct YtDlp {
+    program: &'static str,
+    max_playlist_length: usize,
+}
+
+/// Music queues of all guilds, cheap to clone.
+#[derive(Clone)]
+pub struct MusicQueues {
+    http: reqwest::Client,
+    settings: SharedSettings,
+    feed: MusicFeed,
+    library: Option<MediaLibrary>,
+    yt_dlp: Option<YtDlp>,
+    /// Tracks play to Discord at the music to Discord route.
+    routes: RouteGains,
+    /// Where the playing track is announced, see [`with_now_playing`](Self::with_now_playing).
+    now_playing: Option<(TsCommandSender, NowPlayingConfig)>,
+    /// Track last announced per guild, so resuming it isn't announced again.
+    announced: Arc<StdMutex<HashMap<u64, TrackHandle>>>,
+    /// Set by `/loop` per guild, off if unset.
+    loops: Arc<StdMutex<HashMap<u64, LoopMode>>>,
+    /// Notices about tracks that failed to play, for [`watch`](Self::watch) to pass on.
+    failures: Arc<StdMutex<Vec<String>>>,
+    /// Calls to queue finished tracks again in, while the queue loops.
+    songbird: Option<Arc<Songbird>>,
+    /// Even out the loudness of tracks, see [`crate::loudness`].
+    normalize: bool,
+    queues: Arc<StdMutex<HashMap<u64, TrackQueue>>>,
+}
+
+impl MusicQueues {
+    pub fn new(settings: SharedSettings, feed: MusicFeed, library: Option<MediaLibrary>) -> Self {
+        if let Some(volume) = settings.lock().expect("Can't lock settings!").get().music_volume {
+            feed.set_volume(volume);
+        }
+        Self {
+            http: reqwest::Client::new(),
+            settings,
+            feed,
+            library,
+            yt_dlp: None,
+            routes: RouteGains::default(),
+            now_playing: None,
+            announced: Default::default(),
+            loops: Default::default(),
+            failures: Default::default(),
+            songbird: None,
+            normalize: true,
+            queues: Default::default(),
+        }
+    }
+
+    /// Play everything yt-dlp understands, including playlists.
+    pub fn with_yt_dlp(mut self, program: String, max_playlist_length: usize) -> Self {
+        // Songbird borrows the program name for as long as tracks may play
+        let program = Box::leak(program.into_boxed_str());
+        self.yt_dlp = Some(YtDlp { program, max_playlist_length });
+        self
+    }
+
+    pub fn with_routes(mut self, routes: RouteGains) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Play tracks as loud as they are, without evening them out.
+    pub fn without_normalization(mut self) -> Self {
+        self.normalize = false;
+        self
+    }
+
+    /// Find the calls in `songbird`, needed for `/loop queue`.
+    pub fn with_songbird(mut self, songbird: Arc<Songbird>) -> Self {
+        self.songbird = Some(songbird);
+        self
+    }
+
+    /// Tell TeamSpeak through `commands` whenever a track starts.
+    pub fn with_now_playing(mut self, commands: TsCommandSender, config: NowPlayingConfig) -> Self {
+        if config.chat || config.description {
+            self.now_playing = Some((commands, config));
+        }
+        self
+    }
+
+    pub fn library(&self) -> Option<&MediaLibrary> {
+        self.library.as_ref()
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.feed.volume()
+    }
+
+    /// Change the volume of the music in Discord and TeamSpeak, without
+    /// touching the bridged voices.
+    pub fn set_volume(&self, volume: f32) -> Result<()> {
+        self.feed.set_volume(volume);
+        self.refresh_track_volume();
+        self.settings.lock().expect("Can't lock settings!").update(|s| s.music_volume = Some(volume))?;
+        Ok(())
+    }
+
+    /// Volume of tracks in Discord, the music volume at the music to Discord route.
+    fn track_volume(&self) -> f32 {
+        self.feed.volume() * self.routes.gain(Route::MusicToDiscord)
+    }
+
+    /// Apply a changed music volume or route to the queued tracks.
+    pub fn refresh_track_volume(&self) {
+        let volume = self.track_volume();
+        let queues: Vec<_> = self.queues.lock().expect("Can't lock music queues!").values().cloned().collect();
+        for handle in queues.iter().flat_map(TrackQueue::current_queue) {
+            // Fails for tracks that already ended, nothing to change there
+            let _ = handle.set_volume(volume);
+        }
+    }
+
+    fn queue(&self, guild: u64) -> TrackQueue {
+        self.queues.lock().expect("Can't lock music queues!").entry(guild).or_default().clone()
+    }
+
+    /// Append `url` to the queue of `guild`.
+    ///
+    /// `file:<name>` plays from the media directory instead, all files
+    /// below it if `name` matches a directory. Playlists are expanded with
+    /// yt-dlp. A single track is opened right away to check it plays and
+    /// to read its tags, several are opened when it's their turn.
+    pub async fn play(&self, guild: u64, call: &mut Call, url: String, requested_by: String) -> Result<Queued> {
+        let mut cut_off = false;
+        let mut tracks = match url.strip_prefix(FILE_PREFIX) {
+            Some(query) => {
+                self.library()
+                    .ok_or_else(|| anyhow!("No media directory configured"))?
+                    .resolve(query)?
+                    .into_iter()
+                    .map(|name| TrackInfo::new(format!("{}{}", FILE_PREFIX, name), requested_by.clone()))
+                    .collect()
+            }
+            None if !is_web_link(&url) => bail!("Only http(s) links can be played"),
+            None => {
+                match self.playlist(&url, &requested_by).await? {
+                    Some(playlist) => {
+                        cut_off = playlist.cut_off;
+                        playlist.tracks
+                    }
+                    None => vec![TrackInfo::new(url, requested_by)],
+                }
+            }
+        };
+
+        if let [info] = tracks.as_mut_slice() {
+            let input = self.open(info).await?;
+            self.enqueue(guild, call, input, info.clone());
+        } else {
+            for info in &tracks {
+                self.enqueue(guild, call, self.lazy(&info.url)?, info.clone());
+            }
+        }
+        self.save(guild, None);
+        Ok(Queued { tracks, cut_off })
+    }
+
+    /// Where the audio of `url` comes from.
+    fn source(&self, url: &str) -> Result<Box<dyn Compose>> {
+        if let Some(name) = url.strip_prefix(FILE_PREFIX) {
+            let library = self.library().ok_or_else(|| anyhow!("No media directory configured"))?;
+            return Ok(Box::new(File::new(library.path(name)?)));
+        }
+        if !is_web_link(url) {
+            bail!("Only http(s) links can be played");
+        }
+        Ok(match self.yt_dlp {
+            Some(yt_dlp) if !is_direct(url) => {
+                Box::new(YoutubeDl::new_ytdl_like(yt_dlp.program, self.http.clone(), url.to_string()))
+            }
+            _ => Box::new(HttpRequest::new(self.http.clone(), url.to_string())),
+        })
+    }
+
+    /// `url` as a track opened once songbird is about to play it.
+    fn lazy(&self, url: &str) -> Result<Input> {
+        let source = TeeCompose { inner: self.source(url)?, feed: self.feed.clone(), normalize: self.normalize };
+        Ok(Input::Lazy(Box::new(source)))
+    }
+
+    /// The entries of `url` if yt-dlp says it is a playlist.
+    async fn playlist(&self, url: &str, requested_by: &str) -> Result<Option<Queued>> {
+        let yt_dlp = match self.yt_dlp {
+            Some(yt_dlp) if !is_direct(url) => yt_dlp,
+            _ => return Ok(None),
+        };
+        let output = tokio::process::Command
+            ::new(yt_dlp.program)
+            .args(["-J", "--flat-playlist", "--playlist-end"])
+            .arg((yt_dlp.max_playlist_length + 1).to_string())
+            .arg("--")
+            .arg(url)
+            .output().await
+            .map_err(|e| anyhow!("Can't run {}: {}", yt_dlp.program, e))?;
+        if !output.status.success() {
+            bail!("Can't play {}: {}", url, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        parse_playlist(&output.stdout, requested_by, yt_dlp.max_playlist_length)
+    }
+
+    /// Start streaming `info.url`, filling in what its tags tell about it.
+    async fn open(&self, info: &mut TrackInfo) -> Result<Input> {
+        let input = Input::Lazy(self.source(&info.url)?)
+            .make_playable_async(get_codec_registry(), get_probe()).await
+            .map_err(|e| anyhow!("Can't play {}: {}", info.url, e))?;
+        let mut parsed = match input {
+            Input::Live(LiveInput::Parsed(parsed), _) => parsed,
+            _ => bail!("Can't decode {}", info.url),
+        };
+        apply_metadata(info, &mut parsed);
+        let sample_rate = parsed.decoder
+            .codec_params()
+            .sample_rate.ok_or_else(|| anyhow!("Unknown sample rate of {}", info.url))?;
+        let source = TeeSource::new(parsed, sample_rate, self.feed.clone(), self.normalize);
+        Ok(RawAdapter::new(source, sample_rate, 2).into())
+    }
+
+    fn enqueue(&self, guild: u64, call: &mut Call, input: Input, info: TrackInfo) {
+        let preload = info.duration().map(|d| d.saturating_sub(PRELOAD));
+        let mut track = Track::new_with_data(input, Arc::new(info)).volume(self.track_volume());
+        track.events.add_event(
+            EventData::new(Event::Track(TrackEvent::End), TrackEnded {
+                music: self.clone(),
+                guild,
+            }),
+            Duration::ZERO
+        );
+        track.events.add_event(
+            EventData::new(Event::Track(TrackEvent::Play), TrackStarted {
+                music: self.clone(),
+                guild,
+            }),
+            Duration::ZERO
+        );
+        self.queue(guild).add_with_preload(track, call, preload);
+    }
+
+    /// Queue the finished track `handle` of `guild` again, at the back.
+    async fn requeue(&self, guild: u64, handle: &TrackHandle) -> Result<()> {
+        let call = self.songbird
+            .as_ref()
+            .and_then(|songbird| songbird.get(serenity::model::id::GuildId::new(guild)))
+            .ok_or_else(|| anyhow!("Not in a call"))?;
+        let info = TrackInfo::clone(&handle.data());
+        let input = self.lazy(&info.url)?;
+        self.enqueue(guild, &mut *call.lock().await, input, info);
+        Ok(())
+    }
+
+    pub fn loop_mode(&self, guild: u64) -> LoopMode {
+        self.loops.lock().expect("Can't lock loop modes!").get(&guild).copied().unwrap_or_default()
+    }
+
+    /// Repeat the playing track or the whole queue of `guild`, or stop repeating.
+    pub fn set_loop(&self, guild: u64, mode: LoopMode) -> Result<()> {
+        if mode == LoopMode::Queue && self.songbird.is_none() {
+            bail!("Can't loop the queue");
+        }
+        self.loops.lock().expect("Can't lock loop modes!").insert(guild, mode);
+        if let Some(current) = self.queue(guild).current() {
+            // Fails for a track that already ended, the next one picks the mode up
+            let _ = apply_loop(mode, &current);
+        }
+        Ok(())
+    }
+
+    /// Jump to `position` in the playing track of `guild`, returning the track.
+    pub async fn seek(&self, guild: u64, position: Duration) -> Result<TrackInfo> {
+        let handle = self.queue(guild).current().ok_or_else(|| anyhow!("Nothing is playing"))?;
+        let info = TrackInfo::clone(&handle.data());
+        if let Some(duration) = info.duration().filter(|duration| position >= *duration) {
+            bail!("{} is only {} long", info.name(), format_duration(duration));
+        }
+        handle
+            .seek_async(position).await
+            .map_err(|e| anyhow!("Can't seek in {}: {}", info.name(), e))?;
+        Ok(info)
+    }
+
+    /// Announce `handle` in TeamSpeak, unless it already was.
+    fn announce(&self, guild: u64, handle: &TrackHandle) {
+        let (commands, config) = match &self.now_playing {
+            Some(now_playing) => now_playing,
+            None => return,
+        };
+        let previous = self.announced.lock().expect("Can't lock announced tracks!").insert(guild, handle.clone());
+        if previous.is_some_and(|previous| previous.uuid() == handle.uuid()) {
+            return;
+        }
+        let text = handle.data::<TrackInfo>().now_playing();
+        if config.description {
+            let _ = commands.send(TsCommand::SetChannelDescription { description: text.clone() });
+        }
+        if config.chat {
+            let _ = commands.send(TsCommand::Announce { text });
+        }
+    }
+
+    /// Clear the channel description once the last track of `guild` ended.
+    fn announce_ended(&self, guild: u64, ended: &TrackHandle) {
+        let description = self.now_playing.as_ref().filter(|(_, config)| config.description);
+        let (commands, _) = match description {
+            Some(now_playing) => now_playing,
+            None => return,
+        };
+        let more = self.queue(guild).current_queue().iter().any(|handle| handle.uuid() != ended.uuid());
+        if !more {
+            self.announced.lock().expect("Can't lock announced tracks!").remove(&guild);
+            let _ = commands.send(TsCommand::SetChannelDescription { description: String::new() });
+        }
+    }
+
+    /// Queue the saved tracks of `guild` again after joining it.
+    ///
+    /// Replaces whatever was queued before, tracks of an earlier call can't
+    /// play anymore.
+    pub fn restore(&self, guild: u64, call: &mut Call) {
+        let saved = self.settings
+            .lock()
+            .expect("Can't lock settings!")
+            .get()
+            .music_queues.get(&guild)
+            .cloned()
+            .unwrap_or_default();
+        let old = self.queues.lock().expect("Can't lock music queues!").insert(guild, TrackQueue::new());
+        if let Some(old) = old {
+            old.stop();
+        }
+        if !saved.is_empty() {
+            tracing::info!("Restoring {} queued tracks for guild {}", saved.len(), guild);
+        }
+        for info in saved {
+            match self.lazy(&info.url) {
+                Ok(input) => self.enqueue(guild, call, input, info),
+                Err(e) => tracing::warn!("Dropping queued track: {}", e),
+            }
+        }
+    }
+
+    /// Tracks queued in `guild`, the playing one first.
+    pub fn tracks(&self, guild: u64) -> Vec<TrackInfo> {
+        self.queue(guild)
+            .current_queue()
+            .iter()
+            .map(|handle| TrackInfo::clone(&handle.data()))
+            .collect()
+    }
+
+    pub async fn now_playing(&self, guild: u64) -> Option<NowPlaying> {
+        let handle = self.queue(guild).current()?;
+        let state = handle.get_info().await.ok()?;
+        Some(NowPlaying {
+            track: TrackInfo::clone(&handle.data()),
+            position: state.position,
+            paused: state.playing == PlayMode::Pause,
+            looping: self.loop_mode(guild),
+        })
+    }
+
+    /// Skip the playing track, returning it.
+    pub fn skip(&self, guild: u64) -> Result<Option<TrackInfo>> {
+        let queue = self.queue(guild);
+        let current = match queue.current() {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        queue.skip()?;
+        self.save(guild, Some(&current));
+        Ok(Some(TrackInfo::clone(&current.data())))
+    }
+
+    /// Skip tracks whose stream stalled or failed, telling `notify` about it.
+    ///
+    /// A stream stalls when the playing track doesn't move on, or songbird
+    /// doesn't even answer about it, while it's neither paused nor loading.
+    pub async fn watch(self, notify: impl Fn(&str)) {
+        let mut interval = tokio::time::interval(STALL_CHECK);
+        let mut stalls = StallWatch::default();
+        loop {
+            interval.tick().await;
+            let failures = std::mem::take(&mut *self.failures.lock().expect("Can't lock track failures!"));
+            for notice in failures {
+                notify(&notice);
+            }
+
+            let queues: Vec<_> = self.queues
+                .lock()
+                .expect("Can't lock music queues!")
+                .iter()
+                .map(|(guild, queue)| (*guild, queue.clone()))
+                .collect();
+            for (guild, queue) in queues {
+                let handle = match queue.current() {
+                    Some(handle) => handle,
+                    None => {
+                        stalls.forget(guild);
+                        continue;
+                    }
+                };
+                let position = match tokio::time::timeout(STALL_CHECK, handle.get_info()).await {
+                    Ok(Ok(state)) if state.playing == PlayMode::Play && state.ready == ReadyState::Playable => {
+                        Some(state.position)
+                    }
+                    Ok(_) => {
+                        stalls.forget(guild);
+                        continue;
+                    }
+                    Err(_) => None,
+                };
+                if stalls.stalled(guild, handle.uuid().as_u128(), position, Instant::now()) {
+                    stalls.forget(guild);
+                    match self.skip(guild) {
+                        Ok(Some(track)) => {
+                            notify(&format!(
+                                "Skipped {}, its stream stalled for {} seconds.",
+                                track.name(),
+                                STALL_TIMEOUT.as_secs()
+                            ));
+                        }
+                        Ok(None) => {}
+                        Err(e) => tracing::warn!("Can't skip a stalled track: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shuffle the queue of `guild` behind the playing track, returning
+    /// how many tracks were shuffled.
+    pub fn shuffle(&self, guild: u64) -> usize {
+        let queue = self.queue(guild);
+        let shuffled = queue.modify_queue(|tracks| {
+            if tracks.len() > 2 {
+                shuffle(tracks.make_contiguous()[1..].as_mut());
+            }
+            tracks.len().saturating_sub(1)
+        });
+        self.save(guild, None);
+        shuffled
+    }
+
+    pub fn pause(&self, guild: u64) -> Result<()> {
+        Ok(self.queue(guild).pause()?)
+    }
+
+    pub fn resume(&self, guild: u64) -> Result<()> {
+        Ok(self.queue(guild).resume()?)
+    }
+
+    /// Write the queue of `guild` to the settings file, without `finished`.
+    fn save(&self, guild: u64, finished: Option<&TrackHandle>) {
+        let tracks: Vec<_> = self
+            .queue(guild)
+            .current_queue()
+            .iter()
+            .filter(|handle| finished.is_none_or(|finished| finished.uuid() != handle.uuid()))
+            .map(|handle| TrackInfo::clone(&handle.data()))
+            .collect();
+        let saved = self.settings.lock().expect("Can't lock settings!").update(|s| {
+            if tracks.is_empty() {
+                s.music_queues.remove(&guild);
+            } else {
+                s.music_queues.insert(guild, tracks);
+            }
+        });
+        if let Err(e) = saved {
+            tracing::error!("Failed to save music queue: {}", e);
+        }
+    }
+}
+
+/// Where the playing tracks were last seen moving on, by guild.
+#[derive(Debug, Default)]
+struct StallWatch {
+    /// Track id, its position and since when it is there.
+    tracks: HashMap<u64, (u128, Duration, Instant)>,
+}
+
+impl StallWatch {
+    /// Record where `track` of `guild` is, true once it stood still for [`STALL_TIMEOUT`].
+    ///
+    /// An unknown `position` is taken as standing still.
+    fn stalled(&mut self, guild: u64, track: u128, position: Option<Duration>, now: Instant) -> bool {
+        let (seen, at, since) = self.tracks.entry(guild).or_insert((track, position.unwrap_or_default(), now));
+        let moved = *seen != track || position.is_some_and(|position| position != *at);
+        if moved {
+            *seen = track;
+            *at = position.unwrap_or_default();
+            *since = now;
+        }
+        now.duration_since(*since) >= STALL_TIMEOUT
+    }
+
+    fn forget(&mut self, guild: u64) {
+        self.tracks.remove(&guild);
+    }
+}
+
+/// Drops finished tracks from the saved queue.
+///
+/// Stopped tracks are left alone, they are stopped when the bridge leaves
+/// and should play again after it rejoins. Skipping saves by itself.
+struct TrackEnded {
+    music: MusicQueues,
+    guild: u64,
+}
+
+#[async_trait]
+impl EventHandler for TrackEnded {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (state, handle) in tracks.iter() {
+                let looping = self.music.loop_mode(self.guild) == LoopMode::Queue;
+                if looping && state.playing == PlayMode::End {
+                    if let Err(e) = self.music.requeue(self.guild, handle).await {
+                        tracing::warn!("Can't queue a finished track again: {}", e);
+                    }
+                }
+                if let PlayMode::Errored(e) = &state.playing {
+                    let notice = format!("Skipped {}, it failed to play: {}", handle.data::<TrackInfo>().name(), e);
+                    self.music.failures.lock().expect("Can't lock track failures!").push(notice);
+                }
+                if matches!(state.playing, PlayMode::End | PlayMode::Errored(_)) {
+                    self.music.save(self.guild, Some(handle));
+                }
+                self.music.announce_ended(self.guild, handle);
+            }
+        }
+        None
+    }
+}
+
+/// Make `handle` repeat if `mode` loops single tracks, or stop it repeating.
+fn apply_loop(mode: LoopMode, handle: &TrackHandle) -> songbird::tracks::TrackResult<()> {
+    if mode == LoopMode::Track { handle.enable_loop() } else { handle.disable_loop() }
+}
+
+/// Loops a track that started playing if `/loop track` is on, and tells TeamSpeak about it.
+struct TrackStarted {
+    music: MusicQueues,
+    guild: u64,
+}
+
+#[async_trait]
+impl EventHandler for TrackStarted {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            for (_, handle) in tracks.iter() {
+                if let Err(e) = apply_loop(self.music.loop_mode(self.guild), handle) {
+                    tracing::debug!("Can't loop the started track: {}", e);
+                }
+                self.music.announce(self.guild, handle);
+            }
+        }
+        None
+    }
+}
+
+/// Decodes a track for songbird and copies the audio into the [`MusicFeed`].
+///
+/// Songbird gets stereo at the original sample rate and resamples itself,
+/// the feed gets it at 48 kHz.
+struct TeeSource {
+    parsed: Parsed,
+    sample_rate: u32,
+    feed: MusicFeed,
+    normalizer: Option<Normalizer>,
+    resampler: LinearResampler,
+    /// Frames decoded before the position sought to, dropped.
+    skip_frames: u64,
+    samples: Option<SampleBuffer<f32>>,
+    stereo: Vec<f32>,
+    resampled: Vec<f32>,
+    /// Decoded bytes not read by songbird yet, from `read_pos` on.
+    pending: Vec<u8>,
+    read_pos: usize,
+}
+
+impl TeeSource {
+    fn new(mut parsed: Parsed, sample_rate: u32, feed: MusicFeed, normalize: bool) -> Self {
+        let normalizer = normalize.then(|| {
+            let (gain_db, peak) = replay_gain(&mut parsed);
+            Normalizer::new(sample_rate, gain_db, peak)
+        });
+        Self {
+            parsed,
+            sample_rate,
+            feed,
+            normalizer,
+            resampler: LinearResampler::new(sample_rate),
+            skip_frames: 0,
+            samples: None,
+            stereo: Vec::new(),
+            resampled: Vec::new(),
+            pending: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    /// Decode the next packet into `pending`, `false` at the end of the track.
+    fn decode_next(&mut self) -> std::io::Result<bool> {
+        loop {
+            let packet = match self.parsed.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(false);
+                }
+                Err(e) => return Err(std::io::Error::other(e)),
+            };
+            if packet.track_id() != self.parsed.track_id {
+                continue;
+            }
+            let decoded = match self.parsed.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    tracing::debug!("Skipping undecodable music packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(std::io::Error::other(e)),
+            };
+
+            let spec = *decoded.spec();
+            let needed = decoded.capacity() as u64;
+            let samples = match &mut self.samples {
+                Some(samples) if samples.capacity() as u64 >= needed * (spec.channels.count() as u64) => samples,
+                samples => samples.insert(SampleBuffer::new(needed, spec)),
+            };
+            samples.copy_interleaved_ref(decoded);
+
+            self.stereo.clear();
+            to_stereo(samples.samples(), spec.channels.count(), &mut self.stereo);
+            let skipped = self.skip_frames.min((self.stereo.len() / 2) as u64);
+            self.stereo.drain(..(skipped as usize) * 2);
+            self.skip_frames -= skipped;
+            if self.stereo.is_empty() {
+                continue;
+            }
+            if let Some(normalizer) = &mut self.normalizer {
+                normalizer.process(&mut self.stereo);
+            }
+            self.resampled.clear();
+            self.resampler.process(&self.stereo, &mut self.resampled);
+            self.feed.push(&self.resampled);
+
+            self.pending.clear();
+            self.pending.extend_from_slice(self.stereo.as_byte_slice());
+            self.read_pos = 0;
+            return Ok(true);
+        }
+    }
+}
+
+impl Read for TeeSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read_pos >= self.pending.len() {
+            if !self.decode_next()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.pending[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Seek for TeeSource {
+    /// Seek the decoder to the time of the stereo sample at `pos`, for `/seek`.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(pos) => pos.saturating_sub(RAW_HEADER_BYTES),
+            _ => return Err(std::io::ErrorKind::Unsupported.into()),
+        };
+        let rate = u64::from(self.sample_rate);
+        let frame = offset / (2 * std::mem::size_of::<f32>() as u64);
+        let time = Time::new(frame / rate, (frame % rate) as f64 / rate as f64);
+        let track_id = self.parsed.track_id;
+        let seeked = self.parsed.format
+            .seek(SeekMode::Accurate, SeekTo::Time { time, track_id: Some(track_id) })
+            .map_err(std::io::Error::other)?;
+        self.parsed.decoder.reset();
+
+        // Formats land at or before the time asked for, the rest is decoded and dropped
+        let time_base = self.parsed.format
+            .tracks()
+            .iter()
+            .find(|track| track.id == track_id)
+            .and_then(|track| track.codec_params.time_base);
+        self.skip_frames = match time_base {
+            Some(time_base) => {
+                let early = time_base.calc_time(seeked.required_ts.saturating_sub(seeked.actual_ts));
+                early.seconds * rate + (early.frac * rate as f64) as u64
+            }
+            None => 0,
+        };
+        self.resampler = LinearResampler::new(self.sample_rate);
+        self.pending.clear();
+        self.read_pos = 0;
+        Ok(offset)
+    }
+}
+
+impl MediaSource for TeeSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Opens a source and sets up a [`TeeSource`] when songbird first needs it.
+struct TeeCompose {
+    inner: Box<dyn Compose>,
+    feed: MusicFeed,
+    normalize: bool,
+}
+
+#[async_trait]
+impl Compose for TeeCompose {
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+
+    async fn create_async(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        let stream = if self.inner.should_create_async() {
+            self.inner.create_async().await?
+        } else {
+            self.inner.create()?
+        };
+        let parsed = tokio::task
+            ::spawn_blocking(move || LiveInput::Raw(stream).promote(get_codec_registry(), get_probe())).await
+            .map_err(|e| AudioStreamError::Fail(e.into()))?
+            .map_err(|e| AudioStreamError::Fail(e.into()))?;
+        let parsed = match parsed {
+            LiveInput::Parsed(parsed) => parsed,
+            _ => return Err(AudioStreamError::Unsupported),
+        };
+        let sample_rate = parsed.decoder
+            .codec_params()
+            .sample_rate.ok_or_else(|| AudioStreamError::Fail("unknown sample rate".into()))?;
+        let source = TeeSource::new(parsed, sample_rate, self.feed.clone(), self.normalize);
+        Ok(AudioStream {
+            input: Box::new(RawAdapter::new(source, sample_rate, 2)),
+            hint: None,
+        })
+    }
+
+    fn should_create_async(&self) -> bool {
+        true
+    }
+
+    async fn aux_metadata(&mut self) -> Result<AuxMetadata, AudioStreamError> {
+        self.inner.aux_metadata().await
+    }
+}
+
+/// Shuffle in place, without pulling in a random number crate for it.
+fn shuffle<T>(items: &mut [T]) {
+    let mut rng = std::time::SystemTime
+        ::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default() | 1;
+    for i in (1..items.len()).rev() {
+        // xorshift64
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        items.swap(i, (rng % ((i as u64) + 1)) as usize);
+    }
+}
+
+/// Whether `url` is an http(s) link, nothing yt-dlp could take for an option.
+fn is_web_link(url: &str) -> bool {
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+    matches!(scheme.as_deref(), Some("http" | "https"))
+}
+
+/// Whether `url` points right at an audio file, rather than a page yt-dlp has to look at.
+fn is_direct(url: &str) -> bool {
+    url.split(['?', '#']).next().is_some_and(has_audio_extension)
+}
+
+/// The entries of yt-dlp's `-J --flat-playlist` output, `None` for a single video.
+fn parse_playlist(json: &[u8], requested_by: &str, max_length: usize) -> Result<Option<Queued>> {
+    let playlist: serde_json::Value = serde_json::from_slice(json)?;
+    if playlist["_type"] != "playlist" {
+        return Ok(None);
+    }
+    let mut tracks: Vec<_> = playlist["entries"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entry| {
+            let url = entry["url"].as_str().or_else(|| entry["webpage_url"].as_str())?;
+            let mut info = TrackInfo::new(url.to_string(), requested_by.to_string());
+            if let Some(title) = entry["title"].as_str() {
+                info.title = title.to_string();
+            }
+            info.artist = entry["uploader"].as_str().or_else(|| entry["channel"].as_str()).map(String::from);
+            info.duration_secs = entry["duration"].as_f64().map(|secs| secs as u64);
+            Some(info)
+        })
+        .collect();
+    if tracks.is_empty() {
+        bail!("The playlist is empty");
+    }
+    let cut_off = tracks.len() > max_length;
+    tracks.truncate(max_length);
+    Ok(Some(Queued { tracks, cut_off }))
+}
+
+/// Title, artist and length from the tags and stream of an opened track.
+fn apply_metadata(info: &mut TrackInfo, parsed: &mut Parsed) {
+    if let Some(revision) = parsed.format.metadata().current() {
+        apply_tags(info, revision);
+    } else if let Some(revision) = parsed.meta.get().as_ref().and_then(|meta| meta.current()) {
+        apply_tags(info, revision);
+    }
+    let duration = parsed.format
+        .tracks()
+        .iter()
+        .find(|track| track.id == parsed.track_id)
+        .and_then(|track| Some(track.codec_params.n_frames? / u64::from(track.codec_params.sample_rate?)));
+    if duration.is_some() {
+        info.duration_secs = duration;
+    }
+}
+
+/// ReplayGain track gain in dB and peak from the tags of `parsed`.
+fn replay_gain(parsed: &mut Parsed) -> (Option<f32>, Option<f32>) {
+    let read = |revision: &MetadataRevision| {
+        let mut gain = (None, None);
+        for tag in revision.tags() {
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => gain.0 = parse_gain_db(&tag.value.to_string()),
+                Some(StandardTagKey::ReplayGainTrackPeak) => gain.1 = tag.value.to_string().trim().parse().ok(),
+                _ => {}
+            }
+        }
+        gain
+    };
+    if let Some(revision) = parsed.format.metadata().current() {
+        read(revision)
+    } else if let Some(revision) = parsed.meta.get().as_ref().and_then(|meta| meta.current()) {
+        read(revision)
+    } else {
+        (None, None)
+    }
+}
+
+fn apply_tags(info: &mut TrackInfo, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => info.title = tag.value.to_string(),
+            Some(StandardTagKey::Artist) => info.artist = Some(tag.value.to_string()),
+            _ => {}
+        }
+    }
+}
+
+/// The file name of `url`, or the whole url if it has none.
+fn title_from_url(url: &str) -> String {
+    url.split(['?', '#'])
+        .next()
+        .and_then(|path| path.trim_end_matches('/').rsplit('/').next())
+        .filter(|name| !name.is_empty() && !name.contains(':'))
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn titles_fall_back_to_the_file_name() {
+        assert_eq!(title_from_url("https://example.com/music/song.ogg?dl=1"), "song.ogg");
+        assert_eq!(title_from_url("https://example.com/stream/"), "stream");
+        assert_eq!(title_from_url("https://example.com"), "example.com");
+        assert_eq!(title_from_url("http://"), "http://");
+    }
+
+    #[test]
+    fn tracks_standing_still_are_stalled() {
+        let mut stalls = StallWatch::default();
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        assert!(!stalls.stalled(1, 7, Some(second), now));
+        assert!(!stalls.stalled(1, 7, Some(second * 2), now + STALL_TIMEOUT));
+        assert!(!stalls.stalled(1, 7, None, now + STALL_TIMEOUT + second));
+        assert!(stalls.stalled(1, 7, Some(second * 2), now + STALL_TIMEOUT * 2));
+        assert!(!stalls.stalled(1, 8, Some(second * 2), now + STALL_TIMEOUT * 2));
+        stalls.forget(1);
+        assert!(!stalls.stalled(1, 8, None, now + STALL_TIMEOUT * 3));
+    }
+
+    #[test]
+    fn timestamps_are_parsed() {
+        assert_eq!(parse_timestamp("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:30"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_timestamp("1:75"), None);
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp("soon"), None);
+    }
+
+    #[test]
+    fn now_playing_names_the_requester() {
+        let mut track = TrackInfo::new("https://example.com/song.ogg".to_string(), "Ann".to_string());
+        track.artist = Some("Band".to_string());
+        track.duration_secs = Some(61);
+        assert_eq!(track.now_playing(), "Now playing: Band - song.ogg [1:01], requested by Ann");
+        assert!(NowPlayingConfig::default().chat);
+    }
+
+    #[test]
+    fn feed_mixes_in_order_and_stays_bounded() {
+        let feed = MusicFeed::new();
+        feed.push(&[0.5, 0.25, 1.0]);
+        let mut out = [0.5, 0.5];
+        feed.mix_into(&mut out, 0.5, 0.5);
+        assert_eq!(out, [0.75, 0.625]);
+        let mut out = [0.0; 4];
+        feed.mix_into(&mut out, 1.0, 1.0);
+        assert_eq!(out, [1.0, 0.0, 0.0, 0.0]);
+
+        feed.push(&[1.0, 1.0]);
+        feed.set_volume(0.25);
+        let mut out = [0.0; 2];
+        feed.mix_into(&mut out, 2.0, 2.0);
+        assert_eq!(out, [0.5, 0.5]);
+
+        feed.set_volume(1.0);
+        feed.push(&[1.0; 4]);
+        let mut out = [0.0; 4];
+        feed.mix_into(&mut out, 1.0, 0.0);
+        assert_eq!(out, [1.0, 0.75, 0.5, 0.25]);
+
+        feed.push(&vec![0.0; MAX_FEED_SAMPLES + 10]);
+        assert_eq!(feed.samples.lock().unwrap().len(), MAX_FEED_SAMPLES);
+    }
+
+    #[test]
+    fn playlists_are_parsed_and_cut_off() {
+        let json = br#"{
+            "_type": "playlist",
+            "title": "Mix",
+            "entries": [
+                {"url": "https://example.com/watch?v=1", "title": "One", "uploader": "Band", "duration": 61.5},
+                {"url": "https://example.com/watch?v=2", "title": "Two", "duration": null},
+                {"title": "No url"},
+                {"url": "https://example.com/watch?v=3"}
+            ]
+        }"#;
+        let playlist = parse_playlist(json, "someone", 5).unwrap().unwrap();
+        assert!(!playlist.cut_off);
+        assert_eq!(playlist.tracks.len(), 3);
+        assert_eq!(playlist.tracks[0].to_string(), "Band - One [1:01]");
+        assert_eq!(playlist.tracks[1].to_string(), "Two");
+        assert_eq!(playlist.tracks[2].requested_by, "someone");
+
+        let playlist = parse_playlist(json, "someone", 2).unwrap().unwrap();
+        assert!(playlist.cut_off);
+        assert_eq!(playlist.tracks.len(), 2);
+
+        assert!(parse_playlist(br#"{"_type": "video", "title": "Single"}"#, "someone", 5).unwrap().is_none());
+        assert!(parse_playlist(br#"{"_type": "playlist", "entries": []}"#, "someone", 5).is_err());
+    }
+
+    #[test]
+    fn direct_links_skip_yt_dlp() {
+        assert!(is_direct("https://example.com/song.OGG?dl=1"));
+        assert!(!is_direct("https://example.com/watch?v=song.ogg"));
+        assert!(!is_direct("https://example.com/stream"));
+    }
+
+    #[test]
+    fn only_web_links_are_played() {
+        assert!(is_web_link("https://example.com/watch?v=song"));
+        assert!(is_web_link("HTTP://example.com/song.ogg"));
+        assert!(!is_web_link("--batch-file=/etc/passwd"));
+        assert!(!is_web_link("ftp://example.com/song.ogg"));
+        assert!(!is_web_link("example.com/song.ogg"));
+    }
+
+    #[test]
+    fn shuffle_keeps_every_item() {
+        let mut items: Vec<_> = (0..50).collect();
+        shuffle(&mut items);
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn tracks_display_artist_and_length() {
+        let mut track = TrackInfo {
+            url: "https://example.com/a.ogg".into(),
+            title: "Song".into(),
+            artist: None,
+            duration_secs: None,
+            requested_by: "someone".into(),
+        };
+        assert_eq!(track.to_string(), "Song");
+        track.artist = Some("Band".into());
+        track.duration_secs = Some(3 * 60 + 7);
+        assert_eq!(track.to_string(), "Band - Song [3:07]");
+        assert_eq!(format_duration(Duration::from_secs(3600 + 61)), "1:01:01");
+    }
+}
@@ -0,0 +1,94 @@
+//! Halting the bridge by hand with `/pause-bridge`, e.g. for a private talk on Discord.
+//!
+//! A paused direction passes no audio, the connections and calls stay up.
+
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+
+use crate::levels::Direction;
+
+/// Directions `/pause-bridge` and `/resume-bridge` act on.
+#[derive(Clone, Copy, Debug, PartialEq, poise::ChoiceParameter)]
+pub enum PauseDirection {
+    Both,
+    #[name = "Discord to TeamSpeak"]
+    DiscordToTs,
+    #[name = "TeamSpeak to Discord"]
+    TsToDiscord,
+}
+
+impl PauseDirection {
+    fn covers(self, direction: Direction) -> bool {
+        match self {
+            PauseDirection::Both => true,
+            PauseDirection::DiscordToTs => direction == Direction::DiscordToTs,
+            PauseDirection::TsToDiscord => direction == Direction::TsToDiscord,
+        }
+    }
+}
+
+/// Which directions are paused, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct BridgePause {
+    discord_to_ts: Arc<AtomicBool>,
+    ts_to_discord: Arc<AtomicBool>,
+}
+
+impl BridgePause {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set while `direction` is paused.
+    pub fn flag(&self, direction: Direction) -> Arc<AtomicBool> {
+        match direction {
+            Direction::DiscordToTs => self.discord_to_ts.clone(),
+            Direction::TsToDiscord => self.ts_to_discord.clone(),
+        }
+    }
+
+    pub fn is_paused(&self, direction: Direction) -> bool {
+        self.flag(direction).load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume the directions of `which`, `true` if any of them changed.
+    pub fn set(&self, which: PauseDirection, paused: bool) -> bool {
+        let mut changed = false;
+        for direction in [Direction::DiscordToTs, Direction::TsToDiscord] {
+            if which.covers(direction) {
+                changed |= self.flag(direction).swap(paused, Ordering::Relaxed) != paused;
+            }
+        }
+        changed
+    }
+
+    /// What is paused, in words.
+    pub fn summary(&self) -> String {
+        match (self.is_paused(Direction::DiscordToTs), self.is_paused(Direction::TsToDiscord)) {
+            (false, false) => "The bridge is running both ways.".to_string(),
+            (true, true) => "The bridge is paused both ways.".to_string(),
+            (true, false) => "The bridge is paused from Discord to TeamSpeak.".to_string(),
+            (false, true) => "The bridge is paused from TeamSpeak to Discord.".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directions_pause_and_resume_separately() {
+        let pause = BridgePause::new();
+        assert!(pause.set(PauseDirection::DiscordToTs, true));
+        assert!(!pause.set(PauseDirection::DiscordToTs, true));
+        assert!(pause.is_paused(Direction::DiscordToTs));
+        assert!(!pause.is_paused(Direction::TsToDiscord));
+        assert_eq!(pause.summary(), "The bridge is paused from Discord to TeamSpeak.");
+
+        assert!(pause.set(PauseDirection::Both, true));
+        assert!(pause.flag(Direction::TsToDiscord).load(Ordering::Relaxed));
+        assert!(pause.set(PauseDirection::Both, false));
+        assert_eq!(pause.summary(), "The bridge is running both ways.");
+    }
+}
@@ -0,0 +1,67 @@
+//! Where the delay across the bridge comes from, hop by hop, for `/ping`.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::teamspeak::LinkQuality;
+
+/// Latencies measured for `/ping`, `None` where a hop couldn't be measured.
+#[derive(Clone, Debug, Default)]
+pub struct Hops {
+    /// Heartbeat round trip of the Discord gateway.
+    pub gateway: Option<Duration>,
+    /// Round trip of a Discord REST request.
+    pub rest: Option<Duration>,
+    pub teamspeak: Option<LinkQuality>,
+    /// Held in the Discord jitter buffers, plus the frame sent to TeamSpeak.
+    pub discord_to_ts: Option<Duration>,
+    /// Held in front of songbird, plus its 20 ms mix.
+    pub ts_to_discord: Option<Duration>,
+}
+
+fn millis(latency: Option<Duration>, missing: &str) -> String {
+    match latency {
+        Some(latency) => format!("{} ms", latency.as_millis()),
+        None => missing.to_string(),
+    }
+}
+
+impl fmt::Display for Hops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Discord gateway: {}", millis(self.gateway, "not measured yet"))?;
+        writeln!(f, "Discord REST: {}", millis(self.rest, "failed"))?;
+        // Songbird keeps the voice heartbeat and RTCP timing to itself
+        writeln!(f, "Discord voice (UDP): not reported by songbird")?;
+        match &self.teamspeak {
+            Some(quality) => writeln!(f, "TeamSpeak: {}", quality)?,
+            None => writeln!(f, "TeamSpeak: not connected")?,
+        }
+        write!(
+            f,
+            "Bridge: {} Discord → TeamSpeak, {} TeamSpeak → Discord",
+            millis(self.discord_to_ts, "unknown"),
+            millis(self.ts_to_discord, "unknown")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_hop_is_listed() {
+        let hops = Hops {
+            gateway: Some(Duration::from_millis(41)),
+            rest: None,
+            teamspeak: None,
+            discord_to_ts: Some(Duration::from_millis(80)),
+            ts_to_discord: Some(Duration::from_millis(20)),
+        };
+        assert_eq!(
+            hops.to_string(),
+            "Discord gateway: 41 ms\nDiscord REST: failed\nDiscord voice (UDP): not reported by songbird\n\
+             TeamSpeak: not connected\nBridge: 80 ms Discord → TeamSpeak, 20 ms TeamSpeak → Discord"
+        );
+    }
+}
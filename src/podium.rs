@@ -0,0 +1,121 @@
+//! Podium mode, for bridging large moderated TeamSpeak events.
+//!
+//! Clients with the talk power of a speaker, granted talk power or priority
+//! speaker are mixed for Discord as usual, everybody else is turned down.
+
+use std::collections::HashSet;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use serde::Deserialize;
+use tsclientlib::ClientId;
+
+use crate::audio::db_to_gain;
+
+/// `[podium]` section of the config file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PodiumConfig {
+    /// Start with podium mode on.
+    pub enabled: bool,
+    /// Clients with at least this talk power are on the podium.
+    pub min_talk_power: Option<i32>,
+    /// Priority speakers are on the podium.
+    pub priority_speakers: bool,
+    /// Clients granted talk power, like after a talk request, are on the podium.
+    pub talkers: bool,
+    /// Gain of everybody else, in dB.
+    pub attenuation_db: f32,
+}
+
+impl Default for PodiumConfig {
+    fn default() -> Self {
+        Self { enabled: false, min_talk_power: None, priority_speakers: true, talkers: true, attenuation_db: -18.0 }
+    }
+}
+
+impl PodiumConfig {
+    /// Whether a client with `talk_power`, granted talk power as `talker`, is on the podium.
+    pub fn is_on_podium(&self, talk_power: i32, talker: bool, priority_speaker: bool) -> bool {
+        self.min_talk_power.is_some_and(|min| talk_power >= min) ||
+            (self.talkers && talker) ||
+            (self.priority_speakers && priority_speaker)
+    }
+}
+
+/// Whether podium mode is on and who is on the podium, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Podium {
+    config: PodiumConfig,
+    enabled: Arc<AtomicBool>,
+    speakers: Arc<StdMutex<HashSet<ClientId>>>,
+}
+
+impl Default for Podium {
+    fn default() -> Self {
+        Self::new(PodiumConfig::default())
+    }
+}
+
+impl Podium {
+    pub fn new(config: PodiumConfig) -> Self {
+        Self {
+            config,
+            enabled: Arc::new(AtomicBool::new(config.enabled)),
+            speakers: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PodiumConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn podium mode on or off, false if it already was.
+    pub fn set_enabled(&self, enabled: bool) -> bool {
+        self.enabled.swap(enabled, Ordering::Relaxed) != enabled
+    }
+
+    /// Replace the clients on the podium.
+    pub fn set_speakers(&self, speakers: HashSet<ClientId>) {
+        *self.speakers.lock().expect("Can't lock podium speakers!") = speakers;
+    }
+
+    pub fn speakers(&self) -> usize {
+        self.speakers.lock().expect("Can't lock podium speakers!").len()
+    }
+
+    /// Gain `client` is mixed at, turned down while podium mode is on and they are not on the podium.
+    pub fn gain(&self, client: ClientId) -> f32 {
+        if !self.is_enabled() || self.speakers.lock().expect("Can't lock podium speakers!").contains(&client) {
+            1.0
+        } else {
+            db_to_gain(self.config.attenuation_db)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_audience_is_turned_down() {
+        let config = PodiumConfig { min_talk_power: Some(50), talkers: false, ..PodiumConfig::default() };
+        assert!(config.is_on_podium(75, false, false));
+        assert!(config.is_on_podium(0, false, true));
+        assert!(!config.is_on_podium(0, true, false));
+        assert!(!config.is_on_podium(49, false, false));
+
+        let podium = Podium::new(config);
+        podium.set_speakers(std::iter::once(ClientId(5)).collect());
+        assert_eq!(podium.gain(ClientId(6)), 1.0);
+        assert!(podium.set_enabled(true));
+        assert!(!podium.set_enabled(true));
+        assert_eq!(podium.gain(ClientId(5)), 1.0);
+        assert!((podium.gain(ClientId(6)) - 0.125_9).abs() < 0.001);
+    }
+}
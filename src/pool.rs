@@ -0,0 +1,79 @@
+//! Reuse of packet buffers on the real-time path.
+//!
+//! Every received voice packet used to be copied into a freshly allocated
+//! `Vec`. A [`FramePool`] hands out buffers that were already used for an
+//! earlier packet instead, once the jitter buffer is done with them.
+
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use crate::MAX_OPUS_FRAME_SIZE;
+
+/// Upper bound of idle buffers kept around, about one second of audio for
+/// a handful of talkers.
+const MAX_POOLED: usize = 256;
+
+/// Free list of byte buffers, cheap to clone and share between threads.
+#[derive(Clone, Debug, Default)]
+pub struct FramePool {
+    free: Arc<StdMutex<Vec<Vec<u8>>>>,
+}
+
+impl FramePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A buffer containing a copy of `data`, reusing an idle one if possible.
+    pub fn frame_from(&self, data: &[u8]) -> Vec<u8> {
+        let mut frame = self.free
+            .lock()
+            .expect("Can't lock frame pool!")
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(MAX_OPUS_FRAME_SIZE));
+        frame.clear();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Hand a buffer back once its packet was consumed.
+    pub fn recycle(&self, frame: Vec<u8>) {
+        let mut free = self.free.lock().expect("Can't lock frame pool!");
+        if free.len() < MAX_POOLED && frame.capacity() > 0 {
+            free.push(frame);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_recycled_buffers() {
+        let pool = FramePool::new();
+        let frame = pool.frame_from(&[1, 2, 3]);
+        assert_eq!(frame, [1, 2, 3]);
+        let ptr = frame.as_ptr();
+        pool.recycle(frame);
+        assert_eq!(pool.idle(), 1);
+
+        let frame = pool.frame_from(&[4]);
+        assert_eq!(frame, [4]);
+        assert_eq!(frame.as_ptr(), ptr);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn idle_buffers_are_bounded() {
+        let pool = FramePool::new();
+        for _ in 0..MAX_POOLED + 10 {
+            pool.recycle(Vec::with_capacity(8));
+        }
+        assert_eq!(pool.idle(), MAX_POOLED);
+    }
+}
@@ -0,0 +1,149 @@
+//! State snapshots written when the bridge panics.
+//!
+//! Crashes on the audio threads are rare and hard to reproduce, so the panic
+//! hook saves what the pipelines looked like at that moment: buffer fills, when
+//! the last packets of every source arrived and the connection states.
+//!
+//! The panicking thread may hold any lock, sections only ever `try_lock` and
+//! report `null` for what they couldn't get.
+
+use std::collections::{ HashMap, VecDeque };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use serde_json::{ Map, Value };
+
+/// Arrival times kept per source.
+const KEPT_PACKETS: usize = 32;
+/// Sources not heard from for this long are forgotten.
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Arrival times of the last packets of every source. Cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct PacketTimes {
+    times: Arc<StdMutex<HashMap<String, VecDeque<SystemTime>>>>,
+}
+
+impl PacketTimes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A packet of `source` arrived just now.
+    pub fn record(&self, source: &str) {
+        let now = SystemTime::now();
+        let mut times = self.times.lock().expect("Can't lock packet times!");
+        if let Some(arrivals) = times.get_mut(source) {
+            if arrivals.len() == KEPT_PACKETS {
+                arrivals.pop_front();
+            }
+            arrivals.push_back(now);
+            return;
+        }
+        times.retain(|_, arrivals| {
+            arrivals.back().is_some_and(|last| now.duration_since(*last).unwrap_or_default() < STALE_AFTER)
+        });
+        times.insert(source.to_string(), VecDeque::from([now]));
+    }
+
+    /// Arrival times in ms since the epoch, by source.
+    pub fn snapshot(&self) -> Value {
+        match self.times.try_lock() {
+            Ok(times) => times
+                .iter()
+                .map(|(source, arrivals)| (source.clone(), arrivals.iter().copied().map(unix_ms).collect()))
+                .collect::<Map<_, _>>()
+                .into(),
+            Err(_) => Value::Null,
+        }
+    }
+}
+
+type Section = Box<dyn Fn() -> Value + Send + Sync>;
+
+/// What goes into a snapshot, by section name. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct PostMortem {
+    sections: Arc<StdMutex<Vec<(&'static str, Section)>>>,
+}
+
+impl PostMortem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put what `section` returns into snapshots as `name`.
+    pub fn add(&self, name: &'static str, section: impl Fn() -> Value + Send + Sync + 'static) {
+        self.sections.lock().expect("Can't lock post-mortem sections!").push((name, Box::new(section)));
+    }
+
+    /// The state right now, with why it was taken.
+    pub fn snapshot(&self, reason: &str) -> Value {
+        let mut snapshot = Map::new();
+        snapshot.insert("time_ms".into(), unix_ms(SystemTime::now()).into());
+        snapshot.insert("reason".into(), reason.into());
+        snapshot.insert("thread".into(), std::thread::current().name().unwrap_or("unnamed").into());
+        if let Ok(sections) = self.sections.try_lock() {
+            for (name, section) in sections.iter() {
+                snapshot.insert(name.to_string(), section());
+            }
+        }
+        snapshot.into()
+    }
+
+    /// Write a snapshot into `dir` whenever a thread panics, then panic as before.
+    pub fn install(self, dir: PathBuf) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(
+            Box::new(move |info| {
+                let snapshot = self.snapshot(&info.to_string());
+                let path = dir.join(format!("voice_bridge_panic_{}.json", snapshot["time_ms"]));
+                let written = serde_json
+                    ::to_vec_pretty(&snapshot)
+                    .map_err(std::io::Error::from)
+                    .and_then(|json| std::fs::write(&path, json));
+                match written {
+                    Ok(()) => eprintln!("Saved the bridge state to {}", path.display()),
+                    Err(e) => eprintln!("Failed to save the bridge state to {}: {}", path.display(), e),
+                }
+                previous(info);
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn snapshots_hold_recent_packets_and_skip_locked_state() {
+        let packets = PacketTimes::new();
+        for _ in 0..KEPT_PACKETS + 5 {
+            packets.record("client 5");
+        }
+        packets.record("ssrc 7");
+
+        let busy = Arc::new(StdMutex::new(3));
+        let post_mortem = PostMortem::new();
+        let times = packets.clone();
+        post_mortem.add("packets", move || times.snapshot());
+        let fill = busy.clone();
+        post_mortem.add("fill", move || fill.try_lock().map_or(Value::Null, |fill| json!(*fill)));
+
+        let snapshot = post_mortem.snapshot("test");
+        assert_eq!(snapshot["reason"], "test");
+        assert_eq!(snapshot["packets"]["client 5"].as_array().unwrap().len(), KEPT_PACKETS);
+        assert_eq!(snapshot["packets"]["ssrc 7"].as_array().unwrap().len(), 1);
+        assert_eq!(snapshot["fill"], 3);
+
+        let _held = busy.lock().unwrap();
+        assert_eq!(post_mortem.snapshot("test")["fill"], Value::Null);
+    }
+}
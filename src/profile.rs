@@ -0,0 +1,194 @@
+//! Named audio profiles, switched with `/profile` while the bridge runs.
+//!
+//! A profile bundles how the Discord mix is encoded for TeamSpeak and how
+//! music is ducked, e.g. short frames for low latency or a high bitrate for
+//! music nights.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tsproto_packets::packets::CodecType;
+
+use crate::audio::{ DuckingConfig, FrameDuration };
+use crate::settings::SharedSettings;
+
+/// Name `/profile` takes for the plain config.
+pub const DEFAULT_PROFILE: &str = "default";
+/// Bits per second sent to TeamSpeak by the bandwidth saver.
+const BANDWIDTH_SAVER_BITRATE: i32 = 24_000;
+/// Bits per second sent to Discord by the bandwidth saver, songbird sends 128 kbps otherwise.
+pub const BANDWIDTH_SAVER_DISCORD_BITRATE: i32 = 32_000;
+
+/// Opus codec TeamSpeak is told the audio is in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Voice,
+    Music,
+}
+
+impl From<Codec> for CodecType {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Voice => CodecType::OpusVoice,
+            Codec::Music => CodecType::OpusMusic,
+        }
+    }
+}
+
+/// How the Discord mix is sent to TeamSpeak.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputFormat {
+    pub frame: FrameDuration,
+    pub codec: Codec,
+    /// Bits per second, chosen by Opus if unset.
+    pub bitrate: Option<i32>,
+    /// Downmixed to mono.
+    pub mono: bool,
+    /// Silence is left out.
+    pub dtx: bool,
+    pub ducking: DuckingConfig,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self {
+            frame: FrameDuration::default(),
+            codec: Codec::Music,
+            bitrate: None,
+            mono: false,
+            dtx: false,
+            ducking: DuckingConfig::default(),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// `self` for metered bandwidth: mono voice at 24 kbps, leaving out silence.
+    pub fn bandwidth_saver(self) -> Self {
+        Self {
+            codec: Codec::Voice,
+            bitrate: Some(BANDWIDTH_SAVER_BITRATE),
+            mono: true,
+            dtx: true,
+            ..self
+        }
+    }
+}
+
+/// `[profiles.<name>]` section, unset values are taken from the rest of the config.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioProfile {
+    frame_size_ms: Option<FrameDuration>,
+    codec: Option<Codec>,
+    bitrate: Option<i32>,
+    mono: Option<bool>,
+    dtx: Option<bool>,
+    ducking: Option<DuckingConfig>,
+}
+
+impl AudioProfile {
+    fn apply(&self, base: OutputFormat) -> OutputFormat {
+        OutputFormat {
+            frame: self.frame_size_ms.unwrap_or(base.frame),
+            codec: self.codec.unwrap_or(base.codec),
+            bitrate: self.bitrate.or(base.bitrate),
+            mono: self.mono.unwrap_or(base.mono),
+            dtx: self.dtx.unwrap_or(base.dtx),
+            ducking: self.ducking.unwrap_or(base.ducking),
+        }
+    }
+}
+
+/// The configured profiles and which one `/profile` selected. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct AudioProfiles {
+    base: OutputFormat,
+    profiles: Arc<BTreeMap<String, AudioProfile>>,
+    settings: SharedSettings,
+    changed: Arc<Notify>,
+}
+
+impl AudioProfiles {
+    pub fn new(base: OutputFormat, profiles: BTreeMap<String, AudioProfile>, settings: SharedSettings) -> Self {
+        Self { base, profiles: Arc::new(profiles), settings, changed: Default::default() }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// The selected profile, `None` for the plain config.
+    pub fn active(&self) -> Option<String> {
+        let settings = self.settings.lock().expect("Can't lock settings!");
+        settings.get().audio_profile.clone().filter(|name| self.profiles.contains_key(name))
+    }
+
+    /// The format of the selected profile.
+    pub fn format(&self) -> OutputFormat {
+        match self.active() {
+            Some(name) => self.profiles[&name].apply(self.base),
+            None => self.base,
+        }
+    }
+
+    /// Switch to the profile `name`, or back to the plain config with `None`.
+    pub fn select(&self, name: Option<&str>) -> Result<(), String> {
+        if let Some(name) = name.filter(|name| !self.profiles.contains_key(*name)) {
+            return Err(format!("No audio profile {:?}", name));
+        }
+        let saved = self.settings
+            .lock()
+            .expect("Can't lock settings!")
+            .update(|s| s.audio_profile = name.map(str::to_string));
+        if let Err(e) = saved {
+            tracing::warn!("Failed to save settings: {}", e);
+        }
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Wait for another profile to be selected.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::SettingsStore;
+
+    #[test]
+    fn profiles_override_the_config() {
+        let path = std::env::temp_dir().join(format!("voice_bridge_profiles_{}.json", std::process::id()));
+        let settings = Arc::new(std::sync::Mutex::new(SettingsStore::load(&path).unwrap()));
+        let configured: BTreeMap<String, AudioProfile> = toml::from_str(
+            "[low-latency]\nframe_size_ms = 10\ncodec = \"voice\"\n[music]\nbitrate = 128000\n[saver]\ndtx = true\n"
+        ).unwrap();
+        let profiles = AudioProfiles::new(OutputFormat::default(), configured.clone(), settings);
+        assert_eq!(profiles.names().collect::<Vec<_>>(), ["low-latency", "music", "saver"]);
+        assert_eq!(profiles.format(), OutputFormat::default());
+        profiles.select(Some("saver")).unwrap();
+        assert!(profiles.format().dtx && !profiles.format().mono);
+
+        profiles.select(Some("low-latency")).unwrap();
+        let format = profiles.format();
+        assert_eq!(format.frame.interval(), std::time::Duration::from_millis(10));
+        assert_eq!(format.codec, Codec::Voice);
+        assert_eq!(format.bitrate, None);
+        assert!(profiles.select(Some("loud")).is_err());
+
+        // Still selected after a restart, as long as it is configured
+        let reload = || Arc::new(std::sync::Mutex::new(SettingsStore::load(&path).unwrap()));
+        let profiles = AudioProfiles::new(OutputFormat::default(), configured, reload());
+        assert_eq!(profiles.active().as_deref(), Some("low-latency"));
+        let profiles = AudioProfiles::new(OutputFormat::default(), BTreeMap::new(), reload());
+        assert_eq!(profiles.active(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Ignoring TeamSpeak clients whose packets keep failing to decode.
+//!
+//! A client with a broken codec would otherwise cost a decode attempt and a
+//! log line every 20 ms. Once most of a client's packets fail within a few
+//! seconds, its packets are dropped unseen for a while, and tried again after.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{ Duration, Instant };
+
+/// Failures are counted over windows this long.
+const WINDOW: Duration = Duration::from_secs(5);
+/// Failures in a window from which a client may be quarantined, a second of packets.
+const MIN_FAILURES: u32 = 50;
+/// Share of failed packets in a window that quarantines.
+const FAILURE_RATE: f32 = 0.5;
+/// How long packets of a quarantined client are dropped.
+pub const QUARANTINE_FOR: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Client {
+    window_start: Instant,
+    packets: u32,
+    failures: u32,
+    until: Option<Instant>,
+    /// Packets dropped during the current quarantine.
+    dropped: u64,
+}
+
+/// A quarantined client, for `status`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quarantined<Id> {
+    pub id: Id,
+    pub remaining: Duration,
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+pub struct DecodeQuarantine<Id> {
+    clients: HashMap<Id, Client>,
+}
+
+impl<Id> Default for DecodeQuarantine<Id> {
+    fn default() -> Self {
+        Self { clients: HashMap::new() }
+    }
+}
+
+impl<Id: Clone + Eq + Hash> DecodeQuarantine<Id> {
+    /// Whether packets of `id` are to be dropped, counting them if so.
+    pub fn is_quarantined(&mut self, id: &Id, now: Instant) -> bool {
+        let Some(client) = self.clients.get_mut(id) else {
+            return false;
+        };
+        match client.until {
+            Some(until) if now < until => {
+                client.dropped += 1;
+                true
+            }
+            Some(_) => {
+                self.clients.remove(id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a packet of `id` that did or did not decode, true if that quarantined `id`.
+    pub fn record(&mut self, id: &Id, failed: bool, now: Instant) -> bool {
+        let client = self.clients.entry(id.clone()).or_insert_with(|| Client {
+            window_start: now,
+            packets: 0,
+            failures: 0,
+            until: None,
+            dropped: 0,
+        });
+        if now.saturating_duration_since(client.window_start) >= WINDOW {
+            client.window_start = now;
+            client.packets = 0;
+            client.failures = 0;
+        }
+        client.packets += 1;
+        if failed {
+            client.failures += 1;
+        }
+        let rate = (client.failures as f32) / (client.packets as f32);
+        if client.until.is_none() && client.failures >= MIN_FAILURES && rate >= FAILURE_RATE {
+            client.until = Some(now + QUARANTINE_FOR);
+            return true;
+        }
+        false
+    }
+
+    /// Clients quarantined right now.
+    pub fn quarantined(&mut self, now: Instant) -> Vec<Quarantined<Id>> {
+        // Clients gone quiet are forgotten with their window
+        self.clients.retain(|_, client| match client.until {
+            Some(until) => now < until,
+            None => now.saturating_duration_since(client.window_start) < WINDOW,
+        });
+        self.clients
+            .iter()
+            .filter_map(|(id, client)| {
+                client.until.map(|until| Quarantined {
+                    id: id.clone(),
+                    remaining: until - now,
+                    dropped: client.dropped,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantines_clients_failing_most_packets() {
+        let mut quarantine = DecodeQuarantine::default();
+        let start = Instant::now();
+        let frame = Duration::from_millis(20);
+        let mut quarantined_at = None;
+        for i in 0..200u32 {
+            let now = start + frame * i;
+            // Client 1 fails every packet, client 2 one in ten
+            if !quarantine.is_quarantined(&1, now) && quarantine.record(&1, true, now) {
+                quarantined_at = Some(i);
+            }
+            assert!(!quarantine.is_quarantined(&2, now));
+            assert!(!quarantine.record(&2, i % 10 == 0, now));
+        }
+        assert_eq!(quarantined_at, Some(MIN_FAILURES - 1));
+
+        let now = start + frame * 200;
+        let quarantined = quarantine.quarantined(now);
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].id, 1);
+        assert_eq!(quarantined[0].dropped, 150);
+
+        // Tried again once the quarantine is over
+        let later = start + frame * MIN_FAILURES + QUARANTINE_FOR;
+        assert!(!quarantine.is_quarantined(&1, later));
+        assert!(quarantine.quarantined(later).is_empty());
+    }
+}
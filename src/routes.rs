@@ -0,0 +1,132 @@
+//! How loud each source is in each side's mix, set with `[routes]` and `/route`.
+//!
+//! The bridge has two listening sides, TeamSpeak and Discord, and three
+//! sources: the voices of the other side and `/play` music. Discord voices in
+//! TeamSpeak are the global volume of `/volume`, the other routes are kept here.
+
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::Arc;
+
+use serde::{ Deserialize, Deserializer };
+
+use crate::audio::{ deserialize_volume, format_volume };
+
+/// A source going to one side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Route {
+    #[name = "Discord to TeamSpeak"]
+    DiscordToTs,
+    #[name = "TeamSpeak to Discord"]
+    TsToDiscord,
+    #[name = "Music to TeamSpeak"]
+    MusicToTs,
+    #[name = "Music to Discord"]
+    MusicToDiscord,
+}
+
+impl Route {
+    pub const ALL: [Route; 4] = [Route::DiscordToTs, Route::TsToDiscord, Route::MusicToTs, Route::MusicToDiscord];
+}
+
+fn volume<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    deserialize_volume(deserializer).map(Some)
+}
+
+/// `[routes.teamspeak]`, what TeamSpeak hears.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TeamSpeakMix {
+    /// Discord voices, `volume` if unset.
+    #[serde(deserialize_with = "volume")]
+    pub discord: Option<f32>,
+    /// `/play` music, `music_gain` if unset.
+    #[serde(deserialize_with = "volume")]
+    pub music: Option<f32>,
+}
+
+/// `[routes.discord]`, what Discord hears.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DiscordMix {
+    #[serde(deserialize_with = "volume")]
+    pub teamspeak: Option<f32>,
+    #[serde(deserialize_with = "volume")]
+    pub music: Option<f32>,
+}
+
+/// `[routes]` section of the config file, by listening side and then source.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RouteConfig {
+    pub teamspeak: TeamSpeakMix,
+    pub discord: DiscordMix,
+}
+
+/// Gains of the routes besides Discord to TeamSpeak, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct RouteGains {
+    /// As `f32` bits, indexed like [`Route::ALL`].
+    gains: Arc<[AtomicU32; 4]>,
+}
+
+impl Default for RouteGains {
+    fn default() -> Self {
+        Self { gains: Arc::new(Route::ALL.map(|_| AtomicU32::new((1.0f32).to_bits()))) }
+    }
+}
+
+impl RouteGains {
+    /// Gains from `config`, music to TeamSpeak at `music_gain` unless set there.
+    pub fn new(config: RouteConfig, music_gain: f32) -> Self {
+        let gains = Self::default();
+        gains.set(Route::TsToDiscord, config.discord.teamspeak.unwrap_or(1.0));
+        gains.set(Route::MusicToTs, config.teamspeak.music.unwrap_or(music_gain));
+        gains.set(Route::MusicToDiscord, config.discord.music.unwrap_or(1.0));
+        gains
+    }
+
+    fn slot(&self, route: Route) -> &AtomicU32 {
+        &self.gains[Route::ALL.iter().position(|r| *r == route).expect("Every route has a slot")]
+    }
+
+    pub fn gain(&self, route: Route) -> f32 {
+        f32::from_bits(self.slot(route).load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, route: Route, gain: f32) {
+        self.slot(route).store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Every route with its gain, Discord to TeamSpeak at `discord_volume`.
+    pub fn summary(&self, discord_volume: f32) -> String {
+        Route::ALL
+            .iter()
+            .map(|&route| {
+                let gain = if route == Route::DiscordToTs { discord_volume } else { self.gain(route) };
+                format!("{}: {}", poise::ChoiceParameter::name(&route), format_volume(gain))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_are_read_by_side_and_source() {
+        let config: RouteConfig = toml::from_str("[teamspeak]\ndiscord = \"-6dB\"\n[discord]\nmusic = 0.5\n").unwrap();
+        assert!((config.teamspeak.discord.unwrap() - 0.501).abs() < 0.001);
+        assert_eq!(config.teamspeak.music, None);
+        assert_eq!(config.discord.music, Some(0.5));
+
+        let gains = RouteGains::new(config, 0.8);
+        assert_eq!(gains.gain(Route::TsToDiscord), 1.0);
+        assert_eq!(gains.gain(Route::MusicToTs), 0.8);
+        assert_eq!(gains.gain(Route::MusicToDiscord), 0.5);
+        gains.clone().set(Route::TsToDiscord, 0.25);
+        assert_eq!(gains.gain(Route::TsToDiscord), 0.25);
+        assert!(gains.summary(1.0).starts_with("Discord to TeamSpeak: 100% (+0.0 dB)\nTeamSpeak to Discord: 25% (-12.0 dB)"));
+    }
+}
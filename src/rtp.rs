@@ -0,0 +1,107 @@
+//! Minimal RTP parsing for voice packets received from Discord.
+
+/// The parts of an RTP packet the bridge needs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    pub ssrc: u32,
+    pub sequence: u16,
+    /// Sampling instant of the first sample, in 48 kHz ticks.
+    pub timestamp: u32,
+    /// Opus payload after the fixed header and header extension.
+    pub payload: &'a [u8],
+}
+
+/// Size of the fixed RTP header.
+const HEADER_LEN: usize = 12;
+
+/// Parse an RTP packet.
+///
+/// Returns `None` if the packet is truncated or has no payload. Never panics,
+/// whatever the input.
+pub fn parse(packet: &[u8]) -> Option<RtpPacket<'_>> {
+    if packet.len() < HEADER_LEN {
+        return None;
+    }
+
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+    let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+    let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+
+    let csrc_count = usize::from(packet[0] & 0x0f);
+    let has_extension = (packet[0] & 0x10) != 0;
+    let mut payload_offset = HEADER_LEN + csrc_count * 4;
+
+    if has_extension {
+        let ext_header = packet.get(payload_offset..payload_offset + 4)?;
+        let ext_len = (u16::from_be_bytes([ext_header[2], ext_header[3]]) as usize) * 4;
+        payload_offset += 4 + ext_len;
+    }
+
+    match packet.get(payload_offset..) {
+        Some(payload) if !payload.is_empty() => Some(RtpPacket { ssrc, sequence, timestamp, payload }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(extension: bool, sequence: u16, ssrc: u32) -> Vec<u8> {
+        let mut p = vec![if extension { 0x90 } else { 0x80 }, 0x78];
+        p.extend(sequence.to_be_bytes());
+        p.extend([0; 4]);
+        p.extend(ssrc.to_be_bytes());
+        p
+    }
+
+    #[test]
+    fn parses_plain_packet() {
+        let mut p = header(false, 513, 0xdeadbeef);
+        p[4..8].copy_from_slice(&960u32.to_be_bytes());
+        p.extend([1, 2, 3]);
+        assert_eq!(
+            parse(&p),
+            Some(RtpPacket { ssrc: 0xdeadbeef, sequence: 513, timestamp: 960, payload: &[1, 2, 3] })
+        );
+    }
+
+    #[test]
+    fn skips_header_extension() {
+        let mut p = header(true, 1, 42);
+        // profile 0xBEDE, one 32 bit word of extension data
+        p.extend([0xbe, 0xde, 0, 1, 9, 9, 9, 9]);
+        p.extend([7, 8]);
+        assert_eq!(parse(&p).unwrap().payload, &[7, 8]);
+    }
+
+    #[test]
+    fn skips_csrc_list() {
+        let mut p = header(true, 1, 42);
+        p[0] |= 2;
+        p.extend([0; 8]);
+        p.extend([0xbe, 0xde, 0, 0]);
+        p.extend([5]);
+        assert_eq!(parse(&p).unwrap().payload, &[5]);
+    }
+
+    #[test]
+    fn rejects_truncated_extension_header() {
+        let mut p = header(true, 0, 0);
+        p.extend([0xbe, 0xde, 0]);
+        assert_eq!(parse(&p), None);
+        let mut p = header(false, 0, 0);
+        p[0] |= 0x0f;
+        p.extend([1; 20]);
+        assert_eq!(parse(&p), None);
+    }
+
+    #[test]
+    fn rejects_short_and_empty_packets() {
+        assert_eq!(parse(&[0x80; 11]), None);
+        assert_eq!(parse(&header(false, 0, 0)), None);
+        let mut p = header(true, 0, 0);
+        p.extend([0xbe, 0xde, 0, 4, 1]);
+        assert_eq!(parse(&p), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Hours the bridge is live, e.g. only on raid nights.
+//!
+//! Outside the scheduled windows the bridge stays connected to both sides but
+//! is dormant: nothing is bridged in either direction.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{ Datelike, Local, NaiveDateTime, NaiveTime, Weekday };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::Notify;
+
+use crate::settings::SharedSettings;
+
+/// How often the clock is checked against the schedule.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Days and hours the bridge is live, like `Mon-Fri 19:00-23:30` or `daily 20:00-02:00`.
+///
+/// A window ending before it starts runs over midnight into the next day.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    /// Days the window starts on, Monday first.
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+    text: String,
+}
+
+impl Window {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days[day.num_days_from_monday() as usize]
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let (day, time) = (at.weekday(), at.time());
+        if self.start < self.end {
+            self.starts_on(day) && time >= self.start && time < self.end
+        } else {
+            (self.starts_on(day) && time >= self.start) || (self.starts_on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+fn parse_days(text: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    if text.eq_ignore_ascii_case("daily") {
+        return Ok([true; 7]);
+    }
+    for part in text.split(',') {
+        let day = |name: &str| {
+            Weekday::from_str(name.trim()).map_err(|_| format!("unknown day {:?}", name.trim()))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        let mut current = first;
+        loop {
+            days[current.num_days_from_monday() as usize] = true;
+            if current == last {
+                break;
+            }
+            current = current.succ();
+        }
+    }
+    Ok(days)
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let invalid = || format!("invalid window {:?}, expected e.g. \"Mon-Fri 19:00-23:30\"", text);
+        let (days, hours) = text.rsplit_once(' ').ok_or_else(invalid)?;
+        let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+        let time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            days: parse_days(days.trim())?,
+            start: time(start)?,
+            end: time(end)?,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        window.text
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Whether the bridge is live at `at`, always without any windows.
+pub fn is_live(windows: &[Window], at: NaiveDateTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(at))
+}
+
+/// The configured schedule, changed by `/schedule`, and whether bridging is paused by it.
+///
+/// Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct BridgeSchedule {
+    configured: Arc<Vec<Window>>,
+    settings: SharedSettings,
+    dormant: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+}
+
+impl BridgeSchedule {
+    pub fn new(configured: Vec<Window>, settings: SharedSettings) -> Self {
+        Self {
+            configured: Arc::new(configured),
+            settings,
+            dormant: Default::default(),
+            changed: Default::default(),
+        }
+    }
+
+    /// The windows set by `/schedule`, or the configured ones.
+    pub fn windows(&self) -> Vec<Window> {
+        let settings = self.settings.lock().expect("Can't lock settings!");
+        settings.get().schedule.clone().unwrap_or_else(|| self.configured.to_vec())
+    }
+
+    /// Whether the windows come from `/schedule` instead of the config.
+    pub fn is_overridden(&self) -> bool {
+        self.settings.lock().expect("Can't lock settings!").get().schedule.is_some()
+    }
+
+    /// Set while nothing should be bridged.
+    pub fn dormant_flag(&self) -> Arc<AtomicBool> {
+        self.dormant.clone()
+    }
+
+    pub fn is_dormant(&self) -> bool {
+        self.dormant.load(Ordering::Relaxed)
+    }
+
+    /// Check the schedule again now instead of at the next interval.
+    pub fn notify_changed(&self) {
+        self.changed.notify_one();
+    }
+
+    async fn wait(&self) {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = self.changed.notified() => {}
+        }
+    }
+
+    /// Follow the schedule in local time, calling `announce` with whether the
+    /// bridge went live or dormant.
+    pub async fn run(self, mut announce: impl FnMut(bool)) {
+        self.update(Local::now().naive_local());
+        loop {
+            self.wait().await;
+            if let Some(live) = self.update(Local::now().naive_local()) {
+                announce(live);
+            }
+        }
+    }
+
+    /// Go live or dormant as the schedule says for `at`, `Some(live)` if that changed.
+    pub fn update(&self, at: NaiveDateTime) -> Option<bool> {
+        let live = is_live(&self.windows(), at);
+        let was_dormant = self.dormant.swap(!live, Ordering::Relaxed);
+        (was_dormant == live).then_some(live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    use crate::settings::SettingsStore;
+
+    /// 2024-01-01 was a Monday.
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn windows_cover_their_days_and_hours() {
+        let window: Window = "Mon-Wed,Fri 19:00-23:30".parse().unwrap();
+        assert!(window.contains(at(1, 19, 0)));
+        assert!(window.contains(at(3, 23, 29)));
+        assert!(!window.contains(at(3, 23, 30)));
+        assert!(!window.contains(at(4, 20, 0)));
+        assert!(window.contains(at(5, 20, 0)));
+        assert!(!window.contains(at(1, 18, 59)));
+        assert_eq!(window.to_string(), "Mon-Wed,Fri 19:00-23:30");
+    }
+
+    #[test]
+    fn windows_run_over_midnight() {
+        let window: Window = "Sat 20:00-02:00".parse().unwrap();
+        assert!(window.contains(at(6, 23, 0)));
+        assert!(window.contains(at(7, 1, 59)));
+        assert!(!window.contains(at(7, 2, 0)));
+        assert!(!window.contains(at(6, 1, 0)));
+        assert!("daily 00:00-00:00".parse::<Window>().unwrap().contains(at(3, 12, 0)));
+    }
+
+    #[test]
+    fn bad_windows_are_rejected() {
+        assert!("Mon 19:00".parse::<Window>().is_err());
+        assert!("Someday 19:00-20:00".parse::<Window>().is_err());
+        assert!("Mon 25:00-26:00".parse::<Window>().is_err());
+        assert!(serde_json::from_str::<Window>("\"Tue 18:00-20:00\"").is_ok());
+    }
+
+    #[test]
+    fn going_live_and_dormant_is_reported_once() {
+        let path = std::env::temp_dir().join(format!("voice_bridge_schedule_{}.json", std::process::id()));
+        let settings = Arc::new(std::sync::Mutex::new(SettingsStore::load(&path).unwrap()));
+        let schedule = BridgeSchedule::new(vec!["Mon 19:00-20:00".parse().unwrap()], settings.clone());
+
+        assert_eq!(schedule.update(at(1, 19, 30)), None);
+        assert_eq!(schedule.update(at(1, 20, 0)), Some(false));
+        assert!(schedule.is_dormant());
+        assert_eq!(schedule.update(at(1, 21, 0)), None);
+
+        settings.lock().unwrap().update(|s| s.schedule = Some(Vec::new())).unwrap();
+        assert!(schedule.is_overridden());
+        assert_eq!(schedule.update(at(1, 21, 0)), Some(true));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
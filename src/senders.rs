@@ -0,0 +1,116 @@
+//! Who sends as each Discord SSRC, and how their settings say to bridge them.
+//!
+//! Resolved when an SSRC is mapped to a user and again after settings
+//! changed, so the receive path doesn't lock the settings for every packet.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+/// The sender of an SSRC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sender {
+    pub user: u64,
+    /// Left out of the TeamSpeak feed with `/bridge-mute`.
+    pub muted: bool,
+    /// `/my-settings` gain.
+    pub gain: f32,
+    /// `/delay-user` delay.
+    pub delay: Duration,
+    /// `/calibrate-noise` gate.
+    pub noise_gate_db: Option<f32>,
+}
+
+impl Sender {
+    fn resolve(user: u64, settings: &Settings) -> Self {
+        let prefs = settings.user_prefs.get(&user);
+        let delay_ms = settings.discord_delays_ms.get(&user).copied().unwrap_or_default();
+        Self {
+            user,
+            muted: settings.bridge_muted.contains(&user),
+            gain: prefs.and_then(|prefs| prefs.mic_gain).unwrap_or(1.0),
+            delay: Duration::from_millis(delay_ms.into()),
+            noise_gate_db: prefs.and_then(|prefs| prefs.noise_gate_db),
+        }
+    }
+}
+
+/// Senders by SSRC.
+#[derive(Debug, Default)]
+pub struct Senders {
+    by_ssrc: HashMap<u32, Sender>,
+    /// Settings changes the senders are resolved up to.
+    resolved: u64,
+}
+
+impl Senders {
+    pub fn get(&self, ssrc: u32) -> Option<Sender> {
+        self.by_ssrc.get(&ssrc).copied()
+    }
+
+    /// `user` sends as `ssrc` from now on.
+    pub fn map(&mut self, ssrc: u32, user: u64, settings: &Settings) {
+        self.by_ssrc.insert(ssrc, Sender::resolve(user, settings));
+    }
+
+    /// Whether the settings changed since the senders were resolved, `changes` times so far.
+    pub fn is_stale(&self, changes: u64) -> bool {
+        self.resolved != changes
+    }
+
+    /// Resolve everybody again, the settings having changed `changes` times so far.
+    pub fn resolve(&mut self, settings: &Settings, changes: u64) {
+        for sender in self.by_ssrc.values_mut() {
+            *sender = Sender::resolve(sender.user, settings);
+        }
+        self.resolved = changes;
+    }
+
+    /// `/my-settings` gain of every SSRC.
+    pub fn gains(&self) -> Vec<(u32, f32)> {
+        self.by_ssrc.iter().map(|(&ssrc, sender)| (ssrc, sender.gain)).collect()
+    }
+
+    /// `user` left, their SSRCs may be reused.
+    pub fn forget_user(&mut self, user: u64) {
+        self.by_ssrc.retain(|_, sender| sender.user != user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::UserPrefs;
+
+    #[test]
+    fn senders_follow_the_settings() {
+        let mut settings = Settings::default();
+        settings.bridge_muted.insert(1);
+        settings.user_prefs.insert(2, UserPrefs { mic_gain: Some(0.5), ts_roster_on_join: false, noise_gate_db: Some(-45.0) });
+        settings.discord_delays_ms.insert(2, 300);
+
+        let mut senders = Senders::default();
+        senders.map(10, 1, &settings);
+        senders.map(20, 2, &settings);
+        assert!(senders.get(10).unwrap().muted);
+        let other = senders.get(20).unwrap();
+        assert!(!other.muted);
+        assert_eq!(other.gain, 0.5);
+        assert_eq!(other.delay, Duration::from_millis(300));
+        assert_eq!(other.noise_gate_db, Some(-45.0));
+        assert_eq!(senders.get(30), None);
+
+        settings.bridge_muted.clear();
+        settings.bridge_muted.insert(2);
+        assert!(senders.is_stale(1));
+        senders.resolve(&settings, 1);
+        assert!(!senders.is_stale(1));
+        assert!(!senders.get(10).unwrap().muted);
+        assert!(senders.get(20).unwrap().muted);
+
+        senders.forget_user(2);
+        assert_eq!(senders.get(20), None);
+        assert!(senders.get(10).is_some());
+    }
+}
@@ -0,0 +1,211 @@
+//! Settings changed at runtime through commands, persisted across restarts.
+//!
+//! Unlike `.credentials.toml`, which is only read, this file is written by
+//! the bridge whenever a command changes something.
+
+use std::collections::{ BTreeMap, BTreeSet };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use serde::{ Deserialize, Serialize };
+
+use crate::music::TrackInfo;
+use crate::schedule::Window;
+
+pub const DEFAULT_SETTINGS_FILE: &str = "bridge_settings.json";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// TeamSpeak clients left out of the TS→Discord mix, uid → last known name.
+    pub ts_muted: BTreeMap<String, String>,
+    /// Discord users left out of the Discord→TS feed.
+    pub bridge_muted: BTreeSet<u64>,
+    /// Music queued per guild, the playing track first.
+    pub music_queues: BTreeMap<u64, Vec<TrackInfo>>,
+    /// Set by `/music-volume`, full volume if unset.
+    pub music_volume: Option<f32>,
+    /// Set by `/ts-channel-password` once TeamSpeak took it, used instead of the configured one.
+    pub ts_channel_password: Option<String>,
+    /// Defaults Discord users set for themselves with `/my-settings`.
+    pub user_prefs: BTreeMap<u64, UserPrefs>,
+    /// Set by `/schedule`, used instead of the configured windows.
+    pub schedule: Option<Vec<Window>>,
+    /// Audio profile selected with `/profile`, the plain config if unset.
+    pub audio_profile: Option<String>,
+    /// Extra delay of Discord users set with `/delay-user`, in ms.
+    pub discord_delays_ms: BTreeMap<u64, u32>,
+    /// Extra delay of TeamSpeak clients set with `/delay-user`, by uid.
+    pub ts_delays: BTreeMap<String, TsDelay>,
+    /// Index of the TeamSpeak identity `/rotate-identity` switched to, the first one if unset.
+    pub ts_identity: Option<usize>,
+    /// Speaking time of Discord users over all sessions, in ms.
+    pub discord_talk_ms: BTreeMap<u64, u64>,
+    /// Speaking time of TeamSpeak clients over all sessions, by uid.
+    pub ts_talk: BTreeMap<String, TsTalkTime>,
+}
+
+/// Extra delay of a TeamSpeak client.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TsDelay {
+    /// Last known name, so it can be removed while they are offline.
+    pub name: String,
+    pub ms: u32,
+}
+
+/// Speaking time of a TeamSpeak client.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TsTalkTime {
+    /// Last known name, for the leaderboard.
+    pub name: String,
+    pub ms: u64,
+}
+
+impl Settings {
+    /// Whether the Discord user `user` asked to be sent who is in TeamSpeak when joining.
+    pub fn wants_ts_roster(&self, user: u64) -> bool {
+        self.user_prefs.get(&user).is_some_and(|prefs| prefs.ts_roster_on_join)
+    }
+
+    /// Drop everything kept about the Discord user `user`, naming what there was.
+    pub fn forget_user(&mut self, user: u64) -> Vec<&'static str> {
+        let mut forgotten = Vec::new();
+        if self.user_prefs.remove(&user).is_some() {
+            forgotten.push("personal settings");
+        }
+        if self.bridge_muted.remove(&user) {
+            forgotten.push("bridge mute");
+        }
+        if self.discord_delays_ms.remove(&user).is_some() {
+            forgotten.push("playback delay");
+        }
+        if self.discord_talk_ms.remove(&user).is_some() {
+            forgotten.push("speaking time");
+        }
+        forgotten
+    }
+}
+
+/// What a Discord user wants whenever they are in a bridged channel.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct UserPrefs {
+    /// Gain of their voice in TeamSpeak, unchanged if unset.
+    pub mic_gain: Option<f32>,
+    /// Send them who is in TeamSpeak when they join a bridged channel.
+    pub ts_roster_on_join: bool,
+    /// Their microphone is muted below this level in dBFS, measured by `/calibrate-noise`.
+    pub noise_gate_db: Option<f32>,
+}
+
+/// [`Settings`] and the file they are saved to.
+#[derive(Debug)]
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Settings,
+    /// Counts changes, to notice one without locking the store.
+    changes: Arc<AtomicU64>,
+}
+
+pub type SharedSettings = Arc<StdMutex<SettingsStore>>;
+
+impl SettingsStore {
+    /// Load from `path`, starting with defaults if the file does not exist yet.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let settings = match std::fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Settings::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, settings, changes: Default::default() })
+    }
+
+    pub fn get(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// How often the settings were changed, readable while the store is locked.
+    pub fn changes(&self) -> Arc<AtomicU64> {
+        self.changes.clone()
+    }
+
+    /// Change the settings and save them.
+    ///
+    /// The change is kept in memory even if saving fails.
+    pub fn update<R>(&mut self, change: impl FnOnce(&mut Settings) -> R) -> std::io::Result<R> {
+        let result = change(&mut self.settings);
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.save()?;
+        Ok(result)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let data = serde_json::to_string_pretty(&self.settings)?;
+        // Write and rename, so a crash never leaves a truncated file
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_survive_a_reload() {
+        let path = std::env::temp_dir().join(format!("voice_bridge_settings_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = SettingsStore::load(&path).unwrap();
+        assert_eq!(store.get(), &Settings::default());
+        store
+            .update(|s| s.ts_muted.insert("uid=".into(), "Loud Larry".into()))
+            .unwrap();
+        let prefs = UserPrefs { mic_gain: Some(0.5), ts_roster_on_join: true, noise_gate_db: Some(-40.0) };
+        store.update(|s| s.user_prefs.insert(42, prefs.clone())).unwrap();
+
+        let reloaded = SettingsStore::load(&path).unwrap();
+        assert_eq!(reloaded.get().ts_muted.get("uid="), Some(&"Loud Larry".to_string()));
+        assert_eq!(reloaded.get().user_prefs.get(&42), Some(&prefs));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn forgetting_a_user_leaves_the_others() {
+        let mut settings = Settings::default();
+        settings.user_prefs.insert(1, UserPrefs { mic_gain: Some(2.0), ts_roster_on_join: false, noise_gate_db: None });
+        settings.user_prefs.insert(2, UserPrefs::default());
+        settings.bridge_muted.extend([1, 2]);
+
+        assert_eq!(settings.forget_user(1), vec!["personal settings", "bridge mute"]);
+        assert!(settings.forget_user(1).is_empty());
+        assert_eq!(settings.user_prefs.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(settings.bridge_muted.iter().collect::<Vec<_>>(), [&2]);
+    }
+
+    #[test]
+    fn user_prefs_round_trip_and_gate_the_roster() {
+        let prefs = UserPrefs { mic_gain: Some(1.5), ts_roster_on_join: true, noise_gate_db: None };
+        let json = serde_json::to_string(&prefs).unwrap();
+        assert_eq!(serde_json::from_str::<UserPrefs>(&json).unwrap(), prefs);
+        // Saved before the noise gate existed
+        let older: UserPrefs = serde_json::from_str(r#"{"mic_gain": 0.5}"#).unwrap();
+        assert_eq!(older, UserPrefs { mic_gain: Some(0.5), ..Default::default() });
+
+        let mut settings = Settings::default();
+        settings.user_prefs.insert(1, prefs);
+        settings.user_prefs.insert(2, older);
+        assert!(settings.wants_ts_roster(1));
+        assert!(!settings.wants_ts_roster(2));
+        assert!(!settings.wants_ts_roster(3));
+    }
+
+    #[test]
+    fn unknown_fields_and_missing_sections_are_accepted() {
+        let settings: Settings = serde_json::from_str(r#"{"future_option": 1}"#).unwrap();
+        assert_eq!(settings, Settings::default());
+    }
+}
@@ -0,0 +1,373 @@
+//! Deterministic simulation of both bridge directions on a virtual clock.
+//!
+//! Meant to run inside `#[tokio::test(start_paused = true)]`: tokio time only
+//! advances when every task is idle, so simulated hours of traffic finish in
+//! seconds of wall time and produce the same timing on every run. The senders
+//! are simulated, the receiving side runs the same code as the live pipelines.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Duration;
+
+use audiopus::coder::Decoder;
+use audiopus::{ Channels, SampleRate };
+use tokio::sync::Mutex;
+use tokio::time::{ interval, Instant };
+use tsclientlib::StreamItem;
+use tsproto_packets::packets::{ AudioData, CodecType };
+
+use crate::audio::{ peak, FrameDuration };
+use crate::audio::tests::{ encoder, logger, opus_sine };
+use crate::discord_audiohandler::AudioHandler;
+use crate::impair::{ Fate, Impairer, ImpairmentConfig };
+use crate::mock_ts::s2c_packet;
+use crate::music::{ MusicFeed, MusicMix };
+use crate::teamspeak::TsEventHandler;
+use crate::{
+    AudioBufferDiscord,
+    BufferedPipeline,
+    ConnectionId,
+    TsToDiscordPipeline,
+    MAX_OPUS_FRAME_SIZE,
+    STEREO_20MS,
+    TICK_TIME,
+};
+
+/// Output frames louder than this count as a received probe.
+const PROBE_THRESHOLD: f32 = 0.05;
+/// Length of a probe tone in 20 ms frames.
+const PROBE_FRAMES: usize = 5;
+
+/// Traffic pattern of the simulated sender.
+pub struct Scenario {
+    /// Simulated run time.
+    pub duration: Duration,
+    /// Packet interval of the sender, differs from 20 ms to model clock drift.
+    pub sender_interval: Duration,
+    /// Send one loud probe frame this often, silence otherwise.
+    pub probe_every: Duration,
+    /// `(start, length)` periods in which the sender is in DTX and sends nothing.
+    pub dtx_gaps: Vec<(Duration, Duration)>,
+    /// Network impairment on the bridge's receive path.
+    pub impairment: Option<ImpairmentConfig>,
+    /// Frames the bridge sends to TeamSpeak.
+    pub frame: FrameDuration,
+}
+
+impl Scenario {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            sender_interval: Duration::from_millis(TICK_TIME),
+            probe_every: Duration::from_secs(1),
+            dtx_gaps: Vec::new(),
+            impairment: None,
+            frame: FrameDuration::default(),
+        }
+    }
+
+    /// Let the sender clock run fast (positive) or slow (negative) by `ppm`.
+    pub fn drift_ppm(mut self, ppm: i64) -> Self {
+        let nanos = (TICK_TIME as i64) * 1_000_000;
+        self.sender_interval = Duration::from_nanos((nanos - (nanos * ppm) / 1_000_000) as u64);
+        self
+    }
+
+    pub fn dtx_gap(mut self, start: Duration, length: Duration) -> Self {
+        self.dtx_gaps.push((start, length));
+        self
+    }
+
+    pub fn frame(mut self, frame_ms: u64) -> Self {
+        self.frame = FrameDuration::try_from(frame_ms).unwrap();
+        self
+    }
+
+    pub fn impaired(mut self, config: ImpairmentConfig) -> Self {
+        self.impairment = Some(ImpairmentConfig { seed: config.seed.or(Some(1)), ..config });
+        self
+    }
+
+    fn in_gap(&self, t: Duration) -> bool {
+        self.dtx_gaps.iter().any(|(start, len)| t >= *start && t < *start + *len)
+    }
+}
+
+/// Outcome of a simulation run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub probes_sent: usize,
+    /// Time from sending to hearing each received probe.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn max_latency(&self) -> Duration {
+        self.latencies.iter().copied().max().unwrap_or_default()
+    }
+
+    pub fn min_latency(&self) -> Duration {
+        self.latencies.iter().copied().min().unwrap_or_default()
+    }
+}
+
+/// Detects probes in the output and matches them to their send time.
+struct ProbeDetector {
+    pending: Arc<StdMutex<VecDeque<Instant>>>,
+    was_loud: bool,
+    report: Report,
+}
+
+impl ProbeDetector {
+    fn frame(&mut self, pcm: &[f32]) {
+        let loud = peak(pcm) > PROBE_THRESHOLD;
+        if loud && !self.was_loud {
+            if let Some(sent) = self.pending.lock().unwrap().pop_front() {
+                self.report.latencies.push(sent.elapsed());
+            }
+        }
+        self.was_loud = loud;
+    }
+}
+
+fn opus_frame(pcm: &[f32]) -> Vec<u8> {
+    let mut out = [0; MAX_OPUS_FRAME_SIZE];
+    let len = encoder().encode_float(pcm, &mut out).unwrap();
+    out[..len].to_vec()
+}
+
+/// Run the sender as its own task, `send` gets the sequence number and Opus data.
+fn spawn_sender(
+    scenario: &Scenario,
+    pending: Arc<StdMutex<VecDeque<Instant>>>,
+    mut send: impl FnMut(u16, &[u8]) + Send + 'static
+) -> tokio::task::JoinHandle<usize> {
+    let silence = opus_frame(&[0.0; STEREO_20MS]);
+    // Opus fades in isolated frames, so a probe is a short tone burst
+    let probe = opus_sine(1000.0, PROBE_FRAMES);
+    let dtx_gaps = scenario.dtx_gaps.clone();
+    let scenario = Scenario { dtx_gaps, ..*scenario };
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let mut ticker = interval(scenario.sender_interval);
+        let mut sequence = 0u16;
+        let mut next_probe = scenario.probe_every;
+        let mut probe_frame = PROBE_FRAMES;
+        let mut probes = 0;
+        loop {
+            ticker.tick().await;
+            let t = start.elapsed();
+            if scenario.in_gap(t) {
+                continue;
+            }
+            if t >= next_probe {
+                // Probes due during a DTX gap are skipped, not sent in a burst
+                while next_probe <= t {
+                    next_probe += scenario.probe_every;
+                }
+                pending.lock().unwrap().push_back(Instant::now());
+                probes += 1;
+                probe_frame = 0;
+            }
+            let data = if probe_frame < PROBE_FRAMES {
+                probe_frame += 1;
+                &probe[probe_frame - 1]
+            } else {
+                &silence
+            };
+            send(sequence, data);
+            sequence = sequence.wrapping_add(1);
+            if t >= scenario.duration {
+                return probes;
+            }
+        }
+    })
+}
+
+/// Discord users talking, the bridge encoding for TeamSpeak.
+pub async fn discord_to_ts(scenario: &Scenario) -> Report {
+    let buffer: AudioBufferDiscord = Arc::new(Mutex::new(AudioHandler::new(logger())));
+    let encoders = crate::encode::EncoderPool::new().with_output("teamspeak", encoder());
+    let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+    let pending = Arc::new(StdMutex::new(VecDeque::new()));
+
+    let sink = buffer.clone();
+    let impairer = scenario.impairment.map(Impairer::new);
+    let sender = spawn_sender(scenario, pending.clone(), move |sequence, data| {
+        let data = data.to_vec();
+        match impairer.as_ref().map_or(Fate::Now, Impairer::fate) {
+            Fate::Drop => {}
+            Fate::Now => {
+                let mut lock = sink.try_lock().expect("Bridge holds the buffer across ticks");
+                let _ = lock.handle_packet(1, sequence, data);
+            }
+            Fate::Later(delay) => {
+                let sink = sink.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = sink.lock().await.handle_packet(1, sequence, data);
+                });
+            }
+        }
+    });
+
+    let mut detector = ProbeDetector { pending, was_loud: false, report: Report::default() };
+    let mut ticker = interval(scenario.frame.interval());
+    let mut pcm = vec![0.0; scenario.frame.stereo_samples()];
+    let format = crate::profile::OutputFormat { frame: scenario.frame, ..Default::default() };
+    let mut music_mix = MusicMix::new(MusicFeed::new(), Default::default(), Default::default());
+    let sounds = crate::soundboard::SoundFeed::new();
+    let levels = crate::levels::AudioLevels::new();
+    let start = Instant::now();
+    while start.elapsed() < scenario.duration {
+        ticker.tick().await;
+        if let Some((packet, _)) = crate::process_discord_audio(&buffer, &mut music_mix, &sounds, None, &levels, &encoders, format).await {
+            let len = decoder.decode_float(Some(&packet.content()[3..]), &mut pcm[..], false).unwrap();
+            assert_eq!(len * 2, pcm.len());
+            detector.frame(&pcm);
+        }
+    }
+    detector.report.probes_sent = sender.await.unwrap();
+    detector.report
+}
+
+/// TeamSpeak clients talking, songbird reading the bridged stream.
+pub async fn ts_to_discord(scenario: &Scenario) -> Report {
+    let pipeline = TsToDiscordPipeline::new(logger());
+    let mut events = TsEventHandler::new(ConnectionId(0), pipeline.clone(), logger());
+    if let Some(impairment) = scenario.impairment {
+        events = events.with_impairment(impairment);
+    }
+    let mut buffered = BufferedPipeline::new(pipeline);
+    buffered.start_filler();
+    let pending = Arc::new(StdMutex::new(VecDeque::new()));
+
+    let sender = spawn_sender(scenario, pending.clone(), move |id, data| {
+        let packet = s2c_packet(&AudioData::S2C { id, codec: CodecType::OpusMusic, from: 1, data });
+        events.handle(StreamItem::Audio(packet));
+    });
+
+    let mut detector = ProbeDetector { pending, was_loud: false, report: Report::default() };
+    let mut ticker = interval(Duration::from_millis(TICK_TIME));
+    let mut bytes = vec![0u8; STEREO_20MS * 4];
+    let start = Instant::now();
+    while start.elapsed() < scenario.duration {
+        ticker.tick().await;
+        let n = buffered.read(&mut bytes).unwrap();
+        let pcm: Vec<f32> = bytes[..n]
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        detector.frame(&pcm);
+    }
+    detector.report.probes_sent = sender.await.unwrap();
+    detector.report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn assert_all_probes_heard(report: &Report) {
+        // The last probe may still be in flight when the run ends
+        assert!(
+            report.latencies.len() + 1 >= report.probes_sent,
+            "heard {} of {} probes",
+            report.latencies.len(),
+            report.probes_sent
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_steady_latency() {
+        let report = discord_to_ts(&Scenario::new(MINUTE)).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(100), "{:?}", report.max_latency());
+        assert!(report.max_latency() - report.min_latency() <= Duration::from_millis(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_fast_sender_stays_bounded() {
+        let report = discord_to_ts(&Scenario::new(MINUTE * 5).drift_ppm(5_000)).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(600), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_slow_sender_keeps_playing() {
+        let report = discord_to_ts(&Scenario::new(MINUTE * 5).drift_ppm(-5_000)).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(600), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_resumes_after_dtx() {
+        let scenario = Scenario::new(MINUTE).dtx_gap(Duration::from_secs(10), Duration::from_secs(20));
+        let report = discord_to_ts(&scenario).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(100), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ts_to_discord_steady_latency() {
+        let report = ts_to_discord(&Scenario::new(MINUTE)).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(100), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ts_to_discord_fast_sender_stays_bounded() {
+        let report = ts_to_discord(&Scenario::new(MINUTE * 5).drift_ppm(5_000)).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(1100), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_frame_sizes() {
+        for frame_ms in [10, 40, 60] {
+            let report = discord_to_ts(&Scenario::new(MINUTE / 2).frame(frame_ms)).await;
+            assert_all_probes_heard(&report);
+            let bound = Duration::from_millis(60 + 2 * frame_ms);
+            assert!(report.max_latency() <= bound, "{} ms: {:?}", frame_ms, report.max_latency());
+        }
+    }
+
+    fn bad_network() -> ImpairmentConfig {
+        ImpairmentConfig { drop: 0.05, reorder: 0.05, delay_ms: 20, jitter_ms: 15, seed: None }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discord_to_ts_survives_bad_network() {
+        let report = discord_to_ts(&Scenario::new(MINUTE).impaired(bad_network())).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(200), "{:?}", report.max_latency());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ts_to_discord_survives_bad_network() {
+        let report = ts_to_discord(&Scenario::new(MINUTE).impaired(bad_network())).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(200), "{:?}", report.max_latency());
+    }
+
+    /// Opus coding dominates the run time, about a second per simulated minute
+    /// in debug builds, so the long soak only runs on request.
+    #[tokio::test(start_paused = true)]
+    #[ignore]
+    async fn soak_one_hour_with_drift() {
+        let scenario = Scenario::new(MINUTE * 60)
+            .drift_ppm(2_000)
+            .dtx_gap(MINUTE * 20, MINUTE);
+        let report = discord_to_ts(&scenario).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(600), "{:?}", report.max_latency());
+        let report = ts_to_discord(&scenario).await;
+        assert_all_probes_heard(&report);
+        assert!(report.max_latency() <= Duration::from_millis(1100), "{:?}", report.max_latency());
+    }
+}
@@ -0,0 +1,109 @@
+//! Interview mode with `/solo`, only the selected speakers are bridged.
+//!
+//! Speakers are picked on either side, everybody else is left out both ways
+//! until `/solo off`.
+
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use tsclientlib::ClientId;
+
+#[derive(Debug, Default)]
+struct Speakers {
+    /// Discord users by id, with their names.
+    discord: BTreeMap<u64, String>,
+    /// TeamSpeak clients by id, with their names.
+    ts: BTreeMap<u16, String>,
+}
+
+/// Speakers picked with `/solo`, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct Solo {
+    speakers: Arc<StdMutex<Speakers>>,
+}
+
+impl Solo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn speakers(&self) -> std::sync::MutexGuard<'_, Speakers> {
+        self.speakers.lock().expect("Can't lock solo speakers!")
+    }
+
+    /// Whether only picked speakers are bridged.
+    pub fn is_active(&self) -> bool {
+        let speakers = self.speakers();
+        !speakers.discord.is_empty() || !speakers.ts.is_empty()
+    }
+
+    /// Pick a Discord user, false if they already were.
+    pub fn add_discord(&self, user: u64, name: String) -> bool {
+        self.speakers().discord.insert(user, name).is_none()
+    }
+
+    /// Pick a TeamSpeak client, false if they already were.
+    pub fn add_ts(&self, client: ClientId, name: String) -> bool {
+        self.speakers().ts.insert(client.0, name).is_none()
+    }
+
+    /// Bridge everybody again, false if solo wasn't on.
+    pub fn clear(&self) -> bool {
+        let was_active = self.is_active();
+        *self.speakers() = Speakers::default();
+        was_active
+    }
+
+    /// Whether Discord `user` is bridged, senders not known yet aren't while solo is on.
+    pub fn allows_discord(&self, user: Option<u64>) -> bool {
+        !self.is_active() || user.is_some_and(|user| self.speakers().discord.contains_key(&user))
+    }
+
+    pub fn allows_ts(&self, client: ClientId) -> bool {
+        !self.is_active() || self.speakers().ts.contains_key(&client.0)
+    }
+
+    /// Who is bridged, in words.
+    pub fn summary(&self) -> String {
+        let speakers = self.speakers();
+        let names: Vec<_> = speakers.discord
+            .values()
+            .map(|name| format!("{} (Discord)", name))
+            .chain(speakers.ts.values().map(|name| format!("{} (TeamSpeak)", name)))
+            .collect();
+        match names.len() {
+            0 => "Solo is off, everybody is bridged.".to_string(),
+            1 => format!("Solo: only {} is bridged.", names[0]),
+            n => format!("Solo: only {} and {} are bridged.", names[..n - 1].join(", "), names[n - 1]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_picked_speakers_are_bridged_until_cleared() {
+        let solo = Solo::new();
+        assert!(solo.allows_discord(None));
+        assert!(solo.allows_ts(ClientId(5)));
+
+        assert!(solo.add_discord(1, "Ann".to_string()));
+        assert!(!solo.add_discord(1, "Ann".to_string()));
+        assert!(solo.allows_discord(Some(1)));
+        assert!(!solo.allows_discord(Some(2)));
+        assert!(!solo.allows_discord(None));
+        assert!(!solo.allows_ts(ClientId(5)));
+
+        solo.add_ts(ClientId(5), "Bob".to_string());
+        solo.add_discord(3, "Cid".to_string());
+        assert!(solo.allows_ts(ClientId(5)));
+        assert_eq!(solo.summary(), "Solo: only Ann (Discord), Cid (Discord) and Bob (TeamSpeak) are bridged.");
+
+        assert!(solo.clear());
+        assert!(!solo.clear());
+        assert!(solo.allows_discord(Some(2)));
+        assert_eq!(solo.summary(), "Solo is off, everybody is bridged.");
+    }
+}
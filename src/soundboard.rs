@@ -0,0 +1,182 @@
+//! Discord soundboard sounds, mixed into what TeamSpeak hears.
+//!
+//! Discord clients play soundboard sounds themselves, the voice connection
+//! never carries them. The gateway only says which sound was played, so the
+//! bridge downloads it and mixes it into the Discord→TS feed on its own.
+
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use anyhow::{ anyhow, Result };
+use serde_json::Value;
+use songbird::input::codecs::{ get_codec_registry, get_probe };
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+use crate::audio::{ to_stereo, LinearResampler };
+use crate::SAMPLE_RATE;
+
+/// Gateway event of a sound or emoji played in a voice channel, not modelled by serenity.
+pub const EFFECT_EVENT: &str = "VOICE_CHANNEL_EFFECT_SEND";
+/// Sounds are downloaded from here by id.
+pub const SOUND_URL: &str = "https://cdn.discordapp.com/soundboard-sounds/";
+/// Discord limits sounds to 5.2 seconds, anything past this is cut off.
+const MAX_SOUND_SAMPLES: usize = SAMPLE_RATE * 2 * 6;
+
+/// A soundboard sound played in a voice channel.
+#[derive(Debug, PartialEq)]
+pub struct SoundPlayed {
+    pub guild_id: u64,
+    pub channel_id: u64,
+    pub user_id: u64,
+    pub sound_id: String,
+    /// Volume the member set for the sound, 0 to 1.
+    pub volume: f32,
+}
+
+impl SoundPlayed {
+    /// The sound in the data of an [`EFFECT_EVENT`], `None` for emoji effects.
+    pub fn from_event(data: &Value) -> Option<Self> {
+        let sound_id = match data.get("sound_id")? {
+            Value::String(id) => id.clone(),
+            Value::Number(id) => id.to_string(),
+            _ => return None,
+        };
+        Some(Self {
+            guild_id: snowflake(data, "guild_id")?,
+            channel_id: snowflake(data, "channel_id")?,
+            user_id: snowflake(data, "user_id")?,
+            sound_id,
+            volume: data.get("sound_volume").and_then(Value::as_f64).unwrap_or(1.0) as f32,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}{}", SOUND_URL, self.sound_id)
+    }
+}
+
+fn snowflake(data: &Value, key: &str) -> Option<u64> {
+    data.get(key)?.as_str()?.parse().ok()
+}
+
+/// Sounds on their way to TeamSpeak as 48 kHz interleaved stereo, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct SoundFeed {
+    samples: Arc<StdMutex<VecDeque<f32>>>,
+}
+
+impl SoundFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start playing `samples`, on top of any sound still playing.
+    pub fn play(&self, samples: &[f32]) {
+        let mut queued = self.samples.lock().expect("Can't lock sound feed!");
+        let overlap = queued.len().min(samples.len());
+        for (sample, sound) in queued.iter_mut().zip(&samples[..overlap]) {
+            *sample += sound;
+        }
+        queued.extend(&samples[overlap..]);
+    }
+
+    /// Drop everything not played yet.
+    pub fn clear(&self) {
+        self.samples.lock().expect("Can't lock sound feed!").clear();
+    }
+
+    /// Add the next `out.len()` samples to `out`.
+    pub fn mix_into(&self, out: &mut [f32]) {
+        let mut queued = self.samples.lock().expect("Can't lock sound feed!");
+        let n = out.len().min(queued.len());
+        for (sample, sound) in out.iter_mut().zip(queued.drain(..n)) {
+            *sample += sound;
+        }
+    }
+}
+
+/// Decode a downloaded sound to 48 kHz stereo at `volume`.
+pub fn decode(data: Vec<u8>, volume: f32) -> Result<Vec<f32>> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let mut format = get_probe()
+        .format(&Hint::new(), source, &Default::default(), &Default::default())?
+        .format;
+    let track = format.default_track().ok_or_else(|| anyhow!("Sound has no audio track"))?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate.ok_or_else(|| anyhow!("Sound has no sample rate"))?;
+    let mut decoder = get_codec_registry().make(&track.codec_params, &Default::default())?;
+
+    let mut resampler = LinearResampler::new(sample_rate);
+    let mut stereo = Vec::new();
+    let mut out = Vec::new();
+    while out.len() < MAX_SOUND_SAMPLES {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+
+        stereo.clear();
+        to_stereo(samples.samples(), spec.channels.count(), &mut stereo);
+        resampler.process(&stereo, &mut out);
+    }
+    out.truncate(MAX_SOUND_SAMPLES);
+    for sample in &mut out {
+        *sample *= volume;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sounds_are_read_from_effect_events() {
+        let event = json!({
+            "guild_id": "1",
+            "channel_id": "2",
+            "user_id": "3",
+            "sound_id": "4",
+            "sound_volume": 0.5,
+        });
+        assert_eq!(
+            SoundPlayed::from_event(&event),
+            Some(SoundPlayed { guild_id: 1, channel_id: 2, user_id: 3, sound_id: "4".into(), volume: 0.5 })
+        );
+        assert_eq!(SoundPlayed::from_event(&event).unwrap().url(), format!("{}4", SOUND_URL));
+
+        let emoji = json!({ "guild_id": "1", "channel_id": "2", "user_id": "3", "emoji": { "name": "🔥" } });
+        assert_eq!(SoundPlayed::from_event(&emoji), None);
+    }
+
+    #[test]
+    fn overlapping_sounds_are_mixed() {
+        let feed = SoundFeed::new();
+        feed.play(&[0.1, 0.1, 0.1]);
+        feed.play(&[0.2, 0.2, 0.2, 0.2]);
+
+        let mut out = [1.0; 2];
+        feed.mix_into(&mut out);
+        assert_eq!(out, [1.3, 1.3]);
+        let mut out = [0.0; 4];
+        feed.mix_into(&mut out);
+        assert_eq!(out, [0.3, 0.2, 0.0, 0.0]);
+    }
+}
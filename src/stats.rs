@@ -0,0 +1,306 @@
+//! Counters since the start of the process, for `/uptime`, `status` and the session summary.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use crate::audio::{ peak, DropCounter };
+use crate::levels::Direction;
+use crate::music::format_duration;
+use crate::SAMPLE_RATE;
+
+/// How often who is in the bridged channels is looked at, for the session summary.
+pub const PEOPLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Frames quieter than this, about -60 dBFS, don't count as bridged audio.
+const AUDIBLE_PEAK: f32 = 0.001;
+
+pub fn is_audible(samples: &[f32]) -> bool {
+    peak(samples) >= AUDIBLE_PEAK
+}
+
+/// Where Opus spends CPU time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecStage {
+    Decode,
+    Encode,
+}
+
+#[derive(Debug, Default)]
+struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    /// Audible audio passed on, in µs.
+    audible_micros: AtomicU64,
+    /// Time spent decoding, in µs.
+    decode_micros: DropCounter,
+    /// Time spent encoding, in µs.
+    encode_micros: DropCounter,
+}
+
+/// Who was in the bridged channels.
+#[derive(Debug, Default)]
+struct People {
+    discord: HashSet<u64>,
+    /// TeamSpeak clients by unique id.
+    ts: HashSet<String>,
+    peak_speakers: usize,
+}
+
+#[derive(Debug)]
+struct Counters {
+    started: Instant,
+    /// Since the connection or the last reconnect to TeamSpeak, `None` while not connected.
+    ts_session: StdMutex<Option<Instant>>,
+    reconnects: AtomicU64,
+    /// Discord audio queues reset by the watchdog.
+    audio_resets: AtomicU64,
+    people: StdMutex<People>,
+    discord_to_ts: Traffic,
+    ts_to_discord: Traffic,
+}
+
+/// Uptime and traffic of the bridge, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct BridgeStats {
+    counters: Arc<Counters>,
+}
+
+impl Default for BridgeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BridgeStats {
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::new(Counters {
+                started: Instant::now(),
+                ts_session: StdMutex::new(None),
+                reconnects: AtomicU64::new(0),
+                audio_resets: AtomicU64::new(0),
+                people: Default::default(),
+                discord_to_ts: Traffic::default(),
+                ts_to_discord: Traffic::default(),
+            }),
+        }
+    }
+
+    fn traffic(&self, direction: Direction) -> &Traffic {
+        match direction {
+            Direction::DiscordToTs => &self.counters.discord_to_ts,
+            Direction::TsToDiscord => &self.counters.ts_to_discord,
+        }
+    }
+
+    /// Connected to TeamSpeak, a new session starts.
+    pub fn ts_connected(&self) {
+        *self.counters.ts_session.lock().expect("Can't lock TeamSpeak session!") = Some(Instant::now());
+    }
+
+    /// Lost the TeamSpeak connection, tsclientlib is reconnecting.
+    pub fn ts_reconnecting(&self) {
+        self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.ts_connected();
+    }
+
+    pub fn ts_disconnected(&self) {
+        *self.counters.ts_session.lock().expect("Can't lock TeamSpeak session!") = None;
+    }
+
+    /// The watchdog reset the Discord audio queues.
+    pub fn audio_reset(&self) {
+        self.counters.audio_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note who is in the bridged channels and how many of them are `speaking` right now.
+    pub fn saw_people(&self, discord: impl IntoIterator<Item = u64>, ts: impl IntoIterator<Item = String>, speaking: usize) {
+        let mut people = self.counters.people.lock().expect("Can't lock people seen!");
+        people.discord.extend(discord);
+        people.ts.extend(ts);
+        people.peak_speakers = people.peak_speakers.max(speaking);
+    }
+
+    /// `bytes` arrived for `direction`.
+    pub fn received(&self, direction: Direction, bytes: usize) {
+        self.traffic(direction).received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// `bytes` left for `direction`.
+    pub fn sent(&self, direction: Direction, bytes: usize) {
+        self.traffic(direction).sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// `samples`, interleaved stereo, were passed on, they count if audible.
+    pub fn bridged(&self, direction: Direction, samples: &[f32]) {
+        if is_audible(samples) {
+            let micros = (samples.len() / 2) as u64 * 1_000_000 / (SAMPLE_RATE as u64);
+            self.bridged_frame(direction, Duration::from_micros(micros));
+        }
+    }
+
+    /// An audible frame of length `frame` was passed on.
+    pub fn bridged_frame(&self, direction: Direction, frame: Duration) {
+        self.traffic(direction).audible_micros.fetch_add(frame.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.counters.started.elapsed()
+    }
+
+    pub fn ts_session(&self) -> Option<Duration> {
+        self.counters.ts_session.lock().expect("Can't lock TeamSpeak session!").map(|start| start.elapsed())
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.counters.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn audio_resets(&self) -> u64 {
+        self.counters.audio_resets.load(Ordering::Relaxed)
+    }
+
+    /// What the session was like, posted when the bridge leaves or shuts down.
+    pub fn summary(&self) -> String {
+        let people = self.counters.people.lock().expect("Can't lock people seen!");
+        format!(
+            "Bridge session summary\nUp for {}\nParticipants: {} on Discord, {} on TeamSpeak\nMost speaking at once: {}\nDropouts: {} TeamSpeak reconnects, {} Discord audio resets",
+            format_duration(self.uptime()),
+            people.discord.len(),
+            people.ts.len(),
+            people.peak_speakers,
+            self.reconnects(),
+            self.audio_resets()
+        )
+    }
+
+    /// Audible audio passed on for `direction`.
+    pub fn audio(&self, direction: Direction) -> Duration {
+        Duration::from_micros(self.traffic(direction).audible_micros.load(Ordering::Relaxed))
+    }
+
+    /// Counts µs spent in `stage` for `direction`, for whoever decodes or encodes.
+    pub fn codec_counter(&self, direction: Direction, stage: CodecStage) -> DropCounter {
+        let traffic = self.traffic(direction);
+        match stage {
+            CodecStage::Decode => traffic.decode_micros.clone(),
+            CodecStage::Encode => traffic.encode_micros.clone(),
+        }
+    }
+
+    pub fn add_codec_time(&self, direction: Direction, stage: CodecStage, took: Duration) {
+        self.codec_counter(direction, stage).add(took.as_micros() as u64);
+    }
+
+    /// Time spent in `stage` for `direction` since the start.
+    pub fn codec_time(&self, direction: Direction, stage: CodecStage) -> Duration {
+        Duration::from_micros(self.codec_counter(direction, stage).get())
+    }
+
+    /// Share of one core `stage` took for `direction` since the start, in percent.
+    pub fn codec_load(&self, direction: Direction, stage: CodecStage) -> f32 {
+        let uptime = self.uptime().as_secs_f32();
+        if uptime > 0.0 { (self.codec_time(direction, stage).as_secs_f32() / uptime) * 100.0 } else { 0.0 }
+    }
+}
+
+/// Bytes as B, KiB, MiB or GiB.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+impl fmt::Display for BridgeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Up for {}", format_duration(self.uptime()))?;
+        match self.ts_session() {
+            Some(session) => writeln!(f, "TeamSpeak session: {}", format_duration(session))?,
+            None => writeln!(f, "TeamSpeak session: not connected")?,
+        }
+        writeln!(f, "Reconnects: {}", self.reconnects())?;
+        let directions = [
+            (Direction::DiscordToTs, "Discord → TeamSpeak", "from Discord", "to TeamSpeak"),
+            (Direction::TsToDiscord, "TeamSpeak → Discord", "from TeamSpeak", "to Discord as PCM"),
+        ];
+        for (i, &(direction, name, from, to)) in directions.iter().enumerate() {
+            let traffic = self.traffic(direction);
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}: {} of audio, {} {}, {} {}",
+                name,
+                format_duration(self.audio(direction)),
+                format_bytes(traffic.received.load(Ordering::Relaxed)),
+                from,
+                format_bytes(traffic.sent.load(Ordering::Relaxed)),
+                to
+            )?;
+        }
+        // Songbird encodes for Discord on its own
+        write!(
+            f,
+            "\nOpus CPU: {:.1}% decoding and {:.1}% encoding Discord audio, {:.1}% decoding and mixing TeamSpeak audio",
+            self.codec_load(Direction::DiscordToTs, CodecStage::Decode),
+            self.codec_load(Direction::DiscordToTs, CodecStage::Encode),
+            self.codec_load(Direction::TsToDiscord, CodecStage::Decode)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_audible_audio_and_traffic_per_direction() {
+        let stats = BridgeStats::new();
+        assert_eq!(stats.ts_session(), None);
+        stats.ts_connected();
+        stats.ts_reconnecting();
+        assert_eq!(stats.reconnects(), 1);
+        assert!(stats.ts_session().is_some());
+
+        let second = vec![0.5; SAMPLE_RATE * 2];
+        stats.bridged(Direction::DiscordToTs, &second);
+        stats.bridged(Direction::DiscordToTs, &vec![0.0; SAMPLE_RATE * 2]);
+        stats.received(Direction::DiscordToTs, 1536);
+        stats.sent(Direction::DiscordToTs, 100);
+        stats.bridged_frame(Direction::DiscordToTs, Duration::from_millis(20));
+        assert_eq!(stats.audio(Direction::DiscordToTs), Duration::from_millis(1020));
+        assert_eq!(stats.audio(Direction::TsToDiscord), Duration::ZERO);
+
+        let report = stats.to_string();
+        assert!(report.contains("Discord → TeamSpeak: 0:01 of audio, 1.5 KiB from Discord, 100 B to TeamSpeak"));
+        assert!(report.contains("TeamSpeak → Discord: 0:00 of audio, 0 B from TeamSpeak, 0 B to Discord as PCM"));
+
+        stats.codec_counter(Direction::DiscordToTs, CodecStage::Decode).add(1500);
+        stats.add_codec_time(Direction::DiscordToTs, CodecStage::Encode, Duration::from_millis(2));
+        assert_eq!(stats.codec_time(Direction::DiscordToTs, CodecStage::Decode), Duration::from_micros(1500));
+        assert_eq!(stats.codec_time(Direction::DiscordToTs, CodecStage::Encode), Duration::from_millis(2));
+        assert_eq!(stats.codec_time(Direction::TsToDiscord, CodecStage::Encode), Duration::ZERO);
+        assert!(stats.codec_load(Direction::DiscordToTs, CodecStage::Encode) > 0.0);
+    }
+
+    #[test]
+    fn summary_counts_people_once_and_the_most_speaking() {
+        let stats = BridgeStats::new();
+        stats.saw_people([1, 2], ["uid".to_string()], 2);
+        stats.saw_people([2, 3], ["uid".to_string()], 1);
+        stats.ts_reconnecting();
+        stats.audio_reset();
+        let summary = stats.summary();
+        assert!(summary.contains("Participants: 3 on Discord, 1 on TeamSpeak\nMost speaking at once: 2\n"), "{}", summary);
+        assert!(summary.ends_with("Dropouts: 1 TeamSpeak reconnects, 1 Discord audio resets"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Who talks how much on both sides, for `/stats talk`.
+//!
+//! Discord users count while songbird hears them in a voice tick, TeamSpeak
+//! clients while their audible voice is mixed; both clients only send while
+//! voice activation or push-to-talk is on. Totals of this session stay in
+//! memory, all-time totals are added to the settings file every minute.
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::Duration;
+
+use tsclientlib::ClientId;
+
+use crate::settings::{ Settings, TsTalkTime };
+use crate::stats::is_audible;
+use crate::SAMPLE_RATE;
+
+/// How often TeamSpeak clients heard are matched to their unique id.
+pub const RESOLVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often speaking time is added to the settings file.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Places shown on the leaderboard.
+pub const LEADERBOARD_PLACES: usize = 10;
+
+/// Somebody counted, TeamSpeak clients by unique id.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Talker {
+    Discord(u64),
+    Ts(String),
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// TeamSpeak clients heard but not yet matched to their unique id.
+    pending_ts: HashMap<ClientId, Duration>,
+    session: HashMap<Talker, Duration>,
+    /// Not yet added to the settings.
+    unsaved: HashMap<Talker, Duration>,
+    /// Names of the TeamSpeak clients heard, by unique id.
+    ts_names: HashMap<String, String>,
+}
+
+/// Speaking time of this session and what isn't saved yet, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct TalkTime {
+    counts: Arc<StdMutex<Counts>>,
+}
+
+impl TalkTime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts.lock().expect("Can't lock speaking time!")
+    }
+
+    /// Count `length` of the Discord user `user` talking.
+    pub fn discord(&self, user: u64, length: Duration) {
+        let mut counts = self.lock();
+        *counts.session.entry(Talker::Discord(user)).or_default() += length;
+        *counts.unsaved.entry(Talker::Discord(user)).or_default() += length;
+    }
+
+    /// Count the interleaved stereo `samples` of `client` if audible, credited once [`resolve_ts`](Self::resolve_ts) knows them.
+    pub fn ts(&self, client: ClientId, samples: &[f32]) {
+        if is_audible(samples) {
+            let micros = (samples.len() / 2) as u64 * 1_000_000 / (SAMPLE_RATE as u64);
+            *self.lock().pending_ts.entry(client).or_default() += Duration::from_micros(micros);
+        }
+    }
+
+    /// Credit the TeamSpeak clients heard to the unique id and name `who` gives, dropping unknown ones.
+    pub fn resolve_ts(&self, who: impl Fn(ClientId) -> Option<(String, String)>) {
+        let mut counts = self.lock();
+        for (client, length) in std::mem::take(&mut counts.pending_ts) {
+            let Some((uid, name)) = who(client) else {
+                continue;
+            };
+            *counts.session.entry(Talker::Ts(uid.clone())).or_default() += length;
+            *counts.unsaved.entry(Talker::Ts(uid.clone())).or_default() += length;
+            counts.ts_names.insert(uid, name);
+        }
+    }
+
+    /// Add what was counted since the last save to `settings`.
+    pub fn save(&self, settings: &mut Settings) {
+        let mut counts = self.lock();
+        for (talker, length) in std::mem::take(&mut counts.unsaved) {
+            let ms = length.as_millis() as u64;
+            match talker {
+                Talker::Discord(user) => *settings.discord_talk_ms.entry(user).or_default() += ms,
+                Talker::Ts(uid) => {
+                    let name = counts.ts_names.get(&uid).cloned().unwrap_or_else(|| uid.clone());
+                    let total = settings.ts_talk.entry(uid).or_insert(TsTalkTime { name: String::new(), ms: 0 });
+                    total.name = name;
+                    total.ms += ms;
+                }
+            }
+        }
+    }
+
+    /// Whether there is anything to save.
+    pub fn has_unsaved(&self) -> bool {
+        !self.lock().unsaved.is_empty()
+    }
+
+    /// Drop the Discord user `user` from this session, as they asked to be forgotten.
+    pub fn forget_discord(&self, user: u64) {
+        let mut counts = self.lock();
+        counts.session.remove(&Talker::Discord(user));
+        counts.unsaved.remove(&Talker::Discord(user));
+    }
+
+    pub fn session(&self) -> HashMap<Talker, Duration> {
+        self.lock().session.clone()
+    }
+
+    /// Totals over all sessions, from `settings` and what isn't saved yet.
+    pub fn all_time(&self, settings: &Settings) -> HashMap<Talker, Duration> {
+        let mut totals: HashMap<Talker, Duration> = settings.discord_talk_ms
+            .iter()
+            .map(|(&user, &ms)| (Talker::Discord(user), Duration::from_millis(ms)))
+            .chain(settings.ts_talk.iter().map(|(uid, total)| (Talker::Ts(uid.clone()), Duration::from_millis(total.ms))))
+            .collect();
+        for (talker, length) in &self.lock().unsaved {
+            *totals.entry(talker.clone()).or_default() += *length;
+        }
+        totals
+    }
+
+    /// Name of the TeamSpeak client `uid`, as last heard or saved.
+    pub fn ts_name(&self, settings: &Settings, uid: &str) -> String {
+        self.lock().ts_names
+            .get(uid)
+            .or_else(|| settings.ts_talk.get(uid).map(|total| &total.name))
+            .cloned()
+            .unwrap_or_else(|| uid.to_string())
+    }
+}
+
+/// The `places` who talked most in `totals`, longest first.
+pub fn leaderboard(totals: &HashMap<Talker, Duration>, places: usize) -> Vec<(Talker, Duration)> {
+    let mut ranked: Vec<_> = totals.iter().map(|(talker, length)| (talker.clone(), *length)).collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked.truncate(places);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaking_time_is_counted_and_saved() {
+        let talk = TalkTime::new();
+        let frame = Duration::from_millis(20);
+        for _ in 0..100 {
+            talk.discord(1, frame);
+        }
+        for _ in 0..300 {
+            talk.ts(ClientId(5), &[0.1; 1920]);
+            talk.ts(ClientId(6), &[0.0; 1920]);
+        }
+        talk.resolve_ts(|client| (client == ClientId(5)).then(|| ("uid5".to_string(), "Ann".to_string())));
+
+        let session = talk.session();
+        assert_eq!(session[&Talker::Discord(1)], Duration::from_secs(2));
+        assert_eq!(session[&Talker::Ts("uid5".to_string())], Duration::from_secs(6));
+        assert_eq!(session.len(), 2);
+
+        let mut settings = Settings::default();
+        settings.discord_talk_ms.insert(1, 10_000);
+        assert_eq!(talk.all_time(&settings)[&Talker::Discord(1)], Duration::from_secs(12));
+        talk.save(&mut settings);
+        assert!(!talk.has_unsaved());
+        assert_eq!(settings.discord_talk_ms[&1], 12_000);
+        assert_eq!(settings.ts_talk["uid5"], TsTalkTime { name: "Ann".to_string(), ms: 6_000 });
+        assert_eq!(talk.all_time(&settings)[&Talker::Discord(1)], Duration::from_secs(12));
+
+        let board = leaderboard(&talk.all_time(&settings), 1);
+        assert_eq!(board, vec![(Talker::Discord(1), Duration::from_secs(12))]);
+
+        talk.forget_discord(1);
+        assert_eq!(talk.session().len(), 1);
+    }
+}
@@ -0,0 +1,1421 @@
+//! Routing of the TeamSpeak event stream into the bridge.
+
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex as StdMutex };
+use std::time::{ Duration, Instant };
+
+use serde::Serialize;
+use slog::{ debug, info, warn, Logger };
+use tokio::sync::{ mpsc, oneshot };
+use tsclientlib::data::{ Client, Connection as ConnectionState };
+use tsclientlib::events::Event;
+use tsclientlib::prelude::*;
+use tsclientlib::{
+    ChannelId,
+    ClientId,
+    ClientType,
+    CommandError,
+    Connection,
+    ConnectionStats,
+    FiletransferHandle,
+    Invoker,
+    MessageHandle,
+    MessageTarget,
+    ServerGroupId,
+    StreamItem,
+};
+use tsproto_packets::packets::{ AudioData, CodecType, InAudioBuf };
+
+use crate::bridge_events::{ BridgeEvent, EventBus };
+use crate::chat_bridge::{ ChatRateLimits, FloodGuard };
+use crate::impair::{ Fate, Impairer, ImpairmentConfig };
+use crate::levels::Direction;
+use crate::settings::{ Settings, SharedSettings, TsDelay };
+use crate::hands::{ HandQueue, Speaker };
+use crate::solo::Solo;
+use crate::stats::BridgeStats;
+use crate::ts_avatar::{ self, AVATAR_PATH };
+use crate::ts_chat::{ send_text, ChatCommands };
+use crate::{ ConnectionId, TsToDiscordPipeline };
+
+/// TeamSpeak cuts chat messages longer than this.
+const MAX_MESSAGE_CHARS: usize = 1024;
+/// TeamSpeak refuses pokes longer than this.
+const MAX_POKE_CHARS: usize = 100;
+/// TeamSpeak refuses longer nicknames.
+const MAX_NICKNAME_CHARS: usize = 30;
+
+/// Nickname tried when the configured one is taken, as the TeamSpeak client does.
+pub const DEFAULT_NICKNAME_PATTERN: &str = "{name}{n}";
+/// Taken nicknames tried before giving up.
+pub const MAX_NICKNAME_ATTEMPTS: u32 = 10;
+
+/// Requests from Discord commands for the main loop, which owns the connection.
+#[derive(Debug)]
+pub enum TsCommand {
+    /// Leave a TeamSpeak client out of the TS→Discord mix, or add it back.
+    ///
+    /// Replies with the name of the matched client.
+    SetMuted {
+        client: String,
+        muted: bool,
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// Play a TeamSpeak client later than the others, or on time again with no delay.
+    ///
+    /// Replies with the name of the matched client.
+    SetDelay {
+        client: String,
+        delay: Duration,
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// Turn podium mode on or off.
+    ///
+    /// Replies with how many clients are on the podium.
+    SetPodium {
+        enabled: bool,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Write to the chat of the channel the bridge is in.
+    SendMessage {
+        /// Display name of who sent it from Discord.
+        author: String,
+        text: String,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    /// Poke a TeamSpeak client, so a popup shows them the message.
+    ///
+    /// Replies with the name of the matched client.
+    Poke {
+        client: String,
+        /// Display name of who sent it from Discord.
+        author: String,
+        text: String,
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// Ping and packet loss to the server, `None` while not connected.
+    LinkQuality {
+        reply: oneshot::Sender<Option<LinkQuality>>,
+    },
+    /// Write `text` to the chat of the channel the bridge is in, as the bridge itself.
+    Announce {
+        text: String,
+    },
+    /// Replace the description of the channel the bridge is in.
+    SetChannelDescription {
+        description: String,
+    },
+    /// Upload `image` as the bridge's avatar, unless it already is.
+    SetAvatar {
+        image: Vec<u8>,
+    },
+    /// Move into the configured channel with `password`.
+    ///
+    /// Replies with the name of the channel once the server accepted it.
+    JoinHomeChannel {
+        password: String,
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// A `!command` written in TeamSpeak chat.
+    Chat {
+        target: MessageTarget,
+        invoker: Invoker,
+        message: String,
+    },
+    /// Reconnect with the next configured identity, handled by the main loop.
+    ///
+    /// Replies with the identity now in use.
+    RotateIdentity {
+        reply: IdentityReply,
+    },
+}
+
+/// Where the outcome of `TsCommand::RotateIdentity` goes.
+pub type IdentityReply = oneshot::Sender<Result<String, String>>;
+
+pub type TsCommandSender = mpsc::UnboundedSender<TsCommand>;
+
+/// How well the connection to the TeamSpeak server is doing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LinkQuality {
+    pub ping_ms: f32,
+    /// How much the ping varies.
+    pub ping_deviation_ms: f32,
+    /// Share of voice packets from the server lost on the way, `0.0` to `1.0`.
+    pub voice_loss: f32,
+    /// Share of all packets from the server lost on the way.
+    pub total_loss: f32,
+}
+
+impl From<&ConnectionStats> for LinkQuality {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            ping_ms: stats.rtt.as_secs_f32() * 1000.0,
+            ping_deviation_ms: stats.rtt_dev.as_secs_f32() * 1000.0,
+            voice_loss: stats.get_packetloss_s2c_speech(),
+            total_loss: stats.get_packetloss_s2c_total(),
+        }
+    }
+}
+
+impl fmt::Display for LinkQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.0} ms (±{:.0} ms), {:.1}% voice and {:.1}% total packet loss",
+            self.ping_ms,
+            self.ping_deviation_ms,
+            self.voice_loss * 100.0,
+            self.total_loss * 100.0
+        )
+    }
+}
+
+/// The channel the bridge is configured to be in.
+#[derive(Clone, Debug)]
+pub enum HomeChannel {
+    Id(u64),
+    /// Channel names from the top, separated by `/`.
+    Path(String),
+}
+
+impl HomeChannel {
+    fn find(&self, state: &ConnectionState) -> Option<ChannelId> {
+        match self {
+            HomeChannel::Id(id) => Some(ChannelId(*id)).filter(|id| state.channels.contains_key(id)),
+            HomeChannel::Path(path) => {
+                let channels = state.channels.values().map(|c| (c.id, c.parent, c.name.as_str()));
+                channel_by_path(channels, path)
+            }
+        }
+    }
+}
+
+impl fmt::Display for HomeChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HomeChannel::Id(id) => write!(f, "#{}", id),
+            HomeChannel::Path(path) => f.write_str(path),
+        }
+    }
+}
+
+/// Name of the channel being joined, its password and who to tell how it went.
+type PendingMove = (String, String, oneshot::Sender<Result<String, String>>);
+
+/// Handles items of the TeamSpeak connection event stream.
+pub struct TsEventHandler {
+    con_id: ConnectionId,
+    pipeline: TsToDiscordPipeline,
+    logger: Logger,
+    impairer: Option<Impairer>,
+    /// Chat commands and the queue to the main loop they are forwarded through.
+    chat: Option<(ChatCommands, TsCommandSender)>,
+    /// Clients joined, left or changed since the last [`take_roster_changed`](Self::take_roster_changed).
+    roster_changed: AtomicBool,
+    /// Limits messages and pokes from Discord.
+    flood: StdMutex<FloodGuard>,
+    /// Avatar images and their hashes, waiting for their upload to start.
+    avatar_uploads: StdMutex<HashMap<FiletransferHandle, (Vec<u8>, String)>>,
+    /// Hash of an uploaded avatar, see [`take_uploaded_avatar`](Self::take_uploaded_avatar).
+    avatar_uploaded: Arc<StdMutex<Option<String>>>,
+    /// Channel to be in, see [`check_home_channel`](Self::check_home_channel).
+    home: Option<HomeChannel>,
+    /// Whether the bridge was outside its channel at the last check.
+    away_from_home: AtomicBool,
+    /// Moves into the home channel waiting for the server's answer.
+    home_moves: StdMutex<HashMap<MessageHandle, PendingMove>>,
+    /// Password of the home channel, the configured one or the last one accepted.
+    channel_password: StdMutex<Option<String>>,
+    /// Channel the bridge was last seen in, gone back to after reconnecting.
+    last_channel: StdMutex<Option<ChannelId>>,
+    /// Channel whose audio is bridged, see [`follow_channel`](Self::follow_channel).
+    audio_channel: StdMutex<Option<ChannelId>>,
+    /// Set on a temporary disconnect, see [`restore_channel`](Self::restore_channel).
+    reconnected: AtomicBool,
+    /// Move back to the last channel after a reconnect, waiting for the server's answer.
+    restore_move: StdMutex<Option<MessageHandle>>,
+    /// Set outside the scheduled hours, audio is not bridged then.
+    dormant: Arc<AtomicBool>,
+    /// Set by `/pause-bridge`, audio is not bridged then.
+    paused: Arc<AtomicBool>,
+    /// Set while one side is empty, audio is not even decoded then.
+    idle: Arc<AtomicBool>,
+    /// Server groups whose members are bridged, everybody if unset.
+    voice_groups: Option<HashSet<ServerGroupId>>,
+    /// Connected clients in one of the `voice_groups`, see [`refresh_voice_gate`](Self::refresh_voice_gate).
+    voice_allowed: StdMutex<HashSet<ClientId>>,
+    /// Counts received audio and reconnects.
+    stats: BridgeStats,
+    /// Told about reconnects.
+    events: EventBus,
+    /// Only the picked clients are bridged while it is on.
+    solo: Solo,
+    /// Whoever has the floor is bridged despite mutes and `/solo`.
+    hands: HandQueue,
+}
+
+impl TsEventHandler {
+    pub fn new(con_id: ConnectionId, pipeline: TsToDiscordPipeline, logger: Logger) -> Self {
+        Self {
+            con_id,
+            pipeline,
+            logger,
+            impairer: None,
+            chat: None,
+            roster_changed: AtomicBool::new(true),
+            flood: StdMutex::new(FloodGuard::new(ChatRateLimits::default(), Instant::now())),
+            avatar_uploads: Default::default(),
+            avatar_uploaded: Default::default(),
+            home: None,
+            away_from_home: AtomicBool::new(false),
+            home_moves: Default::default(),
+            channel_password: Default::default(),
+            last_channel: Default::default(),
+            audio_channel: Default::default(),
+            reconnected: AtomicBool::new(false),
+            restore_move: Default::default(),
+            dormant: Default::default(),
+            paused: Default::default(),
+            idle: Default::default(),
+            voice_groups: None,
+            voice_allowed: Default::default(),
+            stats: BridgeStats::new(),
+            events: EventBus::new(),
+            solo: Solo::new(),
+            hands: HandQueue::default(),
+        }
+    }
+
+    /// Only bridge clients in one of `groups`.
+    pub fn with_voice_groups(mut self, groups: Vec<u64>) -> Self {
+        self.voice_groups = Some(groups.into_iter().map(ServerGroupId).collect());
+        self
+    }
+
+    /// Drop received audio while `dormant` is set.
+    pub fn with_dormant_flag(mut self, dormant: Arc<AtomicBool>) -> Self {
+        self.dormant = dormant;
+        self
+    }
+
+    /// Drop received audio while `paused` is set.
+    pub fn with_pause_flag(mut self, paused: Arc<AtomicBool>) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Drop received audio while `idle` is set.
+    pub fn with_idle_flag(mut self, idle: Arc<AtomicBool>) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Only bridge the clients picked with `/solo` while it is on.
+    pub fn with_solo(mut self, solo: Solo) -> Self {
+        self.solo = solo;
+        self
+    }
+
+    /// Bridge whoever has the floor in `hands`, despite mutes and `/solo`.
+    pub fn with_hands(mut self, hands: HandQueue) -> Self {
+        self.hands = hands;
+        self
+    }
+
+    /// Count received audio and reconnects in `stats`.
+    pub fn with_stats(mut self, stats: BridgeStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Use `password` when moving back into the home channel.
+    pub fn with_channel_password(self, password: Option<String>) -> Self {
+        *self.channel_password.lock().expect("Can't lock channel password!") = password;
+        self
+    }
+
+    /// Tell when the bridge is not in `home`, and allow moving back there.
+    pub fn with_home_channel(mut self, home: HomeChannel) -> Self {
+        self.home = Some(home);
+        self
+    }
+
+    /// Start over on another server, whose channel to be in is `home`.
+    pub fn switch_server(&mut self, home: Option<HomeChannel>, channel_password: Option<String>) {
+        self.home = home;
+        self.away_from_home.store(false, Ordering::Relaxed);
+        *self.channel_password.lock().expect("Can't lock channel password!") = channel_password;
+        // Channel ids of the old server mean nothing on this one
+        *self.last_channel.lock().expect("Can't lock last channel!") = None;
+        *self.audio_channel.lock().expect("Can't lock audio channel!") = None;
+        self.new_session();
+    }
+
+    /// Limit how many messages and pokes from Discord go to TeamSpeak.
+    pub fn with_rate_limits(mut self, limits: ChatRateLimits) -> Self {
+        self.flood = StdMutex::new(FloodGuard::new(limits, Instant::now()));
+        self
+    }
+
+    /// Artificially drop, delay and reorder received audio packets.
+    pub fn with_impairment(mut self, config: ImpairmentConfig) -> Self {
+        self.impairer = Some(Impairer::new(config));
+        self
+    }
+
+    /// Answer `!commands` in TeamSpeak chat.
+    ///
+    /// They are queued as [`TsCommand::Chat`] on `commands`, the main loop
+    /// runs them through [`handle_command`](Self::handle_command).
+    pub fn with_chat(mut self, chat: ChatCommands, commands: TsCommandSender) -> Self {
+        self.chat = Some((chat, commands));
+        self
+    }
+
+    /// Route an item of the connection's stream to the part of the bridge it is for.
+    pub fn handle(&self, item: StreamItem) {
+        match item {
+            StreamItem::Audio(packet) => self.handle_audio(packet),
+            StreamItem::BookEvents(events) => self.handle_book_events(events),
+            StreamItem::DisconnectedTemporarily(reason) => {
+                warn!(self.logger, "Temporarily disconnected from TeamSpeak"; "reason" => ?reason);
+                self.stats.ts_reconnecting();
+                self.events.publish(BridgeEvent::TsReconnecting);
+                self.new_session();
+            }
+            StreamItem::IdentityLevelIncreasing(level) => {
+                info!(self.logger, "Improving the TeamSpeak identity, this can take a while"; "level" => level);
+            }
+            StreamItem::IdentityLevelIncreased => info!(self.logger, "Improved the TeamSpeak identity"),
+            StreamItem::FileUpload(handle, upload) => self.handle_upload(handle, upload.stream),
+            StreamItem::FiletransferFailed(handle, e) => {
+                let pending = self.avatar_uploads.lock().expect("Can't lock avatar uploads!").remove(&handle);
+                if pending.is_some() {
+                    warn!(self.logger, "Failed to upload TeamSpeak avatar"; "error" => %e);
+                }
+            }
+            StreamItem::MessageResult(handle, result) => self.handle_message_result(handle, result),
+            _ => {}
+        }
+    }
+
+    fn handle_audio(&self, packet: InAudioBuf) {
+        self.stats.received(Direction::TsToDiscord, packet.raw_data().len());
+        let (from, codec, data) = match packet.data().data() {
+            AudioData::S2C { from, codec, data, .. } => (ClientId(*from), *codec, *data),
+            AudioData::S2CWhisper { from, codec, data, .. } => (ClientId(*from), *codec, *data),
+            _ => {
+                warn!(self.logger, "Can only handle S2C packets but got a C2S packet");
+                return;
+            }
+        };
+        let is_opus = matches!(codec, CodecType::OpusVoice | CodecType::OpusMusic);
+        if is_opus && self.chat.as_ref().is_some_and(|(chat, _)| chat.record_echo(from, data)) {
+            return;
+        }
+        let halted = [&self.dormant, &self.paused, &self.idle];
+        if halted.iter().any(|flag| flag.load(Ordering::Relaxed)) {
+            return;
+        }
+        let has_floor = self.hands.has_floor(Speaker::Ts(from));
+        if !self.is_voice_allowed(from) || !(self.solo.allows_ts(from) || has_floor) {
+            return;
+        }
+        let source = format!("client {}", from.0);
+        if self.pipeline.levels.feedback().is_suppressed(Direction::TsToDiscord, &source) {
+            return;
+        }
+
+        let id = (self.con_id, from);
+        match self.impairer.as_ref().map_or(Fate::Now, Impairer::fate) {
+            Fate::Drop => {}
+            Fate::Now => self.pipeline.ingest(id, packet),
+            Fate::Later(delay) => {
+                let pipeline = self.pipeline.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    pipeline.ingest(id, packet);
+                });
+            }
+        }
+    }
+
+    /// Chat messages and pokes go to the `!commands`, the rest is read from the book by the main loop.
+    fn handle_book_events(&self, events: Vec<Event>) {
+        self.roster_changed.store(true, Ordering::Relaxed);
+        for event in events {
+            match event {
+                Event::Message { target: MessageTarget::Poke(_), invoker, message } if !is_chat_command(&message) => {
+                    info!(self.logger, "Poked"; "client" => &invoker.name, "message" => &message);
+                }
+                Event::Message { target, invoker, message } => self.forward_chat(target, invoker, message),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_upload(&self, handle: FiletransferHandle, stream: tokio::net::TcpStream) {
+        let pending = self.avatar_uploads.lock().expect("Can't lock avatar uploads!").remove(&handle);
+        if let Some((image, hash)) = pending {
+            let uploaded = self.avatar_uploaded.clone();
+            let logger = self.logger.clone();
+            tokio::spawn(async move {
+                match ts_avatar::upload(stream, &image).await {
+                    Ok(()) => {
+                        *uploaded.lock().expect("Can't lock uploaded avatar!") = Some(hash);
+                    }
+                    Err(e) => warn!(logger, "Failed to upload TeamSpeak avatar"; "error" => %e),
+                }
+            });
+        }
+    }
+
+    fn handle_message_result(&self, handle: MessageHandle, result: Result<(), CommandError>) {
+        let restored = {
+            let mut restore = self.restore_move.lock().expect("Can't lock channel moves!");
+            let restored = *restore == Some(handle);
+            if restored {
+                *restore = None;
+            }
+            restored
+        };
+        if restored {
+            if let Err(e) = &result {
+                warn!(self.logger, "Can't go back to the TeamSpeak channel"; "error" => %e);
+            }
+            // Check the channel again, now that the move is through
+            self.roster_changed.store(true, Ordering::Relaxed);
+        }
+
+        let pending = self.home_moves.lock().expect("Can't lock channel moves!").remove(&handle);
+        if let Some((name, password, reply)) = pending {
+            match &result {
+                Ok(()) => {
+                    info!(self.logger, "Joined TeamSpeak channel"; "channel" => &name);
+                    *self.channel_password.lock().expect("Can't lock channel password!") = Some(password);
+                }
+                Err(e) => warn!(self.logger, "Can't join TeamSpeak channel"; "channel" => &name, "error" => %e),
+            }
+            let _ = reply.send(result.map(|()| name).map_err(|e| format!("TeamSpeak refused: {}", e)));
+        }
+    }
+
+    fn forward_chat(&self, target: MessageTarget, invoker: Invoker, message: String) {
+        if !is_chat_command(&message) {
+            return;
+        }
+        if let Some((_, commands)) = &self.chat {
+            let _ = commands.send(TsCommand::Chat { target, invoker, message });
+        }
+    }
+
+    /// Whether clients changed since the last call.
+    pub fn take_roster_changed(&self) -> bool {
+        self.roster_changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Hash of an avatar whose upload finished since the last call.
+    ///
+    /// The main loop sets it on the bridge client, which shows the avatar.
+    pub fn take_uploaded_avatar(&self) -> Option<String> {
+        self.avatar_uploaded.lock().expect("Can't lock uploaded avatar!").take()
+    }
+
+    /// Replace the set of clients muted in the TS→Discord mix.
+    pub fn set_muted(&self, clients: HashSet<ClientId>) {
+        let mut ts_voice = self.pipeline.lock_handler();
+        *self.pipeline.muted.lock().expect("Can't lock muted clients!") = clients;
+        for (&(_, id), queue) in ts_voice.get_mut_queues() {
+            queue.volume = self.pipeline.live_volume(id);
+        }
+    }
+
+    /// Play `clients` later than the others by their delay, everybody else on time.
+    pub fn set_delays(&self, clients: HashMap<ClientId, Duration>) {
+        let mut ts_voice = self.pipeline.lock_handler();
+        {
+            let mut delays = self.pipeline.delays.lock().expect("Can't lock source delays!");
+            delays.retain(|id| clients.contains_key(id));
+            for (&id, &delay) in &clients {
+                delays.set(id, delay);
+            }
+        }
+        for (&(_, id), queue) in ts_voice.get_mut_queues() {
+            queue.volume = self.pipeline.live_volume(id);
+        }
+    }
+
+    /// Re-resolve persisted delays to the ids of connected clients.
+    pub fn refresh_delays(&self, state: &ConnectionState, settings: &Settings) {
+        let delays = state.clients
+            .values()
+            .filter_map(|c| {
+                let delay = settings.ts_delays.get(&c.uid.as_ref()?.to_string())?;
+                Some((c.id, Duration::from_millis(delay.ms.into())))
+            })
+            .collect();
+        self.set_delays(delays);
+    }
+
+    /// Re-check which connected clients are on the podium.
+    pub fn refresh_podium(&self, state: &ConnectionState) {
+        let podium = &self.pipeline.podium;
+        let speakers = state.clients
+            .values()
+            .filter(|c| podium.config().is_on_podium(c.talk_power, c.talk_power_granted, c.is_priority_speaker))
+            .map(|c| c.id)
+            .collect();
+        let mut ts_voice = self.pipeline.lock_handler();
+        podium.set_speakers(speakers);
+        for (&(_, id), queue) in ts_voice.get_mut_queues() {
+            queue.volume = self.pipeline.live_volume(id);
+        }
+    }
+
+    /// Re-resolve persisted mutes to the ids of connected clients, except for whoever has the floor.
+    pub fn refresh_muted(&self, state: &ConnectionState, settings: &Settings) {
+        let mut muted = muted_clients(state.clients.values(), settings);
+        if let Some(Speaker::Ts(client)) = self.hands.floor().map(|hand| hand.speaker) {
+            muted.remove(&client);
+        }
+        self.set_muted(muted);
+    }
+
+    /// Re-check which connected clients are in one of the voice groups.
+    ///
+    /// Clients not seen here yet, like ones that just joined, aren't bridged.
+    pub fn refresh_voice_gate(&self, state: &ConnectionState) {
+        let Some(groups) = &self.voice_groups else {
+            return;
+        };
+        let allowed = state.clients
+            .values()
+            .filter(|c| !c.server_groups.is_disjoint(groups))
+            .map(|c| c.id)
+            .collect();
+        *self.voice_allowed.lock().expect("Can't lock voice gate!") = allowed;
+    }
+
+    fn is_voice_allowed(&self, client: ClientId) -> bool {
+        self.voice_groups.is_none() || self.voice_allowed.lock().expect("Can't lock voice gate!").contains(&client)
+    }
+
+    /// Start over after reconnecting, by tsclientlib or with another identity.
+    pub fn new_session(&self) {
+        // Client ids may be reassigned after reconnecting
+        self.pipeline.lock_handler().reset();
+        self.roster_changed.store(true, Ordering::Relaxed);
+        self.reconnected.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn handle_command(&self, con: &mut Connection, settings: &SharedSettings, command: TsCommand) {
+        match command {
+            TsCommand::SetMuted { client, muted, reply } => {
+                let result = self.set_client_muted(con, settings, &client, muted);
+                let _ = reply.send(result);
+            }
+            TsCommand::SetDelay { client, delay, reply } => {
+                let result = self.set_client_delay(con, settings, &client, delay);
+                let _ = reply.send(result);
+            }
+            TsCommand::SetPodium { enabled, reply } => {
+                self.pipeline.podium.set_enabled(enabled);
+                if let Ok(state) = con.get_state() {
+                    self.refresh_podium(state);
+                }
+                info!(self.logger, "Podium mode"; "enabled" => enabled);
+                let _ = reply.send(self.pipeline.podium.speakers());
+            }
+            TsCommand::SendMessage { author, text, reply } => {
+                let result = self
+                    .check_flood(&author)
+                    .and_then(|()| discord_message(&author, &text, MAX_MESSAGE_CHARS))
+                    .and_then(|message| {
+                        send_text(con, MessageTarget::Channel, &message).map_err(|e| format!("Can't send to TeamSpeak: {}", e))
+                    });
+                if result.is_ok() {
+                    info!(self.logger, "Sent message to TeamSpeak"; "author" => &author);
+                }
+                let _ = reply.send(result);
+            }
+            TsCommand::Poke { client, author, text, reply } => {
+                let result = self.poke_client(con, &client, &author, &text);
+                let _ = reply.send(result);
+            }
+            TsCommand::LinkQuality { reply } => {
+                let _ = reply.send(con.get_network_stats().ok().map(LinkQuality::from));
+            }
+            TsCommand::Announce { text } => {
+                if let Err(e) = send_text(con, MessageTarget::Channel, &text) {
+                    warn!(self.logger, "Can't announce in TeamSpeak"; "error" => %e);
+                }
+            }
+            TsCommand::SetChannelDescription { description } => self.set_channel_description(con, &description),
+            TsCommand::SetAvatar { image } => self.start_avatar_upload(con, image),
+            TsCommand::JoinHomeChannel { password, reply } => {
+                match self.move_home(con, &password) {
+                    Ok((handle, name)) => {
+                        let pending = (name, password, reply);
+                        self.home_moves.lock().expect("Can't lock channel moves!").insert(handle, pending);
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            TsCommand::Chat { target, invoker, message } => {
+                if let Some((chat, _)) = &self.chat {
+                    chat.handle(con, target, invoker, &message).await;
+                }
+            }
+            // Replacing the connection is up to the main loop, which never passes this on
+            TsCommand::RotateIdentity { .. } => unreachable!("identity rotation is handled by the main loop"),
+        }
+    }
+
+    fn check_flood(&self, author: &str) -> Result<(), String> {
+        if self.flood.lock().expect("Can't lock flood guard!").allow(author, Instant::now()) {
+            Ok(())
+        } else {
+            Err("Too many messages to TeamSpeak, try again in a bit".to_string())
+        }
+    }
+
+    /// Say how many messages were held back, once the rate limit allows.
+    pub fn report_suppressed(&self, con: &mut Connection) {
+        let suppressed = self.flood.lock().expect("Can't lock flood guard!").take_suppressed(Instant::now());
+        if let Some(count) = suppressed {
+            let note = match count {
+                1 => "[Discord] …and 1 more message".to_string(),
+                n => format!("[Discord] …and {} more messages", n),
+            };
+            if let Err(e) = send_text(con, MessageTarget::Channel, &note) {
+                warn!(self.logger, "Failed to report held back messages"; "error" => %e);
+            }
+        }
+    }
+
+    fn start_avatar_upload(&self, con: &mut Connection, image: Vec<u8>) {
+        let hash = ts_avatar::avatar_hash(&image);
+        let current = con
+            .get_state()
+            .ok()
+            .and_then(|state| state.clients.get(&state.own_client))
+            .map(|client| client.avatar_hash.clone());
+        if current.as_deref() == Some(hash.as_str()) {
+            debug!(self.logger, "TeamSpeak avatar is up to date");
+            return;
+        }
+
+        match con.upload_file(ChannelId(0), AVATAR_PATH, None, image.len() as u64, true, false) {
+            Ok(handle) => {
+                info!(self.logger, "Uploading TeamSpeak avatar"; "bytes" => image.len());
+                self.avatar_uploads.lock().expect("Can't lock avatar uploads!").insert(handle, (image, hash));
+            }
+            Err(e) => warn!(self.logger, "Can't upload TeamSpeak avatar"; "error" => %e),
+        }
+    }
+
+    /// Show the uploaded avatar with `hash` on the bridge client.
+    pub fn set_avatar(&self, con: &mut Connection, hash: &str) {
+        let result = match con.get_state() {
+            Ok(state) => state.client_update().set_avatar_hash(hash).to_packet().send(con),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(self.logger, "Failed to set TeamSpeak avatar"; "error" => %e);
+        }
+    }
+
+    fn set_channel_description(&self, con: &mut Connection, description: &str) {
+        let result = match con.get_state() {
+            Ok(state) => {
+                let own = state.clients.get(&state.own_client).and_then(|own| state.channels.get(&own.channel));
+                match own {
+                    Some(channel) => channel.edit().set_description(description).to_packet().send(con),
+                    None => return,
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(self.logger, "Failed to set TeamSpeak channel description"; "error" => %e);
+        }
+    }
+
+    /// A notice for the admins if the bridge just ended up outside its channel.
+    ///
+    /// TeamSpeak puts clients into the default channel when the channel
+    /// password is wrong. Only the first check outside gives a notice.
+    pub fn check_home_channel(&self, state: &ConnectionState) -> Option<String> {
+        let home = self.home.as_ref()?;
+        if self.restore_move.lock().expect("Can't lock channel moves!").is_some() {
+            return None;
+        }
+        state.clients.get(&state.own_client)?;
+        let notice = home_channel_notice(home, state);
+        let was_away = self.away_from_home.swap(notice.is_some(), Ordering::Relaxed);
+        if was_away {
+            return None;
+        }
+        notice
+    }
+
+    /// After a reconnect, go back to the channel the bridge was in before.
+    ///
+    /// TeamSpeak reconnects into the channel given when connecting, the
+    /// bridge may have been moved since or the channel password changed.
+    pub fn restore_channel(&self, con: &mut Connection) {
+        let state = match con.get_state() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let own = match state.clients.get(&state.own_client) {
+            Some(own) => own,
+            None => return,
+        };
+        let reconnected = self.reconnected.swap(false, Ordering::Relaxed);
+        let mut last = self.last_channel.lock().expect("Can't lock last channel!");
+        let target = match *last {
+            Some(target) if reconnected && target != own.channel && state.channels.contains_key(&target) => target,
+            _ => {
+                *last = Some(own.channel);
+                return;
+            }
+        };
+        let name = state.channels[&target].name.clone();
+
+        let password = self.channel_password.lock().expect("Can't lock channel password!").clone();
+        let mut part = own.client_move(target);
+        if let Some(password) = &password {
+            part = part.set_password(password);
+        }
+        match part.send_with_result(con) {
+            Ok(handle) => {
+                info!(self.logger, "Going back to TeamSpeak channel after reconnecting"; "channel" => &name);
+                *self.restore_move.lock().expect("Can't lock channel moves!") = Some(handle);
+            }
+            Err(e) => warn!(self.logger, "Can't go back to the TeamSpeak channel"; "channel" => &name, "error" => %e),
+        }
+    }
+
+    /// Switch the bridged audio over when the bridge moved or was moved to another channel.
+    ///
+    /// Subscribes to the new channel and drops the queues of clients left
+    /// behind, so their half-played audio and decoders don't linger.
+    pub fn follow_channel(&self, con: &mut Connection) {
+        let state = match con.get_state() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let current = match state.clients.get(&state.own_client) {
+            Some(own) => own.channel,
+            None => return,
+        };
+        let previous = self.audio_channel.lock().expect("Can't lock audio channel!").replace(current);
+        if previous == Some(current) {
+            return;
+        }
+
+        let audible: HashSet<_> = state.clients
+            .values()
+            .filter(|c| c.channel == current)
+            .map(|c| c.id)
+            .collect();
+        let dropped = drop_inaudible(&mut self.pipeline.lock_handler(), &audible);
+        let channel = match state.channels.get(&current) {
+            Some(channel) => channel,
+            None => return,
+        };
+        let name = channel.name.clone();
+        info!(self.logger, "Bridging TeamSpeak channel"; "channel" => &name, "dropped_queues" => dropped);
+        if !channel.subscribed {
+            if let Err(e) = channel.set_subscribed(true).send(con) {
+                warn!(self.logger, "Can't subscribe to TeamSpeak channel"; "channel" => &name, "error" => %e);
+            }
+        }
+    }
+
+    /// Start moving into the home channel, the answer arrives as a [`StreamItem::MessageResult`].
+    fn move_home(&self, con: &mut Connection, password: &str) -> Result<(MessageHandle, String), String> {
+        let home = self.home.as_ref().ok_or_else(|| "No TeamSpeak channel is configured".to_string())?;
+        let state = con.get_state().map_err(|e| format!("Not connected to TeamSpeak: {}", e))?;
+        let target = home.find(state).ok_or_else(|| format!("The TeamSpeak channel {} doesn't exist", home))?;
+        let name = state.channels[&target].name.clone();
+        let own = state.clients.get(&state.own_client).ok_or_else(|| "Not connected to TeamSpeak".to_string())?;
+        if own.channel == target {
+            return Err(format!("The bridge already is in {}", name));
+        }
+
+        let handle = own
+            .client_move(target)
+            .set_password(password)
+            .send_with_result(con)
+            .map_err(|e| format!("Can't join {}: {}", name, e))?;
+        Ok((handle, name))
+    }
+
+    fn poke_client(&self, con: &mut Connection, query: &str, author: &str, text: &str) -> Result<String, String> {
+        self.check_flood(author)?;
+        let message = discord_message(author, text, MAX_POKE_CHARS)?;
+        let state = con.get_state().map_err(|e| format!("Not connected to TeamSpeak: {}", e))?;
+        let (id, name) = find_client(state.clients.values(), query)
+            .map(|client| (client.id, client.name.clone()))
+            .ok_or_else(|| format!("No TeamSpeak client {} connected", query))?;
+
+        send_text(con, MessageTarget::Poke(id), &message).map_err(|e| format!("Can't poke {}: {}", name, e))?;
+        info!(self.logger, "Poked TeamSpeak client"; "client" => &name, "author" => author);
+        Ok(name)
+    }
+
+    fn set_client_muted(
+        &self,
+        con: &mut Connection,
+        settings: &SharedSettings,
+        query: &str,
+        muted: bool
+    ) -> Result<String, String> {
+        let state = con.get_state().map_err(|e| format!("Not connected to TeamSpeak: {}", e))?;
+        let mut settings = settings.lock().expect("Can't lock settings!");
+
+        let (uid, name) = match find_client(state.clients.values(), query) {
+            Some(client) => {
+                let uid = client.uid
+                    .as_ref()
+                    .map(|uid| uid.to_string())
+                    .ok_or_else(|| format!("{} has no unique id", client.name))?;
+                (uid, client.name.clone())
+            }
+            // Offline clients can still be unmuted by their last known name
+            None if !muted => {
+                settings
+                    .get()
+                    .ts_muted.iter()
+                    .find(|(uid, name)| *uid == query || name.eq_ignore_ascii_case(query))
+                    .map(|(uid, name)| (uid.clone(), name.clone()))
+                    .ok_or_else(|| format!("No muted TeamSpeak client {}", query))?
+            }
+            None => {
+                return Err(format!("No TeamSpeak client {} connected", query));
+            }
+        };
+
+        let saved = settings.update(|s| {
+            if muted {
+                s.ts_muted.insert(uid.clone(), name.clone());
+            } else {
+                s.ts_muted.remove(&uid);
+            }
+        });
+        if let Err(e) = saved {
+            warn!(self.logger, "Failed to save settings"; "error" => %e);
+        }
+        info!(self.logger, "Changed TeamSpeak client mute"; "client" => &name, "muted" => muted);
+        self.refresh_muted(state, settings.get());
+        Ok(name)
+    }
+
+    fn set_client_delay(
+        &self,
+        con: &mut Connection,
+        settings: &SharedSettings,
+        query: &str,
+        delay: Duration
+    ) -> Result<String, String> {
+        let state = con.get_state().map_err(|e| format!("Not connected to TeamSpeak: {}", e))?;
+        let mut settings = settings.lock().expect("Can't lock settings!");
+
+        let (uid, name) = match find_client(state.clients.values(), query) {
+            Some(client) => {
+                let uid = client.uid
+                    .as_ref()
+                    .map(|uid| uid.to_string())
+                    .ok_or_else(|| format!("{} has no unique id", client.name))?;
+                (uid, client.name.clone())
+            }
+            // Offline clients can still lose their delay by their last known name
+            None if delay.is_zero() => {
+                settings
+                    .get()
+                    .ts_delays.iter()
+                    .find(|(uid, delay)| *uid == query || delay.name.eq_ignore_ascii_case(query))
+                    .map(|(uid, delay)| (uid.clone(), delay.name.clone()))
+                    .ok_or_else(|| format!("No delayed TeamSpeak client {}", query))?
+            }
+            None => {
+                return Err(format!("No TeamSpeak client {} connected", query));
+            }
+        };
+
+        let saved = settings.update(|s| {
+            if delay.is_zero() {
+                s.ts_delays.remove(&uid);
+            } else {
+                s.ts_delays.insert(uid.clone(), TsDelay { name: name.clone(), ms: delay.as_millis() as u32 });
+            }
+        });
+        if let Err(e) = saved {
+            warn!(self.logger, "Failed to save settings"; "error" => %e);
+        }
+        info!(self.logger, "Changed TeamSpeak client delay"; "client" => &name, "delay_ms" => delay.as_millis());
+        self.refresh_delays(state, settings.get());
+        Ok(name)
+    }
+}
+
+/// The chat line for `text` sent from Discord by `author`, at most `max_chars` long.
+fn discord_message(author: &str, text: &str, max_chars: usize) -> Result<String, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Nothing to send".to_string());
+    }
+    let message = format!("[Discord] {}: {}", author, text);
+    let length = message.chars().count();
+    if length > max_chars {
+        return Err(format!("Too long for TeamSpeak by {} characters", length - max_chars));
+    }
+    Ok(message)
+}
+
+/// Nickname for the `attempt`th try, `name` itself for the first.
+///
+/// `name` is shortened if the suffix would make it too long for TeamSpeak.
+pub fn nickname_candidate(name: &str, pattern: &str, attempt: u32) -> String {
+    if attempt == 0 {
+        return name.to_string();
+    }
+    let suffix_chars = pattern.replace("{name}", "").replace("{n}", &attempt.to_string()).chars().count();
+    let name: String = name.chars().take(MAX_NICKNAME_CHARS.saturating_sub(suffix_chars)).collect();
+    pattern.replace("{n}", &attempt.to_string()).replace("{name}", &name)
+}
+
+/// Walk `path` down from the top level, `channels` as `(id, parent, name)`.
+fn channel_by_path<'a>(
+    channels: impl Iterator<Item = (ChannelId, ChannelId, &'a str)>,
+    path: &str
+) -> Option<ChannelId> {
+    let channels: Vec<_> = channels.collect();
+    path.split('/').try_fold(ChannelId(0), |parent, name| {
+        channels
+            .iter()
+            .find(|&&(_, p, n)| p == parent && n == name)
+            .map(|&(id, _, _)| id)
+    })
+}
+
+/// Whether `message` is meant for the bridge's `!commands`.
+fn is_chat_command(message: &str) -> bool {
+    message.trim_start().starts_with('!')
+}
+
+/// Whether a person, not a query client, is in the bridge's channel.
+pub fn has_listeners(state: &ConnectionState) -> bool {
+    listeners(state).next().is_some()
+}
+
+/// Unique ids of the people in the bridge's channel.
+pub fn listener_uids(state: &ConnectionState) -> Vec<String> {
+    listeners(state).filter_map(|c| Some(c.uid.as_ref()?.to_string())).collect()
+}
+
+/// Clients other than the bridge in its channel, without server queries.
+fn listeners(state: &ConnectionState) -> impl Iterator<Item = &Client> {
+    let channel = state.clients.get(&state.own_client).map(|own| own.channel);
+    state.clients
+        .values()
+        .filter(move |c| {
+            c.id != state.own_client && Some(c.channel) == channel && matches!(c.client_type, ClientType::Normal)
+        })
+}
+
+/// Ids of the clients whose uid is muted in `settings`.
+fn muted_clients<'a>(clients: impl Iterator<Item = &'a Client>, settings: &Settings) -> HashSet<ClientId> {
+    clients
+        .filter(|c| {
+            c.uid
+                .as_ref()
+                .map(|uid| settings.ts_muted.contains_key(&uid.to_string()))
+                .unwrap_or_default()
+        })
+        .map(|c| c.id)
+        .collect()
+}
+
+/// Find a client by name, ignoring case, or by unique id.
+fn find_client<'a>(mut clients: impl Iterator<Item = &'a Client>, query: &str) -> Option<&'a Client> {
+    clients.find(|c| {
+        c.name.eq_ignore_ascii_case(query) ||
+            c.uid
+                .as_ref()
+                .map(|uid| uid.to_string() == query)
+                .unwrap_or_default()
+    })
+}
+
+/// Why the bridge is not in `home`, `None` if it is.
+pub fn home_channel_notice(home: &HomeChannel, state: &ConnectionState) -> Option<String> {
+    let current = state.clients.get(&state.own_client)?.channel;
+    let target = home.find(state);
+    if target == Some(current) {
+        return None;
+    }
+    let name = |id: ChannelId| state.channels.get(&id).map_or_else(|| id.0.to_string(), |c| c.name.clone());
+    Some(match target {
+        Some(target) => format!(
+            "The bridge couldn't join the TeamSpeak channel {} and is in {} instead. If its password changed, give the new one with /ts-channel-password.",
+            name(target),
+            name(current)
+        ),
+        None => format!("The TeamSpeak channel {} doesn't exist, the bridge is in {} instead.", home, name(current)),
+    })
+}
+
+/// Drop the queues of clients not in `audible`, returns how many were dropped.
+fn drop_inaudible(ts_voice: &mut crate::TsAudioHandler, audible: &HashSet<ClientId>) -> usize {
+    let queues = ts_voice.get_mut_queues();
+    let before = queues.len();
+    queues.retain(|(_, id), _| audible.contains(id));
+    before - queues.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+
+    use crate::audio::tests::{ logger, opus_sine, sine_frame };
+    use crate::audio::peak;
+    use crate::mock_ts::{ MockBook, MockTsPeer };
+    use crate::podium::{ Podium, PodiumConfig };
+    use crate::STEREO_20MS;
+
+    async fn run(handler: &TsEventHandler, peer: MockTsPeer) {
+        peer.into_stream()
+            .try_for_each(|item| {
+                handler.handle(item);
+                future::ready(Ok(()))
+            }).await
+            .unwrap();
+    }
+
+    fn fill(pipeline: &TsToDiscordPipeline, frames: usize) -> Vec<f32> {
+        let mut out = vec![0.0; STEREO_20MS * frames];
+        pipeline.lock_handler().fill_buffer(&mut out);
+        out
+    }
+
+    fn handler() -> (TsEventHandler, TsToDiscordPipeline) {
+        let pipeline = TsToDiscordPipeline::new(logger());
+        pipeline.mixing.store(true, Ordering::Relaxed);
+        (TsEventHandler::new(ConnectionId(0), pipeline.clone(), logger()), pipeline)
+    }
+
+    #[tokio::test]
+    async fn audio_reaches_the_discord_pipeline() {
+        let (handler, pipeline) = handler();
+        let mut peer = MockTsPeer::new();
+        for packet in opus_sine(440.0, 3) {
+            peer = peer.audio(5, &packet);
+        }
+        run(&handler, peer).await;
+
+        assert_eq!(pipeline.lock_handler().get_queues().len(), 1);
+        assert!(peak(&fill(&pipeline, 1)) > 0.1);
+    }
+
+    #[tokio::test]
+    async fn packets_are_discarded_until_something_mixes() {
+        let pipeline = TsToDiscordPipeline::new(logger());
+        let handler = TsEventHandler::new(ConnectionId(0), pipeline.clone(), logger());
+        let packets = opus_sine(440.0, 300);
+        let mut peer = MockTsPeer::new();
+        for packet in &packets {
+            peer = peer.audio(5, packet);
+        }
+        run(&handler, peer).await;
+        assert_eq!(pipeline.ingest_dropped.get(), 0);
+        assert!(pipeline.lock_handler().get_queues().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_packets_are_handed_over_when_mixing() {
+        let (handler, pipeline) = handler();
+        handler.set_muted(std::iter::once(ClientId(5)).collect());
+        let packets = opus_sine(440.0, 2);
+        run(&handler, MockTsPeer::new().audio(5, &packets[0]).audio(6, &packets[1])).await;
+        assert_eq!(pipeline.pending.lock().unwrap().len(), 2);
+
+        let handler = pipeline.lock_handler();
+        assert!(pipeline.pending.lock().unwrap().is_empty());
+        let volumes: HashMap<_, _> = handler.get_queues().iter().map(|(&(_, id), queue)| (id, queue.volume)).collect();
+        // A muted new talker starts out silent
+        assert_eq!(volumes, HashMap::from([(ClientId(5), 0.0), (ClientId(6), 1.0)]));
+    }
+
+    #[tokio::test]
+    async fn whisper_is_bridged_like_normal_audio() {
+        let (handler, pipeline) = handler();
+        let mut peer = MockTsPeer::new();
+        for packet in opus_sine(440.0, 3) {
+            peer = peer.whisper(9, &packet);
+        }
+        run(&handler, peer).await;
+
+        assert!(peak(&fill(&pipeline, 1)) > 0.1);
+    }
+
+    #[tokio::test]
+    async fn temporary_disconnect_drops_stale_queues() {
+        let (handler, pipeline) = handler();
+        let packets = opus_sine(440.0, 4);
+        run(&handler, MockTsPeer::new().audio(5, &packets[0]).audio(6, &packets[1])).await;
+        assert_eq!(pipeline.lock_handler().get_queues().len(), 2);
+
+        run(&handler, MockTsPeer::new().temporary_disconnect().audio(5, &packets[2])).await;
+        assert_eq!(pipeline.lock_handler().get_queues().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_clients_in_voice_groups_are_bridged() {
+        let (handler, pipeline) = handler();
+        let handler = handler.with_voice_groups(vec![7]);
+        let packets = opus_sine(440.0, 6);
+        run(&handler, MockTsPeer::new().audio(5, &packets[0]).audio(5, &packets[1])).await;
+        assert!(pipeline.lock_handler().get_queues().is_empty());
+
+        handler.voice_allowed.lock().unwrap().insert(ClientId(5));
+        run(&handler, MockTsPeer::new().audio(5, &packets[2]).audio(6, &packets[3])).await;
+        assert_eq!(pipeline.lock_handler().get_queues().len(), 1);
+        assert!(peak(&fill(&pipeline, 1)) > 0.1);
+    }
+
+    #[tokio::test]
+    async fn switching_channels_drops_clients_left_behind() {
+        let (handler, pipeline) = handler();
+        let packets = opus_sine(440.0, 3);
+        run(&handler, MockTsPeer::new().audio(5, &packets[0]).audio(6, &packets[1])).await;
+
+        let audible = std::iter::once(ClientId(6)).collect();
+        assert_eq!(drop_inaudible(&mut pipeline.lock_handler(), &audible), 1);
+        let queues = pipeline.lock_handler().get_queues().keys().map(|(_, id)| *id).collect::<Vec<_>>();
+        assert_eq!(queues, [ClientId(6)]);
+    }
+
+    #[tokio::test]
+    async fn muted_clients_are_left_out_of_the_mix() {
+        let (handler, pipeline) = handler();
+        handler.set_muted(std::iter::once(ClientId(5)).collect());
+        let packets = opus_sine(440.0, 3);
+        let mut peer = MockTsPeer::new();
+        for packet in &packets {
+            peer = peer.audio(5, packet);
+        }
+        run(&handler, peer).await;
+        assert_eq!(peak(&fill(&pipeline, 1)), 0.0);
+
+        // Unmuting applies to a client that is already talking
+        handler.set_muted(HashSet::new());
+        assert!(peak(&fill(&pipeline, 1)) > 0.1);
+    }
+
+    #[tokio::test]
+    async fn talk_power_puts_clients_on_the_podium() {
+        let podium = Podium::new(PodiumConfig { enabled: true, min_talk_power: Some(50), ..Default::default() });
+        let pipeline = TsToDiscordPipeline::new(logger()).with_podium(podium);
+        pipeline.mixing.store(true, Ordering::Relaxed);
+        let handler = TsEventHandler::new(ConnectionId(0), pipeline.clone(), logger());
+        let volume = |client| {
+            let handler = pipeline.lock_handler();
+            handler.get_queues().iter().find(|((_, id), _)| *id == ClientId(client)).map(|(_, queue)| queue.volume)
+        };
+        let mut book = MockBook::new();
+        let packets = opus_sine(440.0, 4);
+
+        let peer = MockTsPeer::new()
+            .book(book.client_enters(5, "Audience", 0))
+            .book(book.client_enters(6, "Speaker", 75))
+            .audio(5, &packets[0])
+            .audio(6, &packets[1]);
+        run(&handler, peer).await;
+        // The main loop does this once the book changed
+        handler.refresh_podium(book.state());
+        assert_eq!(volume(5), Some(crate::audio::db_to_gain(-18.0)));
+        assert_eq!(volume(6), Some(1.0));
+
+        run(&handler, MockTsPeer::new().book(book.set_talk_power(5, 50)).audio(5, &packets[2])).await;
+        handler.refresh_podium(book.state());
+        assert_eq!(volume(5), Some(1.0));
+    }
+
+    /// Opus packets of a sine on the left and silence on the right, encoded with `channels`.
+    fn opus_left_only(channels: audiopus::Channels, frames: usize) -> Vec<Vec<u8>> {
+        let encoder = audiopus::coder::Encoder
+            ::new(audiopus::SampleRate::Hz48000, channels, audiopus::Application::Audio)
+            .unwrap();
+        (0..frames)
+            .map(|n| {
+                let mut pcm = sine_frame(440.0, 0.5, n * (STEREO_20MS / 2));
+                if channels == audiopus::Channels::Mono {
+                    pcm = pcm.iter().step_by(2).copied().collect();
+                } else {
+                    pcm.iter_mut().skip(1).step_by(2).for_each(|right| *right = 0.0);
+                }
+                let mut out = [0; crate::MAX_OPUS_FRAME_SIZE];
+                let len = encoder.encode_float(&pcm, &mut out).unwrap();
+                out[..len].to_vec()
+            })
+            .collect()
+    }
+
+    /// Peaks of the left and right side of interleaved stereo.
+    fn side_peaks(samples: &[f32]) -> (f32, f32) {
+        let left: Vec<_> = samples.iter().step_by(2).copied().collect();
+        let right: Vec<_> = samples.iter().skip(1).step_by(2).copied().collect();
+        (peak(&left), peak(&right))
+    }
+
+    #[tokio::test]
+    async fn stereo_clients_keep_their_sides() {
+        let (handler, pipeline) = handler();
+        let mut peer = MockTsPeer::new();
+        for packet in opus_left_only(audiopus::Channels::Stereo, 10) {
+            peer = peer.audio(5, &packet);
+        }
+        run(&handler, peer).await;
+        assert!(pipeline.is_stereo(ClientId(5)));
+
+        let (left, right) = side_peaks(&fill(&pipeline, 8));
+        assert!(left > 0.2, "left peak {}", left);
+        assert!(right < left * 0.1, "right peak {} against left {}", right, left);
+    }
+
+    #[tokio::test]
+    async fn mono_clients_play_on_both_sides() {
+        let (handler, pipeline) = handler();
+        let mut peer = MockTsPeer::new();
+        for packet in opus_left_only(audiopus::Channels::Mono, 10) {
+            peer = peer.audio(6, &packet);
+        }
+        run(&handler, peer).await;
+        assert!(!pipeline.is_stereo(ClientId(6)));
+        assert!(pipeline.stereo_clients().is_empty());
+
+        let (left, right) = side_peaks(&fill(&pipeline, 8));
+        assert!(left > 0.2);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn link_quality_reads_as_ping_and_loss() {
+        let quality = LinkQuality { ping_ms: 42.4, ping_deviation_ms: 3.0, voice_loss: 0.012, total_loss: 0.005 };
+        assert_eq!(quality.to_string(), "42 ms (±3 ms), 1.2% voice and 0.5% total packet loss");
+    }
+
+    #[tokio::test]
+    async fn book_events_mark_the_roster_changed() {
+        let (handler, _) = handler();
+        assert!(handler.take_roster_changed());
+        assert!(!handler.take_roster_changed());
+        run(&handler, MockTsPeer::new().book(vec![])).await;
+        assert!(handler.take_roster_changed());
+    }
+
+    #[tokio::test]
+    async fn chat_commands_are_forwarded() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (handler, _) = handler();
+        let handler = handler.with_chat(crate::ts_chat::tests::commands(None), tx);
+        let invoker = Invoker { name: "alice".into(), id: ClientId(3), uid: None };
+        let events = vec![
+            Event::Message { target: MessageTarget::Channel, invoker: invoker.clone(), message: "hi".into() },
+            Event::Message { target: MessageTarget::Channel, invoker, message: "!volume 50".into() }
+        ];
+        run(&handler, MockTsPeer::new().book(events)).await;
+
+        match rx.try_recv().unwrap() {
+            TsCommand::Chat { message, invoker, .. } => {
+                assert_eq!(message, "!volume 50");
+                assert_eq!(invoker.id, ClientId(3));
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn poked_commands_are_forwarded() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (handler, _) = handler();
+        let handler = handler.with_chat(crate::ts_chat::tests::commands(None), tx);
+        let invoker = Invoker { name: "alice".into(), id: ClientId(3), uid: None };
+        let events = vec![
+            Event::Message { target: MessageTarget::Poke(ClientId(3)), invoker: invoker.clone(), message: "hello?".into() },
+            Event::Message { target: MessageTarget::Poke(ClientId(3)), invoker, message: "!who".into() }
+        ];
+        run(&handler, MockTsPeer::new().book(events)).await;
+        handler.handle(StreamItem::IdentityLevelIncreasing(8));
+        handler.handle(StreamItem::IdentityLevelIncreased);
+
+        match rx.try_recv().unwrap() {
+            TsCommand::Chat { target, message, .. } => {
+                assert_eq!(target, MessageTarget::Poke(ClientId(3)));
+                assert_eq!(message, "!who");
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn channel_messages_name_the_author() {
+        assert_eq!(discord_message("Ann", "  hello  ", MAX_MESSAGE_CHARS).unwrap(), "[Discord] Ann: hello");
+        assert!(discord_message("Ann", " \n ", MAX_MESSAGE_CHARS).is_err());
+        assert!(discord_message("Ann", &"x".repeat(MAX_MESSAGE_CHARS), MAX_MESSAGE_CHARS).is_err());
+        assert_eq!(
+            discord_message("Ann", &"x".repeat(MAX_POKE_CHARS), MAX_POKE_CHARS).unwrap_err(),
+            "Too long for TeamSpeak by 15 characters"
+        );
+    }
+
+    #[test]
+    fn taken_nicknames_get_a_number() {
+        assert_eq!(nickname_candidate("voice bridge", DEFAULT_NICKNAME_PATTERN, 0), "voice bridge");
+        assert_eq!(nickname_candidate("voice bridge", DEFAULT_NICKNAME_PATTERN, 1), "voice bridge1");
+        assert_eq!(nickname_candidate("voice bridge", "{name} ({n})", 12), "voice bridge (12)");
+        let long = "x".repeat(MAX_NICKNAME_CHARS);
+        assert_eq!(nickname_candidate(&long, "{name} ({n})", 3), format!("{} (3)", "x".repeat(26)));
+    }
+
+    #[test]
+    fn channel_paths_are_walked_from_the_top() {
+        let channels = [
+            (ChannelId(1), ChannelId(0), "Lobby"),
+            (ChannelId(2), ChannelId(0), "Games"),
+            (ChannelId(3), ChannelId(2), "Lobby"),
+            (ChannelId(4), ChannelId(3), "AFK"),
+        ];
+        let find = |path| channel_by_path(channels.iter().copied(), path);
+        assert_eq!(find("Lobby"), Some(ChannelId(1)));
+        assert_eq!(find("Games/Lobby"), Some(ChannelId(3)));
+        assert_eq!(find("Games/Lobby/AFK"), Some(ChannelId(4)));
+        assert_eq!(find("AFK"), None);
+        assert_eq!(find("Lobby/AFK"), None);
+    }
+
+    #[tokio::test]
+    async fn book_events_do_not_disturb_audio() {
+        let (handler, pipeline) = handler();
+        let packets = opus_sine(440.0, 2);
+        run(&handler, MockTsPeer::new().audio(5, &packets[0]).book(vec![]).audio(5, &packets[1])).await;
+
+        assert!(peak(&fill(&pipeline, 2)) > 0.1);
+    }
+}
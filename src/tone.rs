@@ -0,0 +1,59 @@
+//! Test tones for checking one leg of the bridge at a time.
+//!
+//! A tone sent to TeamSpeak goes through the Discord→TS encoder only, one
+//! sent to Discord is played straight into the voice call, so a missing tone
+//! narrows down which side loses audio.
+
+use std::f32::consts::TAU;
+
+use crate::SAMPLE_RATE;
+
+pub const DEFAULT_FREQUENCY: f32 = 1000.0;
+pub const DEFAULT_SECONDS: f32 = 2.0;
+/// Level in dBFS, loud enough to hear without startling anybody.
+pub const DEFAULT_LEVEL_DB: f32 = -20.0;
+pub const MAX_SECONDS: f32 = 10.0;
+/// Fade in and out to not click.
+const FADE_SECONDS: f32 = 0.01;
+
+/// Where a test tone is played.
+#[derive(Clone, Copy, Debug, PartialEq, poise::ChoiceParameter)]
+pub enum ToneDirection {
+    TeamSpeak,
+    Discord,
+}
+
+/// A sine tone as 48 kHz interleaved stereo, peaking at `level_db` dBFS.
+pub fn sine(frequency: f32, seconds: f32, level_db: f32) -> Vec<f32> {
+    let amplitude = (10.0f32).powf(level_db.min(0.0) / 20.0);
+    let frames = (seconds.clamp(0.0, MAX_SECONDS) * (SAMPLE_RATE as f32)) as usize;
+    let fade = ((FADE_SECONDS * (SAMPLE_RATE as f32)) as usize).min(frames / 2).max(1);
+    let mut samples = Vec::with_capacity(frames * 2);
+    for i in 0..frames {
+        let envelope = ((i.min(frames - 1 - i) as f32) / (fade as f32)).min(1.0);
+        let phase = (TAU * frequency * (i as f32)) / (SAMPLE_RATE as f32);
+        let sample = amplitude * envelope * phase.sin();
+        samples.push(sample);
+        samples.push(sample);
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::peak;
+
+    #[test]
+    fn tones_have_the_requested_length_and_level() {
+        let tone = sine(DEFAULT_FREQUENCY, 0.5, -6.0);
+        assert_eq!(tone.len(), SAMPLE_RATE);
+        assert!((peak(&tone) - 0.501).abs() < 0.01, "{}", peak(&tone));
+        assert_eq!(tone[0], 0.0);
+        assert_eq!(tone[0], tone[1]);
+        assert!(tone[tone.len() - 1].abs() < 0.01);
+
+        assert_eq!(sine(DEFAULT_FREQUENCY, 60.0, 0.0).len(), ((MAX_SECONDS as usize) * SAMPLE_RATE) * 2);
+        assert!(sine(DEFAULT_FREQUENCY, 0.0, 0.0).is_empty());
+    }
+}
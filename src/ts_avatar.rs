@@ -0,0 +1,87 @@
+//! The bridge's TeamSpeak avatar, mirrored from its Discord avatar.
+//!
+//! TeamSpeak avatars are uploaded through file transfer and announced by
+//! setting the client's avatar flag to the MD5 hash of the image, which
+//! other clients compare against their cached copy.
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// File transfer path of the own avatar, in channel 0.
+pub const AVATAR_PATH: &str = "/avatar";
+
+/// Send `image` over the stream of a started upload.
+pub async fn upload(mut stream: TcpStream, image: &[u8]) -> std::io::Result<()> {
+    stream.write_all(image).await?;
+    stream.flush().await?;
+    stream.shutdown().await
+}
+
+/// Avatar flag for `image`, its MD5 hash in lowercase hex.
+pub fn avatar_hash(image: &[u8]) -> String {
+    md5(image)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// MD5 as in RFC 1321, only used to name avatars, not for security.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((((i + 1) as f64).sin().abs() * 4_294_967_296.0) as u64) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for chunk in message.chunks_exact(64) {
+        let words: Vec<u32> = chunk
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(constants[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[(i / 16) * 4 + i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (out, s) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&s.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_rfc_1321() {
+        assert_eq!(avatar_hash(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(avatar_hash(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            avatar_hash(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+}
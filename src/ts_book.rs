@@ -0,0 +1,160 @@
+//! The TeamSpeak server as last seen, for commands and the control socket.
+//!
+//! The main loop owns the connection and refreshes the snapshot whenever the
+//! roster changes and once a second for who is talking, so autocomplete, the
+//! join roster, `/solo ts` and `status` read it without waiting on it.
+
+use std::collections::HashSet;
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use serde::Serialize;
+use tsclientlib::data::Connection as ConnectionState;
+use tsclientlib::{ ClientId, ClientType };
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BookClient {
+    #[serde(serialize_with = "client_id")]
+    pub id: ClientId,
+    pub uid: Option<String>,
+    pub name: String,
+    pub channel: u64,
+    /// Whether the bridge is mixing their voice right now.
+    pub talking: bool,
+    pub query: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BookChannel {
+    pub id: u64,
+    /// 0 at the top of the tree.
+    pub parent: u64,
+    pub name: String,
+}
+
+/// Channels and clients of the server, without the bridge itself.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub connected: bool,
+    /// Channel the bridge is in.
+    pub channel: Option<u64>,
+    pub channels: Vec<BookChannel>,
+    /// Sorted by name.
+    pub clients: Vec<BookClient>,
+}
+
+fn client_id<S: serde::Serializer>(id: &ClientId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(id.0)
+}
+
+impl Snapshot {
+    /// Take what `state` knows, with the clients in `talking` marked.
+    pub fn from_state(state: &ConnectionState, talking: &HashSet<ClientId>) -> Self {
+        let mut channels: Vec<_> = state.channels
+            .values()
+            .map(|c| BookChannel { id: c.id.0, parent: c.parent.0, name: c.name.clone() })
+            .collect();
+        channels.sort_by_key(|c| c.id);
+        let mut clients: Vec<_> = state.clients
+            .values()
+            .filter(|c| c.id != state.own_client)
+            .map(|c| BookClient {
+                id: c.id,
+                uid: c.uid.as_ref().map(|uid| uid.to_string()),
+                name: c.name.clone(),
+                channel: c.channel.0,
+                talking: talking.contains(&c.id),
+                query: !matches!(c.client_type, ClientType::Normal),
+            })
+            .collect();
+        clients.sort_by_key(|c| c.name.to_lowercase());
+        Self {
+            connected: true,
+            channel: state.clients.get(&state.own_client).map(|own| own.channel.0),
+            channels,
+            clients,
+        }
+    }
+
+    /// Names of all clients, sorted.
+    pub fn client_names(&self) -> Vec<String> {
+        self.clients.iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// A client by name, ignoring case, or by unique id.
+    pub fn find_client(&self, query: &str) -> Option<&BookClient> {
+        self.clients.iter().find(|c| c.name.eq_ignore_ascii_case(query) || c.uid.as_deref() == Some(query))
+    }
+}
+
+/// The latest snapshot, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct TsBook {
+    snapshot: Arc<StdMutex<Arc<Snapshot>>>,
+}
+
+impl TsBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Arc<Snapshot>> {
+        self.snapshot.lock().expect("Can't lock the TeamSpeak book!")
+    }
+
+    /// The latest snapshot, unchanged by later updates.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.lock().clone()
+    }
+
+    pub fn update(&self, snapshot: Snapshot) {
+        let mut current = self.lock();
+        if **current != snapshot {
+            *current = Arc::new(snapshot);
+        }
+    }
+
+    /// Forget everything until connected again.
+    pub fn disconnected(&self) {
+        self.update(Snapshot::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(id: u16, name: &str, uid: &str) -> BookClient {
+        BookClient {
+            id: ClientId(id),
+            uid: Some(uid.to_string()),
+            name: name.to_string(),
+            channel: 1,
+            talking: false,
+            query: false,
+        }
+    }
+
+    #[test]
+    fn readers_see_updates() {
+        let book = TsBook::new();
+        let before = book.snapshot();
+        assert!(!before.connected);
+
+        book.clone().update(Snapshot {
+            connected: true,
+            channel: Some(1),
+            channels: vec![BookChannel { id: 1, parent: 0, name: "Lobby".to_string() }],
+            clients: vec![client(2, "Ann", "a="), client(3, "bob", "b=")],
+        });
+        assert!(before.clients.is_empty());
+
+        let snapshot = book.snapshot();
+        assert_eq!(snapshot.client_names(), ["Ann", "bob"]);
+        assert_eq!(snapshot.find_client("BOB").map(|c| c.id), Some(ClientId(3)));
+        assert_eq!(snapshot.find_client("a=").map(|c| c.id), Some(ClientId(2)));
+        assert!(snapshot.find_client("Carl").is_none());
+
+        book.disconnected();
+        assert_eq!(*book.snapshot(), Snapshot::default());
+    }
+}
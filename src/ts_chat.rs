@@ -0,0 +1,379 @@
+//! Bridge commands typed into TeamSpeak chat, like `!volume 80`.
+
+use std::collections::HashSet;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+
+use slog::{ info, warn, Logger };
+use tsclientlib::prelude::*;
+use tsclientlib::{ ClientId, Connection, Invoker, MessageTarget, ServerGroupId };
+
+use crate::audio::parse_db;
+use crate::discord::{ VoiceMember, VoicePresence };
+use crate::echo::{ EchoRecorder, ECHO_DURATION };
+use crate::hands::{ Hand, HandQueue, Speaker };
+use crate::soundboard::SoundFeed;
+use crate::AudioBufferDiscord;
+
+/// Highest `!volume` in percent, matches the Discord `/volume` range.
+const MAX_VOLUME_PERCENT: f32 = 200.0;
+
+#[derive(Debug, PartialEq)]
+pub enum ChatCommand {
+    /// Show or set how loud Discord comes through, in percent.
+    Volume(Option<f32>),
+    /// Stop or resume sending Discord audio to TeamSpeak.
+    Mute(bool),
+    /// List who is in the Discord voice channel.
+    Who,
+    /// Record the invoker and play it back in TeamSpeak.
+    Echo,
+    /// Raise or lower the invoker's hand.
+    Hand(bool),
+    Help,
+    Unknown(String),
+}
+
+impl ChatCommand {
+    /// Parse a chat message, `None` if it is not meant for the bridge.
+    pub fn parse(message: &str) -> Option<Self> {
+        let mut words = message.trim().strip_prefix('!')?.split_whitespace();
+        let command = words.next()?.to_lowercase();
+        Some(match command.as_str() {
+            "volume" | "vol" => {
+                match words.next() {
+                    None => ChatCommand::Volume(None),
+                    Some(arg) => {
+                        match (parse_db(arg), arg.trim_end_matches('%').parse::<f32>()) {
+                            (Some(gain), _) => ChatCommand::Volume(Some(gain * 100.0)),
+                            (None, Ok(v)) if v.is_finite() => ChatCommand::Volume(Some(v)),
+                            _ => ChatCommand::Unknown(message.trim().to_string()),
+                        }
+                    }
+                }
+            }
+            "mute" => ChatCommand::Mute(true),
+            "unmute" => ChatCommand::Mute(false),
+            "who" => ChatCommand::Who,
+            "echo" => ChatCommand::Echo,
+            "hand" => ChatCommand::Hand(words.next().map(str::to_lowercase).as_deref() != Some("down")),
+            "help" => ChatCommand::Help,
+            _ => ChatCommand::Unknown(message.trim().to_string()),
+        })
+    }
+
+    /// Commands anybody may use, regardless of server groups.
+    fn is_public(&self) -> bool {
+        matches!(
+            self,
+            ChatCommand::Help | ChatCommand::Who | ChatCommand::Echo | ChatCommand::Hand(_) | ChatCommand::Volume(None)
+        )
+    }
+}
+
+/// Runs chat commands against the bridge.
+pub struct ChatCommands {
+    discord_buffer: AudioBufferDiscord,
+    /// Discord audio is not sent to TeamSpeak while set.
+    feed_muted: Arc<AtomicBool>,
+    presence: VoicePresence,
+    /// Server groups allowed to change things, everybody if `None`.
+    allowed_groups: Option<HashSet<ServerGroupId>>,
+    /// Clients running `!echo`, and where their recording is played.
+    echo: Option<(EchoRecorder<ClientId>, SoundFeed)>,
+    /// Queue `!hand` adds to.
+    hands: Option<HandQueue>,
+    logger: Logger,
+}
+
+impl ChatCommands {
+    pub fn new(
+        discord_buffer: AudioBufferDiscord,
+        feed_muted: Arc<AtomicBool>,
+        presence: VoicePresence,
+        allowed_groups: Option<Vec<u64>>,
+        logger: Logger
+    ) -> Self {
+        Self {
+            discord_buffer,
+            feed_muted,
+            presence,
+            allowed_groups: allowed_groups.map(|groups| groups.into_iter().map(ServerGroupId).collect()),
+            echo: None,
+            hands: None,
+            logger,
+        }
+    }
+
+    /// Allow `!echo`, playing recordings back through `sounds`.
+    pub fn with_echo_test(mut self, sounds: SoundFeed) -> Self {
+        self.echo = Some((EchoRecorder::new(), sounds));
+        self
+    }
+
+    /// Allow `!hand`, queueing in `hands`.
+    pub fn with_hands(mut self, hands: HandQueue) -> Self {
+        self.hands = Some(hands);
+        self
+    }
+
+    /// Raise or lower the hand of `invoker`.
+    fn hand(&self, invoker: &Invoker, raised: bool) -> String {
+        let Some(hands) = &self.hands else {
+            return "Raising hands is not available.".to_string();
+        };
+        let speaker = Speaker::Ts(invoker.id);
+        if !raised {
+            return if hands.lower(speaker) { "Your hand is down.".to_string() } else { "Your hand wasn't raised.".to_string() };
+        }
+        let place = hands.raise(Hand { speaker, name: invoker.name.clone() });
+        format!("Your hand is raised, you are number {} in line.", place)
+    }
+
+    /// Keep an Opus packet of `client`, `false` if they are not running `!echo`.
+    pub fn record_echo(&self, client: ClientId, packet: &[u8]) -> bool {
+        self.echo.as_ref().is_some_and(|(echo, _)| echo.record(&client, packet))
+    }
+
+    /// Record `client` and play it back once done.
+    fn echo_test(&self, client: ClientId) -> String {
+        let (echo, sounds) = match &self.echo {
+            Some((echo, sounds)) => (echo.clone(), sounds.clone()),
+            None => {
+                return "The echo test is not available.".to_string();
+            }
+        };
+        if !echo.start(client) {
+            return "Already recording you.".to_string();
+        }
+        let logger = self.logger.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ECHO_DURATION).await;
+            match echo.finish(&client) {
+                Ok(samples) => sounds.play(&samples),
+                Err(e) => warn!(logger, "Can't play back echo test"; "error" => %e),
+            }
+        });
+        format!("Recording you for {} s, talk now. Discord doesn't hear it.", ECHO_DURATION.as_secs())
+    }
+
+    /// Handle a chat message and answer where it was written.
+    pub async fn handle(&self, con: &mut Connection, target: MessageTarget, invoker: Invoker, message: &str) {
+        let command = match ChatCommand::parse(message) {
+            Some(command) => command,
+            None => {
+                return;
+            }
+        };
+        let (own_client, groups) = match con.get_state() {
+            Ok(state) => {
+                let groups = state.clients
+                    .get(&invoker.id)
+                    .map(|c| c.server_groups.clone())
+                    .unwrap_or_default();
+                (state.own_client, groups)
+            }
+            Err(e) => {
+                warn!(self.logger, "Can't answer chat command"; "error" => %e);
+                return;
+            }
+        };
+        if invoker.id == own_client {
+            return;
+        }
+
+        let answer = if command.is_public() || self.is_allowed(&groups) {
+            info!(self.logger, "Chat command"; "client" => &invoker.name, "command" => ?command);
+            self.execute(command, &invoker).await
+        } else {
+            "You are not allowed to control the bridge.".to_string()
+        };
+        reply(con, target, invoker.id, &answer, &self.logger);
+    }
+
+    fn is_allowed(&self, groups: &HashSet<ServerGroupId>) -> bool {
+        match &self.allowed_groups {
+            None => true,
+            Some(allowed) => !allowed.is_disjoint(groups),
+        }
+    }
+
+    async fn execute(&self, command: ChatCommand, invoker: &Invoker) -> String {
+        match command {
+            ChatCommand::Volume(None) => {
+                let volume = self.discord_buffer.lock().await.get_global_volume();
+                format!("Discord volume: {:.0}%", volume * 100.0)
+            }
+            ChatCommand::Volume(Some(percent)) => {
+                let percent = percent.clamp(0.0, MAX_VOLUME_PERCENT);
+                self.discord_buffer.lock().await.set_global_volume(percent / 100.0);
+                format!("Discord volume set to {:.0}%", percent)
+            }
+            ChatCommand::Mute(muted) => {
+                self.feed_muted.store(muted, Ordering::Relaxed);
+                if muted {
+                    "Discord is muted for TeamSpeak.".to_string()
+                } else {
+                    "Discord is audible in TeamSpeak again.".to_string()
+                }
+            }
+            ChatCommand::Who => who_answer(&self.presence.members()),
+            ChatCommand::Echo => self.echo_test(invoker.id),
+            ChatCommand::Hand(raised) => self.hand(invoker, raised),
+            ChatCommand::Help => {
+                "Bridge commands: !volume [0-200 or -6dB], !mute, !unmute, !who, !echo, !hand [down], !help".to_string()
+            }
+            ChatCommand::Unknown(message) => format!("Unknown command {}, try !help", message),
+        }
+    }
+}
+
+fn who_answer(members: &[VoiceMember]) -> String {
+    if members.is_empty() {
+        return "Nobody is in the Discord voice channel.".to_string();
+    }
+    let names: Vec<_> = members
+        .iter()
+        .map(|member| {
+            match (member.speaking, member.muted, member.deafened) {
+                (true, _, _) => format!("{} (speaking)", member.name),
+                (false, _, true) => format!("{} (deafened)", member.name),
+                (false, true, false) => format!("{} (muted)", member.name),
+                (false, false, false) => member.name.clone(),
+            }
+        })
+        .collect();
+    format!("In Discord ({}): {}", members.len(), names.join(", "))
+}
+
+/// Answer in the chat a message came from, privately for private messages and pokes.
+fn reply(con: &mut Connection, target: MessageTarget, invoker: ClientId, message: &str, logger: &Logger) {
+    let target = match target {
+        MessageTarget::Server => MessageTarget::Server,
+        MessageTarget::Channel => MessageTarget::Channel,
+        MessageTarget::Client(_) | MessageTarget::Poke(_) => MessageTarget::Client(invoker),
+    };
+    if let Err(e) = send_text(con, target, message) {
+        warn!(logger, "Failed to answer chat command"; "error" => %e);
+    }
+}
+
+/// Write `message` to a TeamSpeak chat as the bridge.
+pub fn send_text(con: &mut Connection, target: MessageTarget, message: &str) -> Result<(), String> {
+    let result = match con.get_state() {
+        Ok(state) => state.send_message(target, message).send(con),
+        Err(e) => Err(e),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio::sync::Mutex;
+
+    use crate::audio::tests::logger;
+    use crate::discord_audiohandler::AudioHandler;
+
+    fn invoker(id: u16) -> Invoker {
+        Invoker { name: format!("client {}", id), id: ClientId(id), uid: None }
+    }
+
+    pub fn commands(allowed_groups: Option<Vec<u64>>) -> ChatCommands {
+        let buffer = Arc::new(Mutex::new(AudioHandler::new(logger())));
+        let presence = VoicePresence::new(Default::default());
+        ChatCommands::new(buffer, Arc::new(AtomicBool::new(false)), presence, allowed_groups, logger())
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(ChatCommand::parse("hello"), None);
+        assert_eq!(ChatCommand::parse("!"), None);
+        assert_eq!(ChatCommand::parse(" !Volume "), Some(ChatCommand::Volume(None)));
+        assert_eq!(ChatCommand::parse("!volume 80%"), Some(ChatCommand::Volume(Some(80.0))));
+        assert_eq!(ChatCommand::parse("!vol 0dB"), Some(ChatCommand::Volume(Some(100.0))));
+        assert_eq!(ChatCommand::parse("!mute"), Some(ChatCommand::Mute(true)));
+        assert_eq!(ChatCommand::parse("!unmute"), Some(ChatCommand::Mute(false)));
+        assert_eq!(ChatCommand::parse("!who"), Some(ChatCommand::Who));
+        assert_eq!(ChatCommand::parse("!echo"), Some(ChatCommand::Echo));
+        assert_eq!(ChatCommand::parse("!hand"), Some(ChatCommand::Hand(true)));
+        assert_eq!(ChatCommand::parse("!hand Down"), Some(ChatCommand::Hand(false)));
+        assert_eq!(ChatCommand::parse("!volume loud"), Some(ChatCommand::Unknown("!volume loud".into())));
+        assert_eq!(ChatCommand::parse("!volume NaN"), Some(ChatCommand::Unknown("!volume NaN".into())));
+    }
+
+    #[test]
+    fn server_groups_gate_commands() {
+        let groups: HashSet<_> = vec![ServerGroupId(7), ServerGroupId(8)].into_iter().collect();
+        assert!(commands(None).is_allowed(&HashSet::new()));
+        assert!(commands(Some(vec![6, 8])).is_allowed(&groups));
+        assert!(!commands(Some(vec![6])).is_allowed(&groups));
+        assert!(!commands(Some(vec![])).is_allowed(&groups));
+    }
+
+    #[tokio::test]
+    async fn volume_changes_the_discord_gain() {
+        let commands = commands(None);
+        commands.execute(ChatCommand::Volume(Some(50.0)), &invoker(1)).await;
+        assert_eq!(commands.discord_buffer.lock().await.get_global_volume(), 0.5);
+        commands.execute(ChatCommand::Volume(Some(1000.0)), &invoker(1)).await;
+        assert_eq!(commands.discord_buffer.lock().await.get_global_volume(), 2.0);
+        assert_eq!(commands.execute(ChatCommand::Volume(None), &invoker(1)).await, "Discord volume: 200%");
+    }
+
+    #[test]
+    fn who_lists_speaking_muted_and_deafened_members() {
+        let member = |name: &str, speaking, muted, deafened| VoiceMember {
+            id: 0,
+            name: name.into(),
+            speaking,
+            muted,
+            deafened,
+            bot: false,
+        };
+        assert_eq!(who_answer(&[]), "Nobody is in the Discord voice channel.");
+        assert_eq!(
+            who_answer(
+                &[
+                    member("Alice", true, false, false),
+                    member("Bob", false, true, false),
+                    member("Carol", false, false, false),
+                    member("Dave", false, true, true),
+                ]
+            ),
+            "In Discord (4): Alice (speaking), Bob (muted), Carol, Dave (deafened)"
+        );
+    }
+
+    #[tokio::test]
+    async fn mute_toggles_the_feed() {
+        let commands = commands(None);
+        commands.execute(ChatCommand::Mute(true), &invoker(1)).await;
+        assert!(commands.feed_muted.load(Ordering::Relaxed));
+        commands.execute(ChatCommand::Mute(false), &invoker(1)).await;
+        assert!(!commands.feed_muted.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn echo_test_keeps_the_invoker_from_discord() {
+        let without = commands(None);
+        assert_eq!(without.execute(ChatCommand::Echo, &invoker(1)).await, "The echo test is not available.");
+        assert!(!without.record_echo(ClientId(1), &[1]));
+
+        let commands = commands(None).with_echo_test(SoundFeed::new());
+        assert!(commands.execute(ChatCommand::Echo, &invoker(1)).await.starts_with("Recording you"));
+        assert_eq!(commands.execute(ChatCommand::Echo, &invoker(1)).await, "Already recording you.");
+        assert!(commands.record_echo(ClientId(1), &[1]));
+        assert!(!commands.record_echo(ClientId(2), &[1]));
+    }
+
+    #[tokio::test]
+    async fn hands_are_raised_in_order() {
+        let hands = HandQueue::default();
+        let commands = commands(None).with_hands(hands.clone());
+        assert_eq!(commands.execute(ChatCommand::Hand(true), &invoker(1)).await, "Your hand is raised, you are number 1 in line.");
+        assert_eq!(commands.execute(ChatCommand::Hand(true), &invoker(2)).await, "Your hand is raised, you are number 2 in line.");
+        assert_eq!(commands.execute(ChatCommand::Hand(false), &invoker(1)).await, "Your hand is down.");
+        assert_eq!(hands.hands(), vec![Hand { speaker: Speaker::Ts(ClientId(2)), name: "client 2".to_string() }]);
+    }
+}
@@ -0,0 +1,97 @@
+//! Who is in which Discord voice channel, kept from gateway events.
+//!
+//! Guild creates fill it and voice state updates keep it current, so `!who`,
+//! auto-activation and the session summary read it every second without
+//! locking the songbird calls.
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex as StdMutex };
+
+/// What is kept of a user in a voice channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedVoiceState {
+    pub channel: u64,
+    pub name: String,
+    /// Muted by themselves or the server.
+    pub muted: bool,
+    /// Deafened by themselves or the server.
+    pub deafened: bool,
+    pub bot: bool,
+}
+
+/// Voice states by guild and user, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct VoiceStates {
+    states: Arc<StdMutex<HashMap<(u64, u64), CachedVoiceState>>>,
+}
+
+impl VoiceStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(u64, u64), CachedVoiceState>> {
+        self.states.lock().expect("Can't lock voice states!")
+    }
+
+    /// Keep `state` of `user` in `guild`, `None` once they left voice.
+    pub fn update(&self, guild: u64, user: u64, state: Option<CachedVoiceState>) {
+        let mut states = self.lock();
+        match state {
+            Some(state) => {
+                states.insert((guild, user), state);
+            }
+            None => {
+                states.remove(&(guild, user));
+            }
+        }
+    }
+
+    /// Replace everything known of `guild`, as the gateway sent it whole.
+    pub fn replace_guild(&self, guild: u64, users: impl IntoIterator<Item = (u64, CachedVoiceState)>) {
+        let mut states = self.lock();
+        states.retain(|&(in_guild, _), _| in_guild != guild);
+        states.extend(users.into_iter().map(|(user, state)| ((guild, user), state)));
+    }
+
+    /// Users in the same voice channels as `own`, not `own` itself.
+    pub fn around(&self, own: u64) -> Vec<(u64, CachedVoiceState)> {
+        let states = self.lock();
+        let channels: Vec<_> = states
+            .iter()
+            .filter(|((_, user), _)| *user == own)
+            .map(|(&(guild, _), state)| (guild, state.channel))
+            .collect();
+        states
+            .iter()
+            .filter(|(&(guild, user), state)| user != own && channels.contains(&(guild, state.channel)))
+            .map(|(&(_, user), state)| (user, state.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(channel: u64, name: &str) -> CachedVoiceState {
+        CachedVoiceState { channel, name: name.to_string(), muted: false, deafened: false, bot: false }
+    }
+
+    #[test]
+    fn users_around_the_bridge_follow_updates() {
+        let states = VoiceStates::new();
+        states.replace_guild(1, vec![(100, state(10, "bridge")), (2, state(10, "Ann")), (3, state(11, "Bob"))]);
+        states.update(5, 4, Some(state(10, "elsewhere")));
+        assert_eq!(states.around(100), vec![(2, state(10, "Ann"))]);
+
+        states.update(1, 3, Some(CachedVoiceState { muted: true, ..state(10, "Bob") }));
+        states.update(1, 2, None);
+        assert_eq!(states.around(100), vec![(3, CachedVoiceState { muted: true, ..state(10, "Bob") })]);
+
+        states.update(1, 100, None);
+        assert!(states.around(100).is_empty());
+        states.replace_guild(1, vec![(100, state(11, "bridge"))]);
+        assert!(states.around(100).is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Resetting stuck Discord audio queues, what `/reset_audio` does by hand.
+//!
+//! Every check compares the handler's counters with the last check. Decode
+//! errors piling up, queues staying full or packets dropped check after check
+//! reset the queues. A reset that didn't help shouldn't be repeated every
+//! second, so the wait until the next one doubles each time, and falls back
+//! once the audio has been fine for a while.
+
+use std::fmt;
+use std::time::{ Duration, Instant };
+
+/// How often the handler is looked at.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Decode errors in one check that reset right away, about half the frames of a speaker.
+const DECODE_ERRORS: u64 = 25;
+/// Checks in a row with full queues or overflowing that reset.
+const STUCK_CHECKS: u32 = 5;
+/// Packets dropped in one check that count as overflowing.
+const DROPPED_PACKETS: u64 = 10;
+/// Wait after the first reset before another.
+const MIN_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Without a reset for this long the wait is back to [`MIN_BACKOFF`].
+const CALM_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Counters of the Discord audio handler at one check.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Health {
+    pub decode_errors: u64,
+    pub dropped_packets: u64,
+    pub full_queues: usize,
+}
+
+/// Why the queues were reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Packets that failed to decode in the last check.
+    DecodeErrors(u64),
+    /// Queues full for [`STUCK_CHECKS`] checks.
+    StuckFull(usize),
+    /// Packets dropped over the last [`STUCK_CHECKS`] checks.
+    Overflowing(u64),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::DecodeErrors(n) => write!(f, "{} packets failed to decode within a second", n),
+            Trigger::StuckFull(n) => write!(f, "{} speaker queues stayed full for {} seconds", n, STUCK_CHECKS),
+            Trigger::Overflowing(n) => write!(f, "{} packets were dropped over {} seconds", n, STUCK_CHECKS),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AudioWatchdog {
+    last: Option<Health>,
+    full_checks: u32,
+    overflow_checks: u32,
+    overflow_dropped: u64,
+    backoff: Duration,
+    last_reset: Option<Instant>,
+}
+
+impl Default for AudioWatchdog {
+    fn default() -> Self {
+        Self {
+            last: None,
+            full_checks: 0,
+            overflow_checks: 0,
+            overflow_dropped: 0,
+            backoff: MIN_BACKOFF,
+            last_reset: None,
+        }
+    }
+}
+
+impl AudioWatchdog {
+    /// Look at `health`, returns why to reset the queues if they should be.
+    pub fn check(&mut self, health: Health, now: Instant) -> Option<Trigger> {
+        let last = self.last.replace(health).unwrap_or(health);
+        // Counters restart with the handler
+        let decode_errors = health.decode_errors.saturating_sub(last.decode_errors);
+        let dropped = health.dropped_packets.saturating_sub(last.dropped_packets);
+
+        if health.full_queues > 0 {
+            self.full_checks += 1;
+        } else {
+            self.full_checks = 0;
+        }
+        if dropped >= DROPPED_PACKETS {
+            self.overflow_checks += 1;
+            self.overflow_dropped += dropped;
+        } else {
+            self.overflow_checks = 0;
+            self.overflow_dropped = 0;
+        }
+
+        let trigger = if decode_errors >= DECODE_ERRORS {
+            Trigger::DecodeErrors(decode_errors)
+        } else if self.full_checks >= STUCK_CHECKS {
+            Trigger::StuckFull(health.full_queues)
+        } else if self.overflow_checks >= STUCK_CHECKS {
+            Trigger::Overflowing(self.overflow_dropped)
+        } else {
+            return None;
+        };
+
+        if let Some(last_reset) = self.last_reset {
+            let since = now.saturating_duration_since(last_reset);
+            if since < self.backoff {
+                return None;
+            }
+            if since >= self.backoff + CALM_AFTER {
+                self.backoff = MIN_BACKOFF;
+            } else {
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        self.last_reset = Some(now);
+        self.full_checks = 0;
+        self.overflow_checks = 0;
+        self.overflow_dropped = 0;
+        Some(trigger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resets_with_growing_waits_in_between() {
+        let mut watchdog = AudioWatchdog::default();
+        let start = Instant::now();
+        let full = Health { full_queues: 2, ..Health::default() };
+        let mut resets = Vec::new();
+        for second in 0..120 {
+            let now = start + Duration::from_secs(second);
+            if let Some(trigger) = watchdog.check(full, now) {
+                assert_eq!(trigger, Trigger::StuckFull(2));
+                resets.push(second);
+            }
+        }
+        // After 5 s stuck, then 10 s, 20 s and 40 s apart
+        assert_eq!(resets, vec![4, 14, 34, 74]);
+
+        // Decode errors reset right away once the wait is over
+        let now = start + Duration::from_secs(74 + 80 + CALM_AFTER.as_secs());
+        let broken = Health { decode_errors: 30, ..Health::default() };
+        watchdog.check(Health::default(), now);
+        assert_eq!(watchdog.check(broken, now), Some(Trigger::DecodeErrors(30)));
+        assert_eq!(watchdog.backoff, MIN_BACKOFF);
+        assert_eq!(watchdog.check(broken, now), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Bridge events POSTed as JSON to configured URLs.
+//!
+//! Every webhook follows the event bus on its own, so a slow or failing
+//! service neither delays the others nor the bridge. Failed calls are logged
+//! and not retried.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::bridge_events::{ BridgeEvent, EventBus, EVENT_NAMES };
+
+/// How long a service may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `[[webhooks]]` entry.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Names of the events sent, all if unset.
+    pub events: Option<Vec<String>>,
+}
+
+impl WebhookConfig {
+    fn wants(&self, event: &BridgeEvent) -> bool {
+        self.events.as_ref().is_none_or(|events| events.iter().any(|name| name == event.name()))
+    }
+
+    /// Configured event names that don't exist, likely typos.
+    pub fn unknown_events(&self) -> Vec<&str> {
+        self.events
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .filter(|name| !EVENT_NAMES.contains(name))
+            .collect()
+    }
+}
+
+/// Send the events of `bus` to every one of `hooks`, from now on.
+pub fn start(hooks: &[WebhookConfig], bus: &EventBus) {
+    if hooks.is_empty() {
+        return;
+    }
+    let http = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(http) => http,
+        Err(e) => {
+            tracing::error!("Can't set up webhooks: {}", e);
+            return;
+        }
+    };
+    for hook in hooks {
+        let unknown = hook.unknown_events();
+        if !unknown.is_empty() {
+            tracing::warn!("Webhook {} names unknown events {:?}, known are {:?}", hook.url, unknown, EVENT_NAMES);
+        }
+        let (hook, http, mut events) = (hook.clone(), http.clone(), bus.subscribe());
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                if !hook.wants(&event) {
+                    continue;
+                }
+                let sent = http
+                    .post(&hook.url)
+                    .json(&event)
+                    .send().await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = sent {
+                    tracing::warn!("Webhook {} failed for {}: {}", hook.url, event.name(), e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_filtered_by_name() {
+        let events = [
+            BridgeEvent::TsConnected,
+            BridgeEvent::TsReconnecting,
+            BridgeEvent::DiscordJoined { guild_id: 1, channel_id: 2 },
+            BridgeEvent::AudioReset { reason: "stuck".to_string() },
+        ];
+        for event in &events {
+            assert_eq!(serde_json::to_value(event).unwrap()["event"], event.name());
+        }
+
+        let all = WebhookConfig { url: "http://localhost".to_string(), events: None };
+        assert!(events.iter().all(|event| all.wants(event)));
+        let some = WebhookConfig {
+            events: Some(vec!["ts_reconnecting".to_string(), "audio_rest".to_string()]),
+            ..all
+        };
+        assert!(some.wants(&events[1]));
+        assert!(!some.wants(&events[0]));
+        assert_eq!(some.unknown_events(), ["audio_rest"]);
+    }
+}